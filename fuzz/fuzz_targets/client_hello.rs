@@ -0,0 +1,12 @@
+//! Checks that peeking at arbitrary bytes as a ClientHello never panics.
+//!
+//! Run with `cargo fuzz run client_hello` from the repository root.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate linkerd2_proxy;
+
+fuzz_target!(|data: &[u8]| {
+    linkerd2_proxy::transport::tls::fuzz_client_hello(data);
+});
@@ -81,6 +81,9 @@ pub struct Config {
     pub control_connect_timeout: Duration,
 
     pub identity_config: tls::Conditional<identity::Config>,
+
+    /// How identity names are rendered in logs.
+    pub identity_log_policy: identity::LogPolicy,
     //
     // Destination Config
     //
@@ -159,6 +162,7 @@ pub enum ParseError {
     NameError,
     InvalidTokenSource,
     InvalidTrustAnchors,
+    NotALogPolicy,
 }
 
 /// The strings used to build a configuration.
@@ -248,10 +252,16 @@ pub const ENV_OUTBOUND_PORTS_DISABLE_PROTOCOL_DETECTION: &str =
 pub const ENV_IDENTITY_DISABLED: &str = "LINKERD2_PROXY_IDENTITY_DISABLED";
 pub const ENV_IDENTITY_DIR: &str = "LINKERD2_PROXY_IDENTITY_DIR";
 pub const ENV_IDENTITY_TRUST_ANCHORS: &str = "LINKERD2_PROXY_IDENTITY_TRUST_ANCHORS";
+/// Configures a PEM file from which trust anchors are reloaded at runtime,
+/// replacing those in `LINKERD2_PROXY_IDENTITY_TRUST_ANCHORS` once it's read.
+pub const ENV_IDENTITY_TRUST_ANCHORS_FILE: &str = "LINKERD2_PROXY_IDENTITY_TRUST_ANCHORS_FILE";
 pub const ENV_IDENTITY_IDENTITY_LOCAL_NAME: &str = "LINKERD2_PROXY_IDENTITY_LOCAL_NAME";
 pub const ENV_IDENTITY_TOKEN_FILE: &str = "LINKERD2_PROXY_IDENTITY_TOKEN_FILE";
 pub const ENV_IDENTITY_MIN_REFRESH: &str = "LINKERD2_PROXY_IDENTITY_MIN_REFRESH";
 pub const ENV_IDENTITY_MAX_REFRESH: &str = "LINKERD2_PROXY_IDENTITY_MAX_REFRESH";
+/// Configures how identity names are logged: either `full` (the default) or
+/// `redacted`, which logs each name's short id instead.
+pub const ENV_IDENTITY_LOG_POLICY: &str = "LINKERD2_PROXY_IDENTITY_LOG_POLICY";
 
 pub const ENV_IDENTITY_SVC_BASE: &str = "LINKERD2_PROXY_IDENTITY_SVC";
 
@@ -314,6 +324,7 @@ const DEFAULT_DESTINATION_PROFILE_SUFFIXES: &str = "svc.cluster.local.";
 
 const DEFAULT_IDENTITY_MIN_REFRESH: Duration = Duration::from_secs(10);
 const DEFAULT_IDENTITY_MAX_REFRESH: Duration = Duration::from_secs(60 * 60 * 24);
+const DEFAULT_IDENTITY_TRUST_ANCHORS_RELOAD: Duration = Duration::from_secs(10);
 
 // By default, we keep a list of known assigned ports of server-first protocols.
 //
@@ -400,6 +411,7 @@ impl Config {
             .unwrap_or(DEFAULT_CONTROL_CONNECT_TIMEOUT);
 
         let identity_config = parse_identity_config(strings);
+        let identity_log_policy = parse(strings, ENV_IDENTITY_LOG_POLICY, parse_log_policy);
 
         let id_disabled = identity_config
             .as_ref()
@@ -495,6 +507,7 @@ impl Config {
             identity_config: identity_config?
                 .map(Conditional::Some)
                 .unwrap_or_else(|| Conditional::None(tls::ReasonForNoIdentity::Disabled)),
+            identity_log_policy: identity_log_policy?.unwrap_or(identity::LogPolicy::Full),
 
             resolv_conf_path: resolv_conf_path?
                 .unwrap_or(DEFAULT_RESOLV_CONF.into())
@@ -620,6 +633,14 @@ pub(super) fn parse_identity(s: &str) -> Result<identity::Name, ParseError> {
     })
 }
 
+fn parse_log_policy(s: &str) -> Result<identity::LogPolicy, ParseError> {
+    match s.trim() {
+        "full" => Ok(identity::LogPolicy::Full),
+        "redacted" => Ok(identity::LogPolicy::Redacted),
+        _ => Err(ParseError::NotALogPolicy),
+    }
+}
+
 pub(super) fn parse<T, Parse>(
     strings: &Strings,
     name: &str,
@@ -727,7 +748,13 @@ pub fn parse_control_addr_disable_identity<S: Strings>(
 pub fn parse_identity_config<S: Strings>(strings: &S) -> Result<Option<identity::Config>, Error> {
     let sa = parse_control_addr(strings, ENV_IDENTITY_SVC_BASE);
     let ta = parse(strings, ENV_IDENTITY_TRUST_ANCHORS, |ref s| {
-        identity::TrustAnchors::from_pem(s).ok_or(ParseError::InvalidTrustAnchors)
+        identity::TrustAnchors::from_pem(s).map_err(|e| {
+            error!("Could not load {}: {}", ENV_IDENTITY_TRUST_ANCHORS, e);
+            ParseError::InvalidTrustAnchors
+        })
+    });
+    let ta_file = parse(strings, ENV_IDENTITY_TRUST_ANCHORS_FILE, |ref s| {
+        Ok(PathBuf::from(s))
     });
     let dir = parse(strings, ENV_IDENTITY_DIR, |ref s| Ok(PathBuf::from(s)));
     let tok = parse(strings, ENV_IDENTITY_TOKEN_FILE, |ref s| {
@@ -786,8 +813,8 @@ pub fn parse_identity_config<S: Strings>(strings: &S) -> Result<Option<identity:
                         error!("Failed to read key: {}", e);
                         Error::InvalidEnvVar
                     })
-                    .and_then(|b| {
-                        identity::Key::from_pkcs8(&b).map_err(|e| {
+                    .and_then(|mut b| {
+                        identity::Key::from_pkcs8_zeroizing(&mut b).map_err(|e| {
                             error!("Invalid key: {}", e);
                             Error::InvalidEnvVar
                         })
@@ -817,6 +844,8 @@ pub fn parse_identity_config<S: Strings>(strings: &S) -> Result<Option<identity:
                 local_name,
                 token,
                 trust_anchors,
+                trust_anchors_file: ta_file?,
+                trust_anchors_reload: DEFAULT_IDENTITY_TRUST_ANCHORS_RELOAD,
                 csr: csr?,
                 key: key?,
                 min_refresh: min_refresh.unwrap_or(DEFAULT_IDENTITY_MIN_REFRESH),
@@ -926,6 +955,16 @@ mod tests {
         assert_eq!(parse_duration("1"), Err(ParseError::NotADuration));
     }
 
+    #[test]
+    fn parse_log_policies() {
+        assert_eq!(parse_log_policy("full"), Ok(identity::LogPolicy::Full));
+        assert_eq!(
+            parse_log_policy(" redacted "),
+            Ok(identity::LogPolicy::Redacted)
+        );
+        assert_eq!(parse_log_policy("none"), Err(ParseError::NotALogPolicy));
+    }
+
     #[test]
     fn dns_suffixes() {
         fn p(s: &str) -> Result<Vec<String>, ParseError> {
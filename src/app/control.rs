@@ -6,7 +6,7 @@ use Addr;
 #[derive(Clone, Debug)]
 pub struct ControlAddr {
     pub addr: Addr,
-    pub identity: tls::PeerIdentity,
+    pub identity: tls::ServerIdentity,
 }
 
 impl fmt::Display for ControlAddr {
@@ -315,7 +315,7 @@ pub mod client {
     #[derive(Clone, Debug)]
     pub struct Target {
         pub(super) addr: SocketAddr,
-        pub(super) server_name: tls::PeerIdentity,
+        pub(super) server_name: tls::ServerIdentity,
         pub(super) log_ctx: ::logging::Client<&'static str, Addr>,
     }
 
@@ -340,6 +340,12 @@ pub mod client {
 
     impl tls::HasPeerIdentity for Target {
         fn peer_identity(&self) -> tls::PeerIdentity {
+            self.server_name.clone().map(tls::PeerId::Name)
+        }
+    }
+
+    impl tls::HasServerIdentity for Target {
+        fn server_identity(&self) -> tls::ServerIdentity {
             self.server_name.clone()
         }
     }
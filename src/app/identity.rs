@@ -1,5 +1,6 @@
-use futures::{Async, Future, Poll};
+use futures::{Async, Future, Poll, Stream};
 use futures_watch::{Store, Watch};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio_timer::{clock, Delay};
@@ -8,7 +9,10 @@ use tower_grpc::{self as grpc, generic::client::GrpcService, BoxBody};
 use api::identity as api;
 use never::Never;
 
-pub use identity::{Crt, CrtKey, Csr, InvalidName, Key, Name, TokenSource, TrustAnchors};
+pub use identity::{
+    Crt, CrtKey, Csr, InvalidCrt, InvalidName, Key, LogPolicy, Name, TokenSource, TrustAnchors,
+    TrustAnchorsStore,
+};
 use transport::tls;
 
 /// Configures the Identity service and local identity.
@@ -16,6 +20,10 @@ use transport::tls;
 pub struct Config {
     pub svc: super::control::ControlAddr,
     pub trust_anchors: TrustAnchors,
+    /// A PEM file from which `trust_anchors` are replaced at runtime, if any.
+    pub trust_anchors_file: Option<PathBuf>,
+    /// How often `trust_anchors_file` is checked for changes.
+    pub trust_anchors_reload: Duration,
     pub key: Key,
     pub csr: Csr,
     pub token: TokenSource,
@@ -29,7 +37,7 @@ pub struct Config {
 /// Updates dynamically as certificates are provisioned from the Identity service.
 #[derive(Clone, Debug)]
 pub struct Local {
-    trust_anchors: TrustAnchors,
+    trust_anchors: TrustAnchorsStore,
     name: Name,
     crt_key: Watch<Option<CrtKey>>,
 }
@@ -52,6 +60,7 @@ where
     config: Config,
     client: api::client::Identity<T>,
     crt_key: Store<Option<CrtKey>>,
+    trust_anchors: TrustAnchorsStore,
     expiry: SystemTime,
     inner: Inner<T>,
 }
@@ -98,7 +107,7 @@ impl Local {
         let (w, s) = Watch::new(None);
         let l = Local {
             name: config.local_name.clone(),
-            trust_anchors: config.trust_anchors.clone(),
+            trust_anchors: TrustAnchorsStore::new(config.trust_anchors.clone()),
             crt_key: w,
         };
         (l, s)
@@ -108,6 +117,35 @@ impl Local {
         &self.name
     }
 
+    /// Returns the trust anchors that certified keys are verified with.
+    ///
+    /// Keys are certified with the store's current trust anchors, and their
+    /// server configs verify clients with whichever trust anchors are current
+    /// when each connection is accepted, so replacing the trust anchors takes
+    /// effect without a restart.
+    pub fn trust_anchors(&self) -> &TrustAnchorsStore {
+        &self.trust_anchors
+    }
+
+    /// Returns a future that replaces the trust anchors whenever
+    /// `config.trust_anchors_file` changes, or `None` if no file is
+    /// configured.
+    pub fn watch_trust_anchors(
+        &self,
+        config: &Config,
+    ) -> Option<impl Future<Item = (), Error = ()> + Send + 'static> {
+        let path = config.trust_anchors_file.clone()?;
+        let store = self.trust_anchors.clone();
+        let watch = TrustAnchors::watch_file(path, config.trust_anchors_reload)
+            .map_err(|_| ())
+            .for_each(move |anchors| {
+                debug!("replacing trust anchors");
+                store.set(anchors);
+                Ok(())
+            });
+        Some(watch)
+    }
+
     pub fn await_crt(self) -> AwaitCrt {
         AwaitCrt(Some(self))
     }
@@ -119,7 +157,11 @@ impl tls::client::HasConfig for Local {
             return c.tls_client_config();
         }
 
-        self.trust_anchors.tls_client_config()
+        self.trust_anchors.current().tls_client_config()
+    }
+
+    fn tls_client_name(&self) -> Option<Name> {
+        self.crt_key.borrow().as_ref().map(|c| c.name().clone())
     }
 }
 
@@ -135,6 +177,23 @@ impl tls::listen::HasConfig for Local {
 
         tls::listen::empty_config()
     }
+
+    fn tls_client_auth(&self) -> tls::listen::ClientAuth {
+        if let Some(ref c) = *self.crt_key.borrow() {
+            return c.tls_client_auth();
+        }
+
+        // The empty config rejects all handshakes, so this is moot.
+        tls::listen::ClientAuth::Optional
+    }
+
+    fn tls_unavailable(&self) -> Option<tls::ReasonForNoIdentity> {
+        if self.crt_key.borrow().is_some() {
+            return None;
+        }
+
+        Some(tls::ReasonForNoIdentity::TrustAnchorsUnavailable)
+    }
 }
 
 // === impl Daemon ===
@@ -143,10 +202,16 @@ impl<T> Daemon<T>
 where
     T: GrpcService<BoxBody> + Clone,
 {
-    pub fn new(config: Config, crt_key: CrtKeyStore, client: T) -> Self {
+    pub fn new(
+        config: Config,
+        crt_key: CrtKeyStore,
+        trust_anchors: TrustAnchorsStore,
+        client: T,
+    ) -> Self {
         Self {
             config,
             crt_key,
+            trust_anchors,
             inner: Inner::ShouldRefresh,
             expiry: UNIX_EPOCH,
             client: api::client::Identity::new(client),
@@ -213,14 +278,15 @@ where
                                 ),
                                 Some(expiry) => {
                                     let key = self.config.key.clone();
-                                    let crt = Crt::new(
+                                    let certified = Crt::new(
                                         self.config.local_name.clone(),
                                         leaf_certificate,
                                         intermediate_certificates,
                                         expiry,
-                                    );
+                                    )
+                                    .and_then(|crt| certify(&self.trust_anchors, key, crt));
 
-                                    match self.config.trust_anchors.certify(key, crt) {
+                                    match certified {
                                         Err(e) => {
                                             error!("Received invalid ceritficate: {}", e);
                                         }
@@ -251,6 +317,13 @@ where
     }
 }
 
+/// Certifies `key` with the current trust anchors, so that the key's server
+/// config follows later changes to them.
+fn certify(store: &TrustAnchorsStore, key: Key, crt: Crt) -> Result<CrtKey, InvalidCrt> {
+    let crt_key = store.current().certify(key, crt)?;
+    Ok(crt_key.with_trust_anchors_store(store))
+}
+
 // === impl AwaitCrt ===
 
 impl Future for AwaitCrt {
@@ -258,8 +331,6 @@ impl Future for AwaitCrt {
     type Error = LostDaemon;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        use futures::Stream;
-
         let mut local = self.0.take().expect("polled after ready");
         loop {
             if (*local.crt_key.borrow()).is_some() {
@@ -277,3 +348,82 @@ impl Future for AwaitCrt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::{env, fs, process};
+
+    use super::*;
+    use addr::Addr;
+    use identity::test_util::*;
+    use transport::tls::client::HasConfig as _ClientHasConfig;
+    use transport::tls::listen::HasConfig;
+    use Conditional;
+
+    fn config(test: &str) -> Config {
+        let token = env::temp_dir().join(format!("linkerd2-proxy-{}-{}", process::id(), test));
+        fs::write(&token, "token").unwrap();
+        let config = Config {
+            svc: super::super::control::ControlAddr {
+                addr: Addr::from_str("identity.linkerd:8080").expect("addr must be valid"),
+                identity: Conditional::None(tls::ReasonForNoIdentity::Disabled),
+            },
+            trust_anchors: FOO_NS1.trust_anchors().with_clock(FixedClock(valid_time())),
+            trust_anchors_file: None,
+            trust_anchors_reload: Duration::from_secs(1),
+            key: FOO_NS1.key(),
+            csr: Csr::from_der(vec![0]).expect("csr must be valid"),
+            token: TokenSource::if_nonempty_file(token.to_str().unwrap().into())
+                .expect("token must be valid"),
+            local_name: FOO_NS1.name(),
+            min_refresh: Duration::from_secs(1),
+            max_refresh: Duration::from_secs(1),
+        };
+        fs::remove_file(&token).unwrap();
+        config
+    }
+
+    #[test]
+    fn identity_is_unavailable_until_certified() {
+        // While the proxy is starting, it has no certificate.
+        let (local, mut store) = Local::new(&config("identity-unavailable"));
+        assert_eq!(
+            local.tls_unavailable(),
+            Some(tls::ReasonForNoIdentity::TrustAnchorsUnavailable)
+        );
+
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        store.store(Some(crt_key)).expect("local must be watching");
+        assert_eq!(local.tls_unavailable(), None);
+    }
+
+    #[test]
+    fn trust_anchors_are_replaced_without_restart() {
+        let config = config("trust-anchors-replaced");
+        let (local, mut store) = Local::new(&config);
+        assert!(
+            local.watch_trust_anchors(&config).is_none(),
+            "no file is configured"
+        );
+
+        let crt_key = certify(local.trust_anchors(), FOO_NS1.key(), FOO_NS1.crt())
+            .expect("foo.ns1 must be valid");
+        store.store(Some(crt_key)).expect("local must be watching");
+
+        // The client presents a certificate issued by CA2.
+        let both = FOO_NS1
+            .trust_anchors()
+            .merge(&FOO_NS1_CA2.trust_anchors())
+            .with_clock(FixedClock(valid_time()));
+        let client = both
+            .certify(FOO_NS1_CA2.key(), FOO_NS1_CA2.crt())
+            .expect("foo.ns1 must be valid")
+            .tls_client_config();
+        assert!(handshake(&client, &local.tls_server_config(), &FOO_NS1.name()).is_err());
+
+        local.trust_anchors().set(both);
+        handshake(&client, &local.tls_server_config(), &FOO_NS1.name())
+            .expect("CA2 must be trusted once set");
+    }
+}
@@ -49,6 +49,12 @@ impl tls::HasPeerIdentity for Endpoint {
     }
 }
 
+impl tls::HasServerIdentity for Endpoint {
+    fn server_identity(&self) -> tls::ServerIdentity {
+        Conditional::None(tls::ReasonForNoPeerName::Loopback.into())
+    }
+}
+
 impl classify::CanClassify for Endpoint {
     type Classify = classify::Request;
 
@@ -65,7 +71,7 @@ impl tap::Inspect for Endpoint {
     fn src_tls<'a, B>(
         &self,
         req: &'a http::Request<B>,
-    ) -> Conditional<&'a identity::Name, tls::ReasonForNoIdentity> {
+    ) -> Conditional<&'a tls::PeerId, tls::ReasonForNoIdentity> {
         req.extensions()
             .get::<Source>()
             .map(|s| s.tls_peer.as_ref())
@@ -288,7 +294,7 @@ pub mod set_client_id_on_req {
     pub fn layer() -> Layer<&'static str, Source, ReqHeader> {
         add_header::request::layer(L5D_CLIENT_ID, |source: &Source| {
             if let Conditional::Some(ref id) = source.tls_peer {
-                match HeaderValue::from_str(id.as_ref()) {
+                match HeaderValue::from_str(&id.to_string()) {
                     Ok(value) => {
                         debug!("l5d-client-id enabled for {:?}", source);
                         return Some(value);
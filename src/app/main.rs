@@ -82,6 +82,7 @@ where
     {
         let start_time = SystemTime::now();
 
+        identity::LogPolicy::set_current(config.identity_log_policy);
         let identity = config.identity_config.as_ref().map(identity::Local::new);
         let local_identity = identity.as_ref().map(|(l, _)| l.clone());
 
@@ -253,6 +254,7 @@ where
             .and_then(telemetry::process::Report::new(start_time));
 
         let mut identity_daemon = None;
+        let mut trust_anchors_watch = None;
         let (readiness, ready_latch) = Readiness::new();
         let local_identity = match identity {
             Conditional::None(r) => {
@@ -296,7 +298,14 @@ where
                     .make(&id_config.svc)
                     .unwrap_or_else(|e| panic!("failed to build dst_svc: {}", e));
 
-                identity_daemon = Some(identity::Daemon::new(id_config, crt_store, svc));
+                trust_anchors_watch = local_identity.watch_trust_anchors(&id_config);
+                let trust_anchors = local_identity.trust_anchors().clone();
+                identity_daemon = Some(identity::Daemon::new(
+                    id_config,
+                    crt_store,
+                    trust_anchors,
+                    svc,
+                ));
 
                 task::spawn(
                     local_identity
@@ -304,7 +313,10 @@ where
                         .await_crt()
                         .map(move |id| {
                             ready_latch.release();
-                            info!("Certified identity: {}", id.name().as_ref());
+                            info!(
+                                "Certified identity: {}",
+                                id.name().log(identity::LogPolicy::current())
+                            );
                         })
                         .map_err(|_| {
                             // The daemon task was lost?!
@@ -387,6 +399,10 @@ where
                         );
                     }
 
+                    if let Some(w) = trust_anchors_watch {
+                        rt.spawn(::logging::admin().bg("trust-anchors").future(w));
+                    }
+
                     let shutdown = admin_shutdown_signal.then(|_| Ok::<(), ()>(()));
                     rt.block_on(shutdown).expect("admin");
                     trace!("admin shutdown finished");
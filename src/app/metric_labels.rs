@@ -36,7 +36,7 @@ enum Direction {
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 enum TlsId {
-    ClientId(identity::Name),
+    ClientId(tls::PeerId),
     ServerId(identity::Name),
 }
 
@@ -204,10 +204,13 @@ impl fmt::Display for classify::SuccessOrFailure {
 impl FmtLabels for tls::Status {
     fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Conditional::None(tls::ReasonForNoIdentity::NoPeerName(why)) => {
-                write!(f, "tls=\"no_identity\",no_tls_reason=\"{}\"", why)
-            }
-            status => write!(f, "tls=\"{}\"", status),
+            Conditional::None(tls::ReasonForNoIdentity::NoPeerName(why)) => write!(
+                f,
+                "tls=\"{}\",no_tls_reason=\"{}\"",
+                tls::tls_status_label(self),
+                why.as_str()
+            ),
+            status => write!(f, "tls=\"{}\"", tls::tls_status_label(status)),
         }
     }
 }
@@ -215,7 +218,7 @@ impl FmtLabels for tls::Status {
 impl FmtLabels for TlsId {
     fn fmt_labels(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            TlsId::ClientId(ref id) => write!(f, "client_id=\"{}\"", id.as_ref()),
+            TlsId::ClientId(ref id) => write!(f, "client_id=\"{}\"", id),
             TlsId::ServerId(ref id) => write!(f, "server_id=\"{}\"", id.as_ref()),
         }
     }
@@ -13,7 +13,7 @@ use {Conditional, NameAddr};
 pub struct Endpoint {
     pub dst_name: Option<NameAddr>,
     pub addr: SocketAddr,
-    pub identity: tls::PeerIdentity,
+    pub identity: tls::ServerIdentity,
     pub metadata: Metadata,
 }
 
@@ -56,6 +56,12 @@ impl hash::Hash for Endpoint {
 
 impl tls::HasPeerIdentity for Endpoint {
     fn peer_identity(&self) -> tls::PeerIdentity {
+        self.identity.clone().map(tls::PeerId::Name)
+    }
+}
+
+impl tls::HasServerIdentity for Endpoint {
+    fn server_identity(&self) -> tls::ServerIdentity {
         self.identity.clone()
     }
 }
@@ -76,7 +82,7 @@ impl tap::Inspect for Endpoint {
     fn src_tls<'a, B>(
         &self,
         _: &'a http::Request<B>,
-    ) -> Conditional<&'a identity::Name, tls::ReasonForNoIdentity> {
+    ) -> Conditional<&'a tls::PeerId, tls::ReasonForNoIdentity> {
         Conditional::None(tls::ReasonForNoPeerName::Loopback.into())
     }
 
@@ -0,0 +1,131 @@
+//! Verifies signed certificate timestamps (SCTs) that are embedded in
+//! certificates, as described in RFC 6962.
+//!
+//! The `sct` crate verifies SCTs that were issued for a certificate, as TLS
+//! servers and `Crt::with_sct` provide them. Embedded SCTs are issued for the
+//! certificate's precertificate instead, so they're signed over the
+//! precertificate's `TBSCertificate` and the issuer's key, and are verified
+//! here.
+
+use super::{ring, sct, untrusted};
+
+use self::sct::Error;
+
+/// The `digitally-signed` algorithms, by hash and signature algorithm, that
+/// logs may sign with.
+static SIGNATURE_ALGS: &[(u8, u8, &dyn ring::signature::VerificationAlgorithm)] = &[
+    (4, 3, &ring::signature::ECDSA_P256_SHA256_ASN1),
+    (4, 1, &ring::signature::RSA_PKCS1_2048_8192_SHA256),
+];
+
+/// The fields of an SCT.
+struct Sct<'a> {
+    log_id: &'a [u8],
+    timestamp: u64,
+    extensions: &'a [u8],
+    hash_alg: u8,
+    signature_alg: u8,
+    signature: &'a [u8],
+}
+
+/// Reads the SCTs in a TLS-encoded `SignedCertificateTimestampList`.
+pub(super) fn read_list(list: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    untrusted::Input::from(list).read_all(Error::MalformedSCT, |r| {
+        let list = read_u16_prefixed(r)?;
+        list.read_all(Error::MalformedSCT, |r| {
+            let mut scts = Vec::new();
+            while !r.at_end() {
+                scts.push(read_u16_prefixed(r)?.as_slice_less_safe());
+            }
+            Ok(scts)
+        })
+    })
+}
+
+/// Verifies an embedded SCT as of `now_ms`, the milliseconds since the Unix
+/// epoch, returning the index of the log that issued it.
+///
+/// `precert_tbs` is the DER encoding of the `TBSCertificate` of the
+/// certificate's precertificate, and `issuer_spki` is the DER encoding of
+/// the `SubjectPublicKeyInfo` of the certificate's issuer.
+pub(super) fn verify_embedded(
+    sct: &[u8],
+    precert_tbs: &[u8],
+    issuer_spki: &[u8],
+    now_ms: u64,
+    logs: &[&sct::Log],
+) -> Result<usize, Error> {
+    let sct = untrusted::Input::from(sct).read_all(Error::MalformedSCT, read_sct)?;
+    let log = logs
+        .iter()
+        .position(|l| l.id[..] == *sct.log_id)
+        .ok_or(Error::UnknownLog)?;
+    let alg = SIGNATURE_ALGS
+        .iter()
+        .find(|&&(h, s, _)| h == sct.hash_alg && s == sct.signature_alg)
+        .map(|&(_, _, alg)| alg)
+        .ok_or(Error::InvalidSignature)?;
+
+    let issuer_key_hash = ring::digest::digest(&ring::digest::SHA256, issuer_spki);
+    let mut signed = Vec::with_capacity(47 + precert_tbs.len() + sct.extensions.len());
+    // The version and the `certificate_timestamp` signature type.
+    signed.extend_from_slice(&[0, 0]);
+    signed.extend_from_slice(&sct.timestamp.to_be_bytes());
+    // The `precert_entry` entry type.
+    signed.extend_from_slice(&[0, 1]);
+    signed.extend_from_slice(issuer_key_hash.as_ref());
+    let len = precert_tbs.len();
+    signed.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    signed.extend_from_slice(precert_tbs);
+    let len = sct.extensions.len();
+    signed.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+    signed.extend_from_slice(sct.extensions);
+
+    ring::signature::verify(
+        alg,
+        untrusted::Input::from(logs[log].key),
+        untrusted::Input::from(&signed),
+        untrusted::Input::from(sct.signature),
+    )
+    .map_err(|_| Error::InvalidSignature)?;
+
+    if sct.timestamp > now_ms {
+        return Err(Error::TimestampInFuture);
+    }
+    Ok(log)
+}
+
+fn read_sct<'a>(r: &mut untrusted::Reader<'a>) -> Result<Sct<'a>, Error> {
+    if read_byte(r)? != 0 {
+        return Err(Error::UnsupportedSCTVersion);
+    }
+    let log_id = r
+        .skip_and_get_input(32)
+        .map_err(|_| Error::MalformedSCT)?
+        .as_slice_less_safe();
+    let mut timestamp = 0;
+    for _ in 0..8 {
+        timestamp = (timestamp << 8) | u64::from(read_byte(r)?);
+    }
+    let extensions = read_u16_prefixed(r)?.as_slice_less_safe();
+    let hash_alg = read_byte(r)?;
+    let signature_alg = read_byte(r)?;
+    let signature = read_u16_prefixed(r)?.as_slice_less_safe();
+    Ok(Sct {
+        log_id,
+        timestamp,
+        extensions,
+        hash_alg,
+        signature_alg,
+        signature,
+    })
+}
+
+fn read_u16_prefixed<'a>(r: &mut untrusted::Reader<'a>) -> Result<untrusted::Input<'a>, Error> {
+    let len = (usize::from(read_byte(r)?) << 8) | usize::from(read_byte(r)?);
+    r.skip_and_get_input(len).map_err(|_| Error::MalformedSCT)
+}
+
+fn read_byte(r: &mut untrusted::Reader) -> Result<u8, Error> {
+    r.read_byte().map_err(|_| Error::MalformedSCT)
+}
@@ -0,0 +1,106 @@
+#![allow(dead_code)] // Not yet used by the proxy.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use super::{InvalidName, LogPolicy, Name};
+
+/// Shares `Name`s that are parsed repeatedly, e.g. from the SNI of each
+/// accepted connection, so that equal names share one allocation.
+///
+/// At most `capacity` names are cached. Since names may be chosen by peers,
+/// names parsed once the interner is full are returned without being cached,
+/// rather than evicting names that are already shared.
+#[derive(Clone, Debug)]
+pub struct NameInterner {
+    names: Arc<RwLock<HashMap<Vec<u8>, Name>>>,
+    capacity: usize,
+}
+
+// === impl NameInterner ===
+
+impl NameInterner {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            names: Arc::new(RwLock::new(HashMap::new())),
+            capacity,
+        }
+    }
+
+    /// Like `Name::from_hostname`, but returns the cached `Name` for
+    /// `hostname` if there is one.
+    ///
+    /// Hostnames are only validated when they aren't already cached.
+    pub fn from_hostname(&self, hostname: &[u8]) -> Result<Name, InvalidName> {
+        // As with `CrtRegistry`, a poisoned lock still holds a valid value.
+        {
+            let names = self.names.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(name) = names.get(hostname) {
+                return Ok(name.clone());
+            }
+        }
+
+        let name = Name::from_hostname(hostname)?;
+        let mut names = self.names.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(name) = names.get(hostname) {
+            // Another caller interned the name first.
+            return Ok(name.clone());
+        }
+        if names.len() < self.capacity {
+            names.insert(hostname.to_vec(), name.clone());
+        } else {
+            trace!(
+                "name interner is full; not caching {}",
+                name.log(LogPolicy::current())
+            );
+        }
+        Ok(name)
+    }
+
+    /// Returns the number of cached names.
+    pub fn len(&self) -> usize {
+        self.names.read().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Returns true if no names are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_equal_names() {
+        let interner = NameInterner::new(2);
+        let intern = |n: &str| {
+            interner
+                .from_hostname(n.as_bytes())
+                .expect("name must be valid")
+        };
+
+        let foo = intern("foo.ns1.svc.cluster.local");
+        let foo2 = intern("foo.ns1.svc.cluster.local");
+        assert!(Arc::ptr_eq(&foo.0, &foo2.0));
+
+        let bar = intern("bar.ns1.svc.cluster.local");
+        assert_ne!(foo, bar);
+        assert!(!Arc::ptr_eq(&foo.0, &bar.0));
+        assert_eq!(interner.len(), 2);
+
+        // Once the interner is full, names are still parsed but not cached.
+        let baz = intern("baz.ns1.svc.cluster.local");
+        let baz2 = intern("baz.ns1.svc.cluster.local");
+        assert_eq!(baz, baz2);
+        assert!(!Arc::ptr_eq(&baz.0, &baz2.0));
+        assert_eq!(interner.len(), 2);
+
+        assert!(interner.from_hostname(b"not a name!").is_err());
+        assert!(interner
+            .from_hostname(b"foo.ns1.svc.cluster.local.")
+            .is_err());
+        assert_eq!(interner.len(), 2);
+    }
+}
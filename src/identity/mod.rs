@@ -1,31 +1,64 @@
+extern crate aes;
+extern crate base64;
+extern crate block_modes;
 extern crate ring;
 extern crate rustls;
+extern crate sct;
 extern crate tokio_rustls;
 extern crate untrusted;
+extern crate webpki;
 
+use self::block_modes::block_padding::Pkcs7;
+use self::block_modes::{BlockMode, Cbc};
 use self::ring::rand;
 use self::ring::signature::EcdsaKeyPair;
-use std::error::Error;
-use std::sync::Arc;
-use std::time::SystemTime;
-use std::{fmt, fs, io};
+use indexmap::IndexSet;
+use std::error::Error as StdError;
+use std::path::PathBuf;
+use std::sync::{atomic, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{cmp, fmt, fs, hash, io, ptr};
 
-pub use self::ring::error::KeyRejected;
+pub use self::ring::error::{KeyRejected, Unspecified};
 
 use convert::TryFrom;
 use dns;
+use futures::{Async, Future, Poll};
+use tokio_timer::{clock, Delay};
 use transport::tls;
 
+mod ct;
+mod intern;
+mod registry;
+#[cfg(feature = "serde")]
+mod serialize;
 #[cfg(test)]
 pub mod test_util;
+mod watch;
+mod x509;
 
 pub use dns::InvalidName;
 
+pub use self::intern::NameInterner;
+pub use self::registry::{CrtKeyStore, CrtRegistry, CrtRegistryConfig};
+pub use self::watch::WatchFile;
+
 pub trait LocalIdentity {
     fn name(&self) -> &Name;
     fn credentials(&self) -> Option<&CrtKey>;
 }
 
+/// A source of the current time for certificate validity checks.
+///
+/// This allows tests to control time rather than reading the system clock.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// A `Clock` that reads the system clock.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
 /// A DER-encoded X.509 certificate signing request.
 #[derive(Clone, Debug)]
 pub struct Csr(Arc<Vec<u8>>);
@@ -34,46 +67,559 @@ pub struct Csr(Arc<Vec<u8>>);
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub struct Name(Arc<dns::Name>);
 
+/// A SPIFFE ID, e.g. `spiffe://cluster.local/ns/default/sa/web`, which
+/// identifies a workload by its trust domain and a path within it.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SpiffeId {
+    trust_domain: String,
+    path: String,
+}
+
+/// Limits the names accepted by `Name::from_hostname_with_policy`, e.g. to
+/// reject absurdly deep names sent by untrusted peers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NamePolicy {
+    /// The maximum length of the name, in octets.
+    pub max_len: usize,
+
+    /// The maximum number of labels in the name.
+    pub max_labels: usize,
+}
+
+/// Controls how identity names are rendered in logs.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogPolicy {
+    /// Names are logged in full.
+    Full,
+
+    /// Names are logged as their `Name::short_id`, so that log lines may be
+    /// correlated without exposing the identity itself.
+    Redacted,
+}
+
+/// Whether the process-wide `LogPolicy` is `LogPolicy::Redacted`.
+static LOG_REDACTED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// Renders a `Name` as permitted by a `LogPolicy`.
+#[derive(Copy, Clone, Debug)]
+pub struct LogName<'a> {
+    name: &'a Name,
+    policy: LogPolicy,
+}
+
+/// A private key used to sign TLS handshakes.
+///
+/// `Key::from_pkcs8_zeroizing` zeroes the PKCS#8 buffer it is given once the
+/// key has been parsed, so the encoded key does not linger in memory. The key
+/// pair itself is owned by `ring`, which does not zeroize its internal copy of
+/// the private scalar when the last reference is dropped.
 #[derive(Clone, Debug)]
-pub struct Key(Arc<EcdsaKeyPair>);
+pub struct Key(Arc<EcdsaKeyPair>, SignatureAlg);
+
+struct SigningKey {
+    key: Key,
 
-struct SigningKey(Arc<EcdsaKeyPair>);
-struct Signer(Arc<EcdsaKeyPair>);
+    /// Counts the signatures made with `key`, and is shared with each of its
+    /// signers.
+    signatures: Arc<atomic::AtomicUsize>,
+
+    /// The source of ECDSA nonces.
+    ///
+    /// This is `SystemRandom` except in tests, which may need signatures to
+    /// be reproducible.
+    rng: Arc<dyn rand::SecureRandom + Send + Sync>,
+}
+
+struct Signer {
+    key: Key,
+    signatures: Arc<atomic::AtomicUsize>,
+    rng: Arc<dyn rand::SecureRandom + Send + Sync>,
+}
+
+/// Pairs a `ring` signing algorithm with the Rustls scheme it implements.
+#[derive(Copy, Clone)]
+struct SignatureAlg {
+    ring: &'static ring::signature::EcdsaSigningAlgorithm,
+    rustls: rustls::SignatureScheme,
+    curve: x509::Curve,
+}
 
 #[derive(Clone)]
-pub struct TrustAnchors(Arc<rustls::ClientConfig>);
+pub struct TrustAnchors {
+    config: Arc<rustls::ClientConfig>,
+
+    /// The roots in `config`'s root store, in the same order, as they were
+    /// loaded, since the root store doesn't retain them.
+    roots: Arc<Vec<rustls::Certificate>>,
+    duplicate_crts: DuplicateCrtPolicy,
+    client_auth: tls::listen::ClientAuth,
+    crls: Arc<Vec<x509::Crl>>,
+    ct_logs: Option<&'static [&'static sct::Log<'static>]>,
+
+    /// Used in place of the intermediates of certificates that have none.
+    intermediates: Option<IntermediateBundle>,
+
+    /// The most certificates, including the leaf, that a certified chain
+    /// may include.
+    max_chain_len: usize,
+
+    /// The source of the time that certificates are verified as of, by
+    /// `certify` and in TLS handshakes.
+    clock: Arc<dyn Clock + Send + Sync>,
+
+    /// Verifies certificates for `certify`, for TLS clients configured by
+    /// these trust anchors, and, as `client_auth` requires, for TLS servers.
+    ///
+    /// These are built whenever the roots, revocation lists, or client
+    /// authentication policy change, rather than for each certified key,
+    /// since building them copies the roots.
+    verifier: Arc<Verifier>,
+    client_verifier: Arc<dyn rustls::ClientCertVerifier>,
+}
+
+/// Holds the current trust anchors, which may be replaced at runtime.
+///
+/// Clones share the same trust anchors.
+#[derive(Clone, Debug)]
+pub struct TrustAnchorsStore(Arc<RwLock<StoredAnchors>>);
+
+#[derive(Debug)]
+struct StoredAnchors {
+    anchors: TrustAnchors,
+
+    /// Incremented by each `set`, so that values built from the trust
+    /// anchors can tell when they're stale.
+    generation: usize,
+}
+
+/// Holds a value built from a `TrustAnchorsStore`'s trust anchors, which is
+/// only rebuilt once the trust anchors are replaced.
+struct StoreCache<T> {
+    store: TrustAnchorsStore,
+    build: Box<dyn Fn(&TrustAnchors) -> T + Send + Sync>,
+    cached: RwLock<(usize, T)>,
+}
+
+/// Verifies client certificates with the trust anchors that are current when
+/// each certificate is presented.
+struct StoreVerifier(StoreCache<Arc<dyn rustls::ClientCertVerifier>>);
+
+/// Determines how `TrustAnchors::certify` handles a chain that includes the
+/// same certificate more than once.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateCrtPolicy {
+    /// Duplicate certificates are removed from the chain before it is
+    /// verified.
+    Dedupe,
+
+    /// Chains including duplicate certificates are rejected.
+    Reject,
+}
 
 #[derive(Clone, Debug)]
 pub struct TokenSource(Arc<String>);
 
+/// Loads a token, retrying while it is unavailable.
+///
+/// Returned by `TokenSource::load_with_retry`.
+#[derive(Debug)]
+pub struct LoadWithRetry {
+    source: TokenSource,
+    attempt: usize,
+    attempts: usize,
+    backoff: Duration,
+    delay: Option<Delay>,
+}
+
+/// The period during which a certificate is valid, from its notBefore to its
+/// notAfter time, inclusive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Validity {
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+/// Intermediate certificates that are appended to certificates issued
+/// without any, as configured by `TrustAnchors::with_intermediates`.
+///
+/// This allows an authority that only issues leaf certificates to rely on
+/// intermediates that the proxy already holds.
+#[derive(Clone, Debug)]
+pub struct IntermediateBundle(Arc<[rustls::Certificate]>);
+
 #[derive(Clone, Debug)]
 pub struct Crt {
     name: Name,
     expiry: SystemTime,
-    chain: Vec<rustls::Certificate>,
+    leaf: rustls::Certificate,
+
+    /// Shared, so that certificates issued through the same intermediates
+    /// may store them once.
+    intermediates: Arc<[rustls::Certificate]>,
+    ocsp: Option<Vec<u8>>,
+    scts: Vec<Vec<u8>>,
 }
 
 #[derive(Clone)]
 pub struct CrtKey {
     name: Name,
     expiry: SystemTime,
+    scheme: rustls::SignatureScheme,
+    client_auth: tls::listen::ClientAuth,
+    resolver: Arc<CertResolver>,
     client_config: Arc<rustls::ClientConfig>,
     server_config: Arc<rustls::ServerConfig>,
+
+    /// Replaces `server_config` once the key is `with_trust_anchors_store`.
+    store_configs: Option<Arc<StoreCache<Arc<rustls::ServerConfig>>>>,
+    signatures: Arc<atomic::AtomicUsize>,
+}
+
+/// Presents whichever of several keys, all certified for the same name, each
+/// TLS client supports, so that one listener may serve clients that support
+/// different curves.
+///
+/// When a client supports more than one of the keys, the key with the
+/// strongest curve is presented.
+#[allow(dead_code)] // Not yet used by the proxy.
+#[derive(Clone)]
+pub struct CrtKeys {
+    name: Name,
+    client_auth: tls::listen::ClientAuth,
+    server_config: Arc<rustls::ServerConfig>,
+    store_configs: Option<Arc<StoreCache<Arc<rustls::ServerConfig>>>>,
+}
+
+/// Presents the certificate of the first of its resolvers that can serve the
+/// client, so resolvers are ordered by preference.
+#[allow(dead_code)] // Not yet used by the proxy.
+struct MultiCertResolver {
+    resolvers: Vec<Arc<CertResolver>>,
+    mismatch_log: SchemeMismatchLog,
+}
+
+/// Observes the SNI name that each TLS client requests, if any.
+pub type SniObserver = Arc<dyn Fn(Option<&Name>) + Send + Sync>;
+
+struct CertResolver {
+    key: rustls::sign::CertifiedKey,
+
+    /// The signature scheme of `key`, which peers must offer for the
+    /// certificate to be presented.
+    scheme: rustls::SignatureScheme,
+    observe_sni: Option<SniObserver>,
+
+    /// Shared by resolvers for the same key, e.g. with and without an SNI
+    /// observer.
+    mismatch_log: Arc<SchemeMismatchLog>,
+}
+
+/// Logs, at info, that a certificate wasn't presented because the peer
+/// offered none of its key's signature schemes, so that failed handshakes
+/// can be diagnosed.
+///
+/// This is logged at most once per `SCHEME_MISMATCH_LOG_INTERVAL`, so that
+/// misconfigured peers don't flood the log.
+#[derive(Debug, Default)]
+struct SchemeMismatchLog(Mutex<Option<Instant>>);
+
+/// Verifies certificate chains against a fixed set of trust anchors, and
+/// rejects those that have been revoked.
+///
+/// `certify` and the verifiers used in TLS handshakes share this, so that
+/// certificates are held to the same checks wherever they are verified.
+struct Verifier {
+    roots: rustls::RootCertStore,
+    crls: Arc<Vec<x509::Crl>>,
+
+    /// The most certificates, including the leaf, that a verified chain may
+    /// include.
+    max_chain_len: usize,
+
+    /// The logs that verified certificates must be logged by, if any.
+    ct_logs: Option<&'static [&'static sct::Log<'static>]>,
+
+    /// The source of the time that handshakes are verified as of.
+    clock: Arc<dyn Clock + Send + Sync>,
+}
+
+/// The certificates that `Crt::new_reordered` builds a chain from.
+struct PathCandidates<'a> {
+    leaf: webpki::EndEntityCert<'a>,
+    anchors: Vec<webpki::TrustAnchor<'a>>,
+    crts: &'a [Vec<u8>],
+
+    /// The subject and issuer of each of `crts`.
+    names: &'a [(Vec<u8>, Vec<u8>)],
+    time: webpki::Time,
+}
+
+/// Verifies client certificates with `verifier`, as `client_auth` requires.
+struct ClientVerifier {
+    verifier: Arc<Verifier>,
+    client_auth: tls::listen::ClientAuth,
+}
+
+/// How a verified certificate is used.
+#[derive(Copy, Clone)]
+enum Usage<'a> {
+    /// By a TLS server that is expected to be `name`.
+    Server(webpki::DNSNameRef<'a>),
+
+    /// By a TLS client.
+    Client,
+}
+
+#[derive(Clone, Debug)]
+pub struct InvalidCrt(CrtError);
+
+/// Why a certificate was rejected: by Rustls or webpki, or by one of the
+/// checks that the proxy adds to theirs.
+///
+/// Verifiers must report errors to Rustls as `TLSError`s, so the proxy's
+/// errors are converted to `TLSError::General` for TLS handshakes.
+#[derive(Clone, Debug)]
+enum CrtError {
+    Tls(rustls::TLSError),
+
+    /// A certificate in the chain has been revoked.
+    Revoked,
+
+    /// A stapled OCSP response is not current.
+    OcspStale,
+
+    /// A stapled OCSP response doesn't show that the certificate is good.
+    OcspUnknown,
+
+    /// The chain's leaf certificate is missing or empty.
+    MissingLeaf,
+
+    /// The leaf certificate was issued for a different key.
+    KeyMismatch,
+
+    /// The chain includes more certificates than
+    /// `TrustAnchors::with_max_chain_len` allows.
+    ChainTooLong,
+}
+
+/// Describes why a certificate is invalid, as returned by `InvalidCrt::kind`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InvalidCrtKind {
+    /// The chain was not issued by one of the trust anchors.
+    UnknownIssuer,
+
+    /// A certificate in the chain has expired.
+    Expired,
+
+    /// The leaf certificate is not valid for the expected name.
+    NotValidForName,
+
+    /// A signature in the chain is invalid or uses an unsupported algorithm.
+    BadSignature,
+
+    /// The certificate is invalid for any other reason.
+    Other,
+}
+
+/// Indicates that trust anchors could not be loaded by
+/// `TrustAnchors::from_pem`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TrustAnchorsError {
+    /// The input is empty.
+    Empty,
+
+    /// The input could not be parsed as PEM.
+    Parse,
+
+    /// The input did not include any usable trust anchors. `skipped`
+    /// certificates were found but could not be used as trust anchors.
+    NoneAdded { skipped: usize },
+}
+
+/// Any of the errors returned while loading a key and its certificate, so
+/// that they may be propagated together.
+///
+/// The wrapped errors remain public, so callers may match on them.
+#[derive(Debug)]
+pub enum Error {
+    /// A private key was rejected.
+    Key(KeyRejected),
+
+    /// A name is not a valid DNS name.
+    Name(InvalidName),
+
+    /// A certificate is invalid.
+    Crt(InvalidCrt),
+
+    /// A file could not be read.
+    Io(io::Error),
 }
 
-struct CertResolver(rustls::sign::CertifiedKey);
+/// Describes a certificate that `TrustAnchors::from_pem_verbose` skipped,
+/// so that the PEM block can be found.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SkipInfo {
+    /// The index of the certificate among the `CERTIFICATE` blocks.
+    pub index: usize,
+
+    /// The lines on which the block begins and ends, numbered from 1.
+    pub first_line: usize,
+    pub last_line: usize,
 
+    /// Why the certificate is not a valid trust anchor.
+    pub error: webpki::Error,
+}
+
+/// Indicates that revocation lists could not be loaded by
+/// `TrustAnchors::with_crl_pem`.
 #[derive(Clone, Debug)]
-pub struct InvalidCrt(rustls::TLSError);
+pub enum CrlError {
+    /// The input could not be parsed as PEM.
+    InvalidPem,
+
+    /// The input does not include a CRL.
+    MissingCrl,
+
+    /// A CRL is malformed or is not signed by a trust anchor.
+    InvalidCrl(webpki::Error),
+}
+
+/// Indicates that a key could not be loaded by `Key::from_encrypted_pkcs8`.
+///
+/// Unlike `Key::from_pkcs8`, this can't fail with a `KeyRejected`, which only
+/// ring can construct, for the failures that happen before ring sees the
+/// decrypted key; those are reported by the other variants.
+#[derive(Debug)]
+pub enum EncryptedKeyError {
+    /// The input is not a valid `EncryptedPrivateKeyInfo`.
+    Malformed,
+
+    /// The key is encrypted with an unsupported scheme.
+    Unsupported,
+
+    /// The key's PBKDF2 iteration count exceeds `MAX_PBKDF2_ITERATIONS`.
+    TooManyIterations(u32),
+
+    /// The key could not be decrypted, e.g. because the password is wrong.
+    Decrypt,
+
+    /// The decrypted key is not valid.
+    InvalidKey(KeyRejected),
+}
+
+/// Describes why `TokenSource::load` could not read a token.
+///
+/// This is wrapped in an `io::Error`, with the same kind as the underlying
+/// error if there is one.
+#[derive(Debug)]
+enum TokenReadError {
+    /// The token file could not be read.
+    Io { path: String, cause: io::Error },
 
-// These must be kept in sync:
-static SIGNATURE_ALG_RING_SIGNING: &ring::signature::EcdsaSigningAlgorithm =
-    &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING;
-const SIGNATURE_ALG_RUSTLS_SCHEME: rustls::SignatureScheme =
-    rustls::SignatureScheme::ECDSA_NISTP256_SHA256;
+    /// The token file is empty, or only includes whitespace.
+    Empty,
+}
+
+/// Indicates that `CrtKeys::new` rejected its keys.
+#[allow(dead_code)] // Not yet used by the proxy.
+#[derive(Debug)]
+pub enum CrtKeysError {
+    /// No keys were provided.
+    Empty,
+
+    /// A key was certified for a different name than the first key.
+    NameMismatch { expected: Name, actual: Name },
+}
+
+/// Indicates that `CrtKey::rotate` rejected a new key.
+#[derive(Debug)]
+pub enum RotateError {
+    /// The new key was certified for a different name.
+    NameMismatch { expected: Name, actual: Name },
+
+    /// The new key expires before the key it would replace.
+    ExpiryRegressed,
+}
+
+/// Indicates that a PEM bundle could not be loaded by `load_bundle_pem`.
+#[allow(dead_code)] // Not yet used by the proxy.
+#[derive(Debug)]
+pub enum BundleError {
+    /// The bundle could not be parsed as PEM.
+    InvalidPem,
+
+    /// The bundle does not include a PKCS#8 private key.
+    MissingKey,
+
+    /// The bundle includes more than one private key.
+    MultipleKeys(usize),
+
+    /// The bundle does not include a certificate.
+    MissingCrt,
+
+    /// The bundle's private key is not valid.
+    InvalidKey(KeyRejected),
+}
+
+// These must be kept in sync: each `ring` signing algorithm must be paired
+// with the Rustls scheme for the same curve and digest, and all of them must be
+// ECDSA. Keys are parsed with each algorithm, in order, until one succeeds.
+static SIGNATURE_ALGS: &[SignatureAlg] = &[
+    SignatureAlg {
+        ring: &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+        rustls: rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+        curve: x509::Curve::P256,
+    },
+    SignatureAlg {
+        ring: &ring::signature::ECDSA_P384_SHA384_ASN1_SIGNING,
+        rustls: rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+        curve: x509::Curve::P384,
+    },
+];
 const SIGNATURE_ALG_RUSTLS_ALGORITHM: rustls::internal::msgs::enums::SignatureAlgorithm =
     rustls::internal::msgs::enums::SignatureAlgorithm::ECDSA;
-const TLS_VERSIONS: &[rustls::ProtocolVersion] = &[rustls::ProtocolVersion::TLSv1_2];
+/// The TLS versions that servers configured by `certify` negotiate.
+pub const TLS_VERSIONS: &[rustls::ProtocolVersion] = &[rustls::ProtocolVersion::TLSv1_2];
+
+/// The most PBKDF2 iterations that `Key::from_encrypted_pkcs8` performs.
+///
+/// Deriving a key takes time proportional to the iteration count, which is
+/// read from the key itself, so a corrupt or hostile key could otherwise
+/// stall loading for hours. This is far above the counts that tools use in
+/// practice (OpenSSL uses 2048 by default).
+pub const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// The most certificates, including the leaf, that `certify` accepts in a
+/// chain by default.
+pub const DEFAULT_MAX_CHAIN_LEN: usize = 6;
+
+/// How often `SchemeMismatchLog` may log.
+const SCHEME_MISMATCH_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The signature algorithms accepted when verifying certificates; these are
+/// the same as Rustls's `WebPKIVerifier` accepts.
+///
+/// TODO: Restrict accepted signature algorithms.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+#[cfg(test)]
+thread_local! {
+    /// Counts the verifiers that `TrustAnchors::with_verifiers` builds on
+    /// this thread, so that tests can check that they're reused.
+    static VERIFIERS_BUILT: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0);
+}
 
 // === impl Csr ===
 
@@ -94,10 +640,136 @@ impl Csr {
 // === impl Key ===
 
 impl Key {
+    /// Parses a PKCS#8-encoded key.
+    ///
+    /// Both P-256 and P-384 keys are supported.
     pub fn from_pkcs8(b: &[u8]) -> Result<Self, KeyRejected> {
-        let i = untrusted::Input::from(b);
-        let k = EcdsaKeyPair::from_pkcs8(SIGNATURE_ALG_RING_SIGNING, i)?;
-        Ok(Key(Arc::new(k)))
+        let mut key = None;
+        for alg in SIGNATURE_ALGS {
+            match EcdsaKeyPair::from_pkcs8(alg.ring, untrusted::Input::from(b)) {
+                Ok(k) => {
+                    key = Some(Ok(Key(Arc::new(k), *alg)));
+                    break;
+                }
+                // Report why the key couldn't be used with the first
+                // (preferred) algorithm.
+                Err(e) => {
+                    if key.is_none() {
+                        key = Some(Err(e));
+                    }
+                }
+            }
+        }
+        key.expect("SIGNATURE_ALGS must not be empty")
+    }
+
+    /// Like `from_pkcs8`, but zeroes the contents of `b` before returning,
+    /// whether or not the key is valid.
+    pub fn from_pkcs8_zeroizing(b: &mut [u8]) -> Result<Self, KeyRejected> {
+        let key = Self::from_pkcs8(b);
+        zeroize(b);
+        key
+    }
+
+    /// Parses a PKCS#8-encoded key that has been encrypted with `password`,
+    /// as described in RFC 8018.
+    ///
+    /// Only PBES2 encryption with PBKDF2 and AES-CBC is supported, with at
+    /// most `MAX_PBKDF2_ITERATIONS` iterations. The decrypted key is zeroed
+    /// before returning.
+    pub fn from_encrypted_pkcs8(der: &[u8], password: &[u8]) -> Result<Self, EncryptedKeyError> {
+        let enc = x509::parse_encrypted_key(der).map_err(|e| match e {
+            webpki::Error::BadDER => EncryptedKeyError::Malformed,
+            _ => EncryptedKeyError::Unsupported,
+        })?;
+        let iterations = Self::pbkdf2_iterations(enc.iterations)?;
+
+        let mut key = match enc.cipher {
+            x509::Pbes2Cipher::Aes128Cbc => vec![0; 16],
+            x509::Pbes2Cipher::Aes256Cbc => vec![0; 32],
+        };
+        ring::pbkdf2::derive(enc.prf, iterations, enc.salt, password, &mut key);
+
+        let mut buf = enc.data.to_vec();
+        let len = match enc.cipher {
+            x509::Pbes2Cipher::Aes128Cbc => Cbc::<aes::Aes128, Pkcs7>::new_var(&key, enc.iv)
+                .ok()
+                .and_then(|c| c.decrypt(&mut buf).ok().map(|k| k.len())),
+            x509::Pbes2Cipher::Aes256Cbc => Cbc::<aes::Aes256, Pkcs7>::new_var(&key, enc.iv)
+                .ok()
+                .and_then(|c| c.decrypt(&mut buf).ok().map(|k| k.len())),
+        };
+        zeroize(&mut key);
+
+        let result = match len {
+            Some(len) => Self::from_pkcs8(&buf[..len]).map_err(EncryptedKeyError::InvalidKey),
+            None => Err(EncryptedKeyError::Decrypt),
+        };
+        zeroize(&mut buf);
+        result
+    }
+
+    fn pbkdf2_iterations(n: u32) -> Result<::std::num::NonZeroU32, EncryptedKeyError> {
+        if n > MAX_PBKDF2_ITERATIONS {
+            return Err(EncryptedKeyError::TooManyIterations(n));
+        }
+        ::std::num::NonZeroU32::new(n).ok_or(EncryptedKeyError::Malformed)
+    }
+
+    /// Returns the DER-encoded `SubjectPublicKeyInfo` of the key's public
+    /// key, e.g. to check that a certificate was issued for this key.
+    pub fn public_key_der(&self) -> Vec<u8> {
+        use self::ring::signature::KeyPair;
+
+        x509::encode_ec_spki(self.1.curve, self.0.public_key().as_ref())
+    }
+
+    /// Generates a new P-256 key.
+    ///
+    /// The key's PKCS#8 encoding is returned along with it so that it may be
+    /// persisted; callers are responsible for zeroing it once it's no longer
+    /// needed.
+    pub fn generate() -> Result<(Self, Vec<u8>), Unspecified> {
+        let rng = rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(SIGNATURE_ALGS[0].ring, &rng)?
+            .as_ref()
+            .to_vec();
+        let key = Self::from_pkcs8(&pkcs8).map_err(|_| Unspecified)?;
+        Ok((key, pkcs8))
+    }
+}
+
+/// Keys are equal if their public keys are, since `ring` doesn't expose the
+/// private keys to compare.
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key_der() == other.public_key_der()
+    }
+}
+
+impl Eq for Key {}
+
+/// Overwrites secret material so that it doesn't linger in freed memory.
+fn zeroize(b: &mut [u8]) {
+    for byte in b.iter_mut() {
+        // Volatile writes may not be elided by the compiler, even though the
+        // buffer is typically never read again.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+impl SigningKey {
+    fn new(key: Key) -> Self {
+        Self::with_rng(key, Arc::new(rand::SystemRandom::new()))
+    }
+
+    fn with_rng(key: Key, rng: Arc<dyn rand::SecureRandom + Send + Sync>) -> Self {
+        Self {
+            key,
+            signatures: Arc::new(atomic::AtomicUsize::new(0)),
+            rng,
+        }
     }
 }
 
@@ -106,9 +778,18 @@ impl rustls::sign::SigningKey for SigningKey {
         &self,
         offered: &[rustls::SignatureScheme],
     ) -> Option<Box<rustls::sign::Signer>> {
-        if offered.contains(&SIGNATURE_ALG_RUSTLS_SCHEME) {
-            Some(Box::new(Signer(self.0.clone())))
+        let scheme = self.key.1.rustls;
+        if offered.contains(&scheme) {
+            Some(Box::new(Signer {
+                key: self.key.clone(),
+                signatures: self.signatures.clone(),
+                rng: self.rng.clone(),
+            }))
         } else {
+            debug!(
+                "peer did not offer a supported signature scheme; offered={:?}, supported={:?}",
+                offered, scheme,
+            );
             None
         }
     }
@@ -120,9 +801,12 @@ impl rustls::sign::SigningKey for SigningKey {
 
 impl rustls::sign::Signer for Signer {
     fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::TLSError> {
-        let rng = rand::SystemRandom::new();
-        self.0
-            .sign(&rng, untrusted::Input::from(message))
+        // Relaxed, since the count isn't used to synchronize anything else.
+        self.signatures.fetch_add(1, atomic::Ordering::Relaxed);
+
+        self.key
+            .0
+            .sign(&*self.rng, untrusted::Input::from(message))
             .map(|signature| signature.as_ref().to_owned())
             .map_err(|ring::error::Unspecified| {
                 rustls::TLSError::General("Signing Failed".to_owned())
@@ -130,7 +814,13 @@ impl rustls::sign::Signer for Signer {
     }
 
     fn get_scheme(&self) -> rustls::SignatureScheme {
-        SIGNATURE_ALG_RUSTLS_SCHEME
+        self.key.1.rustls
+    }
+}
+
+impl fmt::Debug for SignatureAlg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.rustls, f)
     }
 }
 
@@ -151,9 +841,115 @@ impl Name {
         dns::Name::try_from(hostname).map(|n| Name(Arc::new(n)))
     }
 
+    /// Parses an ASCII hostname, e.g. from a ClientHello's SNI extension,
+    /// failing on any non-ASCII byte.
+    ///
+    /// This is the same as `from_hostname`: `dns::Name` is already validated
+    /// by webpki's `DNSNameRef::try_from_ascii`, which only checks ASCII
+    /// label syntax and does no IDNA processing, and webpki offers no way to
+    /// build a name without that check. So there's no lighter-weight
+    /// validation to be had here.
+    pub fn try_from_ascii(ascii: &[u8]) -> Result<Self, InvalidName> {
+        Self::from_hostname(ascii)
+    }
+
+    /// Like `from_hostname`, but also fails if the name exceeds `policy`'s
+    /// limits.
+    pub fn from_hostname_with_policy(
+        hostname: &[u8],
+        policy: &NamePolicy,
+    ) -> Result<Self, InvalidName> {
+        if hostname.len() > policy.max_len {
+            return Err(dns::InvalidName);
+        }
+        let name = Self::from_hostname(hostname)?;
+        if name.labels().count() > policy.max_labels {
+            return Err(dns::InvalidName);
+        }
+        Ok(name)
+    }
+
     pub fn as_dns_name_ref(&self) -> webpki::DNSNameRef {
         self.0.as_dns_name_ref()
     }
+
+    /// Returns the name's labels, from left to right.
+    pub fn labels<'a>(&'a self) -> impl Iterator<Item = &'a str> + 'a {
+        self.as_ref().split('.')
+    }
+
+    /// Returns true if the name's trailing labels are `suffix`'s labels,
+    /// compared case-insensitively.
+    ///
+    /// Only whole labels match, so `evilfoo.svc` does not end with `foo.svc`.
+    pub fn ends_with(&self, suffix: &Name) -> bool {
+        let mut labels = self.as_ref().rsplit('.');
+        suffix.as_ref().rsplit('.').all(|s| {
+            labels
+                .next()
+                .map(|l| l.eq_ignore_ascii_case(s))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Returns the labels that precede `suffix`, if the name ends with
+    /// `suffix` as `ends_with` determines.
+    ///
+    /// The leading labels are returned as they appear in the name, without
+    /// the dot that separates them from the suffix. If the name is `suffix`,
+    /// the result is empty.
+    pub fn strip_suffix(&self, suffix: &Name) -> Option<&str> {
+        if !self.ends_with(suffix) {
+            return None;
+        }
+        let name = self.as_ref();
+        let leading = name.len() - suffix.as_ref().len();
+        Some(&name[..leading.saturating_sub(1)])
+    }
+
+    /// Returns a short, stable hash of this name.
+    ///
+    /// The same name always produces the same id, so it may be used to
+    /// correlate log lines without revealing the name.
+    pub fn short_id(&self) -> String {
+        let d = ring::digest::digest(&ring::digest::SHA256, self.as_ref().as_bytes());
+        d.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Returns a value that displays this name as permitted by `policy`.
+    pub fn log(&self, policy: LogPolicy) -> LogName {
+        LogName { name: self, policy }
+    }
+}
+
+/// Parses a name as `Name::from_hostname` does.
+impl<'a> TryFrom<&'a [u8]> for Name {
+    type Err = InvalidName;
+
+    fn try_from(hostname: &[u8]) -> Result<Self, Self::Err> {
+        Self::from_hostname(hostname)
+    }
+}
+
+/// Parses a name as `Name::from_hostname` does.
+impl<'a> TryFrom<&'a str> for Name {
+    type Err = InvalidName;
+
+    fn try_from(hostname: &str) -> Result<Self, Self::Err> {
+        Self::from_hostname(hostname.as_bytes())
+    }
+}
+
+/// Parses a name as `Name::from_hostname` does.
+impl TryFrom<String> for Name {
+    type Err = InvalidName;
+
+    fn try_from(hostname: String) -> Result<Self, Self::Err> {
+        Self::from_hostname(hostname.as_bytes())
+    }
 }
 
 impl AsRef<str> for Name {
@@ -168,293 +964,4890 @@ impl fmt::Debug for Name {
     }
 }
 
-// === impl TokenSource ===
-
-impl TokenSource {
-    pub fn if_nonempty_file(p: String) -> io::Result<Self> {
-        let ts = TokenSource(Arc::new(p));
-        ts.load().map(|_| ts)
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(&self.0, f)
     }
+}
 
-    pub fn load(&self) -> io::Result<Vec<u8>> {
-        let t = fs::read(self.0.as_str())?;
+// === impl NamePolicy ===
 
-        if t.is_empty() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other.into(),
-                "token is empty",
-            ));
+/// Imposes no limits beyond those of DNS, so that names are accepted exactly
+/// as `Name::from_hostname` accepts them.
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self {
+            max_len: 253,
+            // Labels are at least one octet and are separated by dots.
+            max_labels: 127,
         }
-
-        Ok(t)
     }
 }
 
-// === impl TrustAnchors ===
+// === impl SpiffeId ===
 
-impl TrustAnchors {
-    #[cfg(test)]
-    fn empty() -> Self {
-        TrustAnchors(Arc::new(rustls::ClientConfig::new()))
-    }
+impl SpiffeId {
+    /// Parses a `spiffe://` URI.
+    ///
+    /// The trust domain must be a non-empty, lowercase host name without a
+    /// port or user info, and the path, if any, must consist of non-empty
+    /// segments other than `.` and `..`. Queries and fragments are not
+    /// permitted.
+    pub fn parse(uri: &str) -> Option<Self> {
+        const SCHEME: &str = "spiffe://";
+        if !uri.starts_with(SCHEME) {
+            return None;
+        }
 
-    pub fn from_pem(s: &str) -> Option<Self> {
-        use std::io::Cursor;
+        let rest = &uri[SCHEME.len()..];
+        let (trust_domain, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
 
-        let mut roots = rustls::RootCertStore::empty();
-        let (added, skipped) = roots.add_pem_file(&mut Cursor::new(s)).ok()?;
-        if skipped != 0 {
-            warn!("skipped {} trust anchors in trust anchors file", skipped);
-        }
-        if added == 0 {
+        let valid_domain = !trust_domain.is_empty()
+            && trust_domain
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"-._".contains(&b));
+        if !valid_domain {
             return None;
         }
 
-        let mut c = rustls::ClientConfig::new();
-
-        // XXX: Rustls's built-in verifiers don't let us tweak things as fully
-        // as we'd like (e.g. controlling the set of trusted signature
-        // algorithms), but they provide good enough defaults for now.
-        // TODO: lock down the verification further.
-        // TODO: Change Rustls's API to Avoid needing to clone `root_cert_store`.
-        c.root_store = roots;
+        if !path.is_empty() {
+            let valid_segment = |s: &str| {
+                !s.is_empty()
+                    && s != "."
+                    && s != ".."
+                    && s.bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b))
+            };
+            if !path[1..].split('/').all(valid_segment) {
+                return None;
+            }
+        }
 
-        // Disable session resumption for the time-being until resumption is
-        // more tested.
-        c.enable_tickets = false;
+        Some(SpiffeId {
+            trust_domain: trust_domain.to_owned(),
+            path: path.to_owned(),
+        })
+    }
 
-        Some(TrustAnchors(Arc::new(c)))
+    pub fn trust_domain(&self) -> &str {
+        &self.trust_domain
     }
 
-    pub fn certify(&self, key: Key, crt: Crt) -> Result<CrtKey, InvalidCrt> {
-        let mut client = self.0.as_ref().clone();
+    /// Returns the path within the trust domain, which is empty or begins
+    /// with a `/`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
 
-        // Ensure the certificate is valid for the services we terminate for
-        // TLS. This assumes that server cert validation does the same or
-        // more validation than client cert validation.
-        //
-        // XXX: Rustls currently only provides access to a
-        // `ServerCertVerifier` through
-        // `rustls::ClientConfig::get_verifier()`.
-        //
-        // XXX: Once `rustls::ServerCertVerified` is exposed in Rustls's
-        // safe API, use it to pass proof to CertCertResolver::new....
-        //
-        // TODO: Restrict accepted signatutre algorithms.
-        static NO_OCSP: &'static [u8] = &[];
-        client
-            .get_verifier()
-            .verify_server_cert(
-                &client.root_store,
-                &crt.chain,
-                crt.name.as_dns_name_ref(),
-                NO_OCSP,
-            )
-            .map_err(InvalidCrt)?;
-        debug!("certified {}", crt.name.as_ref());
+impl fmt::Display for SpiffeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spiffe://{}{}", self.trust_domain, self.path)
+    }
+}
 
-        let k = SigningKey(key.0.clone());
-        let key = rustls::sign::CertifiedKey::new(crt.chain, Arc::new(Box::new(k)));
-        let resolver = Arc::new(CertResolver(key));
+/// Returns the SPIFFE ID in a certificate's URI subject alternative name.
+///
+/// As required of X.509 SVIDs, the certificate must have exactly one URI SAN.
+/// The certificate is not validated, so this must only be used with
+/// certificates that have been verified.
+pub fn spiffe_id_from_cert(crt: &rustls::Certificate) -> Option<SpiffeId> {
+    let tbs = x509::parse(crt.as_ref()).ok()?;
+    match tbs.uri_sans.as_slice() {
+        [uri] => SpiffeId::parse(uri),
+        _ => None,
+    }
+}
+
+/// Returns the name in a certificate's first DNS subject alternative name.
+///
+/// The name must be valid as for `Name::from_hostname`. The subject's common
+/// name is ignored. The certificate is not validated, so this must only be
+/// used with certificates that have been verified.
+#[allow(dead_code)] // Not yet used by the proxy.
+pub fn name_from_leaf(crt: &rustls::Certificate) -> Option<Name> {
+    let crt = webpki::EndEntityCert::from(untrusted::Input::from(crt.as_ref())).ok()?;
+    let names = crt.dns_names().ok()?;
+    let name: &str = (*names.first()?).into();
+    Name::from_hostname(name.as_bytes()).ok()
+}
+
+// === impl LogPolicy ===
+
+impl LogPolicy {
+    /// Returns the policy with which the proxy logs identity names.
+    ///
+    /// Names are logged in full until `LogPolicy::set_current` is called.
+    pub fn current() -> Self {
+        if LOG_REDACTED.load(atomic::Ordering::Relaxed) {
+            LogPolicy::Redacted
+        } else {
+            LogPolicy::Full
+        }
+    }
+
+    /// Sets the policy with which the proxy logs identity names.
+    pub fn set_current(policy: Self) {
+        let redacted = policy == LogPolicy::Redacted;
+        LOG_REDACTED.store(redacted, atomic::Ordering::Relaxed);
+    }
+}
+
+// === impl LogName ===
+
+impl<'a> fmt::Display for LogName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.policy {
+            LogPolicy::Full => f.write_str(self.name.as_ref()),
+            LogPolicy::Redacted => write!(f, "id:{}", self.name.short_id()),
+        }
+    }
+}
+
+// === impl TokenSource ===
+
+impl TokenSource {
+    pub fn if_nonempty_file(p: String) -> io::Result<Self> {
+        let ts = TokenSource(Arc::new(p));
+        ts.load().map(|_| ts)
+    }
+
+    /// Reads the token, without any trailing whitespace.
+    ///
+    /// Token files often end with a newline, which is not part of the token.
+    pub fn load(&self) -> io::Result<Vec<u8>> {
+        let mut t = fs::read(self.0.as_str()).map_err(|cause| {
+            let kind = cause.kind();
+            let path = (*self.0).clone();
+            io::Error::new(kind, TokenReadError::Io { path, cause })
+        })?;
+        let len = t
+            .iter()
+            .rposition(|b| !b.is_ascii_whitespace())
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        t.truncate(len);
+
+        if t.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, TokenReadError::Empty));
+        }
+
+        Ok(t)
+    }
+
+    /// Like `load`, but retries up to `attempts` times, waiting for `backoff`
+    /// between attempts, while the token file is missing or empty.
+    ///
+    /// This tolerates token files that are mounted after the proxy starts;
+    /// other errors, e.g. for files that can't be read, are returned
+    /// immediately. The returned future waits on a timer rather than
+    /// blocking, so it must be polled on a runtime.
+    pub fn load_with_retry(&self, attempts: usize, backoff: Duration) -> LoadWithRetry {
+        LoadWithRetry {
+            source: self.clone(),
+            attempt: 1,
+            attempts,
+            backoff,
+            delay: None,
+        }
+    }
+
+    fn is_transient(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::NotFound
+            || match e.get_ref().and_then(|e| e.downcast_ref::<TokenReadError>()) {
+                Some(TokenReadError::Empty) => true,
+                _ => false,
+            }
+    }
+
+    /// Like `load`, but fails unless the token is shaped like a JWT: three
+    /// non-empty, base64url-encoded segments separated by `.`.
+    ///
+    /// The token's contents are not otherwise validated.
+    pub fn load_jwt(&self) -> io::Result<Vec<u8>> {
+        let t = self.load()?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let s = ::std::str::from_utf8(&t).map_err(|_| invalid("token is not valid UTF-8"))?;
+        let segments = s.split('.').collect::<Vec<_>>();
+        if segments.len() != 3 {
+            return Err(invalid(&format!(
+                "token has {} segments; a JWT has 3",
+                segments.len()
+            )));
+        }
+        let is_base64url = |seg: &&str| {
+            !seg.is_empty()
+                && seg
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        };
+        if !segments.iter().all(is_base64url) {
+            return Err(invalid("token segments must be base64url-encoded"));
+        }
+
+        Ok(t)
+    }
+}
+
+// === impl LoadWithRetry ===
+
+impl Future for LoadWithRetry {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                try_ready!(delay
+                    .poll()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+            }
+            self.delay = None;
+
+            match self.source.load() {
+                Err(ref e) if self.attempt < self.attempts && TokenSource::is_transient(e) => {
+                    debug!(
+                        "token is not yet available (attempt {}): {}",
+                        self.attempt, e
+                    );
+                    self.attempt += 1;
+                    self.delay = Some(Delay::new(clock::now() + self.backoff));
+                }
+                result => return result.map(Async::Ready),
+            }
+        }
+    }
+}
+
+// === impl TrustAnchors ===
+
+impl TrustAnchors {
+    #[cfg(test)]
+    fn empty() -> Self {
+        Self::from_config(rustls::ClientConfig::new())
+    }
+
+    pub fn from_pem(s: &str) -> Result<Self, TrustAnchorsError> {
+        use std::io::Cursor;
+
+        if s.trim().is_empty() {
+            return Err(TrustAnchorsError::Empty);
+        }
+
+        let crts = rustls::internal::pemfile::certs(&mut Cursor::new(s))
+            .map_err(|()| TrustAnchorsError::Parse)?;
+        Self::from_der(crts.into_iter().map(|c| c.0))
+    }
+
+    /// Like `from_pem`, but also describes each certificate that was
+    /// skipped because it isn't a valid trust anchor.
+    pub fn from_pem_verbose(s: &str) -> Result<(Self, Vec<SkipInfo>), TrustAnchorsError> {
+        use std::io::Cursor;
+
+        if s.trim().is_empty() {
+            return Err(TrustAnchorsError::Empty);
+        }
+
+        let crts = rustls::internal::pemfile::certs(&mut Cursor::new(s))
+            .map_err(|()| TrustAnchorsError::Parse)?;
+        let (anchors, skipped) = Self::from_der_with_skipped(crts.into_iter().map(|c| c.0))?;
+
+        // Blocks are found as `pemfile` finds them, so they're in the same
+        // order as the certificates.
+        let mut blocks = Vec::new();
+        let mut first_line = None;
+        for (i, line) in s.lines().enumerate() {
+            if line.starts_with("-----BEGIN CERTIFICATE-----") {
+                first_line = Some(i + 1);
+            } else if line.starts_with("-----END CERTIFICATE-----") {
+                if let Some(first) = first_line.take() {
+                    blocks.push((first, i + 1));
+                }
+            }
+        }
+
+        let skipped = skipped
+            .into_iter()
+            .map(|(index, error)| {
+                let (first_line, last_line) = blocks.get(index).cloned().unwrap_or((0, 0));
+                SkipInfo {
+                    index,
+                    first_line,
+                    last_line,
+                    error,
+                }
+            })
+            .collect();
+        Ok((anchors, skipped))
+    }
+
+    /// Loads trust anchors from DER-encoded root certificates.
+    ///
+    /// Roots that aren't valid trust anchors are skipped, as in `from_pem`.
+    pub fn from_der<I>(roots: I) -> Result<Self, TrustAnchorsError>
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+    {
+        Self::from_der_with_skipped(roots).map(|(anchors, _)| anchors)
+    }
+
+    /// Like `from_der`, but also returns the index of each skipped root and
+    /// the reason it was skipped.
+    fn from_der_with_skipped<I>(
+        roots: I,
+    ) -> Result<(Self, Vec<(usize, webpki::Error)>), TrustAnchorsError>
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+    {
+        let mut store = rustls::RootCertStore::empty();
+        let mut added = Vec::new();
+        let mut skipped = Vec::new();
+        for (i, der) in roots.into_iter().enumerate() {
+            let crt = rustls::Certificate(der);
+            match store.add(&crt) {
+                Ok(()) => added.push(crt),
+                Err(e) => {
+                    trace!("skipping trust anchor: {:?}", e);
+                    skipped.push((i, e));
+                }
+            }
+        }
+        if !skipped.is_empty() {
+            warn!("skipped {} invalid trust anchors", skipped.len());
+        }
+        if added.is_empty() {
+            return Err(TrustAnchorsError::NoneAdded {
+                skipped: skipped.len(),
+            });
+        }
+
+        let mut c = rustls::ClientConfig::new();
+
+        // Server certificates are verified by our own `Verifier`, which is
+        // installed by `with_verifiers`, but Rustls still expects the roots
+        // to be in the root store.
+        //
+        // TODO: Change Rustls's API to Avoid needing to clone `root_cert_store`.
+        c.root_store = store;
+
+        // Disable session resumption for the time-being until resumption is
+        // more tested.
+        c.enable_tickets = false;
+
+        let anchors = TrustAnchors {
+            roots: Arc::new(added),
+            ..Self::from_config(c)
+        };
+        Ok((anchors, skipped))
+    }
+
+    /// Like `from_pem`, but discards the reason that trust anchors could not
+    /// be loaded.
+    pub fn from_pem_opt(s: &str) -> Option<Self> {
+        Self::from_pem(s).ok()
+    }
+
+    /// Returns a stream that emits trust anchors loaded from the PEM file at
+    /// `path` whenever its contents change.
+    ///
+    /// The file is checked every `interval`; the first check happens
+    /// immediately.
+    pub fn watch_file<P: Into<PathBuf>>(path: P, interval: Duration) -> WatchFile {
+        WatchFile::new(path.into(), interval)
+    }
+
+    /// Returns trust anchors that trust the roots of both `self` and
+    /// `other`.
+    ///
+    /// Roots that are present in both are only included once, and the
+    /// revocation lists of both are used. The result uses `self`'s
+    /// configuration otherwise.
+    pub fn merge(&self, other: &TrustAnchors) -> TrustAnchors {
+        let mut c = self.config.as_ref().clone();
+        let mut roots = self.roots.as_ref().clone();
+        // Each of `other`'s roots is parsed again, rather than paired with
+        // its root store, so that the root store and `roots` can't disagree.
+        for crt in other.roots.iter() {
+            let mut parsed = rustls::RootCertStore::empty();
+            if let Err(e) = parsed.add(crt) {
+                // The roots were parsed when they were loaded.
+                debug!("skipping invalid trust anchor: {:?}", e);
+                continue;
+            }
+            let exists = {
+                let anchor = parsed.roots[0].to_trust_anchor();
+                c.root_store.roots.iter().any(|r| {
+                    let r = r.to_trust_anchor();
+                    r.subject == anchor.subject
+                        && r.spki == anchor.spki
+                        && r.name_constraints == anchor.name_constraints
+                })
+            };
+            if !exists {
+                c.root_store.roots.append(&mut parsed.roots);
+                roots.push(crt.clone());
+            }
+        }
+
+        let crls = self.crls.iter().chain(other.crls.iter()).cloned();
+        TrustAnchors {
+            config: Arc::new(c),
+            roots: Arc::new(roots),
+            crls: Arc::new(crls.collect()),
+            ..self.clone()
+        }
+        .with_verifiers()
+    }
+
+    /// Returns the number of trust anchors.
+    pub fn len(&self) -> usize {
+        self.config.root_store.roots.len()
+    }
+
+    /// Returns true if there are no trust anchors, so that no certificate
+    /// can be trusted.
+    pub fn is_empty(&self) -> bool {
+        self.config.root_store.roots.is_empty()
+    }
+
+    /// Returns the subject of each trust anchor, formatted as described in
+    /// RFC 4514.
+    pub fn subjects(&self) -> Vec<String> {
+        self.config
+            .root_store
+            .roots
+            .iter()
+            .map(|r| {
+                x509::name_to_string(r.to_trust_anchor().subject)
+                    .unwrap_or_else(|e| format!("<invalid subject: {:?}>", e))
+            })
+            .collect()
+    }
+
+    fn from_config(config: rustls::ClientConfig) -> Self {
+        TrustAnchors {
+            config: Arc::new(config),
+            roots: Arc::new(Vec::new()),
+            duplicate_crts: DuplicateCrtPolicy::Dedupe,
+            client_auth: tls::listen::ClientAuth::Optional,
+            crls: Arc::new(Vec::new()),
+            ct_logs: None,
+            intermediates: None,
+            max_chain_len: DEFAULT_MAX_CHAIN_LEN,
+            clock: Arc::new(SystemClock),
+            // Replaced below, once the roots can be used to build them.
+            verifier: Arc::new(Verifier {
+                roots: rustls::RootCertStore::empty(),
+                crls: Arc::new(Vec::new()),
+                max_chain_len: DEFAULT_MAX_CHAIN_LEN,
+                ct_logs: None,
+                clock: Arc::new(SystemClock),
+            }),
+            client_verifier: rustls::NoClientAuth::new(),
+        }
+        .with_verifiers()
+    }
+
+    /// Rebuilds the verifiers from the current roots, revocation lists, and
+    /// client authentication policy, and installs the server certificate
+    /// verifier in the client configuration.
+    fn with_verifiers(self) -> Self {
+        #[cfg(test)]
+        VERIFIERS_BUILT.with(|n| n.set(n.get() + 1));
+
+        let verifier = Arc::new(Verifier {
+            roots: self.config.root_store.clone(),
+            crls: self.crls.clone(),
+            max_chain_len: self.max_chain_len,
+            ct_logs: self.ct_logs,
+            clock: self.clock.clone(),
+        });
+        let mut c = self.config.as_ref().clone();
+        c.dangerous().set_certificate_verifier(verifier.clone());
+        let client_verifier = build_client_cert_verifier(&verifier, self.client_auth);
+        Self {
+            config: Arc::new(c),
+            verifier,
+            client_verifier,
+            ..self
+        }
+    }
+
+    /// Requires that certificates are logged by one of `logs`.
+    ///
+    /// `certify` rejects leaf certificates that don't have at least one valid
+    /// signed certificate timestamp (SCT) from one of the logs, either
+    /// attached with `Crt::with_sct` or embedded in the certificate. TLS
+    /// servers' certificates must likewise embed a valid SCT: Rustls doesn't
+    /// pass the SCTs that servers provide in the TLS handshake to verifiers,
+    /// so servers that only provide them there are rejected. As in Rustls,
+    /// those SCTs are still verified against the logs.
+    pub fn with_ct_logs(self, logs: &'static [&'static sct::Log<'static>]) -> Self {
+        let mut c = self.config.as_ref().clone();
+        c.ct_logs = Some(logs);
+        Self {
+            config: Arc::new(c),
+            ct_logs: Some(logs),
+            ..self
+        }
+        .with_verifiers()
+    }
+
+    /// Configures certificate revocation lists.
+    ///
+    /// `pem` may include several CRLs, each of which must be signed by one of
+    /// the trust anchors. Certificates revoked by any of them are rejected by
+    /// `certify` and are not accepted from TLS clients. The CRLs' update times
+    /// are not checked.
+    pub fn with_crl_pem(self, pem: &str) -> Result<Self, CrlError> {
+        const BEGIN: &str = "-----BEGIN X509 CRL-----";
+        const END: &str = "-----END X509 CRL-----";
+
+        let crls = {
+            let anchors = self
+                .config
+                .root_store
+                .roots
+                .iter()
+                .map(|r| r.to_trust_anchor())
+                .collect::<Vec<_>>();
+
+            let mut crls = Vec::new();
+            let mut rest = pem;
+            while let Some(start) = rest.find(BEGIN) {
+                let body = &rest[start + BEGIN.len()..];
+                let end = body.find(END).ok_or(CrlError::InvalidPem)?;
+                let b64 = body[..end].split_whitespace().collect::<String>();
+                let der = base64::decode(&b64).map_err(|_| CrlError::InvalidPem)?;
+                crls.push(x509::Crl::from_der(&der, &anchors).map_err(CrlError::InvalidCrl)?);
+                rest = &body[end + END.len()..];
+            }
+            crls
+        };
+        if crls.is_empty() {
+            return Err(CrlError::MissingCrl);
+        }
+
+        Ok(Self {
+            crls: Arc::new(crls),
+            ..self
+        }
+        .with_verifiers())
+    }
+
+    /// Configures intermediates that `certify` uses for certificates that
+    /// are provided without any.
+    ///
+    /// Certificates that include their own intermediates are certified with
+    /// those instead. By default, no intermediates are added.
+    pub fn with_intermediates(self, bundle: IntermediateBundle) -> Self {
+        Self {
+            intermediates: Some(bundle),
+            ..self
+        }
+    }
+
+    /// Configures the most certificates, including the leaf, that `certify`
+    /// accepts in a chain, as do TLS handshakes, for peers' chains.
+    ///
+    /// Longer chains are rejected before they are verified, which bounds the
+    /// cost of verifying them. By default, this is `DEFAULT_MAX_CHAIN_LEN`.
+    pub fn with_max_chain_len(self, max_chain_len: usize) -> Self {
+        Self {
+            max_chain_len,
+            ..self
+        }
+        .with_verifiers()
+    }
+
+    /// Configures the clock that certificates are verified against, both by
+    /// `certify` and in TLS handshakes.
+    ///
+    /// By default, this is the system clock.
+    pub fn with_clock<C: Clock + Send + Sync + 'static>(self, clock: C) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            ..self
+        }
+        .with_verifiers()
+    }
+
+    /// Configures how chains that include duplicate certificates are handled.
+    ///
+    /// By default, duplicates are removed.
+    pub fn with_duplicate_crt_policy(self, duplicate_crts: DuplicateCrtPolicy) -> Self {
+        Self {
+            duplicate_crts,
+            ..self
+        }
+    }
+
+    /// Configures whether servers configured by `certify` require clients to
+    /// present a certificate.
+    ///
+    /// By default, client certificates are optional.
+    pub fn with_client_auth(self, client_auth: tls::listen::ClientAuth) -> Self {
+        Self {
+            client_auth,
+            ..self
+        }
+        .with_verifiers()
+    }
+
+    /// Returns a verifier for TLS server certificates issued by the trust
+    /// anchors, which also checks the revocation lists, if any.
+    ///
+    /// The verifier always uses these trust anchors, regardless of the roots
+    /// that it is passed.
+    pub fn verifier(&self) -> Arc<dyn rustls::ServerCertVerifier> {
+        self.verifier.clone()
+    }
+
+    /// Returns a verifier for TLS client certificates issued by the trust
+    /// anchors, which also checks the revocation lists, if any.
+    ///
+    /// The verifier for the trust anchors' own client authentication policy
+    /// is shared by all callers; others are built on demand.
+    pub fn client_cert_verifier(
+        &self,
+        client_auth: tls::listen::ClientAuth,
+    ) -> Arc<dyn rustls::ClientCertVerifier> {
+        if client_auth == self.client_auth {
+            return self.client_verifier.clone();
+        }
+        build_client_cert_verifier(&self.verifier, client_auth)
+    }
+
+    /// Like `certify`, but additionally requires that every DNS name in the
+    /// leaf certificate is in `allowed`.
+    ///
+    /// This prevents accepting a certificate that, while valid for the
+    /// expected identity, would also allow impersonating other identities.
+    pub fn certify_with_allowed_sans(
+        &self,
+        key: Key,
+        crt: Crt,
+        allowed: &IndexSet<Name>,
+    ) -> Result<CrtKey, InvalidCrt> {
+        for n in crt.dns_names()? {
+            if !allowed.contains(&n) {
+                return Err(InvalidCrt(
+                    rustls::TLSError::General(format!(
+                        "certificate is valid for unexpected name {}",
+                        n
+                    ))
+                    .into(),
+                ));
+            }
+        }
+
+        self.certify(key, crt)
+    }
+
+    pub fn certify(&self, key: Key, crt: Crt) -> Result<CrtKey, InvalidCrt> {
+        self.certify_with_skew(key, crt, Duration::from_secs(0))
+    }
+
+    /// Like `certify`, but tolerates certificates that only become valid up
+    /// to `skew` in the future.
+    ///
+    /// This allows a certificate to be used immediately after it is issued
+    /// by an authority whose clock is slightly ahead of ours. The skew only
+    /// relaxes each certificate's notBefore; certificates are never accepted
+    /// after their notAfter.
+    pub fn certify_with_skew(
+        &self,
+        key: Key,
+        crt: Crt,
+        skew: Duration,
+    ) -> Result<CrtKey, InvalidCrt> {
+        self.certify_at(key, crt, self.clock.now(), skew)
+    }
+
+    /// Like `certify_with_skew`, but reads the current time from `clock`
+    /// rather than from the clock configured by `with_clock`.
+    pub fn certify_with_clock<C: Clock>(
+        &self,
+        key: Key,
+        crt: Crt,
+        skew: Duration,
+        clock: &C,
+    ) -> Result<CrtKey, InvalidCrt> {
+        self.certify_at(key, crt, clock.now(), skew)
+    }
+
+    /// Certifies each key and certificate, as `certify` would, returning a
+    /// result for each in order.
+    ///
+    /// All of the certificates are verified as of the same time, e.g. when
+    /// many identities are rotated at once.
+    pub fn certify_many<I>(&self, items: I) -> Vec<Result<CrtKey, InvalidCrt>>
+    where
+        I: IntoIterator<Item = (Key, Crt)>,
+    {
+        self.certify_many_at(items, self.clock.now())
+    }
+
+    fn certify_many_at<I>(&self, items: I, now: SystemTime) -> Vec<Result<CrtKey, InvalidCrt>>
+    where
+        I: IntoIterator<Item = (Key, Crt)>,
+    {
+        let skew = Duration::from_secs(0);
+        items
+            .into_iter()
+            .map(|(key, crt)| self.certify_at(key, crt, now, skew))
+            .collect()
+    }
+
+    fn certify_at(
+        &self,
+        key: Key,
+        mut crt: Crt,
+        now: SystemTime,
+        skew: Duration,
+    ) -> Result<CrtKey, InvalidCrt> {
+        if crt.intermediates.is_empty() {
+            if let Some(ref bundle) = self.intermediates {
+                trace!("using {} configured intermediates", bundle.0.len());
+                crt.intermediates = bundle.0.clone();
+            }
+        }
+
+        let duplicates = crt.dedupe();
+        if duplicates > 0 {
+            match self.duplicate_crts {
+                DuplicateCrtPolicy::Dedupe => {
+                    debug!("removed {} duplicate certificates from chain", duplicates);
+                }
+                DuplicateCrtPolicy::Reject => {
+                    return Err(InvalidCrt(
+                        rustls::TLSError::General(
+                            "certificate chain includes duplicate certificates".into(),
+                        )
+                        .into(),
+                    ));
+                }
+            }
+        }
+
+        // Ensure the certificate is valid for the services we terminate for
+        // TLS. This assumes that server cert validation does the same or
+        // more validation than client cert validation.
+        //
+        // XXX: Once `rustls::ServerCertVerified` is exposed in Rustls's
+        // safe API, use it to pass proof to CertCertResolver::new....
+        let usage = Usage::Server(crt.name.as_dns_name_ref());
+        let verified = self
+            .verifier
+            .verify(&crt.leaf, &crt.intermediates, usage, now, skew)
+            .and_then(|()| match crt.ocsp {
+                Some(ref ocsp) => {
+                    self.verifier
+                        .check_ocsp(ocsp, &crt.leaf, &crt.intermediates, now)
+                }
+                None => Ok(()),
+            })
+            .and_then(|()| {
+                self.verifier
+                    .check_scts(&crt.leaf, &crt.intermediates, &crt.scts, now)
+            })
+            .map_err(InvalidCrt);
+        if let Err(e) = verified {
+            if let CrtError::Tls(rustls::TLSError::WebPKIError(webpki::Error::UnknownIssuer)) = e.0
+            {
+                debug!("certificate issuer is not one of {:?}", self.subjects());
+            }
+            return Err(e);
+        }
+
+        // A certificate for another key would fail every handshake.
+        let spki = x509::parse(crt.leaf.as_ref())
+            .and_then(|tbs| tbs.spki_der())
+            .map_err(|e| InvalidCrt(rustls::TLSError::WebPKIError(e).into()))?;
+        if spki != key.public_key_der() {
+            return Err(InvalidCrt(CrtError::KeyMismatch));
+        }
+        debug!("certified {}", crt.name.log(LogPolicy::current()));
+
+        let scheme = key.1.rustls;
+        let k = SigningKey::new(key);
+        let signatures = k.signatures.clone();
+        let sct_list = crt.sct_list();
+        // Rustls's `CertifiedKey` owns its chain, so each certified key holds
+        // its own copy of the intermediates, even when they're shared by
+        // `Crt`s. The leaf is moved rather than copied.
+        let mut chain = Vec::with_capacity(1 + crt.intermediates.len());
+        chain.push(crt.leaf);
+        chain.extend(crt.intermediates.iter().cloned());
+        let mut key = rustls::sign::CertifiedKey::new(chain, Arc::new(Box::new(k)));
+        key.ocsp = crt.ocsp;
+        key.sct_list = sct_list;
+        let resolver = Arc::new(CertResolver {
+            key,
+            scheme,
+            observe_sni: None,
+            mismatch_log: Arc::new(SchemeMismatchLog::default()),
+        });
+
+        let client_config = tls::client::client_config_with_resolver(self, resolver.clone());
+        let server_config =
+            tls::listen::server_config_with_resolver(self, resolver.clone(), self.client_auth);
+        Ok(CrtKey {
+            name: crt.name,
+            expiry: crt.expiry,
+            scheme,
+            client_auth: self.client_auth,
+            resolver,
+            client_config: Arc::new(client_config),
+            server_config: Arc::new(server_config),
+            store_configs: None,
+            signatures,
+        })
+    }
+}
+
+/// Builds a verifier for TLS client certificates, as `client_auth` requires.
+fn build_client_cert_verifier(
+    verifier: &Arc<Verifier>,
+    client_auth: tls::listen::ClientAuth,
+) -> Arc<dyn rustls::ClientCertVerifier> {
+    match client_auth {
+        tls::listen::ClientAuth::None => rustls::NoClientAuth::new(),
+        client_auth => Arc::new(ClientVerifier {
+            verifier: verifier.clone(),
+            client_auth,
+        }),
+    }
+}
+
+/// Converts `t` to the time that webpki verifies certificates as of.
+fn webpki_time(t: SystemTime) -> webpki::Time {
+    let secs = t
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    webpki::Time::from_seconds_since_unix_epoch(secs)
+}
+
+/// Fails if any certificate in `chain` has been revoked by one of `crls`.
+fn check_revocation<'a, I>(crls: &[x509::Crl], chain: I) -> Result<(), CrtError>
+where
+    I: IntoIterator<Item = &'a rustls::Certificate>,
+{
+    if crls.is_empty() {
+        return Ok(());
+    }
+
+    for c in chain {
+        let tbs = x509::parse(c.as_ref()).map_err(rustls::TLSError::WebPKIError)?;
+        if crls.iter().any(|crl| crl.revokes(&tbs)) {
+            return Err(CrtError::Revoked);
+        }
+    }
+
+    Ok(())
+}
+
+impl tls::client::HasConfig for TrustAnchors {
+    fn tls_client_config(&self) -> Arc<rustls::ClientConfig> {
+        self.config.clone()
+    }
+}
+
+impl fmt::Debug for TrustAnchors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TrustAnchors").finish()
+    }
+}
+
+// === impl IntermediateBundle ===
+
+impl IntermediateBundle {
+    /// Holds the DER-encoded `crts`, which must be in chain order: each is
+    /// issued by the one that follows it.
+    pub fn new(crts: Vec<Vec<u8>>) -> Self {
+        let crts = crts
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        IntermediateBundle(crts.into())
+    }
+
+    /// Loads the `CERTIFICATE` blocks of `pem`, in the order they appear.
+    ///
+    /// Returns `None` if `pem` is invalid or includes no certificates.
+    pub fn from_pem(pem: &str) -> Option<Self> {
+        let crts = rustls::internal::pemfile::certs(&mut pem.as_bytes()).ok()?;
+        if crts.is_empty() {
+            return None;
+        }
+        Some(IntermediateBundle(crts.into()))
+    }
+
+    /// Returns the number of certificates in the bundle.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if the bundle has no certificates.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// === impl TrustAnchorsStore ===
+
+impl TrustAnchorsStore {
+    pub fn new(anchors: TrustAnchors) -> Self {
+        TrustAnchorsStore(Arc::new(RwLock::new(StoredAnchors {
+            anchors,
+            generation: 0,
+        })))
+    }
+
+    /// Returns the current trust anchors.
+    pub fn current(&self) -> TrustAnchors {
+        self.snapshot().1
+    }
+
+    /// Replaces the current trust anchors.
+    pub fn set(&self, anchors: TrustAnchors) {
+        let mut stored = self.0.write().expect("trust anchors lock poisoned");
+        stored.anchors = anchors;
+        stored.generation += 1;
+    }
+
+    /// Like `TrustAnchors::client_cert_verifier`, but the returned verifier
+    /// observes trust anchors that are `set` after it is created.
+    ///
+    /// The verifier is rebuilt once per `set`, rather than as each
+    /// certificate is verified. Since each of its methods observes the trust
+    /// anchors that are current when it is called, a handshake that is in
+    /// progress when the trust anchors are `set` may observe both; servers
+    /// should use `CrtKey::with_trust_anchors_store`, which verifies each
+    /// handshake with one snapshot of the trust anchors.
+    pub fn client_cert_verifier(
+        &self,
+        client_auth: tls::listen::ClientAuth,
+    ) -> Arc<dyn rustls::ClientCertVerifier> {
+        Arc::new(StoreVerifier(StoreCache::new(
+            self.clone(),
+            move |anchors: &TrustAnchors| anchors.client_cert_verifier(client_auth),
+        )))
+    }
+
+    fn generation(&self) -> usize {
+        self.0
+            .read()
+            .expect("trust anchors lock poisoned")
+            .generation
+    }
+
+    fn snapshot(&self) -> (usize, TrustAnchors) {
+        let stored = self.0.read().expect("trust anchors lock poisoned");
+        (stored.generation, stored.anchors.clone())
+    }
+}
+
+// === impl StoreCache ===
+
+impl<T: Clone> StoreCache<T> {
+    fn new<F>(store: TrustAnchorsStore, build: F) -> Self
+    where
+        F: Fn(&TrustAnchors) -> T + Send + Sync + 'static,
+    {
+        let (generation, anchors) = store.snapshot();
+        let cached = RwLock::new((generation, build(&anchors)));
+        Self {
+            store,
+            build: Box::new(build),
+            cached,
+        }
+    }
+
+    /// Returns the value built from the current trust anchors.
+    fn get(&self) -> T {
+        let generation = self.store.generation();
+        {
+            // As with `CrtRegistry`, a poisoned lock still holds a valid value.
+            let cached = self.cached.read().unwrap_or_else(|e| e.into_inner());
+            if cached.0 == generation {
+                return cached.1.clone();
+            }
+        }
+
+        let (generation, anchors) = self.store.snapshot();
+        let value = (self.build)(&anchors);
+        let mut cached = self.cached.write().unwrap_or_else(|e| e.into_inner());
+        // Another caller may have built the value for newer trust anchors.
+        if cached.0 < generation {
+            *cached = (generation, value.clone());
+        }
+        value
+    }
+}
+
+impl StoreCache<Arc<rustls::ServerConfig>> {
+    /// Builds configurations for TLS servers that present the certificates
+    /// that `resolver` resolves, as with `server_config_with_resolver`.
+    fn server_configs(
+        store: TrustAnchorsStore,
+        resolver: Arc<dyn rustls::ResolvesServerCert>,
+        client_auth: tls::listen::ClientAuth,
+    ) -> Self {
+        StoreCache::new(store, move |anchors: &TrustAnchors| {
+            let c =
+                tls::listen::server_config_with_resolver(anchors, resolver.clone(), client_auth);
+            Arc::new(c)
+        })
+    }
+}
+
+// === impl StoreVerifier ===
+
+impl rustls::ClientCertVerifier for StoreVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.0.get().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.0.get().client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> rustls::internal::msgs::handshake::DistinguishedNames {
+        self.0.get().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[rustls::Certificate],
+    ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        self.0.get().verify_client_cert(presented_certs)
+    }
+}
+
+// === Crt ===
+
+impl Crt {
+    /// Fails if `leaf` is empty.
+    pub fn new(
+        name: Name,
+        leaf: Vec<u8>,
+        intermediates: Vec<Vec<u8>>,
+        expiry: SystemTime,
+    ) -> Result<Self, InvalidCrt> {
+        let intermediates = intermediates
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        Self::with_shared_intermediates(name, leaf, intermediates.into(), expiry)
+    }
+
+    /// Like `new`, but orders `crts`---which may hold the intermediates in
+    /// any order, and the root---into a chain leading from the leaf to one
+    /// of `anchors`.
+    ///
+    /// The chain is built by webpki: each of `crts` whose subject is the
+    /// issuer of the certificate before it is tried in turn, so that, of
+    /// cross-signed intermediates or intermediates with the same subject,
+    /// one whose signature is valid is chosen. The chain ends as soon as it
+    /// is issued by a trust anchor, so trusted roots, and certificates that
+    /// aren't part of the chain, are dropped.
+    ///
+    /// Fails if `leaf` is empty, if any of the certificates can't be parsed,
+    /// or if no chain leads to one of `anchors` as of their clock's time.
+    pub fn new_reordered(
+        name: Name,
+        leaf: Vec<u8>,
+        crts: Vec<Vec<u8>>,
+        expiry: SystemTime,
+        anchors: &TrustAnchors,
+    ) -> Result<Self, InvalidCrt> {
+        if leaf.is_empty() {
+            return Err(InvalidCrt::missing_leaf());
+        }
+        let invalid = |e| InvalidCrt(rustls::TLSError::WebPKIError(e).into());
+
+        let names = crts
+            .iter()
+            .map(|der| {
+                let tbs = x509::parse(der).map_err(invalid)?;
+                Ok((tbs.subject.to_vec(), tbs.issuer.to_vec()))
+            })
+            .collect::<Result<Vec<_>, InvalidCrt>>()?;
+        let issuer = x509::parse(&leaf).map_err(invalid)?.issuer.to_vec();
+
+        let path = {
+            let mut path = Vec::new();
+            let candidates = PathCandidates {
+                leaf: webpki::EndEntityCert::from(untrusted::Input::from(&leaf[..]))
+                    .map_err(invalid)?,
+                anchors: anchors
+                    .config
+                    .root_store
+                    .roots
+                    .iter()
+                    .map(|r| r.to_trust_anchor())
+                    .collect(),
+                crts: &crts,
+                names: &names,
+                time: webpki_time(anchors.clock.now()),
+            };
+            candidates.extend(&issuer, &mut path).map_err(invalid)?;
+            path
+        };
+        if path.len() < crts.len() {
+            debug!(
+                "dropping {} certificates that aren't in the chain",
+                crts.len() - path.len()
+            );
+        }
+
+        let mut crts = crts.into_iter().map(Some).collect::<Vec<_>>();
+        let intermediates = path
+            .into_iter()
+            .map(|i| crts[i].take().expect("paths must not repeat certificates"))
+            .collect();
+        Self::new(name, leaf, intermediates, expiry)
+    }
+
+    /// Like `new`, but shares `intermediates` with other certificates rather
+    /// than copying them.
+    ///
+    /// Note that each certified `CrtKey` still holds its own copy of the
+    /// full chain, since that is what Rustls serves.
+    ///
+    /// Fails if `leaf` is empty.
+    pub fn with_shared_intermediates(
+        name: Name,
+        leaf: Vec<u8>,
+        intermediates: Arc<[rustls::Certificate]>,
+        expiry: SystemTime,
+    ) -> Result<Self, InvalidCrt> {
+        // An empty leaf would otherwise fail with an opaque parse error when
+        // the certificate is certified.
+        if leaf.is_empty() {
+            return Err(InvalidCrt::missing_leaf());
+        }
+
+        Ok(Self {
+            name,
+            leaf: rustls::Certificate(leaf),
+            intermediates,
+            expiry,
+            ocsp: None,
+            scts: Vec::new(),
+        })
+    }
+
+    /// Returns the leaf certificate.
+    pub fn leaf(&self) -> &rustls::Certificate {
+        &self.leaf
+    }
+
+    /// Returns the intermediate certificates, which may be shared with other
+    /// certificates.
+    pub fn intermediates(&self) -> &Arc<[rustls::Certificate]> {
+        &self.intermediates
+    }
+
+    /// Encodes the chain as `CERTIFICATE` PEM blocks, leaf first.
+    pub fn chain_pem(&self) -> String {
+        let mut pem = String::new();
+        for c in self.chain() {
+            pem.push_str("-----BEGIN CERTIFICATE-----\n");
+            let b64 = base64::encode(&c.0);
+            // PEM lines are at most 64 characters. Base64 is ASCII, so
+            // splitting on byte boundaries is safe.
+            for line in b64.as_bytes().chunks(64) {
+                pem.push_str(::std::str::from_utf8(line).expect("base64 must be ASCII"));
+                pem.push('\n');
+            }
+            pem.push_str("-----END CERTIFICATE-----\n");
+        }
+        pem
+    }
+
+    /// Iterates over the leaf certificate and then the intermediates.
+    fn chain(&self) -> impl Iterator<Item = &rustls::Certificate> {
+        Some(&self.leaf)
+            .into_iter()
+            .chain(self.intermediates.iter())
+    }
+
+    /// Attaches a DER-encoded OCSP response for the leaf certificate, which
+    /// is stapled to the certificate when it is served.
+    ///
+    /// `TrustAnchors::certify` rejects the certificate unless the response is
+    /// current, is signed by the leaf's issuer or a responder it authorized,
+    /// and shows that the leaf has not been revoked. Clients configured by
+    /// `TrustAnchors` check stapled responses in the same way.
+    pub fn with_ocsp(self, ocsp: Vec<u8>) -> Self {
+        Self {
+            ocsp: Some(ocsp),
+            ..self
+        }
+    }
+
+    /// Attaches a serialized signed certificate timestamp for the leaf
+    /// certificate, which is sent to TLS clients that request it.
+    pub fn with_sct(mut self, sct: Vec<u8>) -> Self {
+        self.scts.push(sct);
+        self
+    }
+
+    /// Encodes the certificate's SCTs as a `SignedCertificateTimestampList`.
+    fn sct_list(&self) -> Option<Vec<u8>> {
+        if self.scts.is_empty() {
+            return None;
+        }
+
+        let mut list = Vec::new();
+        for sct in &self.scts {
+            list.push((sct.len() >> 8) as u8);
+            list.push(sct.len() as u8);
+            list.extend_from_slice(sct);
+        }
+        let mut encoded = Vec::with_capacity(list.len() + 2);
+        encoded.push((list.len() >> 8) as u8);
+        encoded.push(list.len() as u8);
+        encoded.extend(list);
+        Some(encoded)
+    }
+
+    /// Returns the leaf certificate's serial number, as the big-endian bytes
+    /// of its DER encoding, or `None` if the leaf can't be parsed.
+    pub fn leaf_serial(&self) -> Option<Vec<u8>> {
+        x509::parse(self.leaf.as_ref())
+            .ok()
+            .map(|tbs| tbs.serial.to_vec())
+    }
+
+    /// Returns the leaf certificate's validity period, or `None` if the leaf
+    /// can't be parsed.
+    pub fn validity(&self) -> Option<Validity> {
+        x509::parse(self.leaf.as_ref()).ok().map(|tbs| Validity {
+            not_before: tbs.not_before,
+            not_after: tbs.not_after,
+        })
+    }
+
+    /// Returns all of the DNS names in the leaf certificate's subject
+    /// alternative names, in order, including wildcard names.
+    ///
+    /// This is empty if the leaf can't be parsed.
+    pub fn dns_sans(&self) -> Vec<Name> {
+        self.dns_names().unwrap_or_default()
+    }
+
+    /// Returns the DNS names in the leaf certificate's subject alternative
+    /// names.
+    fn dns_names(&self) -> Result<Vec<Name>, InvalidCrt> {
+        // An empty leaf will fail to parse.
+        let crt = webpki::EndEntityCert::from(untrusted::Input::from(self.leaf.as_ref()))
+            .map_err(|e| InvalidCrt(rustls::TLSError::WebPKIError(e).into()))?;
+        let names = crt
+            .dns_names()
+            .map_err(|e| InvalidCrt(rustls::TLSError::WebPKIError(e).into()))?;
+        Ok(names
+            .iter()
+            .map(|n| Name::from(dns::Name::from(n.to_owned())))
+            .collect())
+    }
+
+    /// Removes repeated certificates from the chain, keeping the first
+    /// occurrence of each, and returns the number of certificates removed.
+    ///
+    /// The intermediates are only copied if they include duplicates, so that
+    /// shared intermediates otherwise remain shared.
+    fn dedupe(&mut self) -> usize {
+        let len = self.intermediates.len();
+        let mut intermediates = Vec::<rustls::Certificate>::with_capacity(len);
+        for c in self.intermediates.iter() {
+            if c.0 != self.leaf.0 && !intermediates.iter().any(|d| d.0 == c.0) {
+                intermediates.push(c.clone());
+            }
+        }
+
+        let removed = len - intermediates.len();
+        if removed > 0 {
+            self.intermediates = intermediates.into();
+        }
+        removed
+    }
+}
+
+/// Certificates are equal if they have the same name, expiry, and chain.
+///
+/// Stapled OCSP responses and SCTs are not compared, since they don't change
+/// the identity being certified.
+impl PartialEq for Crt {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.expiry == other.expiry
+            && self.leaf.0 == other.leaf.0
+            && chain_eq(&self.intermediates, &other.intermediates)
+    }
+}
+
+impl Eq for Crt {}
+
+impl hash::Hash for Crt {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.expiry.hash(state);
+        for c in self.chain() {
+            c.0.hash(state);
+        }
+    }
+}
+
+/// Returns the cipher suites that can be negotiated with our ECDSA keys.
+pub fn tls_ciphersuites() -> Vec<&'static rustls::SupportedCipherSuite> {
+    rustls::ALL_CIPHERSUITES
+        .iter()
+        .cloned()
+        .filter(|cs| cs.usable_for_sigalg(SIGNATURE_ALG_RUSTLS_ALGORITHM))
+        .collect()
+}
+
+fn chain_eq(a: &[rustls::Certificate], b: &[rustls::Certificate]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.0 == b.0)
+}
+
+// === impl Validity ===
+
+impl Validity {
+    /// Returns true if `now` is within the validity period.
+    pub fn contains(&self, now: SystemTime) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+
+    /// Returns how long remains until the end of the validity period, which
+    /// is zero once it has ended.
+    pub fn remaining(&self, now: SystemTime) -> Duration {
+        self.not_after
+            .duration_since(now)
+            .unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Returns the fraction of the validity period that has elapsed by
+    /// `now`, from 0.0 before it starts to 1.0 once it has ended.
+    pub fn fraction_elapsed(&self, now: SystemTime) -> f64 {
+        let elapsed = match now.duration_since(self.not_before) {
+            Ok(d) => d,
+            Err(_) => return 0.0,
+        };
+        let lifetime = match self.not_after.duration_since(self.not_before) {
+            Ok(d) if d > Duration::from_secs(0) => d,
+            // An empty or inverted period has ended as soon as it starts.
+            _ => return 1.0,
+        };
+        (secs_f64(elapsed) / secs_f64(lifetime)).min(1.0)
+    }
+}
+
+fn secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+// === CrtKey ===
+
+impl CrtKey {
+    /// Returns the name that the certificate was certified for.
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+
+    /// Returns true if the certificate is valid for `identity`, which is
+    /// either a DNS name or a `spiffe://` URI.
+    ///
+    /// A DNS name must match one of the certificate's DNS SANs, as is checked
+    /// when the certificate is resolved for a TLS handshake. A SPIFFE ID is
+    /// instead matched against the certificate's URI SANs; since SNI names
+    /// are always DNS names, this is never checked during a handshake.
+    pub fn is_valid_for(&self, identity: &str) -> bool {
+        if !identity.starts_with("spiffe://") {
+            return match webpki::DNSNameRef::try_from_ascii_str(identity) {
+                Ok(name) => self.resolver.is_valid_for_dns_name(name),
+                Err(_) => {
+                    debug!("not a valid DNS name: {}", identity);
+                    false
+                }
+            };
+        }
+
+        let id = match SpiffeId::parse(identity) {
+            Some(id) => id,
+            None => {
+                debug!("invalid SPIFFE ID: {}", identity);
+                return false;
+            }
+        };
+        match x509::parse(self.resolver.leaf()) {
+            Ok(tbs) => tbs
+                .uri_sans
+                .iter()
+                .any(|uri| SpiffeId::parse(uri).as_ref() == Some(&id)),
+            Err(err) => {
+                debug!("our certificate could not be parsed: {:?}", err);
+                false
+            }
+        }
+    }
+
+    /// Returns true if the certificate is valid for the DNS name `name`, as
+    /// is checked when the certificate is resolved for a TLS handshake.
+    pub fn is_valid_for_name(&self, name: &Name) -> bool {
+        self.resolver.is_valid_for_dns_name(name.as_dns_name_ref())
+    }
+
+    /// Returns a resolver that presents the certificate to TLS servers that
+    /// request client authentication.
+    pub fn client_cert_resolver(&self) -> Arc<dyn rustls::ResolvesClientCert> {
+        self.resolver.clone()
+    }
+
+    /// Returns `new` if it may replace this key: that is, if it is certified
+    /// for the same name and doesn't expire any sooner.
+    pub fn rotate(&self, new: CrtKey) -> Result<CrtKey, RotateError> {
+        if new.name != self.name {
+            return Err(RotateError::NameMismatch {
+                expected: self.name.clone(),
+                actual: new.name,
+            });
+        }
+        if new.expiry < self.expiry {
+            return Err(RotateError::ExpiryRegressed);
+        }
+        Ok(new)
+    }
+
+    /// Returns a resolver that presents the certificate to TLS clients.
+    pub fn server_cert_resolver(&self) -> Arc<dyn rustls::ResolvesServerCert> {
+        self.resolver.clone()
+    }
+
+    /// Calls `observe` with the SNI name that each TLS client requests,
+    /// before it is matched against the certificate.
+    ///
+    /// Names are observed whether or not the certificate is valid for them,
+    /// and `None` is observed for clients that don't send a valid SNI name.
+    pub fn with_sni_observer<F>(mut self, observe: F) -> Self
+    where
+        F: Fn(Option<&Name>) + Send + Sync + 'static,
+    {
+        self.resolver = Arc::new(CertResolver {
+            key: self.resolver.key.clone(),
+            scheme: self.resolver.scheme,
+            observe_sni: Some(Arc::new(observe)),
+            mismatch_log: self.resolver.mismatch_log.clone(),
+        });
+
+        let mut server_config = self.server_config.as_ref().clone();
+        server_config.cert_resolver = self.resolver.clone();
+        self.server_config = Arc::new(server_config);
+        let store = self.store_configs.as_ref().map(|c| c.store.clone());
+        if let Some(store) = store {
+            self = self.with_trust_anchors_store(&store);
+        }
+        self
+    }
+
+    /// Verifies the clients of `tls_server_config` with the trust anchors
+    /// that are current in `store` when each connection is accepted, so that
+    /// the trust anchors may be rotated without certifying the key again.
+    ///
+    /// Each connection's handshake is verified with a single snapshot of the
+    /// trust anchors. Connections that are accepted before the trust anchors
+    /// are `set` keep verifying with the previous trust anchors.
+    pub fn with_trust_anchors_store(self, store: &TrustAnchorsStore) -> Self {
+        let configs =
+            StoreCache::server_configs(store.clone(), self.resolver.clone(), self.client_auth);
+        Self {
+            store_configs: Some(Arc::new(configs)),
+            ..self
+        }
+    }
+
+    /// Returns the time at which the certificate expires.
+    pub fn expiry(&self) -> SystemTime {
+        self.expiry
+    }
+
+    /// Returns the signature scheme of the certificate's key, which peers
+    /// must offer for the certificate to be presented.
+    pub fn signature_scheme(&self) -> rustls::SignatureScheme {
+        self.scheme
+    }
+
+    /// Returns the number of signatures that have been made with the key,
+    /// e.g. for TLS handshakes.
+    pub fn signatures_performed(&self) -> u64 {
+        self.signatures.load(atomic::Ordering::Relaxed) as u64
+    }
+
+    /// Returns true if the certificate has not yet expired according to
+    /// `clock`.
+    pub fn is_valid_at<C: Clock>(&self, clock: &C) -> bool {
+        clock.now() < self.expiry
+    }
+
+    /// Returns how long to wait before the certificate should be refreshed,
+    /// which is 70% of its remaining lifetime according to `clock`.
+    ///
+    /// This is zero once the certificate has expired.
+    pub fn time_until_refresh<C: Clock>(&self, clock: &C) -> Duration {
+        self.time_until_refresh_at(clock, 0.7)
+    }
+
+    /// Like `time_until_refresh`, but refreshes once `fraction` of the
+    /// remaining lifetime has elapsed.
+    ///
+    /// Fractions outside of 0.0--1.0 are clamped.
+    pub fn time_until_refresh_at<C: Clock>(&self, clock: &C, fraction: f64) -> Duration {
+        let clamped = fraction.max(0.0).min(1.0);
+        if !(fraction >= 0.0 && fraction <= 1.0) {
+            debug!("clamping refresh fraction {} to {}", fraction, clamped);
+        }
+        // Scale in integer parts-per-million, so that exact fractions
+        // produce exact durations.
+        let ppm = (clamped * 1_000_000.0).round() as u32;
+        self.expiry
+            .duration_since(clock.now())
+            .map(|d| d * ppm / 1_000_000)
+            .unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Returns true if the certificate expires within `threshold` of `now`,
+    /// e.g. so that readiness probes fail when it hasn't been rotated.
+    ///
+    /// This is true once the certificate has expired.
+    pub fn is_expiring_soon(&self, now: SystemTime, threshold: Duration) -> bool {
+        match self.expiry.duration_since(now) {
+            Ok(remaining) => remaining <= threshold,
+            Err(_) => true,
+        }
+    }
+
+    /// Returns the number of seconds from `now` until the certificate
+    /// expires, which is negative if it has already expired.
+    pub fn seconds_until_expiry(&self, now: SystemTime) -> i64 {
+        match self.expiry.duration_since(now) {
+            Ok(d) => cmp::min(d.as_secs(), i64::max_value() as u64) as i64,
+            Err(e) => -(cmp::min(e.duration().as_secs(), i64::max_value() as u64) as i64),
+        }
+    }
+}
+
+impl tls::client::HasConfig for CrtKey {
+    fn tls_client_config(&self) -> Arc<tls::client::Config> {
+        self.client_config.clone()
+    }
+
+    fn tls_client_name(&self) -> Option<Name> {
+        Some(self.name.clone())
+    }
+}
+
+impl tls::listen::HasConfig for CrtKey {
+    fn tls_server_name(&self) -> Name {
+        self.name.clone()
+    }
+
+    fn tls_server_config(&self) -> Arc<tls::listen::Config> {
+        match self.store_configs {
+            Some(ref configs) => configs.get(),
+            None => self.server_config.clone(),
+        }
+    }
+
+    fn tls_client_auth(&self) -> tls::listen::ClientAuth {
+        self.client_auth
+    }
+}
+
+/// Keys can't be compared directly, so certified keys are equal if they
+/// have the same name, expiry, and certificate chain.
+impl PartialEq for CrtKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.expiry == other.expiry
+            && chain_eq(&self.resolver.key.cert, &other.resolver.key.cert)
+    }
+}
+
+impl Eq for CrtKey {}
+
+impl fmt::Debug for CrtKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("CrtKey")
+            .field("name", &self.name)
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+
+// === impl CrtKeys ===
+
+#[allow(dead_code)] // Not yet used by the proxy.
+impl CrtKeys {
+    /// Serves all of `keys`, which must be certified for the same name.
+    ///
+    /// The server configuration, including how clients are authenticated, is
+    /// taken from the first key.
+    pub fn new<I: IntoIterator<Item = CrtKey>>(keys: I) -> Result<Self, CrtKeysError> {
+        let mut keys = keys.into_iter().collect::<Vec<_>>();
+        let first = match keys.first() {
+            Some(k) => k.clone(),
+            None => return Err(CrtKeysError::Empty),
+        };
+        if let Some(k) = keys.iter().find(|k| k.name != first.name) {
+            return Err(CrtKeysError::NameMismatch {
+                expected: first.name.clone(),
+                actual: k.name.clone(),
+            });
+        }
+
+        // The sort is stable, so keys with the same scheme keep their order.
+        keys.sort_by_key(|k| cmp::Reverse(scheme_strength(k.scheme)));
+        let resolver = MultiCertResolver::new(keys.iter().map(|k| k.resolver.clone()).collect());
+
+        let resolver = Arc::new(resolver);
+        let store_configs = first.store_configs.as_ref().map(|c| {
+            let configs =
+                StoreCache::server_configs(c.store.clone(), resolver.clone(), first.client_auth);
+            Arc::new(configs)
+        });
+        let mut server_config = first.server_config.as_ref().clone();
+        server_config.cert_resolver = resolver;
+        Ok(CrtKeys {
+            name: first.name,
+            client_auth: first.client_auth,
+            server_config: Arc::new(server_config),
+            store_configs,
+        })
+    }
+
+    /// Returns the name that the keys were certified for.
+    pub fn name(&self) -> &Name {
+        &self.name
+    }
+}
+
+impl tls::listen::HasConfig for CrtKeys {
+    fn tls_server_name(&self) -> Name {
+        self.name.clone()
+    }
+
+    fn tls_server_config(&self) -> Arc<tls::listen::Config> {
+        match self.store_configs {
+            Some(ref configs) => configs.get(),
+            None => self.server_config.clone(),
+        }
+    }
+
+    fn tls_client_auth(&self) -> tls::listen::ClientAuth {
+        self.client_auth
+    }
+}
+
+impl fmt::Debug for CrtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("CrtKeys").field("name", &self.name).finish()
+    }
+}
+
+/// Ranks signature schemes by the strength of their curves.
+#[allow(dead_code)] // Not yet used by the proxy.
+fn scheme_strength(scheme: rustls::SignatureScheme) -> u8 {
+    match scheme {
+        rustls::SignatureScheme::ECDSA_NISTP384_SHA384 => 2,
+        rustls::SignatureScheme::ECDSA_NISTP256_SHA256 => 1,
+        _ => 0,
+    }
+}
+
+// === impl MultiCertResolver ===
+
+#[allow(dead_code)] // Not yet used by the proxy.
+impl MultiCertResolver {
+    fn new(resolvers: Vec<Arc<CertResolver>>) -> Self {
+        Self {
+            resolvers,
+            mismatch_log: SchemeMismatchLog::default(),
+        }
+    }
+}
+
+impl rustls::ResolvesServerCert for MultiCertResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        // The resolvers don't log mismatches themselves, since another
+        // resolver may support a scheme that the client offered.
+        let key = self
+            .resolvers
+            .iter()
+            .filter_map(|r| r.resolve_server(server_name, sigschemes, None))
+            .next();
+        if key.is_none()
+            && !self
+                .resolvers
+                .iter()
+                .any(|r| sigschemes.contains(&r.scheme))
+        {
+            let supported = self.resolvers.iter().map(|r| r.scheme).collect::<Vec<_>>();
+            self.mismatch_log.declined(sigschemes, &supported);
+        }
+        key
+    }
+}
+
+// === impl SchemeMismatchLog ===
+
+impl SchemeMismatchLog {
+    fn declined(&self, offered: &[rustls::SignatureScheme], supported: &[rustls::SignatureScheme]) {
+        let now = Instant::now();
+        let mut last = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(t) = *last {
+            if now.duration_since(t) < SCHEME_MISMATCH_LOG_INTERVAL {
+                return;
+            }
+        }
+        *last = Some(now);
+        info!(
+            "peer offered no supported signature scheme -> no certificate; offered={:?}, supported={:?}",
+            offered, supported,
+        );
+    }
+}
+
+// === impl SystemClock ===
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// === impl CertResolver ===
+
+impl rustls::ResolvesClientCert for CertResolver {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        // The proxy's server-side doesn't send the list of acceptable issuers so
+        // don't bother looking at `_acceptable_issuers`.
+        self.resolve_(sigschemes, Some(&self.mismatch_log))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+impl CertResolver {
+    /// Returns our certificate's DER encoding.
+    fn leaf(&self) -> &[u8] {
+        (&self.key.cert)
+            .first()
+            .map(rustls::Certificate::as_ref)
+            .unwrap_or(&[]) // An empty input will fail to parse.
+    }
+
+    /// Returns true if our certificate is valid for the DNS name `name`,
+    /// which must match one of its DNS SANs, including wildcards.
+    fn is_valid_for_dns_name(&self, name: webpki::DNSNameRef) -> bool {
+        let result = webpki::EndEntityCert::from(untrusted::Input::from(self.leaf()))
+            .and_then(|c| c.verify_is_valid_for_dns_name(name));
+        if let Err(err) = result {
+            let name: &str = name.into();
+            debug!("our certificate is not valid for {}: {:?}", name, err);
+            return false;
+        }
+        true
+    }
+
+    /// Returns our certificate if the peer offered our key's scheme, or
+    /// otherwise reports the mismatch to `mismatch_log`, if there is one.
+    fn resolve_(
+        &self,
+        sigschemes: &[rustls::SignatureScheme],
+        mismatch_log: Option<&SchemeMismatchLog>,
+    ) -> Option<rustls::sign::CertifiedKey> {
+        // Only present the certificate to peers that offered our key's
+        // scheme, so that, e.g., ECDSA certificates aren't presented to
+        // peers that only offered RSA schemes.
+        if !sigschemes.contains(&self.scheme) {
+            debug!(
+                "signature scheme not supported -> no certificate; offered={:?}, supported={:?}",
+                sigschemes, self.scheme,
+            );
+            if let Some(log) = mismatch_log {
+                log.declined(sigschemes, &[self.scheme]);
+            }
+            return None;
+        }
+        debug!("using signature scheme {:?}", self.scheme);
+        Some(self.key.clone())
+    }
+}
+
+impl rustls::ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        self.resolve_server(server_name, sigschemes, Some(&self.mismatch_log))
+    }
+}
+
+impl CertResolver {
+    fn resolve_server(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+        mismatch_log: Option<&SchemeMismatchLog>,
+    ) -> Option<rustls::sign::CertifiedKey> {
+        if let Some(ref observe) = self.observe_sni {
+            let name = server_name.and_then(|n| {
+                let n: &str = n.into();
+                Name::from_hostname(n.as_bytes()).ok()
+            });
+            observe(name.as_ref());
+        }
+
+        let server_name = if let Some(server_name) = server_name {
+            server_name
+        } else {
+            debug!("no SNI -> no certificate");
+            return None;
+        };
+
+        // Verify that our certificate is valid for the given SNI name. SNI
+        // names are always DNS names, and match any of the certificate's DNS
+        // SANs, including wildcards.
+        if !self.is_valid_for_dns_name(server_name) {
+            debug!("our certificate is not valid for the SNI name -> no certificate");
+            return None;
+        }
+
+        self.resolve_(sigschemes, mismatch_log)
+    }
+}
+
+// === impl Verifier ===
+
+impl Verifier {
+    /// Verifies `leaf`, issued through `intermediates`, for `usage` as of
+    /// `now`.
+    ///
+    /// The chain is validated as of `now - skew`, so that certificates that
+    /// are not yet valid are tolerated, and then each certificate's notAfter
+    /// is checked against `now`, whatever the skew, so that expired
+    /// certificates are not.
+    fn verify(
+        &self,
+        leaf: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        usage: Usage,
+        now: SystemTime,
+        skew: Duration,
+    ) -> Result<(), CrtError> {
+        let webpki_err = rustls::TLSError::WebPKIError;
+
+        // Chains may be chosen by peers, so they're bounded before webpki
+        // searches them for a path to the roots.
+        let len = 1 + intermediates.len();
+        if len > self.max_chain_len {
+            debug!(
+                "certificate chain has {} certificates; at most {} are allowed",
+                len, self.max_chain_len
+            );
+            return Err(CrtError::ChainTooLong);
+        }
+
+        let crt = webpki::EndEntityCert::from(untrusted::Input::from(leaf.as_ref()))
+            .map_err(webpki_err)?;
+        let anchors = self
+            .roots
+            .roots
+            .iter()
+            .map(|r| r.to_trust_anchor())
+            .collect::<Vec<_>>();
+        let chain = intermediates
+            .iter()
+            .map(|c| untrusted::Input::from(c.as_ref()))
+            .collect::<Vec<_>>();
+
+        let now_secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let time =
+            webpki::Time::from_seconds_since_unix_epoch(now_secs.saturating_sub(skew.as_secs()));
+        match usage {
+            Usage::Server(name) => {
+                crt.verify_is_valid_tls_server_cert(
+                    SUPPORTED_SIG_ALGS,
+                    &webpki::TLSServerTrustAnchors(&anchors),
+                    &chain,
+                    time,
+                )
+                .map_err(webpki_err)?;
+                crt.verify_is_valid_for_dns_name(name).map_err(webpki_err)?;
+            }
+            Usage::Client => {
+                crt.verify_is_valid_tls_client_cert(
+                    SUPPORTED_SIG_ALGS,
+                    &webpki::TLSClientTrustAnchors(&anchors),
+                    &chain,
+                    time,
+                )
+                .map_err(webpki_err)?;
+            }
+        }
+
+        let chain = ::std::iter::once(leaf).chain(intermediates);
+        for c in chain.clone() {
+            let tbs = x509::parse(c.as_ref()).map_err(webpki_err)?;
+            if tbs.not_after < now {
+                return Err(webpki_err(webpki::Error::CertExpired).into());
+            }
+            if tbs.not_before > now {
+                debug!("tolerating certificate that is not yet valid");
+            }
+        }
+
+        check_revocation(&self.crls, chain)
+    }
+
+    /// Fails unless `ocsp`, a DER-encoded OCSP response, shows that `leaf`,
+    /// issued through `intermediates`, had not been revoked as of `now`.
+    ///
+    /// The response must be current: `now` must fall between its
+    /// `thisUpdate` and `nextUpdate` times. Responses without a `nextUpdate`
+    /// can't be shown to be current, so they're rejected.
+    fn check_ocsp(
+        &self,
+        ocsp: &[u8],
+        leaf: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now: SystemTime,
+    ) -> Result<(), CrtError> {
+        let webpki_err = rustls::TLSError::WebPKIError;
+
+        let crt = x509::parse(leaf.as_ref()).map_err(webpki_err)?;
+        let intermediates = intermediates
+            .iter()
+            .map(|c| x509::parse(c.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(webpki_err)?;
+        let roots = self
+            .roots
+            .roots
+            .iter()
+            .map(|r| r.to_trust_anchor())
+            .collect::<Vec<_>>();
+        // The leaf may be issued by an intermediate or by one of the roots.
+        let issuers = intermediates
+            .iter()
+            .map(|c| (c.subject, c.spki))
+            .chain(roots.iter().map(|r| (r.subject, r.spki)))
+            .collect::<Vec<_>>();
+
+        match x509::read_ocsp(ocsp, &crt, &issuers, now).map_err(webpki_err)? {
+            x509::OcspStatus::Good {
+                this_update,
+                next_update,
+            } => {
+                let current = this_update <= now && next_update.map(|t| now <= t).unwrap_or(false);
+                if !current {
+                    debug!(
+                        "OCSP response is current from {:?} until {:?}",
+                        this_update, next_update
+                    );
+                    return Err(CrtError::OcspStale);
+                }
+                Ok(())
+            }
+            x509::OcspStatus::Revoked => Err(CrtError::Revoked),
+            x509::OcspStatus::Unknown => Err(CrtError::OcspUnknown),
+        }
+    }
+
+    /// Fails unless `leaf`, issued through `intermediates`, has a valid SCT
+    /// from one of the configured logs, if any, as of `now`.
+    ///
+    /// `attached` holds SCTs that were issued for `leaf` itself; SCTs
+    /// embedded in `leaf` are issued for its precertificate.
+    fn check_scts(
+        &self,
+        leaf: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        attached: &[Vec<u8>],
+        now: SystemTime,
+    ) -> Result<(), CrtError> {
+        let logs = match self.ct_logs {
+            Some(logs) => logs,
+            None => return Ok(()),
+        };
+        let now_ms = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            .unwrap_or(0);
+
+        let valid = attached
+            .iter()
+            .filter(
+                |sct| match sct::verify_sct(leaf.as_ref(), sct, now_ms, logs) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        debug!("ignoring invalid SCT: {:?}", e);
+                        false
+                    }
+                },
+            )
+            .count();
+        if valid > 0 || self.embedded_scts(leaf, intermediates, now_ms, logs)? > 0 {
+            return Ok(());
+        }
+        Err(
+            rustls::TLSError::General("certificate has no valid SCT from a trusted log".into())
+                .into(),
+        )
+    }
+
+    /// Returns the number of valid SCTs from `logs` that are embedded in
+    /// `leaf`, issued through `intermediates`.
+    fn embedded_scts(
+        &self,
+        leaf: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now_ms: u64,
+        logs: &[&sct::Log],
+    ) -> Result<usize, rustls::TLSError> {
+        let webpki_err = rustls::TLSError::WebPKIError;
+
+        let crt = x509::parse(leaf.as_ref()).map_err(webpki_err)?;
+        let list = match crt.sct_list {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let scts = ct::read_list(list).map_err(rustls::TLSError::InvalidSCT)?;
+        let precert = x509::precert_tbs(leaf.as_ref()).map_err(webpki_err)?;
+
+        // The SCTs are signed over the key of the leaf's issuer, which may be
+        // an intermediate or one of the roots. The chain has already been
+        // verified, so any certificate with the issuer's name will do.
+        let intermediates = intermediates
+            .iter()
+            .map(|c| x509::parse(c.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(webpki_err)?;
+        let roots = self
+            .roots
+            .roots
+            .iter()
+            .map(|r| r.to_trust_anchor())
+            .collect::<Vec<_>>();
+        let issuers = intermediates
+            .iter()
+            .map(|c| (c.subject, c.spki))
+            .chain(roots.iter().map(|r| (r.subject, r.spki)))
+            .filter(|&(subject, _)| subject == crt.issuer)
+            .filter_map(|(_, spki)| x509::spki_der(spki).ok())
+            .collect::<Vec<_>>();
+
+        let valid = scts
+            .iter()
+            .filter(|sct| {
+                let mut result = Err(sct::Error::UnknownLog);
+                for issuer in &issuers {
+                    result = ct::verify_embedded(sct, &precert, issuer, now_ms, logs);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                if let Err(e) = result {
+                    debug!("ignoring invalid embedded SCT: {:?}", e);
+                }
+                result.is_ok()
+            })
+            .count();
+        Ok(valid)
+    }
+
+    /// Verifies a chain presented in a TLS handshake, as of the current time.
+    fn verify_presented(
+        &self,
+        presented_certs: &[rustls::Certificate],
+        usage: Usage,
+    ) -> Result<(), CrtError> {
+        let (leaf, intermediates) = presented_certs
+            .split_first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let skew = Duration::from_secs(0);
+        self.verify(leaf, intermediates, usage, self.clock.now(), skew)
+    }
+}
+
+impl rustls::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        self.verify_presented(presented_certs, Usage::Server(dns_name))?;
+        let (leaf, intermediates) = presented_certs
+            .split_first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let now = self.clock.now();
+
+        // Servers needn't staple OCSP responses, but those that do must
+        // staple a current one.
+        if !ocsp_response.is_empty() {
+            self.check_ocsp(ocsp_response, leaf, intermediates, now)?;
+        }
+
+        // Rustls verifies the SCTs that servers provide in the handshake, but
+        // doesn't pass them here, so only embedded SCTs satisfy the logs.
+        self.check_scts(leaf, intermediates, &[], now)?;
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+// === impl PathCandidates ===
+
+impl<'a> PathCandidates<'a> {
+    /// Extends `path`, the indices of the certificates leading from the leaf
+    /// to one issued by `issuer`, until webpki finds that it leads to one of
+    /// the trust anchors.
+    ///
+    /// `path` is left as it was if no chain is found.
+    fn extend(&self, issuer: &[u8], path: &mut Vec<usize>) -> Result<(), webpki::Error> {
+        let chain = path
+            .iter()
+            .map(|&i| untrusted::Input::from(&self.crts[i][..]))
+            .collect::<Vec<_>>();
+        let mut result = self.leaf.verify_is_valid_tls_server_cert(
+            SUPPORTED_SIG_ALGS,
+            &webpki::TLSServerTrustAnchors(&self.anchors),
+            &chain,
+            self.time,
+        );
+        if result.is_ok() {
+            return result;
+        }
+
+        for (i, &(ref subject, ref next)) in self.names.iter().enumerate() {
+            if subject[..] != *issuer || path.contains(&i) {
+                continue;
+            }
+            path.push(i);
+            result = self.extend(next, path);
+            if result.is_ok() {
+                return result;
+            }
+            path.pop();
+        }
+        result
+    }
+}
+
+// === impl ClientVerifier ===
+
+impl rustls::ClientCertVerifier for ClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.client_auth == tls::listen::ClientAuth::Required
+    }
+
+    fn client_auth_root_subjects(&self) -> rustls::internal::msgs::handshake::DistinguishedNames {
+        self.verifier.roots.get_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[rustls::Certificate],
+    ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        self.verifier
+            .verify_presented(presented_certs, Usage::Client)?;
+        Ok(rustls::ClientCertVerified::assertion())
+    }
+}
+
+// === impl BundleError ===
+
+/// Loads a private key and its certificate chain from a single PEM bundle.
+///
+/// The bundle must include exactly one PKCS#8 `PRIVATE KEY` block. Its
+/// `CERTIFICATE` blocks are returned in the order they appear, which must be
+/// leaf first.
+#[allow(dead_code)] // Not yet used by the proxy.
+pub fn load_bundle_pem(pem: &str) -> Result<(Key, Vec<rustls::Certificate>), BundleError> {
+    use self::rustls::internal::pemfile;
+
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut pem.as_bytes()).map_err(|()| BundleError::InvalidPem)?;
+    let chain = pemfile::certs(&mut pem.as_bytes()).map_err(|()| BundleError::InvalidPem)?;
+
+    let key = match keys.len() {
+        0 => return Err(BundleError::MissingKey),
+        1 => {
+            let mut k = keys.remove(0);
+            Key::from_pkcs8_zeroizing(&mut k.0).map_err(BundleError::InvalidKey)?
+        }
+        n => {
+            for k in keys.iter_mut() {
+                zeroize(&mut k.0);
+            }
+            return Err(BundleError::MultipleKeys(n));
+        }
+    };
+
+    if chain.is_empty() {
+        return Err(BundleError::MissingCrt);
+    }
+
+    Ok((key, chain))
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::InvalidPem => write!(f, "bundle is not valid PEM"),
+            BundleError::MissingKey => write!(f, "bundle does not include a private key"),
+            BundleError::MultipleKeys(n) => write!(f, "bundle includes {} private keys", n),
+            BundleError::MissingCrt => write!(f, "bundle does not include a certificate"),
+            BundleError::InvalidKey(e) => write!(f, "bundle includes an invalid key: {}", e),
+        }
+    }
+}
+
+impl StdError for BundleError {}
+
+// === impl EncryptedKeyError ===
+
+impl fmt::Display for EncryptedKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptedKeyError::Malformed => write!(f, "encrypted key is malformed"),
+            EncryptedKeyError::Unsupported => {
+                write!(f, "key is encrypted with an unsupported scheme")
+            }
+            EncryptedKeyError::TooManyIterations(n) => write!(
+                f,
+                "key is encrypted with {} PBKDF2 iterations; at most {} are allowed",
+                n, MAX_PBKDF2_ITERATIONS
+            ),
+            EncryptedKeyError::Decrypt => write!(f, "key could not be decrypted"),
+            EncryptedKeyError::InvalidKey(e) => write!(f, "decrypted key is invalid: {}", e),
+        }
+    }
+}
+
+impl StdError for EncryptedKeyError {}
+
+// === impl Error ===
+
+impl From<KeyRejected> for Error {
+    fn from(e: KeyRejected) -> Self {
+        Error::Key(e)
+    }
+}
+
+impl From<InvalidName> for Error {
+    fn from(e: InvalidName) -> Self {
+        Error::Name(e)
+    }
+}
+
+impl From<InvalidCrt> for Error {
+    fn from(e: InvalidCrt) -> Self {
+        Error::Crt(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Key(e) => write!(f, "invalid key: {}", e),
+            Error::Name(_) => write!(f, "invalid name"),
+            Error::Crt(e) => write!(f, "invalid certificate: {}", e),
+            Error::Io(e) => write!(f, "identity I/O error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Key(e) => Some(e),
+            Error::Name(_) => None,
+            Error::Crt(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+// === impl TrustAnchorsError ===
+
+impl fmt::Display for TrustAnchorsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrustAnchorsError::Empty => write!(f, "trust anchors are empty"),
+            TrustAnchorsError::Parse => write!(f, "trust anchors are not valid PEM"),
+            TrustAnchorsError::NoneAdded { skipped } => {
+                write!(f, "no valid trust anchors were found ({} skipped)", skipped)
+            }
+        }
+    }
+}
+
+impl StdError for TrustAnchorsError {}
+
+// === impl CrlError ===
+
+impl fmt::Display for CrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrlError::InvalidPem => write!(f, "revocation list is not valid PEM"),
+            CrlError::MissingCrl => write!(f, "no revocation list was found"),
+            CrlError::InvalidCrl(e) => write!(f, "invalid revocation list: {:?}", e),
+        }
+    }
+}
+
+impl StdError for CrlError {}
+
+// === impl RotateError ===
+
+impl fmt::Display for RotateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RotateError::NameMismatch { expected, actual } => write!(
+                f,
+                "new key is certified for {} instead of {}",
+                actual, expected
+            ),
+            RotateError::ExpiryRegressed => write!(f, "new key expires before the current key"),
+        }
+    }
+}
+
+impl StdError for RotateError {}
+
+// === impl CrtKeysError ===
+
+impl fmt::Display for CrtKeysError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrtKeysError::Empty => write!(f, "no keys were provided"),
+            CrtKeysError::NameMismatch { expected, actual } => {
+                write!(f, "key is certified for {} instead of {}", actual, expected)
+            }
+        }
+    }
+}
+
+impl StdError for CrtKeysError {}
+
+// === impl TokenReadError ===
+
+impl fmt::Display for TokenReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenReadError::Io { path, cause } => {
+                write!(f, "failed to read identity token from {}: {}", path, cause)
+            }
+            TokenReadError::Empty => write!(f, "token is empty"),
+        }
+    }
+}
+
+impl StdError for TokenReadError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            TokenReadError::Io { cause, .. } => Some(cause),
+            TokenReadError::Empty => None,
+        }
+    }
+}
+
+// === impl InvalidCrt ===
+
+impl InvalidCrt {
+    /// Returns the reason that the certificate is invalid.
+    pub fn kind(&self) -> InvalidCrtKind {
+        match self.0 {
+            CrtError::Tls(rustls::TLSError::WebPKIError(ref e)) => match e {
+                webpki::Error::UnknownIssuer => InvalidCrtKind::UnknownIssuer,
+                webpki::Error::CertExpired => InvalidCrtKind::Expired,
+                webpki::Error::CertNotValidForName => InvalidCrtKind::NotValidForName,
+                webpki::Error::InvalidSignatureForPublicKey
+                | webpki::Error::UnsupportedSignatureAlgorithm
+                | webpki::Error::UnsupportedSignatureAlgorithmForPublicKey => {
+                    InvalidCrtKind::BadSignature
+                }
+                _ => InvalidCrtKind::Other,
+            },
+            _ => InvalidCrtKind::Other,
+        }
+    }
+
+    fn missing_leaf() -> Self {
+        InvalidCrt(CrtError::MissingLeaf)
+    }
+
+    /// Returns true if the certificate was rejected because it has been
+    /// revoked.
+    pub fn is_revoked(&self) -> bool {
+        match self.0 {
+            CrtError::Revoked => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the certificate was rejected because the chain does
+    /// not include a leaf certificate, e.g. because it was empty.
+    pub fn is_missing_leaf(&self) -> bool {
+        match self.0 {
+            CrtError::MissingLeaf => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the certificate was rejected because its chain
+    /// includes more certificates than the trust anchors allow.
+    pub fn is_chain_too_long(&self) -> bool {
+        match self.0 {
+            CrtError::ChainTooLong => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the certificate was rejected because its leaf
+    /// certificate was issued for a key other than the one it was paired
+    /// with.
+    pub fn is_key_mismatch(&self) -> bool {
+        match self.0 {
+            CrtError::KeyMismatch => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for InvalidCrt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl StdError for InvalidCrt {
+    fn description(&self) -> &str {
+        self.0.description()
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+
+// === impl CrtError ===
+
+impl CrtError {
+    fn description(&self) -> &str {
+        match self {
+            CrtError::Tls(e) => e.description(),
+            CrtError::Revoked => "certificate has been revoked",
+            CrtError::OcspStale => "OCSP response is not current",
+            CrtError::OcspUnknown => "OCSP response does not report the certificate's status",
+            CrtError::MissingLeaf => "certificate chain does not include a leaf certificate",
+            CrtError::KeyMismatch => "certificate was not issued for the key",
+            CrtError::ChainTooLong => "certificate chain is too long",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            CrtError::Tls(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<rustls::TLSError> for CrtError {
+    fn from(e: rustls::TLSError) -> Self {
+        CrtError::Tls(e)
+    }
+}
+
+impl From<CrtError> for rustls::TLSError {
+    fn from(e: CrtError) -> Self {
+        match e {
+            CrtError::Tls(e) => e,
+            e => rustls::TLSError::General(e.description().into()),
+        }
+    }
+}
+
+impl fmt::Display for CrtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrtError::Tls(e) => fmt::Display::fmt(e, f),
+            e => f.write_str(e.description()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::*;
+    use super::{
+        load_bundle_pem, rustls, webpki, BundleError, CrlError, Crt, CrtKeys, CrtKeysError,
+        DuplicateCrtPolicy, EncryptedKeyError, Error, IntermediateBundle, InvalidCrtKind, Key,
+        LogPolicy, Name, NamePolicy, RotateError, SigningKey, SkipInfo, SpiffeId, TokenSource,
+        TrustAnchors, TrustAnchorsError, TrustAnchorsStore, MAX_PBKDF2_ITERATIONS, VERIFIERS_BUILT,
+    };
+    use logging::test_util::capture;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn can_construct_client_and_server_config_from_valid_settings() {
+        FOO_NS1.validate().expect("foo.ns1 must be valid");
+    }
+
+    #[test]
+    fn recognize_ca_did_not_issue_cert() {
+        let s = Strings {
+            trust_anchors: "ca2.pem",
+            ..FOO_NS1
+        };
+        assert!(s.validate().is_err(), "ca2 should not validate foo.ns1");
+    }
+
+    #[test]
+    fn recognize_cert_is_not_valid_for_identity() {
+        let s = Strings {
+            crt: BAR_NS1.crt,
+            key: BAR_NS1.key,
+            ..FOO_NS1
+        };
+        assert!(s.validate().is_err(), "identity should not be valid");
+    }
+
+    #[test]
+    fn recognize_private_key_is_not_valid_for_cert() {
+        let s = Strings {
+            key: BAR_NS1.key,
+            ..FOO_NS1
+        };
+        assert!(s.validate().is_err(), "identity should not be valid");
+    }
+
+    #[test]
+    fn log_name_full() {
+        let n = Name::from_hostname(FOO_NS1.name.as_bytes()).unwrap();
+        assert_eq!(format!("{}", n.log(LogPolicy::Full)), FOO_NS1.name);
+    }
+
+    #[test]
+    fn log_name_redacted() {
+        let foo = Name::from_hostname(FOO_NS1.name.as_bytes()).unwrap();
+        let bar = Name::from_hostname(BAR_NS1.name.as_bytes()).unwrap();
+
+        let redacted = format!("{}", foo.log(LogPolicy::Redacted));
+        assert_eq!(redacted, format!("id:{}", foo.short_id()));
+        assert!(
+            !redacted.contains("foo"),
+            "name must not leak: {}",
+            redacted
+        );
+
+        // Ids are stable for a name and distinct across names.
+        assert_eq!(
+            redacted,
+            format!("{}", foo.clone().log(LogPolicy::Redacted))
+        );
+        assert_ne!(foo.short_id(), bar.short_id());
+    }
+
+    #[test]
+    fn key_from_pkcs8_zeroizing_zeroes_input() {
+        let mut p8 = FOO_NS1.key_pkcs8();
+        Key::from_pkcs8_zeroizing(&mut p8).expect("key must be valid");
+        assert!(!p8.is_empty());
+        assert!(p8.iter().all(|b| *b == 0), "key material must be zeroed");
+    }
+
+    #[test]
+    fn key_public_key_der_matches_crt() {
+        let crt = FOO_NS1.crt_der();
+        let tbs = super::x509::parse(&crt).expect("certificate must parse");
+        let spki = FOO_NS1.key().public_key_der();
+        // The certificate's SPKI excludes the two-byte SEQUENCE header.
+        assert_eq!(&spki[..2], &[0x30, tbs.spki.len() as u8][..]);
+        assert_eq!(&spki[2..], tbs.spki);
+
+        assert_ne!(BAR_NS1.key().public_key_der(), spki);
+    }
+
+    #[test]
+    fn key_from_pkcs8_zeroizing_zeroes_rejected_input() {
+        let mut p8 = FOO_NS1.key_pkcs8();
+        p8.truncate(p8.len() / 2);
+        assert!(Key::from_pkcs8_zeroizing(&mut p8).is_err());
+        assert!(p8.iter().all(|b| *b == 0), "key material must be zeroed");
+    }
+
+    #[test]
+    fn certify_dedupes_duplicate_intermediates() {
+        let ca = FOO_NS1.trust_anchors_der().remove(0);
+        let crt = FOO_NS1.crt();
+        let mut dup = Crt::new(
+            crt.name.clone(),
+            crt.leaf.0.clone(),
+            vec![ca.clone(), ca],
+            crt.expiry,
+        )
+        .expect("crt must be valid");
+        assert_eq!(dup.clone().dedupe(), 1);
+
+        FOO_NS1
+            .trust_anchors()
+            .certify(FOO_NS1.key(), dup.clone())
+            .expect("deduped chain must be valid");
+
+        dup.dedupe();
+        assert_eq!(dup.intermediates.len(), 1);
+    }
+
+    #[test]
+    fn certify_rejects_duplicates_by_policy() {
+        let crt = FOO_NS1.crt();
+        let leaf = crt.leaf.0.clone();
+        let dup = Crt::new(crt.name.clone(), leaf.clone(), vec![leaf], crt.expiry)
+            .expect("crt must be valid");
+
+        let anchors = FOO_NS1
+            .trust_anchors()
+            .with_duplicate_crt_policy(DuplicateCrtPolicy::Reject);
+        assert!(anchors.certify(FOO_NS1.key(), dup).is_err());
+    }
+
+    #[test]
+    fn load_bundle() {
+        let pem = FOO_NS1.bundle_pem();
+        let (_key, chain) = load_bundle_pem(&pem).expect("bundle must be valid");
+
+        let crt = FOO_NS1.crt();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].0, crt.leaf.0, "leaf must be first");
+        assert_eq!(chain[1].0, FOO_NS1.trust_anchors_der()[0]);
+    }
+
+    #[test]
+    fn load_bundle_without_key() {
+        let pem = FOO_NS1.bundle_pem();
+        let crts = &pem[pem.find("-----BEGIN CERTIFICATE-----").unwrap()..];
+        let e = load_bundle_pem(crts).expect_err("bundles without a key must be rejected");
+        assert!(
+            match e {
+                BundleError::MissingKey => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn load_bundle_with_multiple_keys() {
+        let pem = FOO_NS1.bundle_pem();
+        let (key, crts) = pem.split_at(pem.find("-----BEGIN CERTIFICATE-----").unwrap());
+        let pem = format!("{}{}{}", key, key, crts);
+        let e = load_bundle_pem(&pem).expect_err("bundles with two keys must be rejected");
+        assert!(
+            match e {
+                BundleError::MultipleKeys(2) => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn choose_scheme_logs_offered_schemes_on_mismatch() {
+        use self::rustls::sign::SigningKey as RustlsSigningKey;
+
+        let key = SigningKey::new(FOO_NS1.key());
+        let logs = capture(|| {
+            let offered = &[
+                rustls::SignatureScheme::RSA_PKCS1_SHA256,
+                rustls::SignatureScheme::RSA_PSS_SHA256,
+            ];
+            assert!(key.choose_scheme(offered).is_none());
+        });
+
+        assert!(
+            logs.iter().any(|l| l.starts_with("DEBUG")
+                && l.contains("RSA_PKCS1_SHA256")
+                && l.contains("RSA_PSS_SHA256")
+                && l.contains("ECDSA_NISTP256_SHA256")),
+            "logs must describe the mismatch: {:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn certify_with_allowed_sans() {
+        let foo = FOO_NS1.name();
+        let bar = BAR_NS1.name();
+        let anchors = FOO_BAR_NS1.trust_anchors();
+
+        let only_foo = indexset![foo.clone()];
+        anchors
+            .certify_with_allowed_sans(FOO_NS1.key(), FOO_NS1.crt(), &only_foo)
+            .expect("foo.ns1 must only be valid for allowed names");
+        assert!(
+            anchors
+                .certify_with_allowed_sans(FOO_BAR_NS1.key(), FOO_BAR_NS1.crt(), &only_foo)
+                .is_err(),
+            "bar.ns1 must not be allowed"
+        );
+
+        let both = indexset![foo, bar];
+        anchors
+            .certify_with_allowed_sans(FOO_BAR_NS1.key(), FOO_BAR_NS1.crt(), &both)
+            .expect("all names must be allowed");
+    }
+
+    #[test]
+    fn certify_with_skew_tolerates_future_not_before() {
+        // FOO_NS1's certificate is valid from 2019-03-14T18:02:00Z, so it is
+        // not yet valid 5s earlier.
+        let now = UNIX_EPOCH + Duration::from_secs(1_552_586_520 - 5);
+        let anchors = FOO_NS1.trust_anchors();
+
+        anchors
+            .certify_at(FOO_NS1.key(), FOO_NS1.crt(), now, Duration::from_secs(10))
+            .expect("certificate must be valid within the skew");
+        assert!(
+            anchors
+                .certify_at(FOO_NS1.key(), FOO_NS1.crt(), now, Duration::from_secs(0))
+                .is_err(),
+            "certificate must not be valid without skew"
+        );
+    }
+
+    #[test]
+    fn certify_with_skew_does_not_extend_not_after() {
+        // FOO_NS1's certificate expires at 2020-03-13T18:02:00Z.
+        let now = UNIX_EPOCH + Duration::from_secs(1_584_122_520 + 5);
+        assert!(
+            FOO_NS1
+                .trust_anchors()
+                .certify_at(FOO_NS1.key(), FOO_NS1.crt(), now, Duration::from_secs(10))
+                .is_err(),
+            "expired certificate must not be valid within the skew"
+        );
+    }
+
+    #[test]
+    fn trust_anchors_from_pem_errors() {
+        assert_eq!(
+            TrustAnchors::from_pem("").unwrap_err(),
+            TrustAnchorsError::Empty
+        );
+        assert_eq!(
+            TrustAnchors::from_pem(" \n").unwrap_err(),
+            TrustAnchorsError::Empty
+        );
+        assert_eq!(
+            TrustAnchors::from_pem(
+                "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n"
+            )
+            .unwrap_err(),
+            TrustAnchorsError::Parse
+        );
+        assert_eq!(
+            TrustAnchors::from_pem("not a certificate").unwrap_err(),
+            TrustAnchorsError::NoneAdded { skipped: 0 }
+        );
+        assert_eq!(
+            TrustAnchors::from_pem(
+                "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n"
+            )
+            .unwrap_err(),
+            TrustAnchorsError::NoneAdded { skipped: 1 }
+        );
+
+        let pem = FOO_NS1.trust_anchors_pem();
+        assert!(TrustAnchors::from_pem(&pem).is_ok());
+        assert!(TrustAnchors::from_pem_opt(&pem).is_some());
+        assert!(TrustAnchors::from_pem_opt("").is_none());
+    }
+
+    #[test]
+    fn merged_trust_anchors_trust_both() {
+        let ca1 = FOO_NS1.trust_anchors();
+        let ca2 = FOO_NS1_CA2.trust_anchors();
+        let merged = ca1.merge(&ca2);
+        assert_eq!(merged.config.root_store.roots.len(), 2);
+
+        for s in &[&FOO_NS1, &FOO_NS1_CA2] {
+            merged
+                .certify_at(s.key(), s.crt(), valid_time(), Duration::from_secs(0))
+                .expect("certificate must be valid for merged anchors");
+        }
+        assert!(
+            ca1.certify_at(
+                FOO_NS1_CA2.key(),
+                FOO_NS1_CA2.crt(),
+                valid_time(),
+                Duration::from_secs(0)
+            )
+            .is_err(),
+            "ca1 must not trust ca2's certificates"
+        );
+
+        let merged = merged.merge(&ca1);
+        assert_eq!(
+            merged.config.root_store.roots.len(),
+            2,
+            "identical anchors must be deduplicated"
+        );
+    }
+
+    #[test]
+    fn trust_anchors_len() {
+        let ca1 = FOO_NS1.trust_anchors();
+        assert_eq!(ca1.len(), FOO_NS1.trust_anchors_der().len());
+        assert_eq!(ca1.len(), 1);
+        assert!(!ca1.is_empty());
+
+        let merged = ca1.merge(&FOO_NS1_CA2.trust_anchors());
+        assert_eq!(merged.len(), 2);
+
+        let empty = TrustAnchors::empty();
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn trust_anchor_subjects() {
+        let merged = FOO_NS1.trust_anchors().merge(&FOO_NS1_CA2.trust_anchors());
+        assert_eq!(merged.subjects(), vec!["OU=None", "OU=None"]);
+    }
+
+    #[test]
+    fn name_display_is_unquoted() {
+        let name = FOO_NS1.name();
+        let s = format!("{}", name);
+        assert!(!s.starts_with('"') && !s.ends_with('"'), "{}", s);
+        assert_eq!(s, FOO_NS1.name);
+    }
+
+    #[test]
+    fn resolve_requires_an_offered_scheme_for_the_key() {
+        use self::rustls::ResolvesClientCert;
+        use super::ring::signature;
+        use super::{rand, CertResolver, EcdsaKeyPair};
+
+        const P256: rustls::SignatureScheme = rustls::SignatureScheme::ECDSA_NISTP256_SHA256;
+        const P384: rustls::SignatureScheme = rustls::SignatureScheme::ECDSA_NISTP384_SHA384;
+        const RSA: &[rustls::SignatureScheme] = &[
+            rustls::SignatureScheme::RSA_PKCS1_SHA256,
+            rustls::SignatureScheme::RSA_PSS_SHA256,
+        ];
+
+        let rng = rand::SystemRandom::new();
+        let p384 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P384_SHA384_ASN1_SIGNING, &rng)
+            .expect("key must be generated")
+            .as_ref()
+            .to_vec();
+        let keys = vec![
+            (FOO_NS1.key(), P256, P384),
+            (
+                Key::from_pkcs8(&p384).expect("key must be valid"),
+                P384,
+                P256,
+            ),
+        ];
+
+        for (key, ours, other) in keys {
+            let resolver = CertResolver {
+                key: rustls::sign::CertifiedKey::new(
+                    vec![rustls::Certificate(FOO_NS1.crt_der())],
+                    Arc::new(Box::new(SigningKey::new(key))),
+                ),
+                scheme: ours,
+                observe_sni: None,
+                mismatch_log: Default::default(),
+            };
+            assert!(resolver.resolve(&[], &[ours]).is_some(), "{:?}", ours);
+            assert!(
+                resolver.resolve(&[], &[RSA[0], other, ours]).is_some(),
+                "{:?}",
+                ours
+            );
+            assert!(resolver.resolve(&[], &[other]).is_none(), "{:?}", ours);
+            assert!(resolver.resolve(&[], RSA).is_none(), "{:?}", ours);
+            assert!(resolver.resolve(&[], &[]).is_none(), "{:?}", ours);
+        }
+
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.signature_scheme(), P256);
+    }
+
+    #[test]
+    fn resolvers_log_scheme_mismatches() {
+        use self::rustls::{ResolvesClientCert, ResolvesServerCert};
+        use super::{CertResolver, MultiCertResolver};
+
+        const P256: rustls::SignatureScheme = rustls::SignatureScheme::ECDSA_NISTP256_SHA256;
+        const RSA: rustls::SignatureScheme = rustls::SignatureScheme::RSA_PSS_SHA256;
+        let is_mismatch = |l: &String| {
+            l.starts_with("INFO")
+                && l.contains("RSA_PSS_SHA256")
+                && l.contains("ECDSA_NISTP256_SHA256")
+        };
+
+        let resolver = Arc::new(CertResolver {
+            key: rustls::sign::CertifiedKey::new(
+                vec![rustls::Certificate(FOO_NS1.crt_der())],
+                Arc::new(Box::new(SigningKey::new(FOO_NS1.key()))),
+            ),
+            scheme: P256,
+            observe_sni: None,
+            mismatch_log: Default::default(),
+        });
+        let resolve = |offered: &[rustls::SignatureScheme]| {
+            ResolvesClientCert::resolve(&*resolver, &[], offered)
+        };
+        let logs = capture(|| {
+            assert!(resolve(&[RSA]).is_none());
+            // Subsequent mismatches are rate-limited.
+            assert!(resolve(&[RSA]).is_none());
+            assert!(resolve(&[P256]).is_some());
+        });
+        assert_eq!(
+            logs.iter().filter(|l| is_mismatch(*l)).count(),
+            1,
+            "logs must describe the mismatch once: {:?}",
+            logs
+        );
+
+        let multi = MultiCertResolver::new(vec![resolver.clone()]);
+        let foo = FOO_NS1.name();
+        let logs = capture(|| {
+            assert!(multi.resolve(Some(foo.as_dns_name_ref()), &[RSA]).is_none());
+        });
+        assert!(
+            logs.iter().any(is_mismatch),
+            "logs must describe the mismatch: {:?}",
+            logs
+        );
+
+        // Only mismatches are logged at info.
+        let logs = capture(|| {
+            assert!(multi.resolve(None, &[P256]).is_none());
+            assert!(multi
+                .resolve(Some(foo.as_dns_name_ref()), &[P256])
+                .is_some());
+        });
+        assert!(
+            !logs.iter().any(|l| l.starts_with("INFO")),
+            "logs must not describe a mismatch: {:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn multi_cert_resolver_prefers_the_strongest_offered_curve() {
+        use self::rustls::sign::{Signer as _Signer, SigningKey as _SigningKey};
+        use self::rustls::ResolvesServerCert;
+        use super::ring::signature;
+        use super::{rand, CertResolver, EcdsaKeyPair, MultiCertResolver};
+
+        const P256: rustls::SignatureScheme = rustls::SignatureScheme::ECDSA_NISTP256_SHA256;
+        const P384: rustls::SignatureScheme = rustls::SignatureScheme::ECDSA_NISTP384_SHA384;
+
+        let rng = rand::SystemRandom::new();
+        let p384 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P384_SHA384_ASN1_SIGNING, &rng)
+            .expect("key must be generated")
+            .as_ref()
+            .to_vec();
+        let resolver = |key: Key, scheme| {
+            Arc::new(CertResolver {
+                key: rustls::sign::CertifiedKey::new(
+                    vec![rustls::Certificate(FOO_NS1.crt_der())],
+                    Arc::new(Box::new(SigningKey::new(key))),
+                ),
+                scheme,
+                observe_sni: None,
+                mismatch_log: Default::default(),
+            })
+        };
+        let resolver = MultiCertResolver::new(vec![
+            resolver(Key::from_pkcs8(&p384).expect("key must be valid"), P384),
+            resolver(FOO_NS1.key(), P256),
+        ]);
+
+        let resolve = |offered: &[rustls::SignatureScheme]| {
+            resolver
+                .resolve(Some(FOO_NS1.name().as_dns_name_ref()), offered)
+                .map(|k| k.key.choose_scheme(&[P256, P384]).unwrap().get_scheme())
+        };
+        assert_eq!(resolve(&[P256]), Some(P256));
+        assert_eq!(resolve(&[P384]), Some(P384));
+        assert_eq!(resolve(&[P256, P384]), Some(P384));
+        assert_eq!(resolve(&[rustls::SignatureScheme::RSA_PSS_SHA256]), None);
+        assert!(resolver
+            .resolve(Some(BAR_NS1.name().as_dns_name_ref()), &[P256, P384])
+            .is_none());
+    }
+
+    #[test]
+    fn crt_keys() {
+        use transport::tls::listen::HasConfig;
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        match CrtKeys::new(vec![foo.clone(), bar])
+            .expect_err("keys for other names must be rejected")
+        {
+            CrtKeysError::NameMismatch { expected, actual } => {
+                assert_eq!(expected, FOO_NS1.name());
+                assert_eq!(actual, BAR_NS1.name());
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let e = CrtKeys::new(vec![]).expect_err("no keys must be rejected");
+        assert!(
+            match e {
+                CrtKeysError::Empty => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+
+        let keys = CrtKeys::new(vec![foo]).expect("keys must be valid");
+        assert_eq!(keys.name(), &FOO_NS1.name());
+        let client = FOO_NS1.trust_anchors().tls_client_config();
+        handshake(&client, &keys.tls_server_config(), &FOO_NS1.name())
+            .expect("handshake must succeed");
+    }
+
+    #[test]
+    fn p384_keys_sign_and_verify() {
+        use self::rustls::sign::SigningKey as RustlsSigningKey;
+        use super::ring::signature::{self, KeyPair};
+        use super::{rand, untrusted, EcdsaKeyPair};
+
+        let rng = rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P384_SHA384_ASN1_SIGNING, &rng)
+            .expect("key must be generated")
+            .as_ref()
+            .to_vec();
+        let key = Key::from_pkcs8(&pkcs8).expect("P-384 keys must be supported");
+        let public_key = key.0.public_key().as_ref().to_vec();
+
+        let key = SigningKey::new(key);
+        assert!(
+            key.choose_scheme(&[rustls::SignatureScheme::ECDSA_NISTP256_SHA256])
+                .is_none(),
+            "P-384 keys must not sign P-256 signatures"
+        );
+        let signer = key
+            .choose_scheme(&[
+                rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+                rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+            ])
+            .expect("P-384 keys must sign P-384 signatures");
+        assert_eq!(
+            signer.get_scheme(),
+            rustls::SignatureScheme::ECDSA_NISTP384_SHA384
+        );
+
+        let msg = b"hello";
+        let sig = signer.sign(msg).expect("message must be signed");
+        signature::verify(
+            &signature::ECDSA_P384_SHA384_ASN1,
+            untrusted::Input::from(&public_key),
+            untrusted::Input::from(msg),
+            untrusted::Input::from(&sig),
+        )
+        .expect("signature must be valid");
+    }
+
+    #[test]
+    fn certify_preserves_name() {
+        let crt_key = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.name(), &FOO_NS1.name());
+    }
+
+    #[test]
+    fn client_auth_modes() {
+        use self::rustls::Session;
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::{ClientAuth, HasConfig as _ServerHasConfig};
+
+        // The client doesn't have a certificate.
+        let client = FOO_NS1.trust_anchors().tls_client_config();
+
+        let optional = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let (_, server) = handshake(&client, &optional.tls_server_config(), &FOO_NS1.name())
+            .expect("clients without certificates must be accepted");
+        assert!(server.get_peer_certificates().is_none());
+
+        let required = FOO_NS1
+            .trust_anchors()
+            .with_client_auth(ClientAuth::Required)
+            .certify(FOO_NS1.key(), FOO_NS1.crt())
+            .expect("foo.ns1 must be valid");
+        assert!(
+            handshake(&client, &required.tls_server_config(), &FOO_NS1.name()).is_err(),
+            "clients without certificates must be rejected"
+        );
+    }
+
+    #[test]
+    fn generated_keys_sign_and_reload() {
+        use self::rustls::sign::SigningKey as RustlsSigningKey;
+        use super::ring::signature::{self, KeyPair};
+        use super::untrusted;
+
+        let (key, pkcs8) = Key::generate().expect("key must be generated");
+        let public_key = key.0.public_key().as_ref().to_vec();
+
+        let signer = SigningKey::new(key)
+            .choose_scheme(&[rustls::SignatureScheme::ECDSA_NISTP256_SHA256])
+            .expect("generated keys must sign P-256 signatures");
+        let msg = b"hello";
+        let sig = signer.sign(msg).expect("message must be signed");
+        signature::verify(
+            &signature::ECDSA_P256_SHA256_ASN1,
+            untrusted::Input::from(&public_key),
+            untrusted::Input::from(msg),
+            untrusted::Input::from(&sig),
+        )
+        .expect("signature must be valid");
+
+        let reloaded = Key::from_pkcs8(&pkcs8).expect("generated PKCS#8 must be valid");
+        assert_eq!(reloaded.0.public_key().as_ref(), &public_key[..]);
+    }
+
+    #[test]
+    fn key_eq() {
+        let key = FOO_NS1.key();
+        assert_eq!(key, key.clone());
+        assert_eq!(
+            key,
+            FOO_NS1.key(),
+            "keys loaded from the same PKCS#8 are equal"
+        );
+        assert_ne!(key, BAR_NS1.key());
+
+        let (a, _) = Key::generate().expect("key must be generated");
+        let (b, _) = Key::generate().expect("key must be generated");
+        assert_ne!(a, b, "generated keys are distinct");
+    }
+
+    #[test]
+    fn certify_staples_ocsp() {
+        let certify = |ocsp: &str, now| {
+            FOO_NS1.trust_anchors().certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt().with_ocsp(FOO_NS1.ocsp(ocsp)),
+                now,
+                Duration::from_secs(0),
+            )
+        };
+
+        let crt_key = certify("good", valid_time()).expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.resolver.key.ocsp, Some(FOO_NS1.ocsp("good")));
+        certify("delegated", valid_time())
+            .expect("responses may be signed by an authorized responder");
+
+        let err = certify("revoked", valid_time()).expect_err("foo.ns1 must be revoked");
+        assert!(err.is_revoked(), "unexpected error: {}", err);
+        // ca2 has the same subject as ca1, but didn't issue foo.ns1.
+        certify("ca2", valid_time()).expect_err("responses must be signed by the issuer");
+        // The responses are current until 2019-08-01.
+        let later = UNIX_EPOCH + Duration::from_secs(1_567_296_000);
+        certify("good", later).expect_err("stale responses must be rejected");
+        let err = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt().with_ocsp(vec![0x30, 0x03, 0x0a, 0x01, 0x00]),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("responses without a body must be rejected");
+        assert!(!err.is_revoked());
+
+        let crt_key = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.resolver.key.ocsp, None);
+    }
+
+    #[test]
+    fn certify_rejects_revoked_crts() {
+        let anchors = FOO_NS1
+            .trust_anchors()
+            .with_crl_pem(&ca1_crl_pem())
+            .expect("CRL must be valid");
+
+        let revoked = anchors
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("foo.ns1 must be revoked");
+        assert!(revoked.is_revoked());
+
+        anchors
+            .certify_at(
+                BAR_NS1.key(),
+                BAR_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("bar.ns1 must not be revoked");
+    }
+
+    #[test]
+    fn servers_reject_revoked_client_crts() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        let server = BAR_NS1
+            .trust_anchors()
+            .with_crl_pem(&ca1_crl_pem())
+            .expect("CRL must be valid")
+            .certify(BAR_NS1.key(), BAR_NS1.crt())
+            .expect("bar.ns1 must be valid");
+        let server = server.tls_server_config();
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert!(
+            handshake(&foo.tls_client_config(), &server, &BAR_NS1.name()).is_err(),
+            "revoked client certificates must be rejected"
+        );
+
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        handshake(&bar.tls_client_config(), &server, &BAR_NS1.name())
+            .expect("unrevoked client certificates must be accepted");
+    }
+
+    #[test]
+    fn handshakes_are_verified_with_the_configured_clock() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let server = server.tls_server_config();
+
+        // FOO_NS1's certificate expires at 2020-03-13T18:02:00Z.
+        let expired = UNIX_EPOCH + Duration::from_secs(1_584_122_520 + 5);
+        let client = FOO_NS1.trust_anchors().with_clock(FixedClock(expired));
+        assert!(
+            handshake(&client.tls_client_config(), &server, &FOO_NS1.name()).is_err(),
+            "expired server certificates must be rejected"
+        );
+
+        let client = FOO_NS1.trust_anchors();
+        handshake(&client.tls_client_config(), &server, &FOO_NS1.name())
+            .expect("server certificates must be valid at valid_time()");
+    }
+
+    #[test]
+    fn clients_reject_revoked_server_crts() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        // Clients verify servers with the same checks as `certify`.
+        let client = BAR_NS1
+            .trust_anchors()
+            .with_crl_pem(&ca1_crl_pem())
+            .expect("CRL must be valid")
+            .tls_client_config();
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert!(
+            handshake(&client, &foo.tls_server_config(), &FOO_NS1.name()).is_err(),
+            "revoked server certificates must be rejected"
+        );
+
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        handshake(&client, &bar.tls_server_config(), &BAR_NS1.name())
+            .expect("unrevoked server certificates must be accepted");
+    }
+
+    #[test]
+    fn crl_errors() {
+        let e = FOO_NS1
+            .trust_anchors()
+            .with_crl_pem("")
+            .expect_err("empty PEM must be rejected");
+        assert!(
+            match e {
+                CrlError::MissingCrl => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+
+        let e = FOO_NS1
+            .trust_anchors()
+            .with_crl_pem("-----BEGIN X509 CRL-----\n!!!!\n-----END X509 CRL-----\n")
+            .expect_err("invalid PEM must be rejected");
+        assert!(
+            match e {
+                CrlError::InvalidPem => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+
+        // ca2 has the same subject as ca1, but it did not sign ca1's CRL.
+        match FOO_NS1_CA2
+            .trust_anchors()
+            .with_crl_pem(&ca1_crl_pem())
+            .expect_err("CRLs signed by other issuers must be rejected")
+        {
+            CrlError::InvalidCrl(e) => assert_eq!(e, webpki::Error::InvalidSignatureForPublicKey),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn certify_requires_scts_from_trusted_logs() {
+        let anchors = FOO_NS1.trust_anchors().with_ct_logs(ct_logs());
+
+        let crt_key = anchors
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt().with_sct(FOO_NS1.sct()),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("a logged certificate must be valid");
+        assert!(crt_key.resolver.key.sct_list.is_some());
+
+        anchors
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("a certificate without an SCT must be rejected");
+
+        // foo.ns1's SCT does not cover bar.ns1's certificate.
+        anchors
+            .certify_at(
+                BAR_NS1.key(),
+                BAR_NS1.crt().with_sct(FOO_NS1.sct()),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("a certificate without a valid SCT must be rejected");
+
+        anchors
+            .certify_at(
+                FOO_NS1_SCT.key(),
+                FOO_NS1_SCT.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("a certificate with an embedded SCT must be valid");
+
+        // 2019-04-01, before the embedded SCT was issued.
+        let early = UNIX_EPOCH + Duration::from_secs(1_554_076_800);
+        anchors
+            .certify_at(
+                FOO_NS1_SCT.key(),
+                FOO_NS1_SCT.crt(),
+                early,
+                Duration::from_secs(0),
+            )
+            .expect_err("an SCT from the future must be rejected");
+
+        // SCTs are not required unless CT logs are configured.
+        FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                BAR_NS1.key(),
+                BAR_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("SCTs must not be required");
+    }
+
+    #[test]
+    fn seconds_until_expiry() {
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let expiry = crt_key.expiry();
+        let d = Duration::from_secs(30);
+
+        assert_eq!(crt_key.seconds_until_expiry(expiry - d), 30);
+        assert_eq!(crt_key.seconds_until_expiry(expiry), 0);
+        assert_eq!(crt_key.seconds_until_expiry(expiry + d), -30);
+    }
+
+    #[test]
+    fn is_expiring_soon() {
+        let crt_key = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        let expiry = crt_key.expiry();
+        let threshold = Duration::from_secs(60);
+
+        // Outside of the threshold.
+        let now = expiry - Duration::from_secs(61);
+        assert!(!crt_key.is_expiring_soon(now, threshold));
+
+        // Within the threshold.
+        assert!(crt_key.is_expiring_soon(expiry - threshold, threshold));
+        let now = expiry - Duration::from_secs(1);
+        assert!(crt_key.is_expiring_soon(now, threshold));
+
+        // Already expired.
+        assert!(crt_key.is_expiring_soon(expiry, threshold));
+        let now = expiry + Duration::from_secs(1);
+        assert!(crt_key.is_expiring_soon(now, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn parses_spiffe_ids() {
+        let id = SpiffeId::parse("spiffe://cluster.local/ns/ns1/sa/foo")
+            .expect("SPIFFE ID must be valid");
+        assert_eq!(id.trust_domain(), "cluster.local");
+        assert_eq!(id.path(), "/ns/ns1/sa/foo");
+        assert_eq!(id.to_string(), "spiffe://cluster.local/ns/ns1/sa/foo");
+
+        let id = SpiffeId::parse("spiffe://cluster.local").expect("an empty path is valid");
+        assert_eq!(id.path(), "");
+
+        for invalid in &[
+            "https://cluster.local/ns/ns1",
+            "spiffe://",
+            "spiffe:///ns/ns1",
+            "spiffe://Cluster.local/ns/ns1",
+            "spiffe://cluster.local:8080/ns/ns1",
+            "spiffe://user@cluster.local/ns/ns1",
+            "spiffe://cluster.local/",
+            "spiffe://cluster.local/ns//ns1",
+            "spiffe://cluster.local/ns/../ns1",
+            "spiffe://cluster.local/ns/ns1?q",
+            "spiffe://cluster.local/ns/ns1#f",
+        ] {
+            assert!(
+                SpiffeId::parse(invalid).is_none(),
+                "{} must be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn spiffe_id_from_cert() {
+        let crt = FOO_NS1_SPIFFE.crt();
+        let id = super::spiffe_id_from_cert(&crt.leaf).expect("must have a SPIFFE ID");
+        assert_eq!(id.to_string(), "spiffe://cluster.local/ns/ns1/sa/foo");
+
+        // The DNS SAN is used as the certificate's name, as usual.
+        FOO_NS1_SPIFFE
+            .validate()
+            .expect("foo.ns1 must be valid for its DNS name");
+
+        let crt = FOO_NS1.crt();
+        assert!(super::spiffe_id_from_cert(&crt.leaf).is_none());
+    }
+
+    #[test]
+    fn matches_uri_sans_for_spiffe_ids() {
+        let crt_key = FOO_NS1_SPIFFE.validate().expect("foo.ns1 must be valid");
+
+        assert!(crt_key.is_valid_for("spiffe://cluster.local/ns/ns1/sa/foo"));
+        assert!(crt_key.is_valid_for(FOO_NS1.name().as_ref()));
+
+        assert!(!crt_key.is_valid_for("spiffe://cluster.local/ns/ns1/sa/bar"));
+        assert!(!crt_key.is_valid_for(BAR_NS1.name().as_ref()));
+
+        // A DNS-only certificate never matches a SPIFFE ID.
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert!(!crt_key.is_valid_for("spiffe://cluster.local/ns/ns1/sa/foo"));
+    }
+
+    /// Writes `contents` to a temporary file named for `name`, returning a
+    /// `TokenSource` that reads it.
+    fn token_file(name: &str, contents: &[u8]) -> (TokenSource, ::std::path::PathBuf) {
+        use std::{env, fs, process};
+
+        let path = env::temp_dir().join(format!("linkerd2-proxy-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let ts = TokenSource(Arc::new(path.to_str().expect("path must be UTF-8").into()));
+        (ts, path)
+    }
+
+    #[test]
+    fn token_source_trims_trailing_whitespace() {
+        let (ts, path) = token_file("token", b"token \r\n");
+        assert_eq!(ts.load().expect("token must load"), b"token");
+
+        ::std::fs::write(&path, "\n\n").unwrap();
+        let err = ts.load().expect_err("a blank token must be empty");
+        assert_eq!(err.to_string(), "token is empty");
+        assert!(TokenSource::if_nonempty_file((*ts.0).clone()).is_err());
+
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn token_source_load_reports_path() {
+        use std::error::Error;
+
+        let path = ::std::env::temp_dir().join("linkerd2-proxy-nonexistent-token");
+        let path = path.to_str().expect("path must be UTF-8").to_string();
+        let ts = TokenSource(Arc::new(path.clone()));
+
+        let err = ts.load().expect_err("a missing token must fail");
+        assert_eq!(err.kind(), ::std::io::ErrorKind::NotFound);
+        assert!(
+            err.to_string().contains(&path),
+            "error must include the path: {}",
+            err
+        );
+        let source = err.get_ref().and_then(|e| e.source());
+        assert!(source.is_some(), "the original error must be the source");
+    }
+
+    #[test]
+    fn sni_observer_observes_matching_and_other_names() {
+        use std::sync::Mutex;
+        use transport::tls::listen::HasConfig;
+
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let key = {
+            let observed = observed.clone();
+            FOO_NS1
+                .validate()
+                .expect("foo.ns1 must be valid")
+                .with_sni_observer(move |name| {
+                    observed.lock().unwrap().push(name.cloned());
+                })
+        };
+        let client = FOO_NS1.trust_anchors().tls_client_config();
+
+        handshake(&client, &key.tls_server_config(), &FOO_NS1.name())
+            .expect("handshake must succeed for foo.ns1");
+        assert!(
+            handshake(&client, &key.tls_server_config(), &BAR_NS1.name()).is_err(),
+            "handshake must fail for bar.ns1"
+        );
+
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![Some(FOO_NS1.name()), Some(BAR_NS1.name())]
+        );
+    }
+
+    #[test]
+    fn token_source_load_with_retry() {
+        use std::{env, fs, process, thread};
+
+        let path = env::temp_dir().join(format!("linkerd2-proxy-{}-late-token", process::id()));
+        let ts = TokenSource(Arc::new(path.to_str().expect("path must be UTF-8").into()));
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let err = rt
+            .block_on(ts.load_with_retry(1, Duration::from_millis(1)))
+            .expect_err("a missing token must fail");
+        assert_eq!(err.kind(), ::std::io::ErrorKind::NotFound);
+
+        // The token is written after the first attempt.
+        let write = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                fs::write(&path, "token\n").unwrap();
+            })
+        };
+        let token = rt
+            .block_on(ts.load_with_retry(100, Duration::from_millis(10)))
+            .expect("token must load once written");
+        assert_eq!(token, b"token");
+
+        write.join().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn token_source_load_jwt() {
+        let (ts, path) = token_file("jwt", b"eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJmb28ifQ.c2ln-_\n");
+        assert_eq!(
+            ts.load_jwt().expect("JWT must load"),
+            &b"eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJmb28ifQ.c2ln-_"[..]
+        );
+        ::std::fs::remove_file(&path).unwrap();
+
+        let (ts, path) = token_file(
+            "jwt-two-segments",
+            b"eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJmb28ifQ",
+        );
+        let err = ts.load_jwt().expect_err("two segments must be rejected");
+        assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidData);
+        assert!(ts.load().is_ok(), "load must not validate tokens");
+        ::std::fs::remove_file(&path).unwrap();
+
+        let (ts, path) = token_file("jwt-not-utf8", b"\xff\xfe.\xfd.\xfc");
+        let err = ts
+            .load_jwt()
+            .expect_err("non-UTF-8 tokens must be rejected");
+        assert_eq!(err.to_string(), "token is not valid UTF-8");
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn name_try_from() {
+        use convert::TryFrom;
+
+        let hostname = "foo.ns1.serviceaccount.identity.linkerd.cluster.local";
+        let expected = Name::from_hostname(hostname.as_bytes()).expect("name must be valid");
+        assert_eq!(
+            Name::try_from(hostname).expect("name must be valid"),
+            expected
+        );
+        assert_eq!(
+            Name::try_from(hostname.to_string()).expect("name must be valid"),
+            expected
+        );
+        assert_eq!(
+            Name::try_from(hostname.as_bytes()).expect("name must be valid"),
+            expected
+        );
+
+        // Like `from_hostname`, absolute names are rejected.
+        let absolute = "foo.ns1.serviceaccount.identity.linkerd.cluster.local.";
+        assert!(Name::from_hostname(absolute.as_bytes()).is_err());
+        assert!(Name::try_from(absolute).is_err());
+        assert!(Name::try_from(absolute.to_string()).is_err());
+        assert!(Name::try_from("not a name").is_err());
+    }
+
+    #[test]
+    fn crt_rejects_empty_leaf() {
+        let expiry = valid_time() + Duration::from_secs(60);
+        let ca = FOO_NS1.trust_anchors_der().remove(0);
+        for intermediates in vec![vec![], vec![ca]] {
+            let err = Crt::new(FOO_NS1.name(), vec![], intermediates.clone(), expiry)
+                .expect_err("an empty leaf must be rejected");
+            assert!(err.is_missing_leaf(), "unexpected error: {}", err);
+
+            let anchors = FOO_NS1.trust_anchors();
+            let err = Crt::new_reordered(FOO_NS1.name(), vec![], intermediates, expiry, &anchors)
+                .expect_err("an empty leaf must be rejected");
+            assert!(err.is_missing_leaf(), "unexpected error: {}", err);
+        }
+
+        let err = Crt::with_shared_intermediates(FOO_NS1.name(), vec![], Vec::new().into(), expiry)
+            .expect_err("an empty leaf must be rejected");
+        assert!(err.is_missing_leaf(), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn is_valid_for_name() {
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert!(crt_key.is_valid_for_name(&FOO_NS1.name()));
+        assert!(!crt_key.is_valid_for_name(&BAR_NS1.name()));
+    }
+
+    #[test]
+    fn leaf_serial() {
+        let serial = FOO_NS1.crt().leaf_serial().expect("serial must parse");
+        assert_eq!(
+            serial,
+            vec![
+                0x28, 0x24, 0x79, 0x57, 0xef, 0xf4, 0x6d, 0xa4, 0xea, 0x81, 0x8c, 0x79, 0x8f, 0xf3,
+                0xca, 0x2f, 0x4a, 0xfd, 0xc5, 0x18,
+            ]
+        );
+
+        let garbage = Crt::new(
+            FOO_NS1.name(),
+            b"not a certificate".to_vec(),
+            vec![],
+            valid_time(),
+        )
+        .expect("a non-empty leaf must be accepted");
+        assert_eq!(garbage.leaf_serial(), None);
+    }
+
+    #[test]
+    fn name_from_leaf_uses_first_dns_san() {
+        use super::name_from_leaf;
+
+        let leaf = |s: &Strings| rustls::Certificate(s.crt_der());
+        assert_eq!(name_from_leaf(&leaf(&FOO_NS1)), Some(FOO_NS1.name()));
+        assert_eq!(name_from_leaf(&leaf(&FOO_NS1_SPIFFE)), Some(FOO_NS1.name()));
+        // Only the first SAN is used.
+        assert_eq!(name_from_leaf(&leaf(&FOO_BAR_NS1)), Some(FOO_NS1.name()));
+        // Wildcards aren't valid names.
+        assert_eq!(name_from_leaf(&leaf(&WILDCARD_NS1)), None);
+        // Common names are ignored.
+        assert_eq!(name_from_leaf(&leaf(&CN_ONLY_NS1)), None);
+        assert_eq!(name_from_leaf(&rustls::Certificate(vec![])), None);
+    }
+
+    #[test]
+    fn multi_san_crts_are_selected_for_each_name() {
+        use transport::tls::listen::HasConfig;
+
+        let crt = FOO_BAR_NS1.crt();
+        assert_eq!(crt.dns_sans(), vec![FOO_NS1.name(), BAR_NS1.name()]);
+        let wildcard = WILDCARD_NS1.crt();
+        let sans = wildcard.dns_sans();
+        assert_eq!(
+            sans.iter().map(|n| n.as_ref()).collect::<Vec<_>>(),
+            vec!["*.ns1.serviceaccount.identity.linkerd.cluster.local"]
+        );
+        assert!(Crt::new(
+            FOO_NS1.name(),
+            b"not a certificate".to_vec(),
+            vec![],
+            valid_time()
+        )
+        .expect("a non-empty leaf must be accepted")
+        .dns_sans()
+        .is_empty());
+
+        let client = FOO_NS1.trust_anchors().tls_client_config();
+        for s in &[&FOO_BAR_NS1, &WILDCARD_NS1] {
+            let crt_key = s.validate().expect("certificate must be valid");
+            for name in &[FOO_NS1.name(), BAR_NS1.name()] {
+                assert!(crt_key.is_valid_for_name(name));
+                handshake(&client, &crt_key.tls_server_config(), name)
+                    .expect("certificate must be selected for each name");
+            }
+        }
+    }
+
+    #[test]
+    fn crt_validity() {
+        use super::Validity;
+
+        const EPSILON: f64 = 1e-9;
+
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let validity = FOO_NS1.crt().validity().expect("validity must parse");
+        assert_eq!(
+            validity,
+            Validity {
+                not_before: at(1_552_586_520),
+                not_after: at(1_584_122_520),
+            }
+        );
+
+        // Before.
+        let before = at(1_552_586_520 - 1);
+        assert!(!validity.contains(before));
+        assert_eq!(validity.remaining(before), Duration::from_secs(31_536_001));
+        assert!((validity.fraction_elapsed(before) - 0.0).abs() < EPSILON);
+
+        // Within.
+        let midpoint = at((1_552_586_520 + 1_584_122_520) / 2);
+        assert!(validity.contains(validity.not_before));
+        assert!(validity.contains(midpoint));
+        assert!(validity.contains(validity.not_after));
+        assert_eq!(
+            validity.remaining(midpoint),
+            Duration::from_secs(15_768_000)
+        );
+        assert!((validity.fraction_elapsed(midpoint) - 0.5).abs() < EPSILON);
+
+        // After.
+        let after = at(1_584_122_520 + 1);
+        assert!(!validity.contains(after));
+        assert_eq!(validity.remaining(after), Duration::from_secs(0));
+        assert!((validity.fraction_elapsed(after) - 1.0).abs() < EPSILON);
+
+        let garbage = Crt::new(
+            FOO_NS1.name(),
+            b"not a certificate".to_vec(),
+            vec![],
+            valid_time(),
+        )
+        .expect("a non-empty leaf must be accepted");
+        assert_eq!(garbage.validity(), None);
+    }
+
+    #[test]
+    fn encrypted_pkcs8() {
+        let der = FOO_NS1.key_encrypted_pkcs8();
+        let key =
+            Key::from_encrypted_pkcs8(&der, ENCRYPTED_KEY_PASSWORD).expect("key must decrypt");
+        FOO_NS1
+            .trust_anchors()
+            .certify_at(key, FOO_NS1.crt(), valid_time(), Duration::from_secs(0))
+            .expect("decrypted key must match the certificate");
+
+        // A wrong password almost always produces invalid padding, but may
+        // occasionally produce a (garbage) key that fails to parse.
+        let e = Key::from_encrypted_pkcs8(&der, b"hunter3")
+            .expect_err("a wrong password must be rejected");
+        assert!(
+            match e {
+                EncryptedKeyError::Decrypt | EncryptedKeyError::InvalidKey(_) => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+
+        let e = Key::from_encrypted_pkcs8(&FOO_NS1.key_pkcs8(), ENCRYPTED_KEY_PASSWORD)
+            .expect_err("unencrypted keys must be rejected");
+        assert!(
+            match e {
+                EncryptedKeyError::Malformed | EncryptedKeyError::Unsupported => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn pbkdf2_iterations_are_capped() {
+        assert_eq!(
+            Key::pbkdf2_iterations(2048).map(|n| n.get()).ok(),
+            Some(2048)
+        );
+        assert_eq!(
+            Key::pbkdf2_iterations(MAX_PBKDF2_ITERATIONS)
+                .map(|n| n.get())
+                .ok(),
+            Some(MAX_PBKDF2_ITERATIONS)
+        );
+
+        let e = Key::pbkdf2_iterations(MAX_PBKDF2_ITERATIONS + 1)
+            .expect_err("too many iterations must be rejected");
+        assert!(
+            match e {
+                EncryptedKeyError::TooManyIterations(n) => n == MAX_PBKDF2_ITERATIONS + 1,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+
+        let e = Key::pbkdf2_iterations(0).expect_err("zero iterations must be rejected");
+        assert!(
+            match e {
+                EncryptedKeyError::Malformed => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn crt_eq() {
+        use std::collections::HashSet;
+
+        let expiry = valid_time();
+        let crt = |s: &Strings| {
+            Crt::new(s.name(), s.crt_der(), vec![], expiry).expect("crt must be valid")
+        };
+
+        assert_eq!(crt(&FOO_NS1), crt(&FOO_NS1));
+        assert_ne!(crt(&FOO_NS1), crt(&FOO_NS1_CA2));
+        assert_ne!(
+            crt(&FOO_NS1),
+            Crt::new(
+                FOO_NS1.name(),
+                FOO_NS1.crt_der(),
+                vec![],
+                expiry + Duration::from_secs(1)
+            )
+            .expect("crt must be valid")
+        );
+
+        let mut crts = HashSet::new();
+        crts.insert(crt(&FOO_NS1));
+        assert!(!crts.insert(crt(&FOO_NS1)));
+        assert!(crts.insert(crt(&BAR_NS1)));
+
+        let certify = |s: &Strings| {
+            s.trust_anchors()
+                .certify_at(s.key(), crt(s), valid_time(), Duration::from_secs(0))
+                .expect("certificate must be valid")
+        };
+        assert_eq!(certify(&FOO_NS1), certify(&FOO_NS1));
+        assert_ne!(certify(&FOO_NS1), certify(&BAR_NS1));
+    }
+
+    #[test]
+    fn rotate() {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
 
-        // Enable client authentication.
-        client.client_auth_cert_resolver = resolver.clone();
+        let certify = |s: &Strings, expiry| {
+            let crt = Crt::new(s.name(), s.crt_der(), vec![], expiry).expect("crt must be valid");
+            s.trust_anchors()
+                .certify_at(s.key(), crt, valid_time(), Duration::from_secs(0))
+                .expect("certificate must be valid")
+        };
+        let current = certify(&FOO_NS1, valid_time() + HOUR);
 
-        // Ask TLS clients for a certificate and accept any certificate issued
-        // by our trusted CA(s).
-        //
-        // XXX: Rustls's built-in verifiers don't let us tweak things as fully
-        // as we'd like (e.g. controlling the set of trusted signature
-        // algorithms), but they provide good enough defaults for now.
-        // TODO: lock down the verification further.
-        //
-        // TODO: Change Rustls's API to Avoid needing to clone `root_cert_store`.
-        let mut server = rustls::ServerConfig::new(
-            rustls::AllowAnyAnonymousOrAuthenticatedClient::new(self.0.root_store.clone()),
+        let new = current
+            .rotate(certify(&FOO_NS1, valid_time() + 2 * HOUR))
+            .expect("rotation must succeed");
+        assert_eq!(new.expiry(), valid_time() + 2 * HOUR);
+
+        match current
+            .rotate(certify(&BAR_NS1, valid_time() + 2 * HOUR))
+            .expect_err("rotating to another name must fail")
+        {
+            RotateError::NameMismatch { expected, actual } => {
+                assert_eq!(expected, FOO_NS1.name());
+                assert_eq!(actual, BAR_NS1.name());
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let e = current
+            .rotate(certify(&FOO_NS1, valid_time()))
+            .expect_err("rotating to an earlier expiry must fail");
+        assert!(
+            match e {
+                RotateError::ExpiryRegressed => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
         );
-        server.versions = TLS_VERSIONS.to_vec();
-        server.cert_resolver = resolver;
+    }
 
-        Ok(CrtKey {
-            name: crt.name,
-            expiry: crt.expiry,
-            client_config: Arc::new(client),
-            server_config: Arc::new(server),
-        })
+    #[test]
+    fn verifier() {
+        let verifier = FOO_NS1.trust_anchors().verifier();
+        let verify = |name: &Name| {
+            verifier.verify_server_cert(
+                &rustls::RootCertStore::empty(),
+                &[rustls::Certificate(FOO_NS1.crt_der())],
+                name.as_dns_name_ref(),
+                &[],
+            )
+        };
+
+        verify(&FOO_NS1.name()).expect("foo.ns1 must be valid");
+        let e = verify(&BAR_NS1.name())
+            .map(|_| ())
+            .expect_err("bar.ns1 must be rejected");
+        assert_eq!(
+            e,
+            rustls::TLSError::WebPKIError(webpki::Error::CertNotValidForName)
+        );
+
+        let err = FOO_NS1_CA2
+            .trust_anchors()
+            .verifier()
+            .verify_server_cert(
+                &FOO_NS1.trust_anchors().config.root_store,
+                &[rustls::Certificate(FOO_NS1.crt_der())],
+                FOO_NS1.name().as_dns_name_ref(),
+                &[],
+            )
+            .err();
+        assert!(err.is_some(), "the verifier's own anchors must be used");
     }
-}
 
-impl tls::client::HasConfig for TrustAnchors {
-    fn tls_client_config(&self) -> Arc<rustls::ClientConfig> {
-        self.0.clone()
+    #[test]
+    fn trust_anchors_store_reloads() {
+        use std::thread;
+        use transport::tls::listen::ClientAuth;
+
+        let store = TrustAnchorsStore::new(FOO_NS1.trust_anchors());
+        let verifier = store.client_cert_verifier(ClientAuth::Required);
+        let ca2_crt = vec![rustls::Certificate(FOO_NS1_CA2.crt_der())];
+        assert!(verifier.verify_client_cert(&ca2_crt).is_err());
+
+        let verifying = {
+            let verifier = verifier.clone();
+            let crt = ca2_crt.clone();
+            thread::spawn(move || {
+                for _ in 0..100_000 {
+                    if verifier.verify_client_cert(&crt).is_ok() {
+                        return;
+                    }
+                    thread::yield_now();
+                }
+                panic!("verifier did not observe the new trust anchors");
+            })
+        };
+        store.set(FOO_NS1.trust_anchors().merge(&FOO_NS1_CA2.trust_anchors()));
+        verifying.join().expect("verification must succeed");
+
+        assert!(verifier.verify_client_cert(&ca2_crt).is_ok());
+        assert!(verifier
+            .verify_client_cert(&[rustls::Certificate(FOO_NS1.crt_der())])
+            .is_ok());
     }
-}
 
-impl fmt::Debug for TrustAnchors {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("TrustAnchors").finish()
+    #[test]
+    fn trust_anchors_store_snapshots_server_configs() {
+        use self::rustls::Session;
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::{ClientAuth, HasConfig as _ServerHasConfig};
+
+        let store = TrustAnchorsStore::new(FOO_NS1.trust_anchors());
+        let server = FOO_NS1
+            .trust_anchors()
+            .with_client_auth(ClientAuth::Required)
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid")
+            .with_trust_anchors_store(&store);
+
+        // The client presents a certificate issued by CA2.
+        let both = FOO_NS1.trust_anchors().merge(&FOO_NS1_CA2.trust_anchors());
+        let client = both
+            .certify_at(
+                FOO_NS1_CA2.key(),
+                FOO_NS1_CA2.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid")
+            .tls_client_config();
+
+        let before = server.tls_server_config();
+        assert!(
+            Arc::ptr_eq(&before, &server.tls_server_config()),
+            "configs must be reused until the trust anchors are set"
+        );
+        assert!(handshake(&client, &before, &FOO_NS1.name()).is_err());
+
+        store.set(both);
+        let after = server.tls_server_config();
+        assert!(!Arc::ptr_eq(&before, &after));
+        let (_, session) =
+            handshake(&client, &after, &FOO_NS1.name()).expect("CA2 must be trusted once set");
+        assert!(session.get_peer_certificates().is_some());
+
+        // Handshakes that started with the previous trust anchors keep them.
+        assert!(handshake(&client, &before, &FOO_NS1.name()).is_err());
     }
-}
 
-// === Crt ===
+    #[test]
+    fn name_labels() {
+        let name = |n: &str| Name::from_hostname(n.as_bytes()).unwrap();
+        let foo = name("foo.ns1.serviceaccount.identity.linkerd.cluster.local");
 
-impl Crt {
-    pub fn new(name: Name, leaf: Vec<u8>, intermediates: Vec<Vec<u8>>, expiry: SystemTime) -> Self {
-        let mut chain = Vec::with_capacity(intermediates.len() + 1);
-        chain.push(rustls::Certificate(leaf));
-        chain.extend(intermediates.into_iter().map(rustls::Certificate));
+        assert_eq!(
+            foo.labels().collect::<Vec<_>>(),
+            vec![
+                "foo",
+                "ns1",
+                "serviceaccount",
+                "identity",
+                "linkerd",
+                "cluster",
+                "local"
+            ]
+        );
 
-        Self {
-            name,
-            chain,
-            expiry,
+        assert!(foo.ends_with(&foo));
+        assert!(foo.ends_with(&name("ns1.serviceaccount.identity.linkerd.cluster.local")));
+        assert!(foo.ends_with(&name("Cluster.LOCAL")));
+        assert!(!foo.ends_with(&name("s1.serviceaccount.identity.linkerd.cluster.local")));
+        assert!(!foo.ends_with(&name(
+            "bar.foo.ns1.serviceaccount.identity.linkerd.cluster.local"
+        )));
+        assert!(!name("evilfoo.svc").ends_with(&name("foo.svc")));
+    }
+
+    #[test]
+    fn certify_reuses_verifiers() {
+        use transport::tls::listen::ClientAuth;
+
+        let built = || VERIFIERS_BUILT.with(|n| n.get());
+
+        let anchors = FOO_NS1.trust_anchors();
+        let server_verifier = anchors.verifier();
+        let client_verifier = anchors.client_cert_verifier(ClientAuth::Optional);
+        let before = built();
+        for _ in 0..100 {
+            anchors
+                .certify_at(
+                    FOO_NS1.key(),
+                    FOO_NS1.crt(),
+                    valid_time(),
+                    Duration::from_secs(0),
+                )
+                .expect("foo.ns1 must be valid");
         }
+        assert_eq!(built(), before, "certify must not build verifiers");
+
+        // Changing the trust anchors rebuilds their verifiers, once.
+        let required = anchors.clone().with_client_auth(ClientAuth::Required);
+        assert_eq!(built(), before + 1);
+        let _ = anchors.clone().with_ct_logs(ct_logs());
+        assert_eq!(built(), before + 2);
+        let required_verifier = required.client_cert_verifier(ClientAuth::Required);
+        assert!(!Arc::ptr_eq(&client_verifier, &required_verifier));
+        assert!(Arc::ptr_eq(
+            &required_verifier,
+            &required.client_cert_verifier(ClientAuth::Required)
+        ));
+        let merged = anchors.merge(&FOO_NS1_CA2.trust_anchors());
+        assert!(!Arc::ptr_eq(&server_verifier, &merged.verifier()));
     }
-}
 
-// === CrtKey ===
+    #[test]
+    fn name_strip_suffix() {
+        let name = |n: &str| Name::from_hostname(n.as_bytes()).unwrap();
+        let web = name("web.emojivoto.serviceaccount.identity.linkerd.cluster.local");
 
-impl tls::client::HasConfig for CrtKey {
-    fn tls_client_config(&self) -> Arc<tls::client::Config> {
-        self.client_config.clone()
+        let trust_domain = name("serviceaccount.identity.linkerd.cluster.local");
+        assert_eq!(web.strip_suffix(&trust_domain), Some("web.emojivoto"));
+        let shouty = name("ServiceAccount.Identity.Linkerd.Cluster.Local");
+        assert_eq!(web.strip_suffix(&shouty), Some("web.emojivoto"));
+
+        assert_eq!(
+            web.strip_suffix(&name("viceaccount.identity.linkerd.cluster.local")),
+            None
+        );
+        assert_eq!(web.strip_suffix(&name("emojivoto.svc.cluster.local")), None);
+        assert_eq!(web.strip_suffix(&web), Some(""));
     }
-}
 
-impl tls::listen::HasConfig for CrtKey {
-    fn tls_server_name(&self) -> Name {
-        self.name.clone()
+    #[test]
+    fn invalid_crt_kind() {
+        let anchors = FOO_NS1.trust_anchors();
+        let certify =
+            |crt, now| anchors.certify_at(FOO_NS1.key(), crt, now, Duration::from_secs(0));
+
+        let wrong_name = Crt::new(BAR_NS1.name(), FOO_NS1.crt_der(), vec![], valid_time())
+            .expect("crt must be valid");
+        let err = certify(wrong_name, valid_time()).expect_err("name must not match");
+        assert_eq!(err.kind(), InvalidCrtKind::NotValidForName);
+
+        // FOO_NS1's certificate expires at 2020-03-13T18:02:00Z.
+        let expired = UNIX_EPOCH + Duration::from_secs(1_584_122_520 + 5);
+        let err = certify(FOO_NS1.crt(), expired).expect_err("certificate must be expired");
+        assert_eq!(err.kind(), InvalidCrtKind::Expired);
+
+        let err = FOO_NS1_CA2
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("issuer must be unknown");
+        assert_eq!(err.kind(), InvalidCrtKind::UnknownIssuer);
     }
 
-    fn tls_server_config(&self) -> Arc<tls::listen::Config> {
-        self.server_config.clone()
+    #[test]
+    fn time_until_refresh_at_fraction() {
+        let expiry = UNIX_EPOCH + Duration::from_secs(1_584_122_520);
+        let crt =
+            Crt::new(FOO_NS1.name(), FOO_NS1.crt_der(), vec![], expiry).expect("crt must be valid");
+        let clock = FakeClock::new(valid_time());
+        let crt_key = FOO_NS1
+            .trust_anchors()
+            .certify_with_clock(FOO_NS1.key(), crt, Duration::from_secs(0), &clock)
+            .expect("foo.ns1 must be valid");
+        let lifetime = expiry.duration_since(valid_time()).unwrap();
+
+        let aggressive = crt_key.time_until_refresh_at(&clock, 0.5);
+        let conservative = crt_key.time_until_refresh_at(&clock, 0.9);
+        assert_eq!(aggressive, lifetime / 2);
+        assert_eq!(conservative, lifetime * 9 / 10);
+        assert!(aggressive < conservative);
+
+        // Out-of-range fractions are clamped.
+        assert_eq!(crt_key.time_until_refresh_at(&clock, 1.5), lifetime);
+        assert_eq!(
+            crt_key.time_until_refresh_at(&clock, -1.0),
+            Duration::from_secs(0)
+        );
     }
-}
 
-impl fmt::Debug for CrtKey {
-    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        f.debug_struct("CrtKey")
-            .field("name", &self.name)
-            .field("expiry", &self.expiry)
-            .finish()
+    #[test]
+    fn fake_clock_drives_expiry() {
+        let expiry = UNIX_EPOCH + Duration::from_secs(1_584_122_520);
+        let crt = || {
+            Crt::new(FOO_NS1.name(), FOO_NS1.crt_der(), vec![], expiry).expect("crt must be valid")
+        };
+        let clock = FakeClock::new(valid_time());
+        let anchors = FOO_NS1.trust_anchors();
+
+        let crt_key = anchors
+            .certify_with_clock(FOO_NS1.key(), crt(), Duration::from_secs(0), &clock)
+            .expect("foo.ns1 must be valid");
+        assert!(crt_key.is_valid_at(&clock));
+        let lifetime = expiry.duration_since(valid_time()).unwrap();
+        assert_eq!(crt_key.time_until_refresh(&clock), lifetime * 7 / 10);
+
+        clock.advance(lifetime + Duration::from_secs(1));
+        assert!(!crt_key.is_valid_at(&clock));
+        assert_eq!(crt_key.time_until_refresh(&clock), Duration::from_secs(0));
+
+        let err = anchors
+            .certify_with_clock(FOO_NS1.key(), crt(), Duration::from_secs(0), &clock)
+            .expect_err("foo.ns1 must have expired");
+        assert_eq!(err.kind(), InvalidCrtKind::Expired);
     }
-}
 
-// === impl CertResolver ===
+    #[test]
+    fn crt_leaf_and_intermediates() {
+        let ca = FOO_NS1.trust_anchors_der().remove(0);
+        let crt = Crt::new(
+            FOO_NS1.name(),
+            FOO_NS1.crt_der(),
+            vec![ca.clone()],
+            valid_time(),
+        )
+        .expect("crt must be valid");
+        assert_eq!(crt.leaf().as_ref(), &FOO_NS1.crt_der()[..]);
+        assert_eq!(&crt.intermediates()[..], &[rustls::Certificate(ca)][..]);
 
-impl rustls::ResolvesClientCert for CertResolver {
-    fn resolve(
-        &self,
-        _acceptable_issuers: &[&[u8]],
-        sigschemes: &[rustls::SignatureScheme],
-    ) -> Option<rustls::sign::CertifiedKey> {
-        // The proxy's server-side doesn't send the list of acceptable issuers so
-        // don't bother looking at `_acceptable_issuers`.
-        self.resolve_(sigschemes)
+        let crt = FOO_NS1.crt();
+        assert_eq!(crt.leaf().as_ref(), &FOO_NS1.crt_der()[..]);
+        assert!(crt.intermediates().is_empty());
     }
 
-    fn has_certs(&self) -> bool {
-        true
+    #[test]
+    fn crts_share_intermediates() {
+        let ca = FOO_NS1.trust_anchors_der().remove(0);
+        let intermediates: Arc<[rustls::Certificate]> = vec![rustls::Certificate(ca)].into();
+        let crts = (0..16)
+            .map(|_| {
+                Crt::with_shared_intermediates(
+                    FOO_NS1.name(),
+                    FOO_NS1.crt_der(),
+                    intermediates.clone(),
+                    valid_time(),
+                )
+                .expect("crt must be valid")
+            })
+            .collect::<Vec<_>>();
+        for crt in &crts {
+            assert!(Arc::ptr_eq(crt.intermediates(), &intermediates));
+        }
+
+        // Certifying presents the full chain. The certified key holds its
+        // own copy of it, since Rustls owns the chains it serves, but the
+        // `Crt`'s intermediates remain shared.
+        let crt = crts[0].clone();
+        let crt_key = FOO_NS1
+            .trust_anchors()
+            .certify(FOO_NS1.key(), crt.clone())
+            .expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.resolver.key.cert.len(), 2);
+        assert!(Arc::ptr_eq(crt.intermediates(), &intermediates));
     }
-}
 
-impl CertResolver {
-    fn resolve_(
-        &self,
-        sigschemes: &[rustls::SignatureScheme],
-    ) -> Option<rustls::sign::CertifiedKey> {
-        if !sigschemes.contains(&SIGNATURE_ALG_RUSTLS_SCHEME) {
-            debug!("signature scheme not supported -> no certificate");
-            return None;
+    #[test]
+    fn certify_many() {
+        let anchors = FOO_NS1.trust_anchors();
+        let wrong_name = Crt::new(BAR_NS1.name(), FOO_NS1.crt_der(), vec![], valid_time())
+            .expect("crt must be valid");
+        let items = vec![
+            (FOO_NS1.key(), FOO_NS1.crt()),
+            (FOO_NS1.key(), wrong_name.clone()),
+        ];
+
+        let results = anchors.certify_many_at(items, valid_time());
+        assert_eq!(results.len(), 2);
+        let crt_key = results[0].as_ref().expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.name(), &FOO_NS1.name());
+        let err = results[1]
+            .as_ref()
+            .expect_err("the wrong name must be invalid");
+
+        let scalar = anchors
+            .certify_at(
+                FOO_NS1.key(),
+                wrong_name,
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("the wrong name must be invalid");
+        assert_eq!(err.kind(), scalar.kind());
+    }
+
+    #[test]
+    fn chain_pem_round_trips() {
+        let ca = FOO_NS1.trust_anchors_der().remove(0);
+        let crt = Crt::new(
+            FOO_NS1.name(),
+            FOO_NS1.crt_der(),
+            vec![ca.clone()],
+            valid_time(),
+        )
+        .expect("crt must be valid");
+
+        let pem = crt.chain_pem();
+        let chain = rustls::internal::pemfile::certs(&mut pem.as_bytes()).expect("PEM must parse");
+        let chain = chain.into_iter().map(|c| c.0).collect::<Vec<_>>();
+        assert_eq!(chain, vec![FOO_NS1.crt_der(), ca]);
+    }
+
+    #[test]
+    fn certify_rejects_crt_for_another_key() {
+        let err = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                BAR_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect_err("foo.ns1 was not issued for bar.ns1's key");
+        assert!(err.is_key_mismatch());
+        assert!(!err.is_missing_leaf());
+    }
+
+    #[test]
+    fn name_policy() {
+        let deep = b"a.b.c.d.e.f.g.h.foo.ns1.svc.cluster.local";
+        let default = NamePolicy::default();
+        assert!(Name::from_hostname_with_policy(deep, &default).is_ok());
+
+        let shallow = NamePolicy {
+            max_labels: 8,
+            ..default
+        };
+        assert!(Name::from_hostname_with_policy(deep, &shallow).is_err());
+        assert!(Name::from_hostname_with_policy(b"foo.ns1.svc.cluster.local", &shallow).is_ok());
+
+        let short = NamePolicy {
+            max_len: 16,
+            ..default
+        };
+        assert!(Name::from_hostname_with_policy(b"foo.ns1.svc.cluster.local", &short).is_err());
+        assert!(Name::from_hostname_with_policy(b"foo.ns1", &short).is_ok());
+    }
+
+    #[test]
+    fn signatures_performed() {
+        use self::rustls::sign::SigningKey as RustlsSigningKey;
+        use std::thread;
+
+        const THREADS: usize = 4;
+        const SIGNS_PER_THREAD: usize = 25;
+
+        let crt_key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        assert_eq!(crt_key.signatures_performed(), 0);
+
+        let threads = (0..THREADS)
+            .map(|_| {
+                let key = crt_key.resolver.key.key.clone();
+                let scheme = crt_key.signature_scheme();
+                thread::spawn(move || {
+                    let signer = key
+                        .choose_scheme(&[scheme])
+                        .expect("scheme must be offered");
+                    for _ in 0..SIGNS_PER_THREAD {
+                        signer.sign(b"hello").expect("message must be signed");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().expect("signing thread must not panic");
         }
-        Some(self.0.clone())
+
+        assert_eq!(
+            crt_key.signatures_performed(),
+            (THREADS * SIGNS_PER_THREAD) as u64
+        );
     }
-}
 
-impl rustls::ResolvesServerCert for CertResolver {
-    fn resolve(
-        &self,
-        server_name: Option<webpki::DNSNameRef>,
-        sigschemes: &[rustls::SignatureScheme],
-    ) -> Option<rustls::sign::CertifiedKey> {
-        let server_name = if let Some(server_name) = server_name {
-            server_name
-        } else {
-            debug!("no SNI -> no certificate");
-            return None;
+    #[test]
+    fn signatures_are_reproducible_with_an_injected_rng() {
+        use self::rustls::sign::SigningKey as RustlsSigningKey;
+        use super::ring::signature::{self, KeyPair};
+        use super::ring::test::rand::FixedByteRandom;
+        use super::untrusted;
+
+        let key = FOO_NS1.key();
+        let public_key = key.0.public_key().as_ref().to_vec();
+        let sign = |msg: &[u8]| {
+            SigningKey::with_rng(key.clone(), Arc::new(FixedByteRandom { byte: 1 }))
+                .choose_scheme(&[rustls::SignatureScheme::ECDSA_NISTP256_SHA256])
+                .expect("foo.ns1 must sign P-256 signatures")
+                .sign(msg)
+                .expect("message must be signed")
         };
 
-        // Verify that our certificate is valid for the given SNI name.
-        let c = (&self.0.cert)
-            .first()
-            .map(rustls::Certificate::as_ref)
-            .unwrap_or(&[]); // An empty input will fail to parse.
-        if let Err(err) = webpki::EndEntityCert::from(untrusted::Input::from(c))
-            .and_then(|c| c.verify_is_valid_for_dns_name(server_name))
-        {
-            debug!(
-                "our certificate is not valid for the SNI name -> no certificate: {:?}",
-                err
+        let msg = b"hello";
+        let sig = sign(msg);
+        assert_eq!(
+            sig,
+            sign(msg),
+            "the same nonce must yield the same signature"
+        );
+        assert_ne!(sig, sign(b"goodbye"));
+        signature::verify(
+            &signature::ECDSA_P256_SHA256_ASN1,
+            untrusted::Input::from(&public_key),
+            untrusted::Input::from(msg),
+            untrusted::Input::from(&sig),
+        )
+        .expect("signature must be valid");
+    }
+
+    #[test]
+    fn try_from_ascii() {
+        for n in &[
+            &b"foo.ns1.serviceaccount.identity.linkerd.cluster.local"[..],
+            b"FOO.NS1.svc.cluster.local",
+            b"a-b.c1",
+            b"localhost",
+            b"foo.ns1.",
+            b"-foo.ns1",
+            b"foo..ns1",
+            b"",
+        ] {
+            assert_eq!(
+                Name::try_from_ascii(n).ok(),
+                Name::from_hostname(n).ok(),
+                "{:?}",
+                ::std::str::from_utf8(n)
             );
-            return None;
         }
 
-        self.resolve_(sigschemes)
+        assert!(Name::try_from_ascii("f\u{f6}\u{f6}.ns1".as_bytes()).is_err());
+        assert!(Name::try_from_ascii(b"foo.ns1\x80").is_err());
     }
-}
 
-// === impl InvalidCrt ===
+    #[test]
+    fn crt_new_reordered() {
+        let anchors = FOO_NS1_INT.trust_anchors();
+        let certify =
+            |crt| anchors.certify_at(FOO_NS1_INT.key(), crt, valid_time(), Duration::from_secs(0));
+        let reordered = |crts, anchors: &TrustAnchors| {
+            Crt::new_reordered(
+                FOO_NS1_INT.name(),
+                FOO_NS1_INT.crt_der(),
+                crts,
+                valid_time(),
+                anchors,
+            )
+        };
+        let intermediates = |crt: &Crt| {
+            crt.intermediates()
+                .iter()
+                .map(|c| c.0.clone())
+                .collect::<Vec<_>>()
+        };
 
-impl fmt::Display for InvalidCrt {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Display::fmt(&self.0, f)
-    }
-}
+        // The intermediates are returned root-first, so the leaf's issuer is
+        // last.
+        let mut ints = FOO_NS1_INT.intermediates_der();
+        assert_eq!(ints.len(), 2);
+        let in_order = ints.iter().rev().cloned().collect::<Vec<_>>();
+        let crt = Crt::new(
+            FOO_NS1_INT.name(),
+            FOO_NS1_INT.crt_der(),
+            in_order.clone(),
+            valid_time(),
+        )
+        .expect("crt must be valid");
+        certify(crt).expect("an ordered chain must be valid");
 
-impl Error for InvalidCrt {
-    fn description(&self) -> &str {
-        self.0.description()
+        // Shuffled intermediates, with the root, are put in order.
+        ints.extend(FOO_NS1_INT.trust_anchors_der());
+        ints.swap(0, 2);
+        let crt = reordered(ints, &anchors).expect("a chain must be found");
+        assert_eq!(intermediates(&crt), in_order);
+        certify(crt).expect("a reordered chain must be valid");
+
+        // Certificates that aren't part of the chain are dropped.
+        let mut crts = in_order.clone();
+        crts.insert(0, FOO_NS1.crt_der());
+        let crt = reordered(crts, &anchors).expect("a chain must be found");
+        assert_eq!(intermediates(&crt), in_order);
+
+        // int1 and its cross-signed twin have the same subject and issuer,
+        // so only their signatures show which leads to the trust anchors.
+        let crts = vec![int1_ca2_der(), in_order[0].clone(), in_order[1].clone()];
+        let crt = reordered(crts.clone(), &anchors).expect("a chain must be found");
+        assert_eq!(intermediates(&crt), in_order);
+        let ca2 = FOO_NS1_CA2.trust_anchors();
+        let crt = reordered(crts, &ca2).expect("a cross-signed chain must be found");
+        assert_eq!(
+            intermediates(&crt),
+            vec![in_order[0].clone(), int1_ca2_der()]
+        );
+        ca2.certify_at(FOO_NS1_INT.key(), crt, valid_time(), Duration::from_secs(0))
+            .expect("a cross-signed chain must be valid");
+
+        reordered(vec![in_order[0].clone()], &anchors)
+            .expect_err("an incomplete chain must be rejected");
+        reordered(vec![b"not a certificate".to_vec()], &anchors)
+            .expect_err("invalid certificates must be rejected");
     }
 
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        self.0.source()
+    #[test]
+    fn error_from_underlying_errors() {
+        use std::error::Error as StdError;
+        use std::io;
+
+        let p8 = b"not a key".to_vec();
+        let e = Error::from(Key::from_pkcs8(&p8).expect_err("key must be invalid"));
+        assert!(e.to_string().starts_with("invalid key: "), "{}", e);
+        assert!(e.source().is_some());
+
+        let e = Error::from(Name::from_hostname(b"not a name!").expect_err("name must be invalid"));
+        assert_eq!(e.to_string(), "invalid name");
+        assert!(e.source().is_none());
+
+        let crt = Crt::new(
+            FOO_NS1.name(),
+            b"not a certificate".to_vec(),
+            vec![],
+            UNIX_EPOCH,
+        )
+        .expect("a non-empty leaf must be accepted");
+        let e = Error::from(
+            FOO_NS1
+                .trust_anchors()
+                .certify(FOO_NS1.key(), crt)
+                .expect_err("crt must be invalid"),
+        );
+        assert!(e.to_string().starts_with("invalid certificate: "), "{}", e);
+        assert!(e.source().is_some());
+
+        let e = Error::from(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(e.to_string(), "identity I/O error: no such file");
+        assert!(e.source().is_some());
+
+        // Each converts with `?`.
+        fn load() -> Result<Name, Error> {
+            Ok(Name::from_hostname(b"")?)
+        }
+        let e = load().expect_err("an empty name must be rejected");
+        assert!(
+            match e {
+                Error::Name(_) => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::test_util::*;
+    #[test]
+    fn certify_with_intermediates() {
+        let certify = |anchors: &TrustAnchors, crt| {
+            anchors.certify_at(FOO_NS1_INT.key(), crt, valid_time(), Duration::from_secs(0))
+        };
+        let leaf_only = || {
+            Crt::new(
+                FOO_NS1_INT.name(),
+                FOO_NS1_INT.crt_der(),
+                vec![],
+                valid_time(),
+            )
+            .expect("crt must be valid")
+        };
+        let anchors = FOO_NS1_INT.trust_anchors();
+        let err = certify(&anchors, leaf_only()).expect_err("leaf alone must not be valid");
+        assert_eq!(err.kind(), InvalidCrtKind::UnknownIssuer);
+
+        // The intermediates are read root first.
+        let chain = FOO_NS1_INT
+            .intermediates_der()
+            .into_iter()
+            .rev()
+            .collect::<Vec<_>>();
+        let bundle = IntermediateBundle::new(chain.clone());
+        assert_eq!(bundle.len(), 2);
+        let anchors = anchors.with_intermediates(bundle);
+        let crt_key = certify(&anchors, leaf_only()).expect("leaf must be valid with bundle");
+        let presented = crt_key
+            .resolver
+            .key
+            .cert
+            .iter()
+            .map(|c| c.0.clone())
+            .collect::<Vec<_>>();
+        let mut expected = vec![FOO_NS1_INT.crt_der()];
+        expected.extend(chain);
+        assert_eq!(presented, expected);
+
+        // A certificate's own intermediates are used instead.
+        let crt = Crt::new(
+            FOO_NS1_INT.name(),
+            FOO_NS1_INT.crt_der(),
+            vec![FOO_NS1_INT.intermediates_der().remove(0)],
+            valid_time(),
+        )
+        .expect("crt must be valid");
+        certify(&anchors, crt).expect_err("own intermediates must be used");
+
+        assert!(IntermediateBundle::from_pem("").is_none());
+        let bundle = IntermediateBundle::from_pem(&FOO_NS1.trust_anchors_pem())
+            .expect("bundle must be valid");
+        assert_eq!(bundle.len(), 1);
+    }
 
     #[test]
-    fn can_construct_client_and_server_config_from_valid_settings() {
-        FOO_NS1.validate().expect("foo.ns1 must be valid");
+    fn from_pem_verbose_describes_skipped_anchors() {
+        let good = FOO_NS1.trust_anchors_pem();
+        let bad = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            super::base64::encode(b"not a certificate")
+        );
+        let lines = good.lines().count();
+
+        let (anchors, skipped) =
+            TrustAnchors::from_pem_verbose(&format!("{}{}", good, bad)).expect("must load");
+        assert_eq!(anchors.len(), 1);
+        assert_eq!(
+            skipped,
+            vec![SkipInfo {
+                index: 1,
+                first_line: lines + 1,
+                last_line: lines + 3,
+                error: webpki::Error::BadDER,
+            }]
+        );
+
+        let (_, skipped) = TrustAnchors::from_pem_verbose(&good).expect("must load");
+        assert!(skipped.is_empty());
+
+        let e = TrustAnchors::from_pem_verbose(&bad)
+            .map(|(_, s)| s)
+            .expect_err("PEM without valid anchors must be rejected");
+        assert_eq!(e, TrustAnchorsError::NoneAdded { skipped: 1 });
     }
 
     #[test]
-    fn recognize_ca_did_not_issue_cert() {
-        let s = Strings {
-            trust_anchors: "ca2.pem",
-            ..FOO_NS1
+    fn certify_limits_chain_length() {
+        let chain = FOO_NS1_INT
+            .intermediates_der()
+            .into_iter()
+            .rev()
+            .collect::<Vec<_>>();
+        let certify = |anchors: TrustAnchors, intermediates: Vec<Vec<u8>>| {
+            let crt = Crt::new(
+                FOO_NS1_INT.name(),
+                FOO_NS1_INT.crt_der(),
+                intermediates,
+                valid_time(),
+            )
+            .expect("crt must be valid");
+            anchors.certify_at(FOO_NS1_INT.key(), crt, valid_time(), Duration::from_secs(0))
         };
-        assert!(s.validate().is_err(), "ca2 should not validate foo.ns1");
+
+        let anchors = FOO_NS1_INT.trust_anchors();
+        certify(anchors.clone(), chain.clone()).expect("a normal chain must be valid");
+        certify(anchors.clone().with_max_chain_len(3), chain.clone())
+            .expect("a chain at the limit must be valid");
+
+        let err = certify(anchors.clone().with_max_chain_len(2), chain.clone())
+            .expect_err("a chain over the limit must be invalid");
+        assert!(err.is_chain_too_long());
+
+        // By default, chains of more than six certificates are rejected,
+        // before they're verified.
+        let mut long = chain;
+        long.extend(FOO_NS1.trust_anchors_der());
+        long.extend(FOO_NS1_CA2.trust_anchors_der());
+        long.push(FOO_NS1.crt_der());
+        long.push(BAR_NS1.crt_der());
+        long.push(FOO_NS1_CA2.crt_der());
+        assert!(long.len() + 1 > super::DEFAULT_MAX_CHAIN_LEN);
+        let err = certify(anchors, long).expect_err("a long chain must be invalid");
+        assert!(err.is_chain_too_long());
     }
 
     #[test]
-    fn recognize_cert_is_not_valid_for_identity() {
-        let s = Strings {
-            crt: BAR_NS1.crt,
-            key: BAR_NS1.key,
-            ..FOO_NS1
+    fn clients_check_stapled_ocsp() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        let server = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt().with_ocsp(FOO_NS1.ocsp("good")),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        let client = |now| {
+            FOO_NS1
+                .trust_anchors()
+                .with_clock(FixedClock(now))
+                .tls_client_config()
         };
-        assert!(s.validate().is_err(), "identity should not be valid");
+
+        handshake(
+            &client(valid_time()),
+            &server.tls_server_config(),
+            &FOO_NS1.name(),
+        )
+        .expect("a current response must be accepted");
+
+        // 2019-09-01, after the response's nextUpdate but before the
+        // certificate expires.
+        let later = UNIX_EPOCH + Duration::from_secs(1_567_296_000);
+        assert!(
+            handshake(&client(later), &server.tls_server_config(), &FOO_NS1.name()).is_err(),
+            "a stale response must be rejected"
+        );
     }
 
     #[test]
-    #[ignore] // XXX this doesn't fail because we don't actually check the key against the cert...
-    fn recognize_private_key_is_not_valid_for_cert() {
-        let s = Strings {
-            key: BAR_NS1.key,
-            ..FOO_NS1
-        };
-        assert!(s.validate().is_err(), "identity should not be valid");
+    fn clients_require_embedded_scts() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        let client = FOO_NS1
+            .trust_anchors()
+            .with_ct_logs(ct_logs())
+            .tls_client_config();
+
+        let embedded = FOO_NS1_SCT.validate().expect("foo.ns1 must be valid");
+        handshake(&client, &embedded.tls_server_config(), &FOO_NS1.name())
+            .expect("an embedded SCT must be accepted");
+
+        // Rustls verifies this SCT, but it isn't embedded in the certificate.
+        let attached = FOO_NS1
+            .trust_anchors()
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt().with_sct(FOO_NS1.sct()),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        assert!(
+            handshake(&client, &attached.tls_server_config(), &FOO_NS1.name()).is_err(),
+            "servers without an embedded SCT must be rejected"
+        );
+    }
+
+    #[test]
+    fn handshakes_limit_peer_chain_length() {
+        use transport::tls::client::HasConfig as _ClientHasConfig;
+        use transport::tls::listen::{ClientAuth, HasConfig as _ServerHasConfig};
+
+        let chain = FOO_NS1_INT
+            .intermediates_der()
+            .into_iter()
+            .rev()
+            .collect::<Vec<_>>();
+        let crt = Crt::new(
+            FOO_NS1_INT.name(),
+            FOO_NS1_INT.crt_der(),
+            chain,
+            valid_time(),
+        )
+        .expect("crt must be valid");
+        let int = FOO_NS1_INT
+            .trust_anchors()
+            .certify_at(FOO_NS1_INT.key(), crt, valid_time(), Duration::from_secs(0))
+            .expect("foo.ns1 must be valid");
+        let limited = FOO_NS1_INT.trust_anchors().with_max_chain_len(2);
+
+        // Servers' chains are limited by clients...
+        let client = limited.tls_client_config();
+        assert!(
+            handshake(&client, &int.tls_server_config(), &FOO_NS1.name()).is_err(),
+            "a server's chain over the limit must be rejected"
+        );
+        handshake(
+            &FOO_NS1_INT.trust_anchors().tls_client_config(),
+            &int.tls_server_config(),
+            &FOO_NS1.name(),
+        )
+        .expect("a server's chain within the limit must be accepted");
+
+        // ...and clients' chains by servers.
+        let server = limited
+            .with_client_auth(ClientAuth::Required)
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("foo.ns1 must be valid");
+        assert!(
+            handshake(
+                &int.tls_client_config(),
+                &server.tls_server_config(),
+                &FOO_NS1.name()
+            )
+            .is_err(),
+            "a client's chain over the limit must be rejected"
+        );
     }
 }
@@ -0,0 +1,537 @@
+#![allow(dead_code)] // Not yet used by the proxy.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use super::{rustls, x509, CrtKey, LogPolicy, Name};
+use dns;
+use transport::tls::{listen::Config, SelectConfig};
+
+/// Holds the certified keys for each of the identities served by a listener.
+///
+/// When resolving a server certificate, the key registered for the SNI name
+/// is preferred. Otherwise, any registered certificate that is valid for the
+/// name, e.g. with a wildcard SAN, is used; if several are, the one whose
+/// key is registered under the least name is chosen, so that the choice is
+/// stable.
+///
+/// The registry is read by TLS handshakes as they resolve a server
+/// certificate and may be updated concurrently (e.g. by a rotation task).
+/// Readers operate on an immutable snapshot of the registry, so a handshake
+/// never observes a partially-applied update.
+#[derive(Clone, Debug, Default)]
+pub struct CrtRegistry(Arc<RwLock<Snapshot>>);
+
+type Snapshot = Arc<Keys>;
+
+/// The certified keys in a `CrtRegistry` snapshot.
+///
+/// The DNS SANs of every key's certificate are indexed when the snapshot is
+/// built, so that resolving a name that isn't registered doesn't need to
+/// check each key's certificate.
+#[derive(Debug, Default)]
+struct Keys {
+    by_name: HashMap<Name, Arc<CrtKey>>,
+    /// Maps each DNS SAN, in lowercase and including wildcard SANs like
+    /// `*.ns1.svc.cluster.local`, to the key under the least name whose
+    /// certificate has it.
+    by_san: HashMap<String, Arc<CrtKey>>,
+}
+
+/// Selects the server configuration for each TLS client by the SNI name it
+/// requests, so that the identities served from a `CrtRegistry` may each
+/// negotiate their own ALPN protocols.
+///
+/// Rustls negotiates ALPN with the protocols of the configuration that a
+/// session is accepted with, so an identity's protocols can't be chosen when
+/// its certificate is resolved; instead, each identity with its own
+/// protocols has a configuration of its own. Clients that request any other
+/// name, or no name, are accepted with the base configuration.
+///
+/// Created by `CrtRegistry::server_configs`.
+#[derive(Clone)]
+pub struct CrtRegistryConfig {
+    base: Arc<Config>,
+    by_name: HashMap<Name, Arc<Config>>,
+}
+
+/// Holds the current certified key for a single identity.
+///
+/// Handshakes resolve certificates from whichever key is current when they
+/// begin resolution, so a concurrent `set` never causes a handshake to mix
+/// the certificates of two keys.
+#[derive(Clone, Debug)]
+pub struct CrtKeyStore(Arc<RwLock<CrtKey>>);
+
+// === impl CrtRegistry ===
+
+impl CrtRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the certified key for `name`, if there is one.
+    pub fn get(&self, name: &Name) -> Option<Arc<CrtKey>> {
+        self.snapshot().by_name.get(name).cloned()
+    }
+
+    /// Adds a certified key, replacing and returning any existing key for the
+    /// same name.
+    pub fn insert(&self, crt_key: CrtKey) -> Option<Arc<CrtKey>> {
+        self.update(|keys| keys.insert(crt_key.name.clone(), Arc::new(crt_key)))
+    }
+
+    /// Removes and returns the certified key for `name`, if there is one.
+    pub fn remove(&self, name: &Name) -> Option<Arc<CrtKey>> {
+        self.update(|keys| keys.remove(name))
+    }
+
+    /// Replaces all certified keys at once.
+    pub fn replace<I: IntoIterator<Item = CrtKey>>(&self, crt_keys: I) {
+        let keys = crt_keys
+            .into_iter()
+            .map(|k| (k.name.clone(), Arc::new(k)))
+            .collect::<HashMap<_, _>>();
+        *self.write() = Arc::new(Keys::new(keys));
+    }
+
+    /// Returns server configurations that present the registry's
+    /// certificates, and are otherwise like `base`.
+    pub fn server_configs(&self, base: &Config) -> CrtRegistryConfig {
+        let mut base = base.clone();
+        base.cert_resolver = Arc::new(self.clone());
+        CrtRegistryConfig {
+            base: Arc::new(base),
+            by_name: HashMap::new(),
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        // The lock only guards replacing the snapshot, which cannot be
+        // interrupted partway, so a poisoned lock still holds a valid value.
+        match self.0.read() {
+            Ok(s) => s.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn write(&self) -> ::std::sync::RwLockWriteGuard<Snapshot> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut HashMap<Name, Arc<CrtKey>>) -> T,
+    {
+        let mut snapshot = self.write();
+        let mut keys = snapshot.by_name.clone();
+        let t = f(&mut keys);
+        *snapshot = Arc::new(Keys::new(keys));
+        t
+    }
+}
+
+// === impl Keys ===
+
+impl Keys {
+    fn new(by_name: HashMap<Name, Arc<CrtKey>>) -> Self {
+        let mut by_san: HashMap<String, Arc<CrtKey>> = HashMap::new();
+        for key in by_name.values() {
+            let tbs = match x509::parse(key.resolver.leaf()) {
+                Ok(tbs) => tbs,
+                Err(err) => {
+                    debug!(
+                        "certificate for {} could not be parsed: {:?}",
+                        key.name.log(LogPolicy::current()),
+                        err
+                    );
+                    continue;
+                }
+            };
+            for san in tbs.dns_sans {
+                let san = san.to_ascii_lowercase();
+                let least = match by_san.get(&san) {
+                    Some(k) => k.name.as_ref() < key.name.as_ref(),
+                    None => false,
+                };
+                if !least {
+                    by_san.insert(san, key.clone());
+                }
+            }
+        }
+        Keys { by_name, by_san }
+    }
+
+    /// Returns the key registered for `name`, or else the key whose
+    /// certificate has a DNS SAN, possibly a wildcard, that matches `name`.
+    fn resolve(&self, name: &Name) -> Option<&Arc<CrtKey>> {
+        if let Some(key) = self.by_name.get(name) {
+            return Some(key);
+        }
+
+        let name = name.as_ref().to_ascii_lowercase();
+        if let Some(key) = self.by_san.get(&name) {
+            return Some(key);
+        }
+
+        // A wildcard only matches the leftmost label.
+        let parent = name.splitn(2, '.').nth(1)?;
+        self.by_san.get(&format!("*.{}", parent))
+    }
+}
+
+// === impl CrtRegistryConfig ===
+
+impl CrtRegistryConfig {
+    /// Negotiates only `protocols`, in order of preference, with clients
+    /// that request `name`.
+    ///
+    /// Clients that offer none of `protocols` are accepted without ALPN.
+    pub fn with_alpn(mut self, name: Name, protocols: Vec<Vec<u8>>) -> Self {
+        let mut config = self.base.as_ref().clone();
+        config.alpn_protocols = protocols;
+        self.by_name.insert(name, Arc::new(config));
+        self
+    }
+}
+
+impl SelectConfig for CrtRegistryConfig {
+    fn select_config(&self, sni: Option<&Name>) -> Arc<Config> {
+        sni.and_then(|n| self.by_name.get(n))
+            .unwrap_or(&self.base)
+            .clone()
+    }
+}
+
+impl fmt::Debug for CrtRegistryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CrtRegistryConfig")
+            .field("alpn", &self.by_name.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+// === impl CrtKeyStore ===
+
+impl CrtKeyStore {
+    pub fn new(crt_key: CrtKey) -> Self {
+        CrtKeyStore(Arc::new(RwLock::new(crt_key)))
+    }
+
+    /// Returns the current certified key.
+    pub fn current(&self) -> CrtKey {
+        // As with `CrtRegistry`, a poisoned lock still holds a valid value.
+        match self.0.read() {
+            Ok(k) => k.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Replaces the current certified key.
+    pub fn set(&self, crt_key: CrtKey) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = crt_key;
+    }
+}
+
+impl rustls::ResolvesServerCert for CrtKeyStore {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        let current = self.current();
+        rustls::ResolvesServerCert::resolve(&*current.resolver, server_name, sigschemes)
+    }
+}
+
+impl rustls::ResolvesClientCert for CrtKeyStore {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        let current = self.current();
+        rustls::ResolvesClientCert::resolve(&*current.resolver, acceptable_issuers, sigschemes)
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+impl rustls::ResolvesServerCert for CrtRegistry {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<rustls::sign::CertifiedKey> {
+        let server_name = if let Some(server_name) = server_name {
+            server_name
+        } else {
+            debug!("no SNI -> no certificate");
+            return None;
+        };
+
+        let name = Name::from(dns::Name::from(server_name.to_owned()));
+        let snapshot = self.snapshot();
+        match snapshot.resolve(&name) {
+            Some(crt_key) => rustls::ResolvesServerCert::resolve(
+                &*crt_key.resolver,
+                Some(server_name),
+                sigschemes,
+            ),
+            None => {
+                debug!(
+                    "no certificate registered for {}",
+                    name.log(LogPolicy::current())
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn resolves_registered_names() {
+        use super::rustls::ResolvesServerCert;
+
+        let reg = CrtRegistry::new();
+        reg.insert(FOO_NS1.validate().expect("foo.ns1 must be valid"));
+
+        let schemes = &[rustls::SignatureScheme::ECDSA_NISTP256_SHA256];
+        let foo = FOO_NS1.name();
+        let bar = BAR_NS1.name();
+        assert!(reg.resolve(Some(foo.as_dns_name_ref()), schemes).is_some());
+        assert!(reg.resolve(Some(bar.as_dns_name_ref()), schemes).is_none());
+        assert!(reg.resolve(None, schemes).is_none());
+
+        reg.remove(&foo);
+        assert!(reg.resolve(Some(foo.as_dns_name_ref()), schemes).is_none());
+    }
+
+    #[test]
+    fn resolves_wildcard_names() {
+        use super::rustls::ResolvesServerCert;
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let wildcard = WILDCARD_NS1.validate().expect("baz.ns1 must be valid");
+        let reg = CrtRegistry::new();
+        reg.replace(vec![foo.clone(), wildcard.clone()]);
+
+        let schemes = &[rustls::SignatureScheme::ECDSA_NISTP256_SHA256];
+        let resolve = |n: &str| {
+            let n = Name::from_hostname(n.as_bytes()).expect("name must be valid");
+            reg.resolve(Some(n.as_dns_name_ref()), schemes)
+                .map(|k| k.cert[0].clone())
+        };
+
+        // An exact match is preferred, even though the wildcard matches too.
+        assert_eq!(
+            resolve("foo.ns1.serviceaccount.identity.linkerd.cluster.local"),
+            Some(FOO_NS1.crt().leaf.clone())
+        );
+
+        assert_eq!(
+            resolve("qux.ns1.serviceaccount.identity.linkerd.cluster.local"),
+            Some(WILDCARD_NS1.crt().leaf.clone())
+        );
+
+        assert_eq!(
+            resolve("foo.ns2.serviceaccount.identity.linkerd.cluster.local"),
+            None
+        );
+        assert_eq!(
+            resolve("qux.foo.ns1.serviceaccount.identity.linkerd.cluster.local"),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_names_by_san() {
+        use super::rustls::ResolvesServerCert;
+
+        // foo-bar's certificate is also valid for bar.ns1, which is only
+        // registered once the exact match is removed.
+        let foo_bar = FOO_BAR_NS1.validate().expect("foo.ns1 must be valid");
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let reg = CrtRegistry::new();
+        reg.replace(vec![foo_bar, bar]);
+
+        let schemes = &[rustls::SignatureScheme::ECDSA_NISTP256_SHA256];
+        let resolve = |n: &Name| {
+            reg.resolve(Some(n.as_dns_name_ref()), schemes)
+                .map(|k| k.cert[0].clone())
+        };
+
+        assert_eq!(resolve(&BAR_NS1.name()), Some(BAR_NS1.crt().leaf.clone()));
+        reg.remove(&BAR_NS1.name());
+        assert_eq!(
+            resolve(&BAR_NS1.name()),
+            Some(FOO_BAR_NS1.crt().leaf.clone())
+        );
+    }
+
+    #[test]
+    fn selects_alpn_by_sni() {
+        use super::rustls::Session;
+        use transport::tls::listen::HasConfig;
+
+        const H2: &[u8] = b"h2";
+        const HTTP_1_1: &[u8] = b"http/1.1";
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let reg = CrtRegistry::new();
+        reg.replace(vec![foo.clone(), bar]);
+        let configs = reg
+            .server_configs(&foo.tls_server_config())
+            .with_alpn(FOO_NS1.name(), vec![H2.to_vec(), HTTP_1_1.to_vec()])
+            .with_alpn(BAR_NS1.name(), vec![HTTP_1_1.to_vec()]);
+
+        // The client prefers h2, but accepts HTTP/1.1.
+        let mut client = FOO_NS1.trust_anchors().tls_client_config().as_ref().clone();
+        client.alpn_protocols = vec![H2.to_vec(), HTTP_1_1.to_vec()];
+        let client = Arc::new(client);
+        let negotiate = |name: Name| {
+            let server = configs.select_config(Some(&name));
+            let (client, server) =
+                handshake(&client, &server, &name).expect("handshake must succeed");
+            let protocol = server.get_alpn_protocol().map(|p| p.to_vec());
+            assert_eq!(client.get_alpn_protocol().map(|p| p.to_vec()), protocol);
+            protocol
+        };
+
+        assert_eq!(negotiate(FOO_NS1.name()), Some(H2.to_vec()));
+        assert_eq!(negotiate(BAR_NS1.name()), Some(HTTP_1_1.to_vec()));
+
+        // Other clients are accepted with the base configuration, which
+        // doesn't negotiate ALPN.
+        let base = configs.select_config(None);
+        assert!(base.alpn_protocols.is_empty());
+        let other = Name::from_hostname(b"other.ns1").expect("name must be valid");
+        assert!(Arc::ptr_eq(&configs.select_config(Some(&other)), &base));
+    }
+
+    #[test]
+    fn readers_never_observe_partial_updates() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::Barrier;
+
+        const READERS: usize = 4;
+
+        // Updates alternate between two states, each of which must be
+        // observed whole: either foo alone, or both bar and baz.
+        let one = vec![FOO_NS1.validate().expect("foo.ns1 must be valid")];
+        let two = vec![
+            BAR_NS1.validate().expect("bar.ns1 must be valid"),
+            WILDCARD_NS1.validate().expect("baz.ns1 must be valid"),
+        ];
+
+        let reg = CrtRegistry::new();
+        reg.replace(one.clone());
+        let start = Arc::new(Barrier::new(READERS + 1));
+        let done = Arc::new(AtomicBool::new(false));
+        // Counts the readers that have observed both states, which shows
+        // that they raced the writer.
+        let raced = Arc::new(AtomicUsize::new(0));
+
+        let readers = (0..READERS)
+            .map(|_| {
+                let reg = reg.clone();
+                let start = start.clone();
+                let done = done.clone();
+                let raced = raced.clone();
+                thread::spawn(move || {
+                    let (foo, bar, baz) = (FOO_NS1.name(), BAR_NS1.name(), WILDCARD_NS1.name());
+                    let (mut saw_one, mut saw_two, mut counted) = (false, false, false);
+                    start.wait();
+                    while !done.load(Ordering::Acquire) {
+                        let snapshot = reg.snapshot();
+                        match snapshot.by_name.len() {
+                            1 => {
+                                assert!(snapshot.by_name.contains_key(&foo));
+                                saw_one = true;
+                            }
+                            2 => {
+                                assert!(snapshot.by_name.contains_key(&bar));
+                                assert!(snapshot.by_name.contains_key(&baz));
+                                saw_two = true;
+                            }
+                            n => panic!("observed a snapshot with {} keys", n),
+                        }
+                        if saw_one && saw_two && !counted {
+                            raced.fetch_add(1, Ordering::AcqRel);
+                            counted = true;
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        start.wait();
+        let mut updates = 0;
+        while raced.load(Ordering::Acquire) < READERS {
+            if updates == 1_000_000 {
+                done.store(true, Ordering::Release);
+                panic!("readers did not observe concurrent updates");
+            }
+            if updates % 2 == 0 {
+                reg.replace(two.clone());
+            } else {
+                reg.replace(one.clone());
+            }
+            updates += 1;
+        }
+        done.store(true, Ordering::Release);
+
+        for r in readers {
+            r.join().expect("reader must not panic");
+        }
+    }
+
+    #[test]
+    fn store_resolves_current_key_while_rotating() {
+        use super::rustls::ResolvesClientCert;
+
+        let foo = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let bar = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let leaves = vec![FOO_NS1.crt().leaf.0.clone(), BAR_NS1.crt().leaf.0.clone()];
+
+        let store = CrtKeyStore::new(foo.clone());
+
+        let resolvers = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let leaves = leaves.clone();
+                thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        let key = store
+                            .resolve(&[], &[rustls::SignatureScheme::ECDSA_NISTP256_SHA256])
+                            .expect("a certificate must always be resolved");
+                        assert_eq!(key.cert.len(), 1);
+                        assert!(leaves.contains(&key.cert[0].0));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..1_000 {
+            if i % 2 == 0 {
+                store.set(bar.clone());
+            } else {
+                store.set(foo.clone());
+            }
+        }
+
+        for r in resolvers {
+            r.join().expect("resolver must not panic");
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Serialization of certificates and trust anchors, e.g. so that they may be
+//! cached.
+//!
+//! Both are serialized as DER, and deserialization goes through the usual
+//! constructors, so that deserialized values are checked just as loaded ones
+//! are. `Key` is deliberately not serializable.
+
+extern crate serde;
+
+use self::serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::{x509, Crt, Name, TrustAnchors};
+
+/// Serialized as its name, its expiry as seconds and nanoseconds since the
+/// Unix epoch, and its DER-encoded chain, leaf first.
+///
+/// The OCSP response and SCTs attached to a certificate aren't serialized.
+impl Serialize for Crt {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let expiry = self
+            .expiry
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_secs(), d.subsec_nanos()))
+            .unwrap_or((0, 0));
+        let chain = self.chain().map(|c| c.as_ref()).collect::<Vec<&[u8]>>();
+        (self.name.as_ref(), expiry, chain).serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Crt {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let (name, (secs, nanos), mut chain) =
+            <(String, (u64, u32), Vec<Vec<u8>>)>::deserialize(d)?;
+        let name = Name::from_hostname(name.as_bytes())
+            .map_err(|_| de::Error::custom(format!("invalid name: {:?}", name)))?;
+        if chain.is_empty() {
+            return Err(de::Error::custom("certificate chain is empty"));
+        }
+        let leaf = chain.remove(0);
+
+        // Each certificate must parse, as when a chain is reordered, so that
+        // corrupted certificates are rejected here rather than when they're
+        // certified.
+        for der in Some(&leaf).into_iter().chain(chain.iter()) {
+            x509::parse(der)
+                .map_err(|e| de::Error::custom(format!("invalid certificate: {:?}", e)))?;
+        }
+
+        // Out-of-range expiries would otherwise panic as they're converted.
+        if nanos >= 1_000_000_000 || secs > i64::max_value() as u64 {
+            return Err(de::Error::custom(format!(
+                "invalid expiry: {}s {}ns",
+                secs, nanos
+            )));
+        }
+        let expiry = UNIX_EPOCH + Duration::new(secs, nanos);
+        Crt::new(name, leaf, chain, expiry).map_err(de::Error::custom)
+    }
+}
+
+/// Serialized as the DER encodings of the roots.
+///
+/// Only the roots are serialized: revocation lists, CT logs, and policies
+/// configured on the trust anchors aren't.
+impl Serialize for TrustAnchors {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let roots = self
+            .roots
+            .iter()
+            .map(|c| c.as_ref())
+            .collect::<Vec<&[u8]>>();
+        roots.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for TrustAnchors {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let roots = Vec::<Vec<u8>>::deserialize(d)?;
+        TrustAnchors::from_der(roots).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::super::test_util::*;
+    use super::*;
+
+    #[test]
+    fn crt_round_trip() {
+        let crt = FOO_NS1.crt();
+        let json = serde_json::to_string(&crt).expect("crt must serialize");
+        let decoded: Crt = serde_json::from_str(&json).expect("crt must deserialize");
+        assert_eq!(decoded.name, crt.name);
+        assert_eq!(decoded.leaf, crt.leaf);
+        assert_eq!(decoded.intermediates, crt.intermediates);
+
+        let anchors = FOO_NS1.trust_anchors();
+        let skew = Duration::from_secs(0);
+        let certified = anchors
+            .certify_at(FOO_NS1.key(), crt, valid_time(), skew)
+            .expect("foo.ns1 must be valid");
+        let decoded = anchors
+            .certify_at(FOO_NS1.key(), decoded, valid_time(), skew)
+            .expect("decoded foo.ns1 must be valid");
+        assert_eq!(decoded, certified);
+
+        let empty = serde_json::to_string(&(FOO_NS1.name, (0, 0), Vec::<Vec<u8>>::new())).unwrap();
+        assert!(serde_json::from_str::<Crt>(&empty).is_err());
+        let corrupt = vec![b"not a certificate".to_vec()];
+        let corrupt = serde_json::to_string(&(FOO_NS1.name, (0, 0), corrupt)).unwrap();
+        assert!(serde_json::from_str::<Crt>(&corrupt).is_err());
+        let corrupt = vec![FOO_NS1.crt_der(), b"not a certificate".to_vec()];
+        let corrupt = serde_json::to_string(&(FOO_NS1.name, (0, 0), corrupt)).unwrap();
+        assert!(serde_json::from_str::<Crt>(&corrupt).is_err());
+    }
+
+    #[test]
+    fn crt_rejects_out_of_range_expiry() {
+        let chain = vec![FOO_NS1.crt_der()];
+        for expiry in &[(0, 1_000_000_000), (u64::max_value(), 999_999_999)] {
+            let json = serde_json::to_string(&(FOO_NS1.name, expiry, &chain)).unwrap();
+            assert!(
+                serde_json::from_str::<Crt>(&json).is_err(),
+                "expiry={:?}",
+                expiry
+            );
+        }
+    }
+
+    #[test]
+    fn trust_anchors_round_trip() {
+        let anchors = FOO_NS1.trust_anchors();
+        let json = serde_json::to_string(&anchors).expect("anchors must serialize");
+        let decoded: TrustAnchors = serde_json::from_str(&json).expect("anchors must deserialize");
+        assert_eq!(decoded.len(), anchors.len());
+        assert_eq!(decoded.subjects(), anchors.subjects());
+        decoded
+            .certify_at(
+                FOO_NS1.key(),
+                FOO_NS1.crt(),
+                valid_time(),
+                Duration::from_secs(0),
+            )
+            .expect("decoded anchors must trust foo.ns1");
+
+        assert!(serde_json::from_str::<TrustAnchors>("[]").is_err());
+        assert!(serde_json::from_str::<TrustAnchors>("[[1, 2, 3]]").is_err());
+    }
+}
@@ -1,8 +1,11 @@
 extern crate webpki;
 
 use super::*;
+use super::{ring, rustls, sct};
+use std::cell::Cell;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 pub struct Strings {
@@ -21,6 +24,13 @@ pub static FOO_NS1: Strings = Strings {
     //csr: "foo-ns1-ca1/csr.der",
 };
 
+pub static FOO_NS1_CA2: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca2.pem",
+    crt: "foo-ns1-ca2/crt.der",
+    key: "foo-ns1-ca2/key.p8",
+};
+
 pub static BAR_NS1: Strings = Strings {
     name: "bar.ns1.serviceaccount.identity.linkerd.cluster.local",
     trust_anchors: "ca1.pem",
@@ -29,6 +39,146 @@ pub static BAR_NS1: Strings = Strings {
     //csr: "bar-ns1-ca1/csr.der",
 };
 
+/// Valid for both foo.ns1 and bar.ns1.
+pub static FOO_BAR_NS1: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "foo-bar-ns1-ca1/crt.der",
+    key: "foo-bar-ns1-ca1/key.p8",
+};
+
+/// Like FOO_NS1, but also identified by a SPIFFE ID.
+pub static FOO_NS1_SPIFFE: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "foo-ns1-spiffe-ca1/crt.der",
+    key: "foo-ns1-spiffe-ca1/key.p8",
+};
+
+/// Certified as baz.ns1, but valid for any name in ns1.
+pub static WILDCARD_NS1: Strings = Strings {
+    name: "baz.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "wildcard-ns1-ca1/crt.der",
+    key: "wildcard-ns1-ca1/key.p8",
+};
+
+/// Names foo.ns1 only in its subject's common name, and has no SANs.
+///
+/// Like the other fixtures, this is only valid at `valid_time()`.
+pub static CN_ONLY_NS1: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "cn-only-ns1-ca1/crt.der",
+    key: "cn-only-ns1-ca1/key.p8",
+};
+
+/// Like FOO_NS1, but issued by ca1 through two intermediate CAs, which are
+/// returned by `intermediates_der`. The intermediates are, like the leaf,
+/// only valid at `valid_time()`.
+pub static FOO_NS1_INT: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "foo-ns1-int-ca1/crt.der",
+    key: "foo-ns1-int-ca1/key.p8",
+};
+
+/// Like FOO_NS1, but with an SCT from the test log embedded in its certificate.
+pub static FOO_NS1_SCT: Strings = Strings {
+    name: "foo.ns1.serviceaccount.identity.linkerd.cluster.local",
+    trust_anchors: "ca1.pem",
+    crt: "foo-ns1-sct-ca1/crt.der",
+    key: "foo-ns1-sct-ca1/key.p8",
+};
+
+/// The password that encrypted test keys are encrypted with.
+pub const ENCRYPTED_KEY_PASSWORD: &[u8] = b"hunter2";
+
+/// Returns a time at which all of the test certificates are valid.
+pub fn valid_time() -> SystemTime {
+    // 2019-06-01T00:00:00Z
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1_559_347_200)
+}
+
+thread_local! {
+    static CRTS_PARSED: Cell<usize> = Cell::new(0);
+}
+
+/// Records that a certificate was parsed on this thread.
+pub fn count_crt_parse() {
+    CRTS_PARSED.with(|n| n.set(n.get() + 1));
+}
+
+/// Returns the number of certificates that were parsed on this thread.
+pub fn crts_parsed() -> usize {
+    CRTS_PARSED.with(Cell::get)
+}
+
+/// A `Clock` whose time only changes when it is advanced.
+#[derive(Debug)]
+pub struct FakeClock(Cell<SystemTime>);
+
+impl FakeClock {
+    pub fn new(now: SystemTime) -> Self {
+        FakeClock(Cell::new(now))
+    }
+
+    pub fn advance(&self, d: Duration) {
+        self.0.set(self.0.get() + d);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.0.get()
+    }
+}
+
+/// A `Clock` that always reads the same time.
+///
+/// Unlike `FakeClock`, this may be shared with the verifiers that
+/// `TrustAnchors` installs for TLS handshakes.
+#[derive(Copy, Clone, Debug)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Returns a revocation list, signed by ca1, that revokes FOO_NS1's
+/// certificate.
+pub fn ca1_crl_pem() -> String {
+    String::from_utf8(Strings::read("ca1-crl.pem")).expect("utf-8")
+}
+
+/// Returns the first of FOO_NS1_INT's intermediates, cross-signed by ca2,
+/// which has the same subject as ca1.
+pub fn int1_ca2_der() -> Vec<u8> {
+    Strings::read("foo-ns1-int-ca1/int1-ca2.der")
+}
+
+/// Returns the test CT log, which has logged FOO_NS1's and FOO_NS1_SCT's
+/// certificates.
+pub fn ct_logs() -> &'static [&'static sct::Log<'static>] {
+    let spki = Strings::read("ct-log.der");
+    // A P-256 SubjectPublicKeyInfo ends with the uncompressed public key.
+    let key = spki[spki.len() - 65..].to_vec().into_boxed_slice();
+    let mut id = [0; 32];
+    id.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &spki).as_ref());
+
+    let log = Box::new(sct::Log {
+        description: "test log",
+        url: "",
+        operated_by: "",
+        key: Box::leak(key),
+        id,
+        max_merge_delay: 0,
+    });
+    Box::leak(Box::new([&*Box::leak(log)]))
+}
+
 impl Strings {
     fn read(n: &str) -> Vec<u8> {
         let dir = PathBuf::from("src/identity/testdata");
@@ -41,28 +191,144 @@ impl Strings {
         }
     }
 
+    /// Returns the trust anchors, which verify certificates as of
+    /// `valid_time()`, since the test certificates have since expired.
     pub fn trust_anchors(&self) -> TrustAnchors {
         let b = Self::read(&self.trust_anchors);
         let pem = ::std::str::from_utf8(&b).expect("utf-8");
-        TrustAnchors::from_pem(pem).unwrap_or_else(|| TrustAnchors::empty())
+        TrustAnchors::from_pem(pem)
+            .unwrap_or_else(|_| TrustAnchors::empty())
+            .with_clock(FixedClock(valid_time()))
+    }
+
+    pub fn trust_anchors_pem(&self) -> String {
+        String::from_utf8(Self::read(&self.trust_anchors)).expect("utf-8")
+    }
+
+    /// Returns the DER encoding of each of the trust anchors.
+    pub fn trust_anchors_der(&self) -> Vec<Vec<u8>> {
+        let pem = Self::read(&self.trust_anchors);
+        rustls::internal::pemfile::certs(&mut &pem[..])
+            .expect("trust anchors must be valid PEM")
+            .into_iter()
+            .map(|c| c.0)
+            .collect()
+    }
+
+    fn dir(&self) -> &str {
+        self.key
+            .rsplitn(2, '/')
+            .last()
+            .expect("key must be in a directory")
+    }
+
+    /// Returns a PEM bundle holding the key, the certificate, and the CA.
+    pub fn bundle_pem(&self) -> String {
+        let b = Self::read(&format!("{}/bundle.pem", self.dir()));
+        String::from_utf8(b).expect("utf-8")
+    }
+
+    /// Returns a serialized SCT for the certificate from the test CT log.
+    pub fn sct(&self) -> Vec<u8> {
+        Self::read(&format!("{}/sct.bin", self.dir()))
+    }
+
+    /// Returns one of the OCSP responses for the certificate, which are
+    /// described in gen-certs.sh.
+    pub fn ocsp(&self, name: &str) -> Vec<u8> {
+        Self::read(&format!("{}/ocsp-{}.der", self.dir(), name))
+    }
+
+    pub fn key_pkcs8(&self) -> Vec<u8> {
+        Self::read(&self.key)
+    }
+
+    /// Returns the key as a PBES2-encrypted PKCS#8 document, encrypted with
+    /// `ENCRYPTED_KEY_PASSWORD`.
+    pub fn key_encrypted_pkcs8(&self) -> Vec<u8> {
+        Self::read(&format!("{}/key-encrypted.p8", self.dir()))
     }
 
     pub fn key(&self) -> Key {
-        let p8 = Self::read(&self.key);
-        Key::from_pkcs8(&p8).expect("key must be valid")
+        Key::from_pkcs8(&self.key_pkcs8()).expect("key must be valid")
+    }
+
+    pub fn name(&self) -> Name {
+        Name::from_hostname(self.name.as_bytes()).expect("name must be valid")
+    }
+
+    pub fn crt_der(&self) -> Vec<u8> {
+        Self::read(&self.crt)
+    }
+
+    /// Returns the DER encoding of each intermediate certificate, starting
+    /// with the one closest to the root.
+    pub fn intermediates_der(&self) -> Vec<Vec<u8>> {
+        (1..)
+            .map(|i| PathBuf::from(format!("{}/int{}.der", self.dir(), i)))
+            .take_while(|p| PathBuf::from("src/identity/testdata").join(p).exists())
+            .map(|p| Self::read(p.to_str().expect("path must be UTF-8")))
+            .collect()
     }
 
     pub fn crt(&self) -> Crt {
         const HOUR: Duration = Duration::from_secs(60 * 60);
 
-        let n = Name::from_hostname(self.name.as_bytes()).expect("name must be valid");
-        let der = Self::read(&self.crt);
-        Crt::new(n, der, vec![], SystemTime::now() + HOUR)
+        Crt::new(
+            self.name(),
+            self.crt_der(),
+            vec![],
+            SystemTime::now() + HOUR,
+        )
+        .expect("crt must be valid")
     }
 
     pub fn validate(&self) -> Result<CrtKey, InvalidCrt> {
         let k = self.key();
         let c = self.crt();
-        self.trust_anchors().certify(k, c)
+        self.trust_anchors()
+            .certify_at(k, c, valid_time(), Duration::from_secs(0))
+    }
+}
+
+/// Drives a TLS handshake between a client and a server entirely in memory.
+///
+/// Returns an error as soon as either side fails to process the other's
+/// messages.
+pub fn handshake(
+    client: &Arc<rustls::ClientConfig>,
+    server: &Arc<rustls::ServerConfig>,
+    server_name: &Name,
+) -> Result<(rustls::ClientSession, rustls::ServerSession), rustls::TLSError> {
+    use self::rustls::Session;
+
+    let mut client = rustls::ClientSession::new(client, server_name.as_dns_name_ref());
+    let mut server = rustls::ServerSession::new(server);
+
+    // Bound the number of round trips so that a stuck handshake fails the
+    // test instead of hanging it.
+    for _ in 0..16 {
+        if !client.is_handshaking() && !server.is_handshaking() {
+            return Ok((client, server));
+        }
+        transfer(&mut client, &mut server)?;
+        transfer(&mut server, &mut client)?;
     }
+
+    panic!("handshake did not complete");
+}
+
+fn transfer(from: &mut rustls::Session, to: &mut rustls::Session) -> Result<(), rustls::TLSError> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf).expect("write_tls");
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        to.read_tls(&mut rd).expect("read_tls");
+        to.process_new_packets()?;
+    }
+
+    Ok(())
 }
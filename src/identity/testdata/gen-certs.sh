@@ -3,6 +3,7 @@
 # Requires:
 # go get -u github.com/cloudflare/cfssl/cmd/cfssl
 # go get -u github.com/cloudflare/cfssl/cmd/cfssljson
+# pip install cryptography
 #
 set -euox pipefail
 
@@ -57,3 +58,417 @@ ee ca1 controller linkerd linkerd
 ee ca1 foo ns1 linkerd
 ee ca2 foo ns1 linkerd # Same, but different CA
 ee ca1 bar ns1 linkerd # Different service.
+
+# A single PEM bundle holding a key, its certificate, and the issuing CA.
+{
+  openssl pkey -inform der -in foo-ns1-ca1/key.p8
+  openssl x509 -inform der -in foo-ns1-ca1/crt.der
+  cat ca1.pem
+} > foo-ns1-ca1/bundle.pem
+
+# cfssl can't produce certificates with arbitrary SANs or validity periods, so
+# the remaining certificates are issued with `openssl ca`. Their validity
+# periods fall within that of the corresponding cfssl-issued certificates.
+openssl_ee() {
+  ca_name=$1
+  ee=$2
+  subj=$3
+  ext=$4
+  serial=$5
+  curve=${6:-prime256v1}
+
+  tmp=$(mktemp -d)
+  touch "${tmp}/index.txt"
+  echo "${serial}" > "${tmp}/serial"
+  cat > "${tmp}/ca.cnf" <<CNF
+[ca]
+default_ca = ca_default
+
+[ca_default]
+database = ${tmp}/index.txt
+serial = ${tmp}/serial
+new_certs_dir = ${tmp}
+policy = policy_any
+unique_subject = no
+
+[policy_any]
+commonName = optional
+
+[ext]
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth, clientAuth
+basicConstraints = critical, CA:FALSE
+${ext}
+CNF
+
+  mkdir -p "${ee}"
+  openssl ecparam -name "${curve}" -genkey -noout -out "${tmp}/key.pem"
+  openssl pkcs8 -topk8 -nocrypt -inform pem -outform der \
+    -in "${tmp}/key.pem" \
+    -out "${ee}/key.p8"
+  openssl req -new -key "${tmp}/key.pem" -subj "${subj}" -out "${tmp}/csr.pem"
+  openssl ca -batch -notext -config "${tmp}/ca.cnf" -extensions ext \
+    -cert "${ca_name}.pem" -keyfile "${ca_name}-key.pem" -md sha256 \
+    -startdate 20190314180200Z -enddate 20200313180200Z \
+    -in "${tmp}/csr.pem" -out "${tmp}/crt.pem"
+  openssl x509 -inform pem -outform der \
+    -in "${tmp}/crt.pem" \
+    -out "${ee}/crt.der"
+  rm -r "${tmp}"
+}
+
+# A certificate that is valid for two identities.
+openssl_ee ca1 foo-bar-ns1-ca1 "/" \
+  "subjectAltName = critical, DNS:foo.ns1.serviceaccount.identity.linkerd.cluster.local, DNS:bar.ns1.serviceaccount.identity.linkerd.cluster.local" \
+  1001
+
+# A certificate that identifies foo.ns1 by a SPIFFE ID as well as a DNS name.
+openssl_ee ca1 foo-ns1-spiffe-ca1 "/" \
+  "subjectAltName = critical, DNS:foo.ns1.serviceaccount.identity.linkerd.cluster.local, URI:spiffe://cluster.local/ns/ns1/sa/foo" \
+  1002
+
+# A certificate that is valid for every name in ns1.
+openssl_ee ca1 wildcard-ns1-ca1 "/" \
+  "subjectAltName = critical, DNS:*.ns1.serviceaccount.identity.linkerd.cluster.local" \
+  1003
+
+# A certificate that names foo.ns1 only in its subject's common name. Like the
+# others, it expired in 2020; tests only check it at `valid_time()`.
+openssl_ee ca1 cn-only-ns1-ca1 "/CN=foo.ns1.serviceaccount.identity.linkerd.cluster.local" \
+  "" \
+  1004
+
+# A certificate for foo.ns1 that is issued through two intermediate CAs, so
+# that ca1 -> int1 -> int2 -> foo.ns1. The intermediates are written to
+# `int1.der` and `int2.der`. The whole chain shares the other certificates'
+# validity period, so tests only check it at `valid_time()`.
+openssl_chain() {
+  ca_name=$1
+  ee=$2
+  serial=$3
+
+  tmp=$(mktemp -d)
+  touch "${tmp}/index.txt"
+  echo "${serial}" > "${tmp}/serial"
+  cat > "${tmp}/ca.cnf" <<CNF
+[ca]
+default_ca = ca_default
+
+[ca_default]
+database = ${tmp}/index.txt
+serial = ${tmp}/serial
+new_certs_dir = ${tmp}
+policy = policy_any
+unique_subject = no
+
+[policy_any]
+commonName = optional
+
+[int]
+keyUsage = critical, keyCertSign, cRLSign
+basicConstraints = critical, CA:TRUE
+
+[ext]
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth, clientAuth
+basicConstraints = critical, CA:FALSE
+subjectAltName = critical, DNS:foo.ns1.serviceaccount.identity.linkerd.cluster.local
+CNF
+
+  mkdir -p "${ee}"
+  issuer_crt="${ca_name}.pem"
+  issuer_key="${ca_name}-key.pem"
+  for i in 1 2 3; do
+    if [ "${i}" = 3 ]; then
+      subj="/"
+      ext=ext
+    else
+      subj="/CN=Intermediate CA ${i}"
+      ext=int
+    fi
+
+    openssl ecparam -name prime256v1 -genkey -noout -out "${tmp}/key${i}.pem"
+    openssl req -new -key "${tmp}/key${i}.pem" -subj "${subj}" -out "${tmp}/csr${i}.pem"
+    openssl ca -batch -notext -config "${tmp}/ca.cnf" -extensions "${ext}" \
+      -cert "${issuer_crt}" -keyfile "${issuer_key}" -md sha256 \
+      -startdate 20190314180200Z -enddate 20200313180200Z \
+      -in "${tmp}/csr${i}.pem" -out "${tmp}/crt${i}.pem"
+    issuer_crt="${tmp}/crt${i}.pem"
+    issuer_key="${tmp}/key${i}.pem"
+  done
+
+  openssl x509 -inform pem -outform der -in "${tmp}/crt1.pem" -out "${ee}/int1.der"
+  openssl x509 -inform pem -outform der -in "${tmp}/crt2.pem" -out "${ee}/int2.der"
+  openssl x509 -inform pem -outform der -in "${tmp}/crt3.pem" -out "${ee}/crt.der"
+  openssl pkcs8 -topk8 -nocrypt -inform pem -outform der \
+    -in "${tmp}/key3.pem" \
+    -out "${ee}/key.p8"
+  rm -r "${tmp}"
+}
+
+openssl_chain ca1 foo-ns1-int-ca1 1005
+
+# Issues a certificate with the subject and key of an existing CA certificate,
+# `crt`, from another CA, `ca_name`, as when an intermediate is cross-signed.
+openssl_cross_sign() {
+  ca_name=$1
+  crt=$2
+  out=$3
+  serial=$4
+
+  tmp=$(mktemp -d)
+  cat > "${tmp}/ext.cnf" <<CNF
+[int]
+keyUsage = critical, keyCertSign, cRLSign
+basicConstraints = critical, CA:TRUE
+CNF
+
+  openssl x509 -inform der -in "${crt}" -noout -pubkey > "${tmp}/pub.pem"
+  openssl x509 -new -subj "$(openssl x509 -inform der -in "${crt}" -noout -subject -nameopt compat | sed 's/^subject=//')" \
+    -force_pubkey "${tmp}/pub.pem" -CA "${ca_name}.pem" -CAkey "${ca_name}-key.pem" \
+    -set_serial "${serial}" -sha256 -extfile "${tmp}/ext.cnf" -extensions int \
+    -not_before 20190314180200Z -not_after 20200313180200Z \
+    -outform der -out "${out}"
+  rm -r "${tmp}"
+}
+
+# The first of foo.ns1's intermediates, cross-signed by ca2. Since ca1 and ca2
+# have the same subject, it can only be told apart from `int1.der` by its
+# signature. Like the rest of the chain, it's only valid at `valid_time()`.
+openssl_cross_sign ca2 foo-ns1-int-ca1/int1.der foo-ns1-int-ca1/int1-ca2.der 1006
+
+openssl_crl() {
+  ca_name=$1
+  shift
+
+  tmp=$(mktemp -d)
+  touch "${tmp}/index.txt"
+  cat > "${tmp}/ca.cnf" <<CNF
+[ca]
+default_ca = ca_default
+
+[ca_default]
+database = ${tmp}/index.txt
+default_md = sha256
+default_crl_days = 3650
+CNF
+
+  for ee in "$@"; do
+    openssl x509 -inform der -in "${ee}/crt.der" -out "${tmp}/crt.pem"
+    openssl ca -batch -config "${tmp}/ca.cnf" \
+      -cert "${ca_name}.pem" -keyfile "${ca_name}-key.pem" \
+      -revoke "${tmp}/crt.pem"
+  done
+  openssl ca -batch -gencrl -config "${tmp}/ca.cnf" \
+    -cert "${ca_name}.pem" -keyfile "${ca_name}-key.pem" \
+    -out "${ca_name}-crl.pem"
+  rm -r "${tmp}"
+}
+
+# A revocation list that revokes foo.ns1's certificate.
+openssl_crl ca1 foo-ns1-ca1
+
+openssl_ct_log() {
+  log_name=$1
+
+  openssl ecparam -name prime256v1 -genkey -noout -out "${log_name}-key.pem"
+  openssl pkey -in "${log_name}-key.pem" -pubout -outform der -out "${log_name}.der"
+}
+
+# Writes a v1 signed certificate timestamp for `ee`'s certificate, signed by
+# `log_name`, to `${ee}/sct.bin`.
+openssl_sct() {
+  log_name=$1
+  ee=$2
+  ts_ms=$3
+
+  ts=$(printf '%016x' "${ts_ms}")
+  crt_len=$(printf '%06x' "$(wc -c < "${ee}/crt.der")")
+  tmp=$(mktemp -d)
+
+  # The signed data: version, signature type, timestamp, entry type, the
+  # certificate, and (empty) extensions.
+  {
+    echo "0000${ts}0000${crt_len}" | xxd -r -p
+    cat "${ee}/crt.der"
+    echo "0000" | xxd -r -p
+  } > "${tmp}/signed"
+  openssl dgst -sha256 -sign "${log_name}-key.pem" -out "${tmp}/sig" "${tmp}/signed"
+  sig_len=$(printf '%04x' "$(wc -c < "${tmp}/sig")")
+  log_id=$(openssl dgst -sha256 -binary "${log_name}.der" | xxd -p -c 64)
+
+  # The SCT: version, log ID, timestamp, extensions, and an ECDSA/SHA-256
+  # signature.
+  {
+    echo "00${log_id}${ts}00000403${sig_len}" | xxd -r -p
+    cat "${tmp}/sig"
+  } > "${ee}/sct.bin"
+  rm -r "${tmp}"
+}
+
+# A CT log, and an SCT for foo.ns1's certificate logged 2019-05-01.
+openssl_ct_log ct-log
+openssl_sct ct-log foo-ns1-ca1 1556668800000
+
+# Issues a certificate for foo.ns1, from `ca_name`, with an SCT from `log_name`
+# embedded in it, to `${ee}`. The SCT is issued for the certificate's
+# precertificate, as described in RFC 6962, and was logged at `ts_ms`. The
+# certificate shares the other certificates' validity period.
+embedded_sct_ee() {
+  ca_name=$1
+  log_name=$2
+  ee=$3
+  ts_ms=$4
+
+  mkdir -p "${ee}"
+  python3 - "${ca_name}" "${log_name}" "${ee}" "${ts_ms}" <<'PY'
+import datetime, hashlib, struct, sys
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.x509.oid import ExtendedKeyUsageOID, ObjectIdentifier
+
+ca_name, log_name, ee, ts_ms = sys.argv[1:]
+def load(path):
+    with open(path, "rb") as f:
+        return f.read()
+ca = x509.load_pem_x509_certificate(load(ca_name + ".pem"))
+ca_key = serialization.load_pem_private_key(load(ca_name + "-key.pem"), None)
+log_key = serialization.load_pem_private_key(load(log_name + "-key.pem"), None)
+log_spki = load(log_name + ".der")
+
+key = ec.generate_private_key(ec.SECP256R1())
+name = "foo.ns1.serviceaccount.identity.linkerd.cluster.local"
+builder = (
+    x509.CertificateBuilder()
+    .subject_name(x509.Name([]))
+    .issuer_name(ca.subject)
+    .public_key(key.public_key())
+    .serial_number(1008)
+    .not_valid_before(datetime.datetime(2019, 3, 14, 18, 2))
+    .not_valid_after(datetime.datetime(2020, 3, 13, 18, 2))
+    .add_extension(x509.KeyUsage(True, False, True, False, False, False, False, False, False), critical=True)
+    .add_extension(x509.ExtendedKeyUsage([ExtendedKeyUsageOID.SERVER_AUTH, ExtendedKeyUsageOID.CLIENT_AUTH]), critical=False)
+    .add_extension(x509.BasicConstraints(ca=False, path_length=None), critical=True)
+    .add_extension(x509.SubjectAlternativeName([x509.DNSName(name)]), critical=True)
+)
+
+# The precertificate's TBSCertificate is the certificate's, without the SCT
+# list extension, which is added last.
+tbs = builder.sign(ca_key, hashes.SHA256()).tbs_certificate_bytes
+issuer_key_hash = hashlib.sha256(
+    ca.public_key().public_bytes(
+        serialization.Encoding.DER, serialization.PublicFormat.SubjectPublicKeyInfo
+    )
+).digest()
+ts = struct.pack(">Q", int(ts_ms))
+signed = (
+    b"\x00\x00" + ts + b"\x00\x01" + issuer_key_hash
+    + struct.pack(">I", len(tbs))[1:] + tbs + b"\x00\x00"
+)
+sig = log_key.sign(signed, ec.ECDSA(hashes.SHA256()))
+sct = (
+    b"\x00" + hashlib.sha256(log_spki).digest() + ts + b"\x00\x00"
+    + b"\x04\x03" + struct.pack(">H", len(sig)) + sig
+)
+scts = struct.pack(">H", len(sct)) + sct
+scts = struct.pack(">H", len(scts)) + scts
+def der_octet_string(b):
+    assert len(b) < 0x80
+    return b"\x04" + bytes([len(b)]) + b
+
+crt = builder.add_extension(
+    x509.UnrecognizedExtension(ObjectIdentifier("1.3.6.1.4.1.11129.2.4.2"), der_octet_string(scts)),
+    critical=False,
+).sign(ca_key, hashes.SHA256())
+with open(ee + "/crt.der", "wb") as f:
+    f.write(crt.public_bytes(serialization.Encoding.DER))
+with open(ee + "/key.p8", "wb") as f:
+    f.write(key.private_bytes(
+        serialization.Encoding.DER, serialization.PrivateFormat.PKCS8, serialization.NoEncryption()
+    ))
+PY
+}
+
+# A certificate for foo.ns1 with an SCT from the test log, logged 2019-05-01,
+# embedded in it.
+embedded_sct_ee ca1 ct-log foo-ns1-sct-ca1 1556668800000
+
+# Writes OCSP responses for `ee`'s certificate, issued by `ca_name`, to
+# `${ee}/ocsp-*.der`. openssl can't backdate responses, so they're built with
+# Python's cryptography package. Each response is current from 2019-05-01
+# until 2019-08-01, which includes `valid_time()`:
+#
+# - `ocsp-good.der` reports that the certificate is good;
+# - `ocsp-revoked.der` reports that it has been revoked;
+# - `ocsp-delegated.der` is signed by a responder that the CA issued for
+#   OCSP signing, which is included in the response; and
+# - `ocsp-ca2.der` is signed by ca2, which has the same subject as ca1.
+ocsp_responses() {
+  ca_name=$1
+  ee=$2
+
+  python3 - "${ca_name}" "${ee}" <<'PY'
+import datetime, sys
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.x509 import ocsp
+from cryptography.x509.oid import ExtendedKeyUsageOID
+
+ca_name, ee = sys.argv[1:]
+def load(path):
+    with open(path, "rb") as f:
+        return f.read()
+ca = x509.load_pem_x509_certificate(load(ca_name + ".pem"))
+ca_key = serialization.load_pem_private_key(load(ca_name + "-key.pem"), None)
+ca2 = x509.load_pem_x509_certificate(load("ca2.pem"))
+ca2_key = serialization.load_pem_private_key(load("ca2-key.pem"), None)
+crt = x509.load_der_x509_certificate(load(ee + "/crt.der"))
+
+this_update = datetime.datetime(2019, 5, 1)
+next_update = datetime.datetime(2019, 8, 1)
+
+responder_key = ec.generate_private_key(ec.SECP256R1())
+responder = (
+    x509.CertificateBuilder()
+    .subject_name(x509.Name([x509.NameAttribute(x509.oid.NameOID.COMMON_NAME, "OCSP responder")]))
+    .issuer_name(ca.subject)
+    .public_key(responder_key.public_key())
+    .serial_number(1007)
+    .not_valid_before(datetime.datetime(2019, 3, 14, 18, 2))
+    .not_valid_after(datetime.datetime(2020, 3, 13, 18, 2))
+    .add_extension(x509.ExtendedKeyUsage([ExtendedKeyUsageOID.OCSP_SIGNING]), critical=False)
+    .sign(ca_key, hashes.SHA256())
+)
+
+def response(name, status, key, signer, digest=hashes.SHA1(), certs=()):
+    revoked = status == ocsp.OCSPCertStatus.REVOKED
+    builder = (
+        ocsp.OCSPResponseBuilder()
+        .add_response(
+            crt, ca, digest, status, this_update, next_update,
+            this_update if revoked else None, None,
+        )
+        .responder_id(ocsp.OCSPResponderEncoding.HASH, signer)
+    )
+    if certs:
+        builder = builder.certificates(list(certs))
+    der = builder.sign(key, hashes.SHA256()).public_bytes(serialization.Encoding.DER)
+    with open("{}/ocsp-{}.der".format(ee, name), "wb") as f:
+        f.write(der)
+
+response("good", ocsp.OCSPCertStatus.GOOD, ca_key, ca)
+response("revoked", ocsp.OCSPCertStatus.REVOKED, ca_key, ca)
+response("delegated", ocsp.OCSPCertStatus.GOOD, responder_key, responder,
+         digest=hashes.SHA256(), certs=[responder])
+response("ca2", ocsp.OCSPCertStatus.GOOD, ca2_key, ca2)
+PY
+}
+
+ocsp_responses ca1 foo-ns1-ca1
+
+# foo.ns1's key, encrypted with the password "hunter2".
+openssl pkcs8 -topk8 -inform der -in foo-ns1-ca1/key.p8 \
+  -v2 aes-256-cbc -v2prf hmacWithSHA256 -iter 2048 \
+  -passout pass:hunter2 -outform der -out foo-ns1-ca1/key-encrypted.p8
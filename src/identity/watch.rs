@@ -0,0 +1,150 @@
+use futures::{Async, Poll, Stream};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_timer::{clock, Interval};
+
+use never::Never;
+
+use super::{TrustAnchors, TrustAnchorsError};
+
+/// A stream of `TrustAnchors` loaded from a PEM file.
+///
+/// The file is re-read on each interval. Reading the path anew each time
+/// means that updates that replace the file by renaming another file over
+/// it---as Kubernetes does when updating mounted secrets---are observed just
+/// like in-place writes. A new `TrustAnchors` is only emitted when the
+/// file's contents have changed.
+///
+/// Files that can't be read or that don't contain valid trust anchors are
+/// logged and skipped, so that the most recently emitted anchors remain in
+/// use until the file is fixed.
+#[derive(Debug)]
+pub struct WatchFile {
+    path: PathBuf,
+    interval: Interval,
+    contents: Option<Vec<u8>>,
+}
+
+// === impl WatchFile ===
+
+impl WatchFile {
+    pub(super) fn new(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval: Interval::new(clock::now(), interval),
+            contents: None,
+        }
+    }
+
+    /// Reads the file, returning new trust anchors if its contents have
+    /// changed since they were last loaded.
+    fn reload(&mut self) -> Option<TrustAnchors> {
+        let contents = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("failed to read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        if self.contents.as_ref() == Some(&contents) {
+            trace!("{} is unchanged", self.path.display());
+            return None;
+        }
+
+        let anchors = ::std::str::from_utf8(&contents)
+            .map_err(|_| TrustAnchorsError::Parse)
+            .and_then(TrustAnchors::from_pem);
+        match anchors {
+            Ok(anchors) => {
+                debug!("loaded trust anchors from {}", self.path.display());
+                self.contents = Some(contents);
+                Some(anchors)
+            }
+            Err(e) => {
+                warn!(
+                    "{} does not contain valid trust anchors: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Stream for WatchFile {
+    type Item = TrustAnchors;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Option<TrustAnchors>, Never> {
+        loop {
+            match self.interval.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(_))) => {}
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Err(e) => {
+                    error!("trust anchors watch timer failed: {}", e);
+                    return Ok(Async::Ready(None));
+                }
+            }
+
+            if let Some(anchors) = self.reload() {
+                return Ok(Async::Ready(Some(anchors)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::*;
+    use std::{env, process};
+
+    fn tmp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("linkerd2-proxy-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn reloads_on_rename_but_not_identical_rewrite() {
+        let path = tmp_path("watch-trust-anchors.pem");
+        let staged = tmp_path("watch-trust-anchors.pem.tmp");
+        fs::write(&path, FOO_NS1.trust_anchors_pem()).unwrap();
+
+        let mut watch = WatchFile::new(path.clone(), Duration::from_secs(1));
+        assert!(watch.reload().is_some(), "initial anchors must be loaded");
+
+        fs::write(&path, FOO_NS1.trust_anchors_pem()).unwrap();
+        assert!(
+            watch.reload().is_none(),
+            "an identical rewrite must not be emitted"
+        );
+
+        fs::write(&staged, FOO_NS1_CA2.trust_anchors_pem()).unwrap();
+        fs::rename(&staged, &path).unwrap();
+        assert!(watch.reload().is_some(), "a renamed update must be emitted");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_invalid_anchors() {
+        let path = tmp_path("watch-invalid-trust-anchors.pem");
+        fs::write(&path, FOO_NS1.trust_anchors_pem()).unwrap();
+
+        let mut watch = WatchFile::new(path.clone(), Duration::from_secs(1));
+        assert!(watch.reload().is_some(), "initial anchors must be loaded");
+
+        fs::write(&path, "not a certificate").unwrap();
+        assert!(watch.reload().is_none(), "invalid anchors must be skipped");
+
+        fs::write(&path, FOO_NS1.trust_anchors_pem()).unwrap();
+        assert!(
+            watch.reload().is_none(),
+            "the restored anchors are unchanged from those last emitted"
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1218 @@
+//! A minimal reader for the fields of DER-encoded X.509 certificates,
+//! certificate revocation lists, and encrypted PKCS#8 keys.
+//!
+//! webpki validates certificates but exposes very little of their contents,
+//! so the handful of fields that the proxy inspects directly are read here.
+//! Only the structure of a certificate is checked; callers must still verify
+//! the certificate with webpki before trusting anything read from it.
+
+use super::{ring, untrusted, webpki};
+use indexmap::IndexSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::webpki::Error;
+
+const SEQUENCE: u8 = 0x30;
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OID: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const IA5_STRING: u8 = 0x16;
+const SET: u8 = 0x31;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Short names for the attribute types of `Name`s, from RFC 4514.
+static ATTRIBUTE_TYPES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+];
+
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+/// The embedded SCT list extension, from RFC 6962.
+const SCT_LIST: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+/// The contents of the `successful` `OCSPResponseStatus`.
+const OCSP_SUCCESSFUL: &[u8] = &[0x00];
+const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+const PBES2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d];
+const PBKDF2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0c];
+const HMAC_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x07];
+const HMAC_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09];
+const AES_128_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02];
+const AES_256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+
+/// The algorithms that CRL and OCSP signatures may be verified with, by the
+/// named curve of the signer's key and the signature algorithm.
+static ECDSA_SIGNATURE_ALGS: &[(&[u8], &[u8], &ring::signature::EcdsaVerificationAlgorithm)] = &[
+    (
+        SECP256R1,
+        ECDSA_WITH_SHA256,
+        &ring::signature::ECDSA_P256_SHA256_ASN1,
+    ),
+    (
+        SECP256R1,
+        ECDSA_WITH_SHA384,
+        &ring::signature::ECDSA_P256_SHA384_ASN1,
+    ),
+    (
+        SECP384R1,
+        ECDSA_WITH_SHA256,
+        &ring::signature::ECDSA_P384_SHA256_ASN1,
+    ),
+    (
+        SECP384R1,
+        ECDSA_WITH_SHA384,
+        &ring::signature::ECDSA_P384_SHA384_ASN1,
+    ),
+];
+
+/// `[0] EXPLICIT`, as used for the certificate version, CRL extensions, and
+/// the parts of OCSP responses that are tagged `[0]`.
+const CONTEXT_0: u8 = 0xa0;
+/// `[0] IMPLICIT`, as used for OCSP's `good` status.
+const CONTEXT_0_PRIMITIVE: u8 = 0x80;
+/// `[1] EXPLICIT`, as used for OCSP extensions and the `revoked` status.
+const CONTEXT_1: u8 = 0xa1;
+/// `[1] IMPLICIT`, as used for the issuer unique ID.
+const CONTEXT_1_PRIMITIVE: u8 = 0x81;
+/// `[2] IMPLICIT`, as used for the subject unique ID, DNS subject
+/// alternative names, and OCSP's `unknown` status.
+const CONTEXT_2_PRIMITIVE: u8 = 0x82;
+/// `[3] EXPLICIT`, as used for extensions.
+const CONTEXT_3: u8 = 0xa3;
+/// `[6] IMPLICIT`, as used for URI subject alternative names.
+const CONTEXT_6_PRIMITIVE: u8 = 0x86;
+
+/// The fields of a certificate's `TBSCertificate` that the proxy uses.
+#[derive(Clone, Debug)]
+pub(super) struct Tbs<'a> {
+    /// The contents of the serial number, as encoded.
+    pub serial: &'a [u8],
+    /// The contents of the issuer's `Name`.
+    pub issuer: &'a [u8],
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    /// The contents of the subject's `Name`.
+    pub subject: &'a [u8],
+    /// The contents of the subject's `SubjectPublicKeyInfo`.
+    pub spki: &'a [u8],
+    /// The DNS names in the subject alternative names extension, if any.
+    pub dns_sans: Vec<&'a str>,
+    /// The URIs in the subject alternative names extension, if any.
+    pub uri_sans: Vec<&'a str>,
+    /// Whether the extended key usage extension permits OCSP signing.
+    pub ocsp_signing: bool,
+    /// The TLS-encoded `SignedCertificateTimestampList` embedded in the
+    /// certificate, if any.
+    pub sct_list: Option<&'a [u8]>,
+}
+
+/// The contents of a PBES2-encrypted PKCS#8 key, as described in RFC 8018.
+#[derive(Debug)]
+pub(super) struct EncryptedKey<'a> {
+    pub salt: &'a [u8],
+    pub iterations: u32,
+    /// The digest of the HMAC that PBKDF2 uses.
+    pub prf: &'static ring::digest::Algorithm,
+    pub cipher: Pbes2Cipher,
+    pub iv: &'a [u8],
+    pub data: &'a [u8],
+}
+
+/// The encryption schemes supported for PBES2-encrypted keys.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum Pbes2Cipher {
+    Aes128Cbc,
+    Aes256Cbc,
+}
+
+/// The named curves of the keys that the proxy signs with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum Curve {
+    P256,
+    P384,
+}
+
+/// The status that an OCSP response reports for a certificate.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) enum OcspStatus {
+    /// The certificate had not been revoked as of `this_update`. Newer
+    /// information is available from `next_update`, if it is set.
+    Good {
+        this_update: SystemTime,
+        next_update: Option<SystemTime>,
+    },
+
+    Revoked,
+
+    /// The responder doesn't know the certificate's status, or the response
+    /// doesn't include it.
+    Unknown,
+}
+
+/// One of the statuses in an OCSP response.
+struct SingleResponse<'a> {
+    /// The OID of the digest algorithm of the issuer name and key hashes.
+    hash_alg: &'a [u8],
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial: &'a [u8],
+    status: OcspStatus,
+}
+
+/// A certificate revocation list, as described in RFC 5280.
+///
+/// Only the list of revoked certificates is used; the CRL's update times,
+/// its extensions, and those of its entries are ignored. In particular,
+/// delta CRLs are treated as complete CRLs.
+#[derive(Clone, Debug)]
+pub(super) struct Crl {
+    issuer: Vec<u8>,
+    revoked: IndexSet<Vec<u8>>,
+}
+
+impl<'a> Tbs<'a> {
+    /// Returns the DER encoding of the subject's `SubjectPublicKeyInfo`.
+    pub fn spki_der(&self) -> Result<Vec<u8>, Error> {
+        spki_der(self.spki)
+    }
+}
+
+/// Returns the DER encoding of a `SubjectPublicKeyInfo` from its contents.
+pub(super) fn spki_der(spki: &[u8]) -> Result<Vec<u8>, Error> {
+    write_tlv(SEQUENCE, spki)
+}
+
+/// Reads the `TBSCertificate` of a DER-encoded certificate.
+pub(super) fn parse(der: &[u8]) -> Result<Tbs, Error> {
+    #[cfg(test)]
+    super::test_util::count_crt_parse();
+
+    untrusted::Input::from(der).read_all(Error::BadDER, |crt| {
+        expect(crt, SEQUENCE)?.read_all(Error::BadDER, |crt| {
+            let tbs = expect(crt, SEQUENCE)?;
+            let _signature_algorithm = expect(crt, SEQUENCE)?;
+            let _signature = expect(crt, BIT_STRING)?;
+            tbs.read_all(Error::BadDER, read_tbs)
+        })
+    })
+}
+
+fn read_tbs<'a>(tbs: &mut untrusted::Reader<'a>) -> Result<Tbs<'a>, Error> {
+    let _version = optional(tbs, CONTEXT_0)?;
+    let serial = expect(tbs, INTEGER)?.as_slice_less_safe();
+    let _signature = expect(tbs, SEQUENCE)?;
+    let issuer = expect(tbs, SEQUENCE)?.as_slice_less_safe();
+    let (not_before, not_after) = expect(tbs, SEQUENCE)?.read_all(Error::BadDER, |v| {
+        let not_before = read_time(v)?;
+        let not_after = read_time(v)?;
+        Ok((not_before, not_after))
+    })?;
+    let subject = expect(tbs, SEQUENCE)?.as_slice_less_safe();
+    let spki = expect(tbs, SEQUENCE)?.as_slice_less_safe();
+    let _issuer_unique_id = optional(tbs, CONTEXT_1_PRIMITIVE)?;
+    let _subject_unique_id = optional(tbs, CONTEXT_2_PRIMITIVE)?;
+
+    let mut dns_sans = Vec::new();
+    let mut uri_sans = Vec::new();
+    let mut ocsp_signing = false;
+    let mut sct_list = None;
+    if let Some(extensions) = optional(tbs, CONTEXT_3)? {
+        read_extensions(extensions, |id, value| {
+            if id == SUBJECT_ALT_NAME {
+                read_sans(value, &mut dns_sans, &mut uri_sans)?;
+            } else if id == EXTENDED_KEY_USAGE {
+                ocsp_signing = permits_ocsp_signing(value)?;
+            } else if id == SCT_LIST {
+                let list = value.read_all(Error::BadDER, |v| expect(v, OCTET_STRING))?;
+                sct_list = Some(list.as_slice_less_safe());
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(Tbs {
+        serial,
+        issuer,
+        not_before,
+        not_after,
+        subject,
+        spki,
+        dns_sans,
+        uri_sans,
+        ocsp_signing,
+        sct_list,
+    })
+}
+
+/// Returns the DER encoding of the `TBSCertificate` of the precertificate
+/// that a DER-encoded certificate's embedded SCTs were issued for.
+///
+/// As described in RFC 6962, this is the certificate's `TBSCertificate`
+/// without the embedded SCT list extension. The other fields are copied as
+/// they were encoded.
+pub(super) fn precert_tbs(der: &[u8]) -> Result<Vec<u8>, Error> {
+    let (tbs, _, _) = read_signed_crt(untrusted::Input::from(der))?;
+    let fields = tbs.read_all(Error::BadDER, |tbs| {
+        expect(tbs, SEQUENCE)?.read_all(Error::BadDER, |fields| {
+            let mut out = Vec::new();
+            while !fields.at_end() {
+                let (field, (tag, value)) = fields.read_partial(read_tlv)?;
+                if tag != CONTEXT_3 {
+                    out.extend_from_slice(field.as_slice_less_safe());
+                    continue;
+                }
+
+                let exts = value.read_all(Error::BadDER, |v| {
+                    expect(v, SEQUENCE)?.read_all(Error::BadDER, |exts| {
+                        let mut out = Vec::new();
+                        while !exts.at_end() {
+                            let (ext, contents) = exts.read_partial(|e| expect(e, SEQUENCE))?;
+                            let id = contents.read_all(Error::BadDER, |c| {
+                                let id = expect(c, OID)?;
+                                c.skip_to_end();
+                                Ok(id)
+                            })?;
+                            if id.as_slice_less_safe() != SCT_LIST {
+                                out.extend_from_slice(ext.as_slice_less_safe());
+                            }
+                        }
+                        Ok(out)
+                    })
+                })?;
+                // Extensions, if present, must not be empty.
+                if !exts.is_empty() {
+                    out.extend(write_tlv(CONTEXT_3, &write_tlv(SEQUENCE, &exts)?)?);
+                }
+            }
+            Ok(out)
+        })
+    })?;
+    write_tlv(SEQUENCE, &fields)
+}
+
+/// Calls `f` with the ID and value of each of `extensions`.
+fn read_extensions<'a, F>(extensions: untrusted::Input<'a>, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(&'a [u8], untrusted::Input<'a>) -> Result<(), Error>,
+{
+    extensions.read_all(Error::BadDER, |exts| {
+        expect(exts, SEQUENCE)?.read_all(Error::BadDER, |exts| {
+            while !exts.at_end() {
+                let (id, value) = expect(exts, SEQUENCE)?.read_all(Error::BadDER, |ext| {
+                    let id = expect(ext, OID)?.as_slice_less_safe();
+                    let _critical = optional(ext, BOOLEAN)?;
+                    let value = expect(ext, OCTET_STRING)?;
+                    Ok((id, value))
+                })?;
+                f(id, value)?;
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Reads the DNS names and URIs, respectively, from the value of a subject
+/// alternative names extension.
+///
+/// Other kinds of names are skipped.
+fn read_sans<'a>(
+    value: untrusted::Input<'a>,
+    dns_names: &mut Vec<&'a str>,
+    uris: &mut Vec<&'a str>,
+) -> Result<(), Error> {
+    value.read_all(Error::BadDER, |v| {
+        expect(v, SEQUENCE)?.read_all(Error::BadDER, |names| {
+            while !names.at_end() {
+                let (tag, name) = read_tlv(names)?;
+                let sans = match tag {
+                    CONTEXT_2_PRIMITIVE => &mut *dns_names,
+                    CONTEXT_6_PRIMITIVE => &mut *uris,
+                    _ => continue,
+                };
+                let name =
+                    ::std::str::from_utf8(name.as_slice_less_safe()).map_err(|_| Error::BadDER)?;
+                sans.push(name);
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Returns true if the value of an extended key usage extension includes
+/// OCSP signing.
+fn permits_ocsp_signing(value: untrusted::Input) -> Result<bool, Error> {
+    value.read_all(Error::BadDER, |v| {
+        expect(v, SEQUENCE)?.read_all(Error::BadDER, |usages| {
+            let mut permitted = false;
+            while !usages.at_end() {
+                permitted |= expect(usages, OID)?.as_slice_less_safe() == OCSP_SIGNING;
+            }
+            Ok(permitted)
+        })
+    })
+}
+
+/// Reads a DER-encoded certificate's `TBSCertificate`, as encoded, with the
+/// algorithm and value of the issuer's signature over it.
+fn read_signed_crt<'a>(
+    der: untrusted::Input<'a>,
+) -> Result<
+    (
+        untrusted::Input<'a>,
+        untrusted::Input<'a>,
+        untrusted::Input<'a>,
+    ),
+    Error,
+> {
+    der.read_all(Error::BadDER, |crt| {
+        expect(crt, SEQUENCE)?.read_all(Error::BadDER, |crt| {
+            let (tbs, _) = crt.read_partial(|c| expect(c, SEQUENCE))?;
+            let signature_alg = expect(crt, SEQUENCE)?;
+            let signature = read_bit_string(crt)?;
+            Ok((tbs, signature_alg, signature))
+        })
+    })
+}
+
+// === OCSP ===
+
+/// Reads a DER-encoded `OCSPResponse`, as described in RFC 6960, returning
+/// the status that it reports for `crt`.
+///
+/// `issuers` holds the contents of the subject and `SubjectPublicKeyInfo` of
+/// each certificate that may have issued `crt`. The response must be signed
+/// by `crt`'s issuer, or by a responder, valid as of `now`, that the issuer
+/// authorized to sign OCSP responses. As with CRLs, only ECDSA signatures are
+/// supported. The response's times are returned rather than checked.
+pub(super) fn read_ocsp(
+    der: &[u8],
+    crt: &Tbs,
+    issuers: &[(&[u8], &[u8])],
+    now: SystemTime,
+) -> Result<OcspStatus, Error> {
+    let basic = untrusted::Input::from(der).read_all(Error::BadDER, |resp| {
+        expect(resp, SEQUENCE)?.read_all(Error::BadDER, |resp| {
+            // Unsuccessful responses, e.g. `tryLater`, don't report a status.
+            if expect(resp, ENUMERATED)?.as_slice_less_safe() != OCSP_SUCCESSFUL {
+                let _ = resp.skip_to_end();
+                return Ok(None);
+            }
+            expect(resp, CONTEXT_0)?.read_all(Error::BadDER, |bytes| {
+                expect(bytes, SEQUENCE)?.read_all(Error::BadDER, |bytes| {
+                    if expect(bytes, OID)?.as_slice_less_safe() != OCSP_BASIC {
+                        return Err(Error::BadDER);
+                    }
+                    expect(bytes, OCTET_STRING).map(Some)
+                })
+            })
+        })
+    })?;
+    let basic = match basic {
+        Some(basic) => basic,
+        None => return Ok(OcspStatus::Unknown),
+    };
+
+    let (tbs, signature_alg, signature, responders) = basic.read_all(Error::BadDER, |basic| {
+        expect(basic, SEQUENCE)?.read_all(Error::BadDER, |basic| {
+            let (tbs, _) = basic.read_partial(|b| expect(b, SEQUENCE))?;
+            let signature_alg = expect(basic, SEQUENCE)?;
+            let signature = read_bit_string(basic)?;
+            let mut responders = Vec::new();
+            if let Some(crts) = optional(basic, CONTEXT_0)? {
+                crts.read_all(Error::BadDER, |crts| {
+                    expect(crts, SEQUENCE)?.read_all(Error::BadDER, |crts| {
+                        while !crts.at_end() {
+                            let (crt, _) = crts.read_partial(|c| expect(c, SEQUENCE))?;
+                            responders.push(crt);
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            Ok((tbs, signature_alg, signature, responders))
+        })
+    })?;
+
+    let responses = tbs.read_all(Error::BadDER, |tbs| {
+        expect(tbs, SEQUENCE)?.read_all(Error::BadDER, |tbs| {
+            let _version = optional(tbs, CONTEXT_0)?;
+            let _responder_id = read_tlv(tbs)?;
+            let _produced_at = read_time(tbs)?;
+            let responses = expect(tbs, SEQUENCE)?;
+            let _extensions = optional(tbs, CONTEXT_1)?;
+            Ok(responses)
+        })
+    })?;
+    let mut found = None;
+    responses.read_all(Error::BadDER, |responses| {
+        while !responses.at_end() {
+            let single =
+                expect(responses, SEQUENCE)?.read_all(Error::BadDER, read_single_response)?;
+            if found.is_none() {
+                found = issuers
+                    .iter()
+                    .find(|&&issuer| single.is_for(crt, issuer))
+                    .map(|&issuer| (single.status, issuer));
+            }
+        }
+        Ok(())
+    })?;
+    let (status, (issuer_subject, issuer_spki)) = match found {
+        Some(found) => found,
+        None => return Ok(OcspStatus::Unknown),
+    };
+
+    if verify_signature(issuer_spki, signature_alg, tbs, signature).is_ok() {
+        return Ok(status);
+    }
+    for der in responders {
+        let responder = parse(der.as_slice_less_safe())?;
+        if responder.issuer != issuer_subject
+            || !responder.ocsp_signing
+            || responder.not_before > now
+            || responder.not_after < now
+        {
+            continue;
+        }
+        let (crt_tbs, crt_signature_alg, crt_signature) = read_signed_crt(der)?;
+        let signed = verify_signature(issuer_spki, crt_signature_alg, crt_tbs, crt_signature)
+            .and_then(|()| verify_signature(responder.spki, signature_alg, tbs, signature));
+        if signed.is_ok() {
+            return Ok(status);
+        }
+    }
+    Err(Error::InvalidSignatureForPublicKey)
+}
+
+/// Reads the contents of a `SingleResponse`.
+fn read_single_response<'a>(r: &mut untrusted::Reader<'a>) -> Result<SingleResponse<'a>, Error> {
+    let (hash_alg, issuer_name_hash, issuer_key_hash, serial) =
+        expect(r, SEQUENCE)?.read_all(Error::BadDER, |id| {
+            let hash_alg = expect(id, SEQUENCE)?.read_all(Error::BadDER, |alg| {
+                let oid = expect(alg, OID)?.as_slice_less_safe();
+                let _null = optional(alg, NULL)?;
+                Ok(oid)
+            })?;
+            let name_hash = expect(id, OCTET_STRING)?.as_slice_less_safe();
+            let key_hash = expect(id, OCTET_STRING)?.as_slice_less_safe();
+            let serial = expect(id, INTEGER)?.as_slice_less_safe();
+            Ok((hash_alg, name_hash, key_hash, serial))
+        })?;
+    let (status, _) = read_tlv(r)?;
+    let this_update = read_time(r)?;
+    let next_update = match optional(r, CONTEXT_0)? {
+        Some(t) => Some(t.read_all(Error::BadDERTime, read_time)?),
+        None => None,
+    };
+    let _extensions = optional(r, CONTEXT_1)?;
+
+    let status = match status {
+        CONTEXT_0_PRIMITIVE => OcspStatus::Good {
+            this_update,
+            next_update,
+        },
+        CONTEXT_1 => OcspStatus::Revoked,
+        CONTEXT_2_PRIMITIVE => OcspStatus::Unknown,
+        _ => return Err(Error::BadDER),
+    };
+    Ok(SingleResponse {
+        hash_alg,
+        issuer_name_hash,
+        issuer_key_hash,
+        serial,
+        status,
+    })
+}
+
+impl<'a> SingleResponse<'a> {
+    /// Returns true if this is the status of `crt`, issued by `issuer`, the
+    /// contents of a subject and `SubjectPublicKeyInfo`.
+    fn is_for(&self, crt: &Tbs, issuer: (&[u8], &[u8])) -> bool {
+        let (subject, spki) = issuer;
+        if self.serial != crt.serial || subject != crt.issuer {
+            return false;
+        }
+
+        let digest = if self.hash_alg == SHA1 {
+            &ring::digest::SHA1
+        } else if self.hash_alg == SHA256 {
+            &ring::digest::SHA256
+        } else {
+            return false;
+        };
+        // The key hash covers the bits of the issuer's public key.
+        let key = untrusted::Input::from(spki).read_all(Error::BadDER, |spki| {
+            let _alg = expect(spki, SEQUENCE)?;
+            read_bit_string(spki)
+        });
+        let key = match key {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let name = match write_tlv(SEQUENCE, subject) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let name_hash = ring::digest::digest(digest, &name);
+        let key_hash = ring::digest::digest(digest, key.as_slice_less_safe());
+        name_hash.as_ref() == self.issuer_name_hash && key_hash.as_ref() == self.issuer_key_hash
+    }
+}
+
+// === impl Crl ===
+
+impl Crl {
+    /// Reads a DER-encoded CRL, which must be signed by one of `anchors`.
+    pub(super) fn from_der(der: &[u8], anchors: &[webpki::TrustAnchor]) -> Result<Self, Error> {
+        let (tbs, signature_alg, signature) =
+            untrusted::Input::from(der).read_all(Error::BadDER, |crl| {
+                expect(crl, SEQUENCE)?.read_all(Error::BadDER, |crl| {
+                    let (tbs, _) = crl.read_partial(|c| expect(c, SEQUENCE))?;
+                    let signature_alg = expect(crl, SEQUENCE)?;
+                    let signature = read_bit_string(crl)?;
+                    Ok((tbs, signature_alg, signature))
+                })
+            })?;
+
+        let (issuer, revoked) = tbs.read_all(Error::BadDER, |tbs| {
+            expect(tbs, SEQUENCE)?.read_all(Error::BadDER, read_tbs_crl)
+        })?;
+
+        let mut verified = Err(Error::UnknownIssuer);
+        for anchor in anchors.iter().filter(|a| a.subject == issuer) {
+            verified = verify_signature(anchor.spki, signature_alg, tbs, signature);
+            if verified.is_ok() {
+                break;
+            }
+        }
+        verified?;
+
+        Ok(Crl {
+            issuer: issuer.to_vec(),
+            revoked,
+        })
+    }
+
+    /// Returns true if the certificate is revoked by this CRL.
+    pub(super) fn revokes(&self, crt: &Tbs) -> bool {
+        crt.issuer == &self.issuer[..] && self.revoked.contains(crt.serial)
+    }
+}
+
+/// Reads the contents of a `TBSCertList`, returning the contents of its
+/// issuer and the serial numbers of the certificates it revokes.
+fn read_tbs_crl<'a>(
+    tbs: &mut untrusted::Reader<'a>,
+) -> Result<(&'a [u8], IndexSet<Vec<u8>>), Error> {
+    let _version = optional(tbs, INTEGER)?;
+    let _signature = expect(tbs, SEQUENCE)?;
+    let issuer = expect(tbs, SEQUENCE)?.as_slice_less_safe();
+    let _this_update = read_time(tbs)?;
+    if tbs.peek(UTC_TIME) || tbs.peek(GENERALIZED_TIME) {
+        let _next_update = read_time(tbs)?;
+    }
+
+    let mut revoked = IndexSet::new();
+    if let Some(entries) = optional(tbs, SEQUENCE)? {
+        entries.read_all(Error::BadDER, |entries| {
+            while !entries.at_end() {
+                let serial = expect(entries, SEQUENCE)?.read_all(Error::BadDER, |entry| {
+                    let serial = expect(entry, INTEGER)?;
+                    let _revocation_date = read_time(entry)?;
+                    let _extensions = optional(entry, SEQUENCE)?;
+                    Ok(serial)
+                })?;
+                revoked.insert(serial.as_slice_less_safe().to_vec());
+            }
+            Ok(())
+        })?;
+    }
+    let _extensions = optional(tbs, CONTEXT_0)?;
+
+    Ok((issuer, revoked))
+}
+
+/// Verifies an ECDSA signature over `msg` by the key in `spki`, the contents
+/// of a `SubjectPublicKeyInfo`.
+fn verify_signature(
+    spki: &[u8],
+    signature_alg: untrusted::Input,
+    msg: untrusted::Input,
+    signature: untrusted::Input,
+) -> Result<(), Error> {
+    let (curve, key) = untrusted::Input::from(spki).read_all(Error::BadDER, |spki| {
+        let curve = expect(spki, SEQUENCE)?.read_all(Error::BadDER, |alg| {
+            if expect(alg, OID)?.as_slice_less_safe() != EC_PUBLIC_KEY {
+                return Err(Error::UnsupportedSignatureAlgorithmForPublicKey);
+            }
+            Ok(expect(alg, OID)?.as_slice_less_safe())
+        })?;
+        let key = read_bit_string(spki)?;
+        Ok((curve, key))
+    })?;
+    // ECDSA signature algorithms don't have parameters.
+    let signature_alg = signature_alg.read_all(Error::BadDER, |alg| {
+        expect(alg, OID).map(|oid| oid.as_slice_less_safe())
+    })?;
+
+    let alg = ECDSA_SIGNATURE_ALGS
+        .iter()
+        .find(|&&(c, s, _)| c == curve && s == signature_alg)
+        .map(|&(_, _, alg)| alg)
+        .ok_or(Error::UnsupportedSignatureAlgorithm)?;
+    ring::signature::verify(alg, key, msg, signature)
+        .map_err(|_| Error::InvalidSignatureForPublicKey)
+}
+
+/// Encodes an uncompressed EC public key as a DER `SubjectPublicKeyInfo`.
+pub(super) fn encode_ec_spki(curve: Curve, point: &[u8]) -> Vec<u8> {
+    let curve = match curve {
+        Curve::P256 => SECP256R1,
+        Curve::P384 => SECP384R1,
+    };
+    let encode = || {
+        let mut alg = write_tlv(OID, EC_PUBLIC_KEY)?;
+        alg.extend(write_tlv(OID, curve)?);
+
+        let mut bits = vec![0]; // No unused bits.
+        bits.extend_from_slice(point);
+
+        let mut spki = write_tlv(SEQUENCE, &alg)?;
+        spki.extend(write_tlv(BIT_STRING, &bits)?);
+        write_tlv(SEQUENCE, &spki)
+    };
+    encode().expect("EC public keys must be short")
+}
+
+/// Renders the contents of a DER-encoded `Name`, as held in a trust anchor's
+/// `subject`, as an RFC 4514 string.
+pub(super) fn name_to_string(name: &[u8]) -> Result<String, Error> {
+    let mut rdns = untrusted::Input::from(name).read_all(Error::BadDER, |name| {
+        let mut rdns = Vec::new();
+        while !name.at_end() {
+            let rdn = expect(name, SET)?.read_all(Error::BadDER, |rdn| {
+                let mut attributes = Vec::new();
+                while !rdn.at_end() {
+                    let a = expect(rdn, SEQUENCE)?.read_all(Error::BadDER, read_attribute)?;
+                    attributes.push(a);
+                }
+                Ok(attributes.join("+"))
+            })?;
+            rdns.push(rdn);
+        }
+        Ok(rdns)
+    })?;
+
+    // RFC 4514 lists the most specific RDN first.
+    rdns.reverse();
+    Ok(rdns.join(","))
+}
+
+fn read_attribute(a: &mut untrusted::Reader) -> Result<String, Error> {
+    let oid = expect(a, OID)?.as_slice_less_safe();
+    let (tlv, (tag, value)) = a.read_partial(read_tlv)?;
+
+    let ty = match ATTRIBUTE_TYPES.iter().find(|&&(o, _)| o == oid) {
+        Some(&(_, name)) => name.to_owned(),
+        None => oid_to_string(oid)?,
+    };
+
+    let value = match tag {
+        UTF8_STRING | PRINTABLE_STRING | IA5_STRING => {
+            match ::std::str::from_utf8(value.as_slice_less_safe()) {
+                Ok(v) => escape(v),
+                Err(_) => return Err(Error::BadDER),
+            }
+        }
+        // Other types are rendered as the hex of their encoding.
+        _ => tlv
+            .as_slice_less_safe()
+            .iter()
+            .fold(String::from("#"), |s, b| s + &format!("{:02x}", b)),
+    };
+
+    Ok(format!("{}={}", ty, value))
+}
+
+fn oid_to_string(oid: &[u8]) -> Result<String, Error> {
+    // The last byte must end a subidentifier.
+    if oid.last().map(|b| b & 0x80 != 0).unwrap_or(true) {
+        return Err(Error::BadDER);
+    }
+
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    let mut start = true;
+    for &b in oid {
+        // Subidentifiers must be minimally encoded.
+        if (start && b == 0x80) || arc > (::std::u64::MAX >> 7) {
+            return Err(Error::BadDER);
+        }
+        arc = (arc << 7) | u64::from(b & 0x7f);
+        start = b & 0x80 == 0;
+        if start {
+            if arcs.is_empty() {
+                // The first subidentifier encodes the first two arcs.
+                let first = if arc < 80 { arc / 40 } else { 2 };
+                arcs.push(first.to_string());
+                arcs.push((arc - first * 40).to_string());
+            } else {
+                arcs.push(arc.to_string());
+            }
+            arc = 0;
+        }
+    }
+
+    Ok(arcs.join("."))
+}
+
+/// Escapes an attribute value as described in RFC 4514.
+fn escape(v: &str) -> String {
+    let last = v.chars().count().saturating_sub(1);
+    let mut s = String::with_capacity(v.len());
+    for (i, c) in v.chars().enumerate() {
+        let special = match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => true,
+            '#' | ' ' if i == 0 => true,
+            ' ' if i == last => true,
+            _ => false,
+        };
+        if special {
+            s.push('\\');
+        }
+        s.push(c);
+    }
+    s
+}
+
+// === Encrypted keys ===
+
+/// Reads a DER-encoded `EncryptedPrivateKeyInfo`.
+///
+/// Only PBES2, with PBKDF2 and AES-CBC, is supported. Keys encrypted with
+/// other schemes fail with `Error::UnsupportedSignatureAlgorithm`.
+pub(super) fn parse_encrypted_key(der: &[u8]) -> Result<EncryptedKey, Error> {
+    untrusted::Input::from(der).read_all(Error::BadDER, |info| {
+        expect(info, SEQUENCE)?.read_all(Error::BadDER, |info| {
+            let (salt, iterations, prf, cipher, iv) =
+                expect(info, SEQUENCE)?.read_all(Error::BadDER, |alg| {
+                    if expect(alg, OID)?.as_slice_less_safe() != PBES2 {
+                        return Err(Error::UnsupportedSignatureAlgorithm);
+                    }
+                    expect(alg, SEQUENCE)?.read_all(Error::BadDER, |params| {
+                        let (salt, iterations, prf) =
+                            expect(params, SEQUENCE)?.read_all(Error::BadDER, read_pbkdf2)?;
+                        let (cipher, iv) =
+                            expect(params, SEQUENCE)?.read_all(Error::BadDER, |scheme| {
+                                let oid = expect(scheme, OID)?.as_slice_less_safe();
+                                let cipher = if oid == AES_128_CBC {
+                                    Pbes2Cipher::Aes128Cbc
+                                } else if oid == AES_256_CBC {
+                                    Pbes2Cipher::Aes256Cbc
+                                } else {
+                                    return Err(Error::UnsupportedSignatureAlgorithm);
+                                };
+                                let iv = expect(scheme, OCTET_STRING)?.as_slice_less_safe();
+                                Ok((cipher, iv))
+                            })?;
+                        Ok((salt, iterations, prf, cipher, iv))
+                    })
+                })?;
+            let data = expect(info, OCTET_STRING)?.as_slice_less_safe();
+
+            Ok(EncryptedKey {
+                salt,
+                iterations,
+                prf,
+                cipher,
+                iv,
+                data,
+            })
+        })
+    })
+}
+
+/// Reads PBKDF2's `AlgorithmIdentifier`, returning its salt, iteration count,
+/// and pseudorandom function.
+fn read_pbkdf2<'a>(
+    kdf: &mut untrusted::Reader<'a>,
+) -> Result<(&'a [u8], u32, &'static ring::digest::Algorithm), Error> {
+    if expect(kdf, OID)?.as_slice_less_safe() != PBKDF2 {
+        return Err(Error::UnsupportedSignatureAlgorithm);
+    }
+    expect(kdf, SEQUENCE)?.read_all(Error::BadDER, |params| {
+        // Salts may also be an `AlgorithmIdentifier`, which is reserved for
+        // future use.
+        let salt = expect(params, OCTET_STRING)?.as_slice_less_safe();
+        let iterations = read_u32(params)?;
+        let _key_length = optional(params, INTEGER)?;
+        let prf = match optional(params, SEQUENCE)? {
+            None => &ring::digest::SHA1,
+            Some(prf) => prf.read_all(Error::BadDER, |prf| {
+                let oid = expect(prf, OID)?.as_slice_less_safe();
+                let alg = if oid == HMAC_WITH_SHA1 {
+                    &ring::digest::SHA1
+                } else if oid == HMAC_WITH_SHA256 {
+                    &ring::digest::SHA256
+                } else {
+                    return Err(Error::UnsupportedSignatureAlgorithm);
+                };
+                let _null = optional(prf, NULL)?;
+                Ok(alg)
+            })?,
+        };
+        Ok((salt, iterations, prf))
+    })
+}
+
+/// Reads a positive `INTEGER` that fits in a `u32`.
+fn read_u32(r: &mut untrusted::Reader) -> Result<u32, Error> {
+    let bytes = expect(r, INTEGER)?.as_slice_less_safe();
+    if bytes.is_empty() || bytes[0] & 0x80 != 0 {
+        return Err(Error::BadDER); // Negative.
+    }
+    let bytes = if bytes[0] == 0 && bytes.len() > 1 {
+        &bytes[1..]
+    } else {
+        bytes
+    };
+    if bytes.len() > 4 {
+        return Err(Error::BadDER);
+    }
+    let n = bytes.iter().fold(0u32, |n, &b| (n << 8) | u32::from(b));
+    if n == 0 {
+        return Err(Error::BadDER);
+    }
+    Ok(n)
+}
+
+// === DER ===
+
+fn read_tlv<'a>(r: &mut untrusted::Reader<'a>) -> Result<(u8, untrusted::Input<'a>), Error> {
+    let tag = read_byte(r)?;
+    // High tag numbers aren't used by any of the fields read here.
+    if tag & 0x1f == 0x1f {
+        return Err(Error::BadDER);
+    }
+
+    // DER requires the shortest encoding of each length.
+    let len = match read_byte(r)? {
+        n if n < 0x80 => usize::from(n),
+        0x81 => match read_byte(r)? {
+            n if n < 0x80 => return Err(Error::BadDER),
+            n => usize::from(n),
+        },
+        0x82 => match (usize::from(read_byte(r)?) << 8) | usize::from(read_byte(r)?) {
+            n if n < 0x100 => return Err(Error::BadDER),
+            n => n,
+        },
+        0x83 => {
+            let hi = usize::from(read_byte(r)?) << 16;
+            let mid = usize::from(read_byte(r)?) << 8;
+            match hi | mid | usize::from(read_byte(r)?) {
+                n if n < 0x1_0000 => return Err(Error::BadDER),
+                n => n,
+            }
+        }
+        // Indefinite lengths aren't permitted in DER, and nothing read here
+        // is large enough to need a longer length.
+        _ => return Err(Error::BadDER),
+    };
+
+    let value = r.skip_and_get_input(len).map_err(|_| Error::BadDER)?;
+    Ok((tag, value))
+}
+
+/// Encodes `value` with the shortest length that DER permits.
+///
+/// Values too long to be read by `read_tlv` are rejected.
+fn write_tlv(tag: u8, value: &[u8]) -> Result<Vec<u8>, Error> {
+    let len = value.len();
+    let mut tlv = vec![tag];
+    if len < 0x80 {
+        tlv.push(len as u8);
+    } else if len < 0x100 {
+        tlv.extend_from_slice(&[0x81, len as u8]);
+    } else if len < 0x1_0000 {
+        tlv.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    } else if len < 0x100_0000 {
+        tlv.extend_from_slice(&[0x83, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    } else {
+        return Err(Error::BadDER);
+    }
+    tlv.extend_from_slice(value);
+    Ok(tlv)
+}
+
+/// Reads a `BIT STRING` that has no unused bits, returning its bits.
+fn read_bit_string<'a>(r: &mut untrusted::Reader<'a>) -> Result<untrusted::Input<'a>, Error> {
+    expect(r, BIT_STRING)?.read_all(Error::BadDER, |bits| {
+        if read_byte(bits)? != 0 {
+            return Err(Error::BadDER);
+        }
+        Ok(bits.skip_to_end())
+    })
+}
+
+fn read_byte(r: &mut untrusted::Reader) -> Result<u8, Error> {
+    r.read_byte().map_err(|_| Error::BadDER)
+}
+
+fn expect<'a>(r: &mut untrusted::Reader<'a>, tag: u8) -> Result<untrusted::Input<'a>, Error> {
+    let (t, value) = read_tlv(r)?;
+    if t != tag {
+        return Err(Error::BadDER);
+    }
+    Ok(value)
+}
+
+fn optional<'a>(
+    r: &mut untrusted::Reader<'a>,
+    tag: u8,
+) -> Result<Option<untrusted::Input<'a>>, Error> {
+    if !r.peek(tag) {
+        return Ok(None);
+    }
+    expect(r, tag).map(Some)
+}
+
+// === Time ===
+
+/// Reads a `Time`, which is either a `UTCTime` or a `GeneralizedTime`.
+///
+/// RFC 5280 requires both forms to be expressed in UTC, with seconds and
+/// without fractional seconds.
+fn read_time(r: &mut untrusted::Reader) -> Result<SystemTime, Error> {
+    let (tag, value) = read_tlv(r).map_err(|_| Error::BadDERTime)?;
+    let value = value.as_slice_less_safe();
+    let (year, rest) = match tag {
+        UTC_TIME if value.len() == 13 => {
+            // Two-digit years from 50 are in the 1900s (RFC 5280 4.1.2.5.1).
+            let yy = digits(&value[..2])?;
+            let year = if yy >= 50 { 1900 + yy } else { 2000 + yy };
+            (year, &value[2..])
+        }
+        GENERALIZED_TIME if value.len() == 15 => (digits(&value[..4])?, &value[4..]),
+        _ => return Err(Error::BadDERTime),
+    };
+    if rest[10] != b'Z' {
+        return Err(Error::BadDERTime);
+    }
+
+    let month = digits(&rest[0..2])?;
+    let day = digits(&rest[2..4])?;
+    let hour = digits(&rest[4..6])?;
+    let minute = digits(&rest[6..8])?;
+    let second = digits(&rest[8..10])?;
+    if year < 1970
+        || month < 1
+        || month > 12
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(Error::BadDERTime);
+    }
+
+    let days = days_since_epoch(year, month, day);
+    let secs = ((days * 24 + hour) * 60 + minute) * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn digits(ds: &[u8]) -> Result<u64, Error> {
+    ds.iter().try_fold(0, |n, &d| {
+        if d < b'0' || d > b'9' {
+            return Err(Error::BadDERTime);
+        }
+        Ok(n * 10 + u64::from(d - b'0'))
+    })
+}
+
+fn is_leap_year(year: u64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let years = (1970..year).map(|y| if is_leap_year(y) { 366 } else { 365 });
+    let months = (1..month).map(|m| days_in_month(year, m));
+    years.sum::<u64>() + months.sum::<u64>() + day - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::*;
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parses_validity() {
+        let crt = FOO_NS1.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        // 2019-03-14T18:02:00Z and 2020-03-13T18:02:00Z.
+        assert_eq!(tbs.not_before, at(1_552_586_520));
+        assert_eq!(tbs.not_after, at(1_584_122_520));
+    }
+
+    #[test]
+    fn parses_generalized_time() {
+        // 20500101000000Z, which must be encoded as a GeneralizedTime.
+        let der = b"\x18\x0f20500101000000Z";
+        let t = untrusted::Input::from(&der[..]).read_all(Error::BadDER, read_time);
+        assert_eq!(t, Ok(at(2_524_608_000)));
+    }
+
+    #[test]
+    fn rejects_invalid_time() {
+        for der in &[
+            &b"\x17\x0d190230000000Z"[..], // February 30th
+            &b"\x17\x0d1903141802000"[..], // Not UTC
+            &b"\x17\x0b1903141802Z"[..],   // No seconds
+        ] {
+            let t = untrusted::Input::from(*der).read_all(Error::BadDER, read_time);
+            assert_eq!(t, Err(Error::BadDERTime));
+        }
+    }
+
+    #[test]
+    fn renders_names() {
+        // O=Buoyant, Inc + OU=Linkerd; CN=#1; serialNumber=1 (as an OCTET
+        // STRING rather than a PrintableString).
+        let der = b"\
+            \x31\x25\
+                \x30\x13\x06\x03\x55\x04\x0a\x0c\x0cBuoyant, Inc\
+                \x30\x0e\x06\x03\x55\x04\x0b\x13\x07Linkerd\
+            \x31\x0b\x30\x09\x06\x03\x55\x04\x03\x0c\x02#1\
+            \x31\x0a\x30\x08\x06\x03\x55\x04\x05\x04\x011";
+        assert_eq!(
+            name_to_string(&der[..]).unwrap(),
+            "2.5.4.5=#040131,CN=\\#1,O=Buoyant\\, Inc+OU=Linkerd"
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut der = FOO_NS1.crt().leaf.0.clone();
+        der.push(0);
+        assert_eq!(parse(&der).unwrap_err(), Error::BadDER);
+    }
+
+    #[test]
+    fn writes_long_lengths() {
+        let value = vec![0; 0x1_0000];
+        let tlv = write_tlv(SEQUENCE, &value).expect("value must be encoded");
+        assert_eq!(&tlv[..5], &[SEQUENCE, 0x83, 1, 0, 0]);
+        let (tag, read) = untrusted::Input::from(&tlv)
+            .read_all(Error::BadDER, read_tlv)
+            .expect("value must be read");
+        assert_eq!(tag, SEQUENCE);
+        assert_eq!(read.as_slice_less_safe(), &value[..]);
+
+        let value = vec![0; 0x100_0000];
+        assert_eq!(write_tlv(SEQUENCE, &value).unwrap_err(), Error::BadDER);
+    }
+
+    #[test]
+    fn parses_uri_sans() {
+        let crt = FOO_NS1_SPIFFE.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        assert_eq!(tbs.uri_sans, vec!["spiffe://cluster.local/ns/ns1/sa/foo"]);
+        assert_eq!(tbs.dns_sans, vec![FOO_NS1.name]);
+
+        let crt = FOO_NS1.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        assert!(tbs.uri_sans.is_empty());
+    }
+
+    #[test]
+    fn parses_dns_sans() {
+        let crt = WILDCARD_NS1.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        assert_eq!(
+            tbs.dns_sans,
+            vec!["*.ns1.serviceaccount.identity.linkerd.cluster.local"]
+        );
+
+        let crt = CN_ONLY_NS1.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        assert!(tbs.dns_sans.is_empty());
+    }
+
+    #[test]
+    fn reads_precertificates() {
+        let crt = FOO_NS1_SCT.crt();
+        let tbs = parse(crt.leaf.as_ref()).expect("certificate must parse");
+        assert!(tbs.sct_list.is_some());
+
+        let precert = precert_tbs(crt.leaf.as_ref()).expect("certificate must parse");
+        let der = write_tlv(
+            SEQUENCE,
+            &[&precert[..], &b"\x30\x00\x03\x01\x00"[..]].concat(),
+        )
+        .unwrap();
+        let pre = parse(&der).expect("precertificate must parse");
+        assert!(pre.sct_list.is_none());
+        assert_eq!(pre.serial, tbs.serial);
+        assert_eq!(pre.dns_sans, tbs.dns_sans);
+
+        // Certificates without embedded SCTs are their own precertificates.
+        let crt = FOO_NS1.crt();
+        assert!(parse(crt.leaf.as_ref()).unwrap().sct_list.is_none());
+        let (tbs, _, _) = read_signed_crt(untrusted::Input::from(crt.leaf.as_ref())).unwrap();
+        assert_eq!(
+            precert_tbs(crt.leaf.as_ref()).unwrap(),
+            tbs.as_slice_less_safe()
+        );
+    }
+
+    #[test]
+    fn parses_encrypted_key() {
+        let der = FOO_NS1.key_encrypted_pkcs8();
+        let key = parse_encrypted_key(&der).expect("key must parse");
+        assert_eq!(
+            key.salt,
+            &[0xdd, 0x02, 0xc9, 0xda, 0x66, 0x77, 0x36, 0xa4][..]
+        );
+        assert_eq!(key.iterations, 2048);
+        assert!(::std::ptr::eq(key.prf, &ring::digest::SHA256));
+        assert_eq!(key.cipher, Pbes2Cipher::Aes256Cbc);
+        assert_eq!(key.iv.len(), 16);
+        assert_eq!(key.data.len(), 144);
+    }
+}
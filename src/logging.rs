@@ -367,3 +367,88 @@ impl fmt::Display for Bg {
         write!(f, "{}={{bg={}}}", self.section, self.name)
     }
 }
+
+#[cfg(test)]
+pub mod test_util {
+    use log::{self, LevelFilter, Log, Metadata, Record};
+    use std::cell::RefCell;
+    use std::sync::{Mutex, Once, ONCE_INIT};
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = RefCell::new(None);
+    }
+
+    /// A logger that records messages logged on threads that are capturing.
+    struct Capture;
+
+    static CAPTURE: Capture = Capture;
+
+    /// Counts the threads that are capturing, so that the maximum log level is
+    /// only raised while at least one of them is.
+    struct Capturing(&'static Mutex<usize>);
+
+    /// Runs `f`, returning the messages it logged on the current thread.
+    ///
+    /// Messages are captured at all levels, formatted as `LEVEL message`.
+    /// The maximum log level is only raised while `f` runs, so tests that
+    /// don't capture logs are unaffected.
+    ///
+    /// The proxy logs through `log` rather than `tracing`, so structured
+    /// fields, such as a handshake's, are asserted on as `key=value` text.
+    pub fn capture<F: FnOnce()>(f: F) -> Vec<String> {
+        let _capturing = Capturing::start();
+        CAPTURED.with(|c| *c.borrow_mut() = Some(Vec::new()));
+        f();
+        CAPTURED.with(|c| c.borrow_mut().take().unwrap_or_default())
+    }
+
+    // === impl Capturing ===
+
+    impl Capturing {
+        fn start() -> Self {
+            static INIT: Once = ONCE_INIT;
+            static mut COUNT: *const Mutex<usize> = 0 as *const _;
+            // `COUNT` is only written once, before it is read.
+            let count = unsafe {
+                INIT.call_once(|| {
+                    log::set_logger(&CAPTURE).expect("no other logger may be set in tests");
+                    COUNT = Box::into_raw(Box::new(Mutex::new(0)));
+                });
+                &*COUNT
+            };
+
+            let mut n = count.lock().unwrap_or_else(|e| e.into_inner());
+            *n += 1;
+            log::set_max_level(LevelFilter::Trace);
+            Capturing(count)
+        }
+    }
+
+    impl Drop for Capturing {
+        fn drop(&mut self) {
+            let mut n = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            *n -= 1;
+            if *n == 0 {
+                log::set_max_level(LevelFilter::Off);
+            }
+        }
+    }
+
+    // === impl Capture ===
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            CAPTURED.with(|c| c.borrow().is_some())
+        }
+
+        fn log(&self, record: &Record) {
+            CAPTURED.with(|c| {
+                if let Some(ref mut c) = *c.borrow_mut() {
+                    c.push(format!("{} {}", record.level(), record.args()));
+                }
+            })
+        }
+
+        fn flush(&self) {}
+    }
+}
@@ -541,8 +541,7 @@ fn base_event<B, I: Inspect>(req: &http::Request<B>, inspect: &I) -> api::TapEve
             let tls_status = tls.as_ref().map(|_| ()).to_string();
             m.labels.insert("tls".to_owned(), tls_status);
             if let Conditional::Some(id) = tls {
-                m.labels
-                    .insert("client_id".to_owned(), id.as_ref().to_owned());
+                m.labels.insert("client_id".to_owned(), id.to_string());
             }
             Some(m)
         },
@@ -4,7 +4,7 @@ use std::net;
 use std::sync::Arc;
 
 use identity;
-use transport::tls::ReasonForNoIdentity;
+use transport::tls::{PeerId, ReasonForNoIdentity};
 use Conditional;
 
 mod daemon;
@@ -47,7 +47,7 @@ pub trait Inspect {
     fn src_tls<'a, B>(
         &self,
         req: &'a http::Request<B>,
-    ) -> Conditional<&'a identity::Name, ReasonForNoIdentity>;
+    ) -> Conditional<&'a PeerId, ReasonForNoIdentity>;
 
     fn dst_addr<B>(&self, req: &http::Request<B>) -> Option<net::SocketAddr>;
     fn dst_labels<B>(&self, req: &http::Request<B>) -> Option<&IndexMap<String, String>>;
@@ -5,6 +5,7 @@ use tokio::net::TcpStream;
 
 pub trait AddrInfo: Debug {
     fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error>;
     fn get_original_dst(&self) -> Option<SocketAddr>;
 }
 
@@ -13,6 +14,10 @@ impl<T: AddrInfo + ?Sized> AddrInfo for Box<T> {
         self.as_ref().local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.as_ref().peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.as_ref().get_original_dst()
     }
@@ -23,6 +28,10 @@ impl AddrInfo for TcpStream {
         TcpStream::local_addr(&self)
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        TcpStream::peer_addr(&self)
+    }
+
     #[cfg(target_os = "linux")]
     fn get_original_dst(&self) -> Option<SocketAddr> {
         use self::linux;
@@ -25,6 +25,12 @@ impl BoxedIo {
     pub fn shutdown_write(&mut self) -> Result<(), io::Error> {
         self.0.shutdown_write()
     }
+
+    /// Like `shutdown_write`, this is an inherent method since `Io` isn't
+    /// publicly exported.
+    pub fn send_close_notify(&mut self) {
+        self.0.send_close_notify()
+    }
 }
 
 impl io::Read for BoxedIo {
@@ -67,6 +73,10 @@ impl AddrInfo for BoxedIo {
         self.0.local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.0.peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.0.get_original_dst()
     }
@@ -82,6 +92,20 @@ impl SetKeepalive for BoxedIo {
     }
 }
 
+impl Io for BoxedIo {
+    fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        self.0.shutdown_write()
+    }
+
+    fn write_buf_erased(&mut self, buf: &mut Buf) -> Poll<usize, io::Error> {
+        self.0.write_buf_erased(buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        self.0.send_close_notify()
+    }
+}
+
 pub(super) mod internal {
     use super::{AddrInfo, AsyncRead, AsyncWrite, Buf, Poll, SetKeepalive, Shutdown};
     use std::io;
@@ -98,6 +122,12 @@ pub(super) mod internal {
         /// This method is to allow using `Async::write_buf` even through a
         /// trait object.
         fn write_buf_erased(&mut self, buf: &mut Buf) -> Poll<usize, io::Error>;
+
+        /// Queues a TLS close_notify alert, which is written as the stream is
+        /// shut down.
+        ///
+        /// This does nothing for streams that aren't TLS.
+        fn send_close_notify(&mut self) {}
     }
 
     impl Io for TcpStream {
@@ -150,6 +180,10 @@ mod tests {
             unreachable!("not called in test")
         }
 
+        fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+            unreachable!("not called in test")
+        }
+
         fn get_original_dst(&self) -> Option<SocketAddr> {
             unreachable!("not called in test")
         }
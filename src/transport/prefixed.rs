@@ -90,6 +90,10 @@ where
         self.io.local_addr()
     }
 
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.peer_addr()
+    }
+
     fn get_original_dst(&self) -> Option<SocketAddr> {
         self.io.get_original_dst()
     }
@@ -119,4 +123,8 @@ where
     fn write_buf_erased(&mut self, buf: &mut Buf) -> Result<Async<usize>, io::Error> {
         self.io.write_buf_erased(buf)
     }
+
+    fn send_close_notify(&mut self) {
+        self.io.send_close_notify()
+    }
 }
@@ -1,6 +1,9 @@
 use futures::{Async, Future, Poll};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use std::{fmt, io};
+use tokio_timer::clock;
 
 use identity;
 use svc;
@@ -11,6 +14,14 @@ pub use super::rustls::ClientConfig as Config;
 
 pub trait HasConfig {
     fn tls_client_config(&self) -> Arc<Config>;
+
+    /// Returns the name of the certificate that `tls_client_config` presents
+    /// to servers, if it presents one.
+    ///
+    /// Defaults to `None`, for configs that only verify servers.
+    fn tls_client_name(&self) -> Option<identity::Name> {
+        None
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -25,6 +36,7 @@ pub struct Stack<L, S> {
 #[derive(Clone, Debug)]
 pub struct Connect<L, C> {
     inner: C,
+    local: tls::Conditional<L>,
     tls: tls::Conditional<(identity::Name, L)>,
 }
 
@@ -37,9 +49,75 @@ pub enum ConnectFuture<L, F: Future> {
     Handshake {
         future: tls::tokio_rustls::Connect<F::Item>,
         server_name: identity::Name,
+        peer: Option<SocketAddr>,
+        local_name: Option<identity::Name>,
+        started: Instant,
+        /// Whether a remembered session was offered to the server.
+        offered: bool,
+        /// Whether the server's certificate was verified, which it isn't
+        /// when the server resumes the offered session.
+        verified: bool,
     },
 }
 
+/// Builds a configuration for mutually-authenticated TLS connections, which
+/// trusts servers certified by `anchors` and authenticates with `key`.
+///
+/// Sessions are not resumed unless `with_resumption` is used.
+pub fn client_config(anchors: &identity::TrustAnchors, key: &identity::CrtKey) -> Config {
+    client_config_with_resolver(anchors, key.client_cert_resolver())
+}
+
+/// Like `client_config`, but authenticates with the certificates that
+/// `resolver` resolves, so that a `CrtKey`'s configuration may be built
+/// along with the key.
+pub fn client_config_with_resolver(
+    anchors: &identity::TrustAnchors,
+    resolver: Arc<dyn super::rustls::ResolvesClientCert>,
+) -> Config {
+    let mut c = anchors.tls_client_config().as_ref().clone();
+    c.client_auth_cert_resolver = resolver;
+    c.set_persistence(Arc::new(super::rustls::NoClientSessionStorage {}));
+    c
+}
+
+/// Restricts the cipher suites that are offered to `allowed`, in order of
+/// preference.
+///
+/// Fails if `allowed` is empty or if it includes suites that can't be used
+/// with our keys.
+pub fn with_ciphersuites(
+    mut config: Config,
+    allowed: &[&'static super::rustls::SupportedCipherSuite],
+) -> Result<Config, tls::InvalidCipherSuites> {
+    config.ciphersuites = tls::ciphersuites(allowed)?;
+    Ok(config)
+}
+
+/// Enables resumption of sessions with servers that support it, remembering
+/// up to `sessions` sessions.
+///
+/// Session tickets are not used, so sessions are only resumed with servers
+/// that cache them (e.g. as configured by `listen::with_session_cache`).
+///
+/// Servers are verified by `anchors`, replacing the configuration's verifier,
+/// so that connections can tell whether the server resumed the session that
+/// they offered: only those that it resumed report that they were resumed by
+/// `Connection::is_resumed`.
+pub fn with_resumption(
+    mut config: Config,
+    anchors: &identity::TrustAnchors,
+    sessions: usize,
+) -> Config {
+    let sessions = super::rustls::ClientSessionMemoryCache::new(sessions);
+    config.set_persistence(Arc::new(tls::resumption::ClientSessions(sessions)));
+    let verifier = tls::resumption::ServerVerifier(anchors.verifier());
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(verifier));
+    config
+}
+
 // === impl Layer ===
 
 pub fn layer<L: HasConfig + Clone>(l: tls::Conditional<L>) -> Layer<L> {
@@ -49,7 +127,7 @@ pub fn layer<L: HasConfig + Clone>(l: tls::Conditional<L>) -> Layer<L> {
 impl<T, L, S> svc::Layer<T, T, S> for Layer<L>
 where
     L: HasConfig + fmt::Debug + Clone,
-    T: tls::HasPeerIdentity,
+    T: tls::HasServerIdentity,
     S: svc::Stack<T> + Clone,
     S::Value: connect::Connect + Clone + Send + Sync + 'static,
     <S::Value as connect::Connect>::Connected: Send + 'static,
@@ -73,7 +151,7 @@ where
 impl<T, L, S> svc::Stack<T> for Stack<L, S>
 where
     L: HasConfig + fmt::Debug + Clone,
-    T: tls::HasPeerIdentity,
+    T: tls::HasServerIdentity,
     S: svc::Stack<T> + Clone,
     S::Value: connect::Connect + Clone + Send + Sync + 'static,
     <S::Value as connect::Connect>::Connected: Send + 'static,
@@ -85,14 +163,31 @@ where
 
     fn make(&self, target: &T) -> Result<Self::Value, Self::Error> {
         let inner = self.inner.make(&target)?;
-        let server_name = target.peer_identity();
+        let server_name = target.server_identity();
         let tls = self.local.clone().and_then(|l| server_name.map(|n| (n, l)));
-        Ok(Connect { inner, tls })
+        Ok(Connect {
+            inner,
+            local: self.local.clone(),
+            tls,
+        })
     }
 }
 
 // === impl Connect ===
 
+impl<L: Clone, C> Connect<L, C> {
+    /// Verifies the server as `name`, rather than as the target's identity.
+    ///
+    /// `name` is used both as the SNI name and to validate the server's
+    /// certificate, independently of the address that is dialed. TLS is
+    /// enabled even if the target has no identity, unless TLS is disabled
+    /// locally.
+    pub fn with_server_identity(self, name: identity::Name) -> Self {
+        let tls = self.local.clone().map(|l| (name, l));
+        Self { tls, ..self }
+    }
+}
+
 impl<L, C> connect::Connect for Connect<L, C>
 where
     L: HasConfig + fmt::Debug + Clone,
@@ -134,12 +229,25 @@ where
 
                     match tls {
                         Conditional::Some((server_name, local_tls)) => {
-                            trace!("initiating TLS to {}", server_name.as_ref());
-                            let future = tls::Connector::from(local_tls.tls_client_config())
-                                .connect(server_name.as_dns_name_ref(), io);
+                            trace!(
+                                "initiating TLS to {}",
+                                server_name.log(identity::LogPolicy::current())
+                            );
+                            let peer = io.peer_addr().ok();
+                            // Rustls looks for a session to offer as it
+                            // starts the handshake.
+                            let connector = tls::Connector::from(local_tls.tls_client_config());
+                            let (future, observed) = tls::resumption::observe(|| {
+                                connector.connect(server_name.as_dns_name_ref(), io)
+                            });
                             ConnectFuture::Handshake {
                                 future,
                                 server_name: server_name.clone(),
+                                peer,
+                                local_name: local_tls.tls_client_name(),
+                                started: clock::now(),
+                                offered: observed.found,
+                                verified: observed.verified,
                             }
                         }
                         Conditional::None(why) => {
@@ -151,14 +259,393 @@ where
                 ConnectFuture::Handshake {
                     future,
                     server_name,
+                    peer,
+                    local_name,
+                    started,
+                    offered,
+                    verified,
                 } => {
-                    let io = try_ready!(future.poll());
+                    let (poll, observed) = tls::resumption::observe(|| future.poll());
+                    *offered = *offered || observed.found;
+                    *verified = *verified || observed.verified;
+                    let fields = || tls::HandshakeFields {
+                        peer: *peer,
+                        sni: server_name.clone(),
+                    };
+                    let io = match poll {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(io)) => io,
+                        Err(e) => {
+                            debug!(
+                                "tls handshake failed; {} outcome={} error={}",
+                                fields(),
+                                failure_outcome(&e),
+                                e
+                            );
+                            return Err(e.into());
+                        }
+                    };
+                    let negotiated = tls::Negotiated::new(io.get_ref().1)
+                        .with_handshake_duration(clock::now() - *started)
+                        .with_resumed(*offered && !*verified)
+                        .with_local_name(local_name.take());
                     let io = BoxedIo::new(super::TlsIo::from(io));
-                    trace!("established TLS to {}", server_name.as_ref());
-                    let c = Connection::tls(io, Conditional::Some(server_name.clone()));
+                    let server_id = tls::PeerId::Name(server_name.clone());
+                    let c = Connection::tls(io, negotiated, Conditional::Some(server_id));
+                    debug!(
+                        "tls handshake succeeded; {} outcome=succeeded version={:?} suite={:?}",
+                        fields(),
+                        c.protocol_version(),
+                        c.negotiated_cipher_suite().map(|cs| cs.suite),
+                    );
                     return Ok(Async::Ready(c));
                 }
             };
         }
     }
 }
+
+/// Describes why a handshake failed, for logs, as `listen` does.
+///
+/// We reject the server's certificate as the handshake proceeds; when the
+/// server rejects ours, it tells us so with an alert.
+fn failure_outcome(e: &io::Error) -> &'static str {
+    match e.get_ref().and_then(|e| e.downcast_ref::<tls::Error>()) {
+        Some(tls::Error::WebPKIError(_)) => "failed_server_auth",
+        Some(tls::Error::AlertReceived(alert)) if tls::is_certificate_alert(*alert) => {
+            "failed_client_auth"
+        }
+        _ => "failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rustls::{ResolvesClientCert, SignatureScheme};
+    use super::*;
+    use futures::future;
+    use identity::test_util::*;
+    use tokio;
+    use transport::connect::{Connect as _Connect, ConnectSocketAddr};
+    use transport::tls::listen::Listen;
+    use transport::tls::{HasPeerIdentity, ReasonForNoPeerName};
+
+    #[test]
+    fn client_config_uses_anchors_and_key() {
+        let anchors = FOO_NS1.trust_anchors();
+        let key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let c = client_config(&anchors, &key);
+
+        let roots = |c: &Config| {
+            c.root_store
+                .roots
+                .iter()
+                .map(|r| r.to_trust_anchor().spki.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(roots(&c), roots(&anchors.tls_client_config()));
+        // Rustls's default cipher suites are offered.
+        assert_eq!(
+            c.ciphersuites.len(),
+            super::super::rustls::ALL_CIPHERSUITES.len()
+        );
+
+        let resolved = c
+            .client_auth_cert_resolver
+            .resolve(&[], &[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .expect("client certificate must be resolved");
+        assert_eq!(resolved.cert[0].0, FOO_NS1.crt_der());
+        assert!(c.client_auth_cert_resolver.has_certs());
+    }
+
+    #[test]
+    fn with_server_identity_overrides_target_identity() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), _| Ok(()))
+            .map_err(|e| panic!("server failed: {}", e));
+
+        // The target, an IP address, has no identity.
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let connect = Connect {
+            inner: ConnectSocketAddr::from(addr),
+            local: Conditional::Some(client),
+            tls: Conditional::None(ReasonForNoPeerName::NotHttp.into()),
+        };
+        let connect = connect.with_server_identity(FOO_NS1.name());
+        let client = connect
+            .connect()
+            .map(|conn| {
+                assert_eq!(conn.peer_identity(), tls::peer_identity(FOO_NS1.name()));
+            })
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(future::lazy(|| server.join(client).map(|_| ())));
+    }
+
+    #[test]
+    fn records_handshake_duration() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let (durations_tx, durations_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(1, durations_tx, |durations, (conn, _)| {
+                durations.send(conn.handshake_duration()).unwrap();
+                Ok(durations)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let connect = Connect {
+            inner: ConnectSocketAddr::from(addr),
+            local: Conditional::Some(client.clone()),
+            tls: Conditional::Some((FOO_NS1.name(), client)),
+        };
+        let (client_tx, client_rx) = mpsc::channel();
+        let client = connect
+            .connect()
+            .map(move |conn| client_tx.send(conn.handshake_duration()).unwrap())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(future::lazy(|| server.join(client).map(|_| ())));
+
+        let zero = Duration::from_secs(0);
+        let server = durations_rx
+            .try_recv()
+            .expect("connection must be accepted");
+        assert!(server.expect("server handshake must be timed") > zero);
+        let client = client_rx.try_recv().expect("client must connect");
+        assert!(client.expect("client handshake must be timed") > zero);
+
+        // Neither side of a plaintext connection has a handshake duration.
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let (durations_tx, durations_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(1, durations_tx, |durations, (conn, _)| {
+                durations.send(conn.handshake_duration()).unwrap();
+                Ok(durations)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let connect = Connect {
+            inner: ConnectSocketAddr::from(addr),
+            local: Conditional::Some(client),
+            tls: Conditional::None(ReasonForNoPeerName::NotHttp.into()),
+        };
+        let client = connect
+            .connect()
+            .map(|conn| assert!(conn.handshake_duration().is_none()))
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(future::lazy(|| server.join(client).map(|_| ())));
+        assert!(durations_rx
+            .try_recv()
+            .expect("connection must be accepted")
+            .is_none());
+    }
+
+    #[test]
+    fn established_connections_have_identity_state() {
+        use std::sync::mpsc;
+        use transport::tls::{HasIdentity, HasStatus, IdentityState, Mode, ReasonForNoIdentity};
+
+        fn connect<L>(local: L) -> IdentityState
+        where
+            L: HasConfig + fmt::Debug + Clone + Send + 'static,
+        {
+            let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+            let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+                .expect("must bind");
+            let addr = listen.local_addr();
+            let server = listen
+                .listen_and_fold_n(1, (), |(), _| Ok(()))
+                .map_err(|e| panic!("server failed: {}", e));
+
+            let connect = Connect {
+                inner: ConnectSocketAddr::from(addr),
+                local: Conditional::Some(local.clone()),
+                tls: Conditional::Some((FOO_NS1.name(), local)),
+            };
+            let (tx, rx) = mpsc::channel();
+            let client = connect
+                .connect()
+                .map(move |conn| {
+                    assert_eq!(conn.tls_status(), Conditional::Some(()));
+                    tx.send(conn.identity_state()).unwrap()
+                })
+                .map_err(|e| panic!("client failed: {}", e));
+
+            tokio::run(future::lazy(|| server.join(client).map(|_| ())));
+            rx.try_recv().expect("client must connect")
+        }
+
+        let mtls = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        assert_eq!(
+            connect(mtls),
+            IdentityState {
+                status: Conditional::Some(Mode::Mutual),
+                server: tls::peer_identity(FOO_NS1.name()),
+                client: tls::peer_identity(BAR_NS1.name()),
+            }
+        );
+
+        // Without a certificate of its own, the client only verifies the
+        // server.
+        assert_eq!(
+            connect(FOO_NS1.trust_anchors()),
+            IdentityState {
+                status: Conditional::Some(Mode::OneWay),
+                server: tls::peer_identity(FOO_NS1.name()),
+                client: Conditional::None(ReasonForNoIdentity::Disabled),
+            }
+        );
+    }
+
+    #[test]
+    fn logs_failed_handshakes() {
+        use logging::test_util::capture;
+        use tokio::net::TcpListener;
+        use tokio::runtime::current_thread::Runtime;
+        use transport::tls::listen::HasConfig as _ServerHasConfig;
+
+        // The client doesn't trust the server's CA.
+        let client = FOO_NS1_CA2.trust_anchors();
+        let server = FOO_NS1
+            .validate()
+            .expect("foo.ns1 must be valid")
+            .tls_server_config();
+
+        // The handshake is driven on this thread so that its logs are
+        // captured.
+        let logs = capture(|| {
+            let mut rt = Runtime::new().expect("runtime");
+            let connect = future::lazy(move || {
+                let listener =
+                    TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("must bind");
+                let addr = listener.local_addr().expect("must have an address");
+                let accept = listener
+                    .incoming()
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .and_then(move |(socket, _)| {
+                        tls::Acceptor::from(server).accept(socket.expect("must accept"))
+                    })
+                    .then(|_| Ok(()));
+                tokio::executor::current_thread::spawn(accept);
+
+                Connect {
+                    inner: ConnectSocketAddr::from(addr),
+                    local: Conditional::Some(client.clone()),
+                    tls: Conditional::Some((FOO_NS1.name(), client)),
+                }
+                .connect()
+            });
+            assert!(rt.block_on(connect).is_err(), "handshake must fail");
+        });
+
+        let sni = format!("sni={}", FOO_NS1.name());
+        assert!(
+            logs.iter()
+                .any(|l| l.starts_with("DEBUG tls handshake failed;")
+                    && l.contains("peer=127.0.0.1:")
+                    && l.contains(&sni)
+                    && l.contains("outcome=failed_server_auth")),
+            "logs must describe the failed handshake: {:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn reports_resumed_sessions() {
+        use std::sync::mpsc;
+        use transport::tls::listen;
+
+        #[derive(Clone)]
+        struct Resuming {
+            client: Arc<Config>,
+            server: Arc<listen::Config>,
+        }
+
+        impl fmt::Debug for Resuming {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct("Resuming").finish()
+            }
+        }
+
+        impl HasConfig for Resuming {
+            fn tls_client_config(&self) -> Arc<Config> {
+                self.client.clone()
+            }
+        }
+
+        impl listen::HasConfig for Resuming {
+            fn tls_server_name(&self) -> identity::Name {
+                FOO_NS1.name()
+            }
+
+            fn tls_server_config(&self) -> Arc<listen::Config> {
+                self.server.clone()
+            }
+        }
+
+        let anchors = FOO_NS1.trust_anchors();
+        let key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let server = listen::server_config(&anchors, &key, listen::ClientAuth::Optional);
+        let resuming = Resuming {
+            client: Arc::new(with_resumption(client_config(&anchors, &key), &anchors, 8)),
+            server: Arc::new(listen::with_session_cache(server, 8)),
+        };
+
+        let listen = Listen::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Conditional::Some(resuming.clone()),
+        )
+        .expect("must bind");
+        let addr = listen.local_addr();
+        let (resumed_tx, resumed_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(2, resumed_tx, |resumed, (conn, _)| {
+                resumed.send(conn.is_resumed()).unwrap();
+                Ok(resumed)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let connect = Connect {
+            inner: ConnectSocketAddr::from(addr),
+            local: Conditional::Some(resuming.clone()),
+            tls: Conditional::Some((FOO_NS1.name(), resuming)),
+        };
+        let (client_tx, client_rx) = mpsc::channel();
+        let client = connect
+            .connect()
+            .and_then(move |first| {
+                // The session is remembered once the first handshake completes.
+                let first = first.is_resumed();
+                connect
+                    .connect()
+                    .map(move |second| (first, second.is_resumed()))
+            })
+            .map(move |resumed| client_tx.send(resumed).unwrap())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(future::lazy(|| server.join(client).map(|_| ())));
+
+        let (first, second) = client_rx.try_recv().expect("client must connect");
+        assert!(!first, "the first connection must not be resumed");
+        assert!(second, "the second connection must be resumed");
+        let accepted = |n| resumed_rx.try_recv().expect(n);
+        assert!(!accepted("the first connection must be accepted"));
+        assert!(accepted("the second connection must be accepted"));
+    }
+}
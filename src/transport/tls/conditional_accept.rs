@@ -1,6 +1,26 @@
-use super::untrusted;
+use bytes::BytesMut;
+use futures::{Async, Future, Poll};
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::{clock, Delay};
 
+use super::{listen::Config, untrusted, Accept, Acceptor};
 use identity;
+use transport::prefixed::Prefixed;
+
+/// The default limit on how much of a connection is buffered while waiting
+/// for a complete ClientHello.
+///
+/// This is enough for any ClientHello that `extract_sni` would parse: a
+/// record header followed by at most 8192 bytes.
+pub const DEFAULT_PEEK_CAPACITY: usize = 8192 + 5;
+
+/// The default limit on how long to wait for a complete ClientHello.
+pub const DEFAULT_PEEK_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Match {
@@ -9,6 +29,167 @@ pub enum Match {
     NotMatched,
 }
 
+/// Indicates that a stream that started like a TLS connection did not send a
+/// complete ClientHello within the peek limits.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClientHelloError {
+    /// The ClientHello was not received before the peek timeout.
+    TimedOut,
+
+    /// The ClientHello is larger than the peek capacity.
+    TooLarge,
+}
+
+/// What `PeekClientHello` found at the start of a stream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientHello {
+    /// The stream does not start with a TLS ClientHello.
+    NotTls,
+
+    /// The stream starts with a ClientHello, which requested the given server
+    /// name, if any.
+    Sni(Option<identity::Name>),
+
+    /// The stream starts with a ClientHello whose SNI is not a valid name,
+    /// e.g. because it has a trailing dot or isn't ASCII.
+    InvalidSni,
+
+    /// The stream starts with a complete TLS record that isn't a ClientHello
+    /// we can parse, e.g. because its lengths are inconsistent or it's for
+    /// an older version of TLS.
+    Malformed,
+}
+
+/// Why `extract_sni` couldn't read an SNI.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Invalid {
+    /// More input is needed.
+    Incomplete,
+
+    /// The input isn't a ClientHello that we can parse, and more input
+    /// wouldn't help.
+    Malformed,
+}
+
+/// Reads the start of a stream until it can tell whether the stream begins
+/// with a TLS ClientHello and, if so, which server name it requests.
+///
+/// This is how both `Detect` and `Listen` peek at streams, so that they
+/// handle them alike:
+///
+/// - Streams that don't start with a TLS record, that end before a
+///   ClientHello is complete, or that are still ambiguous once the timeout
+///   has elapsed---as with protocols in which the server speaks first---are
+///   `ClientHello::NotTls`.
+/// - Streams that start with a TLS record, but whose ClientHello isn't
+///   complete within the capacity or the timeout, fail with a
+///   `ClientHelloError`.
+///
+/// Created by `peek_client_hello`.
+pub struct PeekClientHello<I> {
+    io: Option<I>,
+    peek_buf: BytesMut,
+    capacity: usize,
+    timeout: Delay,
+}
+
+/// Chooses the configuration with which `Detect` accepts a TLS stream.
+pub trait SelectConfig {
+    /// Returns the configuration for a client that requested the server name
+    /// `sni`, if any.
+    fn select_config(&self, sni: Option<&identity::Name>) -> Arc<Config>;
+}
+
+/// Detects whether a stream is TLS or plaintext.
+///
+/// Created by `detect`.
+pub struct Detect<I, S = Arc<Config>> {
+    peek: PeekClientHello<I>,
+    config: S,
+}
+
+/// The result of `Detect`.
+///
+/// When the stream is returned, the bytes that were read to detect the
+/// protocol are replayed to its readers.
+pub enum Detected<I> {
+    /// The stream starts with a TLS ClientHello and is being accepted.
+    Accepted {
+        /// The server name that the client requested, if any.
+        sni: Option<identity::Name>,
+        accept: Accept<Prefixed<I>>,
+    },
+
+    /// The stream does not start with a ClientHello that can be accepted.
+    ///
+    /// This includes streams whose ClientHello has an SNI that isn't a valid
+    /// name, which `Listen` passes through as well.
+    Plaintext(Prefixed<I>),
+
+    /// The stream starts like a TLS ClientHello that could not be read
+    /// within the peek limits, so the stream has been dropped.
+    Rejected(ClientHelloError),
+}
+
+/// Peeks at the start of `io`, as `peek_client_hello` does, to determine
+/// whether it is a TLS connection, which is then accepted with the
+/// configuration chosen by `config`, or plaintext.
+///
+/// Once a stream is known to be TLS, its ClientHello is read so that `config`
+/// may choose a configuration for the requested SNI, if possible. At most
+/// `DEFAULT_PEEK_CAPACITY` bytes are buffered.
+pub fn detect<I, S>(io: I, config: S, timeout: Duration) -> Detect<I, S>
+where
+    I: AsyncRead + AsyncWrite + Debug,
+    S: SelectConfig,
+{
+    Detect {
+        peek: peek_client_hello(io, DEFAULT_PEEK_CAPACITY, timeout),
+        config,
+    }
+}
+
+/// Peeks at the start of `io`, buffering at most `capacity` bytes for at
+/// most `timeout`, until it's known whether `io` starts with a ClientHello.
+pub fn peek_client_hello<I: AsyncRead>(
+    io: I,
+    capacity: usize,
+    timeout: Duration,
+) -> PeekClientHello<I> {
+    PeekClientHello {
+        io: Some(io),
+        peek_buf: BytesMut::with_capacity(capacity),
+        capacity,
+        timeout: Delay::new(clock::now() + timeout),
+    }
+}
+
+/// Determines whether the given `input` looks like the start of a TLS
+/// record that holds a ClientHello, without regard to its contents.
+///
+/// Only the first six bytes of `input` are inspected.
+pub fn match_tls_record(input: &[u8]) -> Match {
+    // The record's type and version, its length, and then the type of the
+    // handshake message.
+    let expected: [fn(u8) -> bool; 6] = [
+        |b| b == 22, // ContentType::handshake
+        |b| b == 0x03,
+        |b| b == 0x01 || b == 0x03,
+        |_| true,
+        |_| true,
+        |b| b == 1, // HandshakeType::client_hello
+    ];
+
+    for (i, matches) in expected.iter().enumerate() {
+        match input.get(i) {
+            None => return Match::Incomplete,
+            Some(b) if !matches(*b) => return Match::NotMatched,
+            Some(_) => {}
+        }
+    }
+    Match::Matched
+}
+
 /// Determintes whether the given `input` looks like the start of a TLS
 /// connection that the proxy should terminate.
 ///
@@ -24,12 +205,7 @@ pub enum Match {
 /// record, which is what all reasonable implementations do. (If they were not
 /// to, they wouldn't interoperate with picky servers.)
 pub fn match_client_hello(input: &[u8], identity: &identity::Name) -> Match {
-    let r = untrusted::Input::from(input).read_all(untrusted::EndOfInput, |input| {
-        let r = extract_sni(input);
-        input.skip_to_end(); // Ignore anything after what we parsed.
-        r
-    });
-    match r {
+    match read_sni(input) {
         Ok(Some(sni)) => {
             let m = identity::Name::from_hostname(sni.as_slice_less_safe())
                 .map(|sni| {
@@ -47,38 +223,246 @@ pub fn match_client_hello(input: &[u8], identity: &identity::Name) -> Match {
             m
         }
         Ok(None) => {
+            trace!("match_client_hello: no SNI");
+            Match::NotMatched
+        }
+        Err(Invalid::Malformed) => {
             trace!("match_client_hello: failed to parse up to SNI");
             Match::NotMatched
         }
-        Err(untrusted::EndOfInput) => {
+        Err(Invalid::Incomplete) => {
             trace!("match_client_hello: needs more input");
             Match::Incomplete
         }
     }
 }
 
+/// Determines what `input` starts with, or returns `None` if more input is
+/// needed to tell.
+///
+/// A ClientHello without an SNI extension is `ClientHello::Sni(None)`. A
+/// complete TLS record that isn't a ClientHello we can parse is
+/// `ClientHello::Malformed`, so that clients that misbehave are
+/// distinguished both from those that don't send an SNI and from those that
+/// want a name that we don't serve.
+pub fn read_client_hello(input: &[u8]) -> Option<ClientHello> {
+    match match_tls_record(input) {
+        Match::NotMatched => Some(ClientHello::NotTls),
+        Match::Incomplete => None,
+        Match::Matched => match read_sni(input) {
+            Ok(Some(sni)) => match identity::Name::from_hostname(sni.as_slice_less_safe()) {
+                Ok(sni) => Some(ClientHello::Sni(Some(sni))),
+                Err(_) => Some(ClientHello::InvalidSni),
+            },
+            Ok(None) => Some(ClientHello::Sni(None)),
+            Err(Invalid::Malformed) => Some(ClientHello::Malformed),
+            Err(Invalid::Incomplete) => None,
+        },
+    }
+}
+
+/// Reads the raw SNI from the ClientHello at the start of `input`, as
+/// `extract_sni` does.
+fn read_sni(input: &[u8]) -> Result<Option<untrusted::Input>, Invalid> {
+    untrusted::Input::from(input).read_all(Invalid::Incomplete, |input| {
+        let r = extract_sni(input);
+        input.skip_to_end(); // Ignore anything after what we parsed.
+        r
+    })
+}
+
+/// Runs each of the ClientHello parsers over arbitrary `input`, for fuzzing.
+///
+/// The parsers must neither panic nor loop on any input.
+#[cfg(fuzzing)]
+pub fn fuzz_client_hello(input: &[u8]) {
+    let name = identity::Name::from_hostname(b"example.com").expect("name must be valid");
+    let _ = match_tls_record(input);
+    let _ = match_client_hello(input, &name);
+    let _ = read_client_hello(input);
+}
+
+// === impl ClientHelloError ===
+
+impl fmt::Display for ClientHelloError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientHelloError::TimedOut => write!(f, "timed out waiting for ClientHello"),
+            ClientHelloError::TooLarge => write!(f, "ClientHello exceeds peek capacity"),
+        }
+    }
+}
+
+impl Error for ClientHelloError {}
+
+impl From<ClientHelloError> for io::Error {
+    fn from(e: ClientHelloError) -> Self {
+        let kind = match e {
+            ClientHelloError::TimedOut => io::ErrorKind::TimedOut,
+            ClientHelloError::TooLarge => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+// === impl Invalid ===
+
+impl From<untrusted::EndOfInput> for Invalid {
+    fn from(_: untrusted::EndOfInput) -> Self {
+        Invalid::Incomplete
+    }
+}
+
+// === impl PeekClientHello ===
+
+impl<I> PeekClientHello<I> {
+    fn ready(&mut self, hello: ClientHello) -> (ClientHello, I, BytesMut) {
+        let io = self.io.take().expect("polled after ready");
+        (hello, io, self.peek_buf.take())
+    }
+}
+
+impl<I: AsyncRead> Future for PeekClientHello<I> {
+    /// What the stream starts with, followed by the stream and the bytes
+    /// that were read from it.
+    type Item = (ClientHello, I, BytesMut);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let hello = read_client_hello(self.peek_buf.as_ref());
+            if let Some(hello) = hello {
+                return Ok(Async::Ready(self.ready(hello)));
+            }
+
+            // Only a TLS record can still be incomplete once the buffer is
+            // full, since the record header is identified in a few bytes.
+            if self.peek_buf.len() >= self.capacity {
+                return Err(ClientHelloError::TooLarge.into());
+            }
+
+            let read = self
+                .io
+                .as_mut()
+                .expect("polled after ready")
+                .read_buf(&mut self.peek_buf)?;
+            match read {
+                Async::Ready(0) => {
+                    trace!("stream ended before a ClientHello was read");
+                    return Ok(Async::Ready(self.ready(ClientHello::NotTls)));
+                }
+                Async::Ready(_) => continue,
+                Async::NotReady => {}
+            }
+
+            match self.timeout.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {
+                    if match_tls_record(self.peek_buf.as_ref()) == Match::Matched {
+                        return Err(ClientHelloError::TimedOut.into());
+                    }
+                    trace!("timed out detecting protocol");
+                    return Ok(Async::Ready(self.ready(ClientHello::NotTls)));
+                }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+}
+
+// === impl SelectConfig ===
+
+/// Accepts all TLS streams with the same configuration.
+impl SelectConfig for Arc<Config> {
+    fn select_config(&self, _: Option<&identity::Name>) -> Arc<Config> {
+        self.clone()
+    }
+}
+
+// === impl Detect ===
+
+impl<I, S> Future for Detect<I, S>
+where
+    I: AsyncRead + AsyncWrite + Debug,
+    S: SelectConfig,
+{
+    type Item = Detected<I>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (hello, io, prefix) = match self.peek.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(peeked)) => peeked,
+            Err(e) => {
+                let rejected = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<ClientHelloError>())
+                    .cloned();
+                return match rejected {
+                    Some(reason) => {
+                        debug!("rejecting TLS stream: {}", reason);
+                        Ok(Async::Ready(Detected::Rejected(reason)))
+                    }
+                    None => Err(e),
+                };
+            }
+        };
+
+        let io = Prefixed::new(prefix.freeze(), io);
+        match hello {
+            ClientHello::Sni(sni) => {
+                match sni {
+                    Some(ref sni) => trace!(
+                        "detected TLS; sni={}",
+                        sni.log(identity::LogPolicy::current())
+                    ),
+                    None => trace!("detected TLS; sni=-"),
+                }
+                let config = self.config.select_config(sni.as_ref());
+                let accept = Acceptor::from(config).accept(io);
+                Ok(Async::Ready(Detected::Accepted { sni, accept }))
+            }
+            ClientHello::InvalidSni => {
+                debug!("client sent an invalid SNI; passing through as plaintext");
+                Ok(Async::Ready(Detected::Plaintext(io)))
+            }
+            ClientHello::Malformed => {
+                debug!("client sent a malformed ClientHello");
+                let config = self.config.select_config(None);
+                let accept = Acceptor::from(config).accept(io);
+                Ok(Async::Ready(Detected::Accepted { sni: None, accept }))
+            }
+            ClientHello::NotTls => {
+                trace!("detected plaintext");
+                Ok(Async::Ready(Detected::Plaintext(io)))
+            }
+        }
+    }
+}
+
 /// The result is `Ok(Some(hostname))` if the SNI extension was found, `Ok(None)`
-/// if we affirmatively rejected the input before we found the SNI extension, or
-/// `Err(EndOfInput)` if we don't have enough input to continue.
+/// if the ClientHello has no SNI extension, `Err(Invalid::Malformed)` if we
+/// affirmatively rejected the input before we found the SNI extension, or
+/// `Err(Invalid::Incomplete)` if we don't have enough input to continue.
 fn extract_sni<'a>(
     input: &mut untrusted::Reader<'a>,
-) -> Result<Option<untrusted::Input<'a>>, untrusted::EndOfInput> {
+) -> Result<Option<untrusted::Input<'a>>, Invalid> {
     // TLS ciphertext record header.
 
     if input.read_byte()? != 22 {
         // ContentType::handshake
-        return Ok(None);
+        return Err(Invalid::Malformed);
     }
     if input.read_byte()? != 0x03 {
         // legacy_record_version.major is always 0x03.
-        return Ok(None);
+        return Err(Invalid::Malformed);
     }
     {
         // legacy_record_version.minor may be 0x01 or 0x03 according to
         // https://tools.ietf.org/html/draft-ietf-tls-tls13-28#section-5.1
         let minor = input.read_byte()?;
         if minor != 0x01 && minor != 0x03 {
-            return Ok(None);
+            return Err(Invalid::Malformed);
         }
     }
 
@@ -86,27 +470,24 @@ fn extract_sni<'a>(
     let r = read_vector(input, |input| {
         if input.read_byte()? != 1 {
             // HandshakeType::client_hello
-            return Ok(None);
+            return Err(Invalid::Malformed);
         }
         // The length is a 24-bit big-endian value. Nobody (good) will never
         // send a value larger than 0xffff so treat it as a 0x00 followed
         // by vector<u16>
         if input.read_byte()? != 0 {
             // Most significant byte of the length
-            return Ok(None);
+            return Err(Invalid::Malformed);
         }
         read_vector(input, |input| {
             // version{.major,.minor} == {0x3, 0x3} for TLS 1.2 and later.
             if input.read_byte()? != 0x03 || input.read_byte()? != 0x03 {
-                return Ok(None);
+                return Err(Invalid::Malformed);
             }
 
             input.skip(32)?; // random
             skip_vector_u8(input)?; // session_id
-            if !skip_vector(input)? {
-                // cipher_suites
-                return Ok(None);
-            }
+            skip_vector(input)?; // cipher_suites
             skip_vector_u8(input)?; // compression_methods
 
             // Look for the SNI extension as specified in
@@ -129,7 +510,7 @@ fn extract_sni<'a>(
                             // other than a single `host_name` value.
                             if input.read_byte()? != 0 {
                                 // NameType::host_name
-                                return Ok(None);
+                                return Err(Invalid::Malformed);
                             }
                             // Return the value of the `HostName`.
                             read_vector(input, |input| Ok(Some(input.skip_to_end())))
@@ -153,12 +534,9 @@ fn extract_sni<'a>(
 
 /// Reads a `u16` vector, which is formatted as a big-endian `u16` length
 /// followed by that many bytes.
-fn read_vector<'a, F, T>(
-    input: &mut untrusted::Reader<'a>,
-    f: F,
-) -> Result<Option<T>, untrusted::EndOfInput>
+fn read_vector<'a, F, T>(input: &mut untrusted::Reader<'a>, f: F) -> Result<Option<T>, Invalid>
 where
-    F: Fn(&mut untrusted::Reader<'a>) -> Result<Option<T>, untrusted::EndOfInput>,
+    F: Fn(&mut untrusted::Reader<'a>) -> Result<Option<T>, Invalid>,
     T: 'a,
 {
     let length = read_u16(input)?;
@@ -168,29 +546,35 @@ where
     // at TLS traffic, and we're definitely not looking at proxy-terminated
     // traffic, so bail out early.
     if length > 8192 {
-        return Ok(None);
+        return Err(Invalid::Malformed);
     }
     let r = input.skip_and_get_input(usize::from(length))?;
-    r.read_all(untrusted::EndOfInput, f)
+
+    // The whole vector is available, so if its contents are truncated or
+    // have trailing bytes, then it's malformed; waiting for more input
+    // wouldn't help.
+    r.read_all(Invalid::Malformed, f)
+        .map_err(|_| Invalid::Malformed)
 }
 
 /// Like `read_vector` except the contents are ignored.
-fn skip_vector(input: &mut untrusted::Reader) -> Result<bool, untrusted::EndOfInput> {
-    let r = read_vector(input, |input| {
+fn skip_vector(input: &mut untrusted::Reader) -> Result<(), Invalid> {
+    read_vector(input, |input| {
         input.skip_to_end();
         Ok(Some(()))
-    });
-    r.map(|r| r.is_some())
+    })
+    .map(|_| ())
 }
 
 /// Like `skip_vector` for vectors with `u8` lengths.
-fn skip_vector_u8(input: &mut untrusted::Reader) -> Result<(), untrusted::EndOfInput> {
+fn skip_vector_u8(input: &mut untrusted::Reader) -> Result<(), Invalid> {
     let length = input.read_byte()?;
-    input.skip(usize::from(length))
+    input.skip(usize::from(length))?;
+    Ok(())
 }
 
 /// Read a big-endian-encoded `u16`.
-fn read_u16(input: &mut untrusted::Reader) -> Result<u16, untrusted::EndOfInput> {
+fn read_u16(input: &mut untrusted::Reader) -> Result<u16, Invalid> {
     let hi = input.read_byte()?;
     let lo = input.read_byte()?;
     Ok(u16::from(hi) << 8 | u16::from(lo))
@@ -237,6 +621,389 @@ mod tests {
         );
     }
 
+    /// A minimal ClientHello with no extensions.
+    static CLIENT_HELLO_WITHOUT_SNI: &[u8] = &[
+        0x16, 0x03, 0x01, 0x00, 0x2f, // record header
+        0x01, 0x00, 0x00, 0x2b, // handshake header
+        0x03, 0x03, // version
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // random
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,    // random
+        0x00, // session_id
+        0x00, 0x02, 0xc0, 0x2b, // cipher_suites
+        0x01, 0x00, // compression_methods
+        0x00, 0x00, // extensions
+    ];
+
+    fn name(n: &str) -> identity::Name {
+        identity::Name::from_hostname(n.as_bytes()).unwrap()
+    }
+
+    /// The `read_client_hello` result for a ClientHello with no valid SNI.
+    const NO_SNI: Option<ClientHello> = Some(ClientHello::Sni(None));
+
+    /// The `read_client_hello` result for a record that can't be parsed.
+    const MALFORMED: Option<ClientHello> = Some(ClientHello::Malformed);
+
+    #[test]
+    fn parses_sni() {
+        assert_eq!(
+            read_client_hello(VALID_EXAMPLE_COM),
+            Some(ClientHello::Sni(Some(name("example.com"))))
+        );
+        assert_eq!(read_client_hello(CLIENT_HELLO_WITHOUT_SNI), NO_SNI);
+        assert_eq!(read_client_hello(&VALID_EXAMPLE_COM[..16]), None);
+    }
+
+    #[test]
+    fn detects_client_hello_without_sni() {
+        assert_eq!(read_client_hello(CLIENT_HELLO_WITHOUT_SNI), NO_SNI);
+        assert_ne!(read_client_hello(VALID_EXAMPLE_COM), NO_SNI);
+        assert_eq!(read_client_hello(&CLIENT_HELLO_WITHOUT_SNI[..16]), None);
+        assert_eq!(
+            read_client_hello(b"GET / HTTP/1.1\r\n"),
+            Some(ClientHello::NotTls)
+        );
+    }
+
+    #[test]
+    fn detects_invalid_sni() {
+        let invalid = Some(ClientHello::InvalidSni);
+        assert_ne!(read_client_hello(VALID_EXAMPLE_COM), invalid);
+        assert_ne!(read_client_hello(CLIENT_HELLO_WITHOUT_SNI), invalid);
+        assert_ne!(read_client_hello(&VALID_EXAMPLE_COM[..16]), invalid);
+        for sni in &[&b"example.com."[..], b"ex ample.com"] {
+            let hello = client_hello_with_extensions(&sni_extensions(sni));
+            assert_eq!(read_client_hello(&hello), invalid);
+        }
+    }
+
+    /// Builds a ClientHello record like `CLIENT_HELLO_WITHOUT_SNI` with the
+    /// given extensions block, including its length.
+    fn client_hello_with_extensions(extensions: &[u8]) -> Vec<u8> {
+        let mut body = CLIENT_HELLO_WITHOUT_SNI[9..].to_vec();
+        let len = body.len();
+        body.truncate(len - 2);
+        body.extend_from_slice(extensions);
+
+        let mut hello = vec![0x01, 0x00];
+        hello.extend_from_slice(&[(body.len() >> 8) as u8, body.len() as u8]);
+        hello.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&[(hello.len() >> 8) as u8, hello.len() as u8]);
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    /// Builds an extensions block with only a server_name extension naming
+    /// `name`, including the block's length.
+    fn sni_extensions(name: &[u8]) -> Vec<u8> {
+        let u16_be = |n: usize| vec![(n >> 8) as u8, n as u8];
+        let n = name.len();
+        let mut extensions = u16_be(n + 9);
+        extensions.extend_from_slice(&[0x00, 0x00]); // ExtensionType::server_name
+        extensions.extend(u16_be(n + 5));
+        extensions.extend(u16_be(n + 3)); // server_name_list
+        extensions.push(0x00); // NameType::host_name
+        extensions.extend(u16_be(n));
+        extensions.extend_from_slice(name);
+        extensions
+    }
+
+    #[test]
+    fn client_hello_with_extensions_matches_fixture() {
+        assert_eq!(
+            client_hello_with_extensions(&[0x00, 0x00]),
+            CLIENT_HELLO_WITHOUT_SNI
+        );
+    }
+
+    #[test]
+    fn malformed_truncated_record_header() {
+        for i in 0..5 {
+            assert_eq!(
+                read_client_hello(&VALID_EXAMPLE_COM[..i]),
+                None,
+                "a {}-byte record header must be incomplete",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_zero_length_extensions() {
+        // An empty server_name extension.
+        let empty_sni = client_hello_with_extensions(&[0x00, 0x04, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(read_client_hello(&empty_sni), MALFORMED);
+
+        // A server_name extension with an empty server_name_list.
+        let empty_list =
+            client_hello_with_extensions(&[0x00, 0x06, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00]);
+        assert_eq!(read_client_hello(&empty_list), MALFORMED);
+
+        // A server_name extension with an empty host_name.
+        let empty_name = client_hello_with_extensions(&[
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(
+            read_client_hello(&empty_name),
+            Some(ClientHello::InvalidSni)
+        );
+
+        // An extensions block that ends partway through an extension.
+        let partial = client_hello_with_extensions(&[0x00, 0x01, 0x00]);
+        assert_eq!(read_client_hello(&partial), MALFORMED);
+    }
+
+    #[test]
+    fn malformed_oversized_lengths() {
+        // A record that is larger than any reasonable ClientHello.
+        let mut huge_record = CLIENT_HELLO_WITHOUT_SNI.to_vec();
+        huge_record[3] = 0xff;
+        assert_eq!(read_client_hello(&huge_record), MALFORMED);
+
+        // A handshake message with a 24-bit length.
+        let mut huge_handshake = CLIENT_HELLO_WITHOUT_SNI.to_vec();
+        huge_handshake[6] = 0x01;
+        assert_eq!(read_client_hello(&huge_handshake), MALFORMED);
+
+        // A handshake message that is longer than its record.
+        let mut long_handshake = CLIENT_HELLO_WITHOUT_SNI.to_vec();
+        long_handshake[8] += 1;
+        assert_eq!(read_client_hello(&long_handshake), MALFORMED);
+
+        // An extension that is longer than the extensions block.
+        let long_extension = client_hello_with_extensions(&[0x00, 0x04, 0x00, 0x00, 0x00, 0x10]);
+        assert_eq!(read_client_hello(&long_extension), MALFORMED);
+
+        // A host_name that is longer than its server_name_list.
+        let long_name = client_hello_with_extensions(&[
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x05, 0x00, 0x03, 0x00, 0x00, 0x10,
+        ]);
+        assert_eq!(read_client_hello(&long_name), MALFORMED);
+
+        // A cipher_suites vector that is larger than any reasonable
+        // ClientHello.
+        let mut huge_cipher_suites = CLIENT_HELLO_WITHOUT_SNI.to_vec();
+        huge_cipher_suites[44] = 0xff;
+        assert_eq!(read_client_hello(&huge_cipher_suites), MALFORMED);
+    }
+
+    #[test]
+    fn malformed_oversized_extension_lengths() {
+        // An extension that is larger than any reasonable ClientHello,
+        // followed by one that would otherwise be skipped.
+        let huge_extension = client_hello_with_extensions(&[
+            0x00, 0x08, 0x00, 0x0a, 0xff, 0xff, 0x00, 0x0b, 0x00, 0x00,
+        ]);
+        assert_eq!(read_client_hello(&huge_extension), MALFORMED);
+
+        // An extension that is larger than any reasonable ClientHello,
+        // followed by an SNI that must not be read.
+        let mut extensions = sni_extensions(b"example.com");
+        let len = extensions.len() - 2 + 4;
+        extensions[0] = (len >> 8) as u8;
+        extensions[1] = len as u8;
+        for (i, b) in [0x00, 0x0a, 0x20, 0x01].iter().enumerate() {
+            extensions.insert(2 + i, *b);
+        }
+        let huge_then_sni = client_hello_with_extensions(&extensions);
+        assert_eq!(read_client_hello(&huge_then_sni), MALFORMED);
+
+        // An SNI extension that is larger than any reasonable ClientHello.
+        let huge_sni = client_hello_with_extensions(&[0x00, 0x04, 0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(read_client_hello(&huge_sni), MALFORMED);
+
+        // Oversized lengths are rejected without waiting for more input.
+        let name = name("example.com");
+        assert_eq!(
+            match_client_hello(&huge_extension, &name),
+            Match::NotMatched
+        );
+    }
+
+    #[test]
+    fn malformed_corruptions_do_not_panic() {
+        let name = name("example.com");
+        let corpus = vec![
+            VALID_EXAMPLE_COM.to_vec(),
+            CLIENT_HELLO_WITHOUT_SNI.to_vec(),
+            b"GET / HTTP/1.1\r\n".to_vec(),
+            vec![0x16, 0x03, 0x03, 0xff, 0xff, 0x01],
+        ];
+        for input in &corpus {
+            for i in 0..input.len() {
+                for b in &[0x00, 0x01, 0x7f, 0x80, 0xff] {
+                    let mut corrupt = input.clone();
+                    corrupt[i] = *b;
+                    let _ = match_client_hello(&corrupt, &name);
+                    let _ = read_client_hello(&corrupt);
+                    let _ = read_client_hello(&corrupt[..i]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn detects_tls_record() {
+        assert_eq!(match_tls_record(&VALID_EXAMPLE_COM[..3]), Match::Incomplete);
+        assert_eq!(match_tls_record(&VALID_EXAMPLE_COM[..6]), Match::Matched);
+        assert_eq!(match_tls_record(b"GE"), Match::NotMatched);
+    }
+
+    #[test]
+    fn detect() {
+        use std::io::{Cursor, Read};
+        use tokio::runtime::current_thread::Runtime;
+        use transport::tls::listen::empty_config;
+
+        let mut rt = Runtime::new().expect("runtime");
+        let mut detect = |input: &[u8]| {
+            let io = Cursor::new(input.to_vec());
+            rt.block_on(super::detect(io, empty_config(), Duration::from_secs(1)))
+                .expect("detect must succeed")
+        };
+
+        let accepted_sni = |d: Detected<_>| match d {
+            Detected::Accepted { sni, .. } => sni,
+            Detected::Plaintext(_) => panic!("a ClientHello must be detected as TLS"),
+            Detected::Rejected(r) => panic!("a ClientHello must not be rejected: {}", r),
+        };
+        let is_plaintext = |d: Detected<_>| match d {
+            Detected::Plaintext(_) => true,
+            _ => false,
+        };
+
+        // The stream ends before the ClientHello is complete.
+        assert!(is_plaintext(detect(&VALID_EXAMPLE_COM[..16])));
+
+        assert_eq!(
+            accepted_sni(detect(VALID_EXAMPLE_COM)),
+            Some(name("example.com"))
+        );
+        let example_org = client_hello_with_extensions(&sni_extensions(b"example.org"));
+        assert_eq!(
+            accepted_sni(detect(&example_org[..])),
+            Some(name("example.org"))
+        );
+        assert_eq!(accepted_sni(detect(CLIENT_HELLO_WITHOUT_SNI)), None);
+
+        // As with `Listen`, streams with an invalid SNI are passed through.
+        for invalid in &[&b"example.com."[..], b"ex ample.com", b""] {
+            let hello = client_hello_with_extensions(&sni_extensions(invalid));
+            assert!(
+                is_plaintext(detect(&hello[..])),
+                "a ClientHello with an invalid SNI must be passed through"
+            );
+        }
+
+        let http = b"GET /TheProject.html HTTP/1.0\r\n\r\n";
+        match detect(&http[..]) {
+            Detected::Plaintext(mut io) => {
+                let mut read = Vec::new();
+                io.read_to_end(&mut read).expect("read");
+                assert_eq!(&read[..], &http[..], "peeked bytes must not be consumed");
+            }
+            _ => panic!("HTTP must be detected as plaintext"),
+        }
+
+        match detect(&[]) {
+            Detected::Plaintext(_) => {}
+            _ => panic!("an empty stream must be detected as plaintext"),
+        }
+    }
+
+    /// A stream that sends its input and then never becomes ready again.
+    #[derive(Debug)]
+    struct Stalled(Option<Vec<u8>>);
+
+    impl io::Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.take() {
+                Some(input) => {
+                    buf[..input.len()].copy_from_slice(&input);
+                    Ok(input.len())
+                }
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl io::Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Stalled {}
+
+    impl AsyncWrite for Stalled {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn detect_rejects_incomplete_client_hello() {
+        use tokio::runtime::current_thread::Runtime;
+        use transport::tls::listen::empty_config;
+
+        let mut rt = Runtime::new().expect("runtime");
+        let timeout = Duration::from_millis(10);
+
+        // The client stalls partway through its ClientHello.
+        let io = Stalled(Some(VALID_EXAMPLE_COM[..16].to_vec()));
+        match rt.block_on(super::detect(io, empty_config(), timeout)) {
+            Ok(Detected::Rejected(r)) => assert_eq!(r, ClientHelloError::TimedOut),
+            Ok(_) => panic!("a stalled ClientHello must be rejected"),
+            Err(e) => panic!("detect must succeed: {}", e),
+        }
+
+        // A server that speaks first never sees a ClientHello.
+        match rt.block_on(super::detect(Stalled(None), empty_config(), timeout)) {
+            Ok(Detected::Plaintext(_)) => {}
+            Ok(_) => panic!("an idle stream must be detected as plaintext"),
+            Err(e) => panic!("detect must succeed: {}", e),
+        }
+    }
+
+    #[test]
+    fn peek_rejects_large_client_hello() {
+        use std::io::Cursor;
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut rt = Runtime::new().expect("runtime");
+
+        // A TLS record that claims to be 4KB long.
+        let mut large = vec![0x16, 0x03, 0x01, 0x10, 0x00, 0x01];
+        large.resize(1024, 0);
+        let peek = peek_client_hello(Cursor::new(large), 256, DEFAULT_PEEK_TIMEOUT);
+        let e = rt.block_on(peek).map(|_| ()).expect_err("peek must fail");
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref()
+                .and_then(|e| e.downcast_ref::<ClientHelloError>()),
+            Some(&ClientHelloError::TooLarge)
+        );
+
+        // The same ClientHello fits in the default capacity, so it's only
+        // incomplete once the stream ends.
+        let mut large = vec![0x16, 0x03, 0x01, 0x10, 0x00, 0x01];
+        large.resize(1024, 0);
+        let peek = peek_client_hello(
+            Cursor::new(large),
+            DEFAULT_PEEK_CAPACITY,
+            DEFAULT_PEEK_TIMEOUT,
+        );
+        let (hello, _, buf) = rt.block_on(peek).expect("peek must succeed");
+        assert_eq!(hello, ClientHello::NotTls);
+        assert_eq!(buf.len(), 1024);
+    }
+
     fn check_all_prefixes(expected_match: Match, identity: &str, input: &[u8]) {
         assert!(expected_match == Match::Matched || expected_match == Match::NotMatched);
 
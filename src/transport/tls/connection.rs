@@ -1,8 +1,11 @@
 use bytes::{Buf, BytesMut};
 use std::net::SocketAddr;
+use std::time::Duration;
 use std::{cmp, io};
 use tokio::prelude::*;
 
+use super::idle_timeout::IdleTimeout;
+use super::rustls;
 use identity;
 use transport::io::internal::Io;
 use transport::tls::{ReasonForNoIdentity, ReasonForNoPeerName};
@@ -29,7 +32,7 @@ pub struct Connection {
     peek_buf: BytesMut,
 
     /// Whether or not the connection is secured with TLS.
-    tls_peer_identity: super::PeerIdentity,
+    tls: Tls,
 
     /// If true, the proxy should attempt to detect the protocol for this
     /// connection. If false, protocol detection should be skipped.
@@ -37,6 +40,66 @@ pub struct Connection {
 
     /// The connection's original destination address, if there was one.
     orig_dst: Option<SocketAddr>,
+
+    /// The number of application bytes read from and written to the
+    /// connection, if they're being counted.
+    byte_counts: Option<ByteCounts>,
+}
+
+/// Whether a `Connection` is secured with TLS.
+///
+/// Only TLS connections have a peer identity, so plaintext connections can't
+/// be given one.
+#[derive(Debug)]
+enum Tls {
+    /// The connection isn't secured with TLS, for the given reason.
+    Plain(ReasonForNoIdentity),
+
+    /// The connection completed a TLS handshake.
+    ///
+    /// The peer's identity is determined once, when the handshake completes,
+    /// from the peer's verified certificate, so that `peer_identity` needn't
+    /// parse the certificate again.
+    Negotiated(Negotiated, super::PeerIdentity),
+}
+
+/// Totals of the application bytes that have passed through a `Connection`.
+///
+/// These are the plaintext bytes seen by the proxy, so they don't include
+/// TLS framing.
+#[derive(Clone, Copy, Debug, Default)]
+struct ByteCounts {
+    read: u64,
+    written: u64,
+}
+
+/// Describes the parameters negotiated by a completed TLS handshake.
+#[derive(Clone, Debug)]
+pub(super) struct Negotiated {
+    cipher_suite: Option<&'static rustls::SupportedCipherSuite>,
+    protocol_version: Option<rustls::ProtocolVersion>,
+
+    /// Whether the client presented a certificate. This is only set for
+    /// accepted connections.
+    client_crt_present: bool,
+
+    /// The SPIFFE ID in the client's certificate, if any. This is only set
+    /// for accepted connections.
+    client_spiffe_id: Option<identity::SpiffeId>,
+
+    /// How long the handshake took, from when it started until it completed.
+    handshake_duration: Duration,
+
+    /// Whether the handshake resumed a session. Rustls doesn't expose this
+    /// (as of 0.15), so it's observed by the `resumption` module.
+    resumed: bool,
+
+    /// Whether the connection was accepted, rather than established by us.
+    accepted: bool,
+
+    /// The name of the certificate that we presented, if any. This is the
+    /// name that our certificate was certified for, i.e. one of its SANs.
+    local_name: Option<identity::Name>,
 }
 
 // === impl Connection ===
@@ -50,11 +113,12 @@ impl Connection {
         Connection {
             io: BoxedIo::new(io),
             peek_buf: BytesMut::new(),
-            tls_peer_identity: Conditional::None(ReasonForNoIdentity::NoPeerName(
+            tls: Tls::Plain(ReasonForNoIdentity::NoPeerName(
                 ReasonForNoPeerName::NotHttp,
             )),
             detect_protocol: false,
             orig_dst: None,
+            byte_counts: None,
         }
     }
 
@@ -66,22 +130,25 @@ impl Connection {
         Connection {
             io: BoxedIo::new(io),
             peek_buf,
-            tls_peer_identity: Conditional::None(why_no_tls),
+            tls: Tls::Plain(why_no_tls),
             detect_protocol: true,
             orig_dst: None,
+            byte_counts: None,
         }
     }
 
     pub(super) fn tls(
         io: BoxedIo,
-        tls_peer_identity: Conditional<identity::Name, super::ReasonForNoPeerName>,
+        negotiated: Negotiated,
+        tls_peer_identity: Conditional<super::PeerId, super::ReasonForNoPeerName>,
     ) -> Self {
         Connection {
             io: io,
             peek_buf: BytesMut::new(),
-            tls_peer_identity: tls_peer_identity.map_reason(|r| r.into()),
+            tls: Tls::Negotiated(negotiated, tls_peer_identity.map_reason(|r| r.into())),
             detect_protocol: true,
             orig_dst: None,
+            byte_counts: None,
         }
     }
 
@@ -89,22 +156,269 @@ impl Connection {
         Self { orig_dst, ..self }
     }
 
+    /// Fails reads and writes with `io::ErrorKind::TimedOut` once the
+    /// connection has been idle for `timeout`.
+    ///
+    /// The timeout is reset whenever a read or write makes progress. By
+    /// default, connections never time out.
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        Self {
+            io: BoxedIo::new(IdleTimeout::new(self.io, timeout)),
+            ..self
+        }
+    }
+
+    /// Counts the bytes read from and written to the connection, so that
+    /// they can be reported by `byte_counts`.
+    ///
+    /// Only bytes read or written after this is called are counted. By
+    /// default, bytes aren't counted.
+    pub fn with_byte_counts(self) -> Self {
+        Self {
+            byte_counts: Some(ByteCounts::default()),
+            ..self
+        }
+    }
+
+    /// Returns the number of application bytes `(read, written)` over the
+    /// connection.
+    ///
+    /// For TLS connections, these are decrypted bytes rather than bytes on
+    /// the wire. This is always `(0, 0)` unless `with_byte_counts` was used.
+    pub fn byte_counts(&self) -> (u64, u64) {
+        self.byte_counts
+            .map(|c| (c.read, c.written))
+            .unwrap_or((0, 0))
+    }
+
+    fn count_read(&mut self, n: usize) {
+        if let Some(ref mut c) = self.byte_counts {
+            c.read += n as u64;
+        }
+    }
+
+    fn count_written(&mut self, n: usize) {
+        if let Some(ref mut c) = self.byte_counts {
+            c.written += n as u64;
+        }
+    }
+
+    /// Tells a TLS peer that no more data will be written, by queueing a
+    /// close_notify alert. Reads may continue, so that the connection can be
+    /// drained.
+    ///
+    /// The alert is written by `poll_shutdown`. This does nothing for
+    /// plaintext connections.
+    pub fn send_close_notify(&mut self) {
+        self.io.send_close_notify()
+    }
+
+    /// Shuts down the write side of the connection, after flushing a TLS
+    /// close_notify alert so that the peer sees a clean teardown.
+    ///
+    /// If `send_close_notify` hasn't been called, the close_notify is sent
+    /// now.
+    pub fn poll_shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(self)
+    }
+
     pub fn original_dst_addr(&self) -> Option<SocketAddr> {
         self.orig_dst
     }
 
-    pub fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
         self.io.local_addr()
     }
 
+    /// Returns the address of the remote peer, for both TLS and plaintext
+    /// connections.
+    pub fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.peer_addr()
+    }
+
     pub fn should_detect_protocol(&self) -> bool {
         self.detect_protocol
     }
+
+    /// Returns the cipher suite negotiated by the TLS handshake.
+    ///
+    /// This is `None` for plaintext connections.
+    pub fn negotiated_cipher_suite(&self) -> Option<&'static rustls::SupportedCipherSuite> {
+        self.negotiated().and_then(|n| n.cipher_suite)
+    }
+
+    /// Returns the TLS protocol version negotiated by the TLS handshake.
+    ///
+    /// This is `None` for plaintext connections.
+    pub fn protocol_version(&self) -> Option<rustls::ProtocolVersion> {
+        self.negotiated().and_then(|n| n.protocol_version)
+    }
+
+    /// Returns how long the TLS handshake took to complete, as measured from
+    /// when the handshake started rather than when the connection was
+    /// established.
+    ///
+    /// This is `None` for plaintext connections.
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.negotiated().map(|n| n.handshake_duration)
+    }
+
+    /// Returns true if the TLS handshake resumed a previous session, so
+    /// that resumption hit rates may be measured.
+    ///
+    /// Accepted connections are resumed when the client's session was found
+    /// in the cache configured by `listen::with_session_cache`. Connections
+    /// made by clients are resumed when the server resumed the session that
+    /// was remembered by `client::with_resumption`; they're not when the
+    /// server declined it and completed a full handshake.
+    ///
+    /// This is false for plaintext connections and full handshakes.
+    pub fn is_resumed(&self) -> bool {
+        self.negotiated().map(|n| n.resumed).unwrap_or(false)
+    }
+
+    /// Returns true if this is an accepted TLS connection and the client
+    /// presented a certificate.
+    pub fn client_cert_present(&self) -> bool {
+        self.negotiated()
+            .map(|n| n.client_crt_present)
+            .unwrap_or(false)
+    }
+
+    /// Returns the SPIFFE ID of the client, if this is an accepted TLS
+    /// connection and the client's certificate has one.
+    ///
+    /// Clients that are identified only by a SPIFFE ID have it as their
+    /// `peer_identity`; this also returns the SPIFFE IDs of clients that
+    /// are identified by a DNS name.
+    pub fn client_spiffe_id(&self) -> Option<&identity::SpiffeId> {
+        self.negotiated().and_then(|n| n.client_spiffe_id.as_ref())
+    }
+
+    fn negotiated(&self) -> Option<&Negotiated> {
+        match self.tls {
+            Tls::Negotiated(ref n, _) => Some(n),
+            Tls::Plain(_) => None,
+        }
+    }
 }
 
+// === impl Negotiated ===
+
+impl Negotiated {
+    pub(super) fn new<S: rustls::Session>(session: &S) -> Self {
+        Self {
+            cipher_suite: session.get_negotiated_ciphersuite(),
+            protocol_version: session.get_protocol_version(),
+            client_crt_present: false,
+            client_spiffe_id: None,
+            handshake_duration: Duration::from_secs(0),
+            resumed: false,
+            accepted: false,
+            local_name: None,
+        }
+    }
+
+    pub(super) fn accepted(session: &rustls::ServerSession) -> Self {
+        use super::rustls::Session;
+
+        let crts = session.get_peer_certificates().unwrap_or_default();
+        Self {
+            client_crt_present: !crts.is_empty(),
+            client_spiffe_id: crts.first().and_then(identity::spiffe_id_from_cert),
+            accepted: true,
+            ..Self::new(session)
+        }
+    }
+
+    /// Records how long the handshake took.
+    pub(super) fn with_handshake_duration(self, handshake_duration: Duration) -> Self {
+        Self {
+            handshake_duration,
+            ..self
+        }
+    }
+
+    /// Records whether the handshake resumed a session.
+    pub(super) fn with_resumed(self, resumed: bool) -> Self {
+        Self { resumed, ..self }
+    }
+
+    /// Records the name of the certificate that we presented, if any.
+    pub(super) fn with_local_name(self, local_name: Option<identity::Name>) -> Self {
+        Self { local_name, ..self }
+    }
+
+    pub(super) fn client_crt_present(&self) -> bool {
+        self.client_crt_present
+    }
+
+    pub(super) fn client_spiffe_id(&self) -> Option<&identity::SpiffeId> {
+        self.client_spiffe_id.as_ref()
+    }
+}
+
+/// Returns the identity determined by the handshake; this is cheap for DNS
+/// names, since `identity::Name`s are reference-counted.
 impl super::HasPeerIdentity for Connection {
     fn peer_identity(&self) -> super::PeerIdentity {
-        self.tls_peer_identity.clone()
+        match self.tls {
+            Tls::Negotiated(_, ref peer) => peer.clone(),
+            Tls::Plain(why) => Conditional::None(why),
+        }
+    }
+}
+
+/// Unlike the peer's identity, the status is known for TLS connections whose
+/// client didn't present a certificate.
+impl super::HasStatus for Connection {
+    fn tls_status(&self) -> super::Status {
+        match self.tls {
+            Tls::Negotiated(..) => Conditional::Some(()),
+            Tls::Plain(why) => Conditional::None(why),
+        }
+    }
+}
+
+/// Accepted connections are identified as the server by our own certificate
+/// and as the client by the peer's; connections that we establish are the
+/// reverse.
+impl super::HasIdentity for Connection {
+    fn identity_state(&self) -> super::IdentityState {
+        use super::{IdentityState, Mode};
+
+        let (n, peer) = match self.tls {
+            Tls::Negotiated(ref n, ref peer) => (n, peer.clone()),
+            Tls::Plain(why) => {
+                return IdentityState {
+                    status: Conditional::None(why),
+                    server: Conditional::None(why),
+                    client: Conditional::None(why),
+                };
+            }
+        };
+
+        let local = match n.local_name {
+            Some(ref name) => super::peer_identity(name.clone()),
+            // We had no certificate to present.
+            None => Conditional::None(ReasonForNoIdentity::Disabled),
+        };
+        let mutual = if n.accepted {
+            n.client_crt_present
+        } else {
+            n.local_name.is_some()
+        };
+        let mode = if mutual { Mode::Mutual } else { Mode::OneWay };
+        let (server, client) = if n.accepted {
+            (local, peer)
+        } else {
+            (peer, local)
+        };
+        IdentityState {
+            status: Conditional::Some(mode),
+            server,
+            client,
+        }
     }
 }
 
@@ -118,7 +432,9 @@ impl io::Read for Connection {
         let peeked_len = self.peek_buf.len();
 
         if peeked_len == 0 {
-            self.io.read(buf)
+            let n = self.io.read(buf)?;
+            self.count_read(n);
+            Ok(n)
         } else {
             let len = cmp::min(buf.len(), peeked_len);
             buf[..len].copy_from_slice(&self.peek_buf.as_ref()[..len]);
@@ -129,6 +445,7 @@ impl io::Read for Connection {
             if peeked_len == len {
                 self.peek_buf = Default::default();
             }
+            self.count_read(len);
             Ok(len)
         }
     }
@@ -142,7 +459,9 @@ impl AsyncRead for Connection {
 
 impl io::Write for Connection {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.io.write(buf)
+        let n = self.io.write(buf)?;
+        self.count_written(n);
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -164,7 +483,9 @@ impl AsyncWrite for Connection {
     }
 
     fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
-        self.io.write_buf(buf)
+        let n = try_ready!(self.io.write_buf(buf));
+        self.count_written(n);
+        Ok(Async::Ready(n))
     }
 }
 
@@ -192,3 +513,91 @@ impl Peek for Connection {
         self.peek_buf.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::rustls;
+    use super::Negotiated;
+    use identity::test_util::*;
+    use std::sync::Arc;
+    use transport::tls::{client::HasConfig as HasClientConfig, listen::HasConfig};
+
+    #[test]
+    fn client_crt_present() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let server = server.tls_server_config();
+
+        // Client authentication is optional, so the handshake succeeds without
+        // a client certificate.
+        let anonymous = FOO_NS1.trust_anchors().tls_client_config();
+        let (_, session) =
+            handshake(&anonymous, &server, &FOO_NS1.name()).expect("handshake must succeed");
+        assert!(!Negotiated::accepted(&session).client_crt_present());
+
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let (_, session) = handshake(&client.tls_client_config(), &server, &FOO_NS1.name())
+            .expect("handshake must succeed");
+        assert!(Negotiated::accepted(&session).client_crt_present());
+    }
+
+    #[test]
+    fn client_spiffe_id() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let server = server.tls_server_config();
+
+        let client = FOO_NS1_SPIFFE.validate().expect("foo.ns1 must be valid");
+        let (_, session) = handshake(&client.tls_client_config(), &server, &FOO_NS1.name())
+            .expect("handshake must succeed");
+        let id = Negotiated::accepted(&session)
+            .client_spiffe_id
+            .expect("client must have a SPIFFE ID");
+        assert_eq!(id.to_string(), "spiffe://cluster.local/ns/ns1/sa/foo");
+
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let (_, session) = handshake(&client.tls_client_config(), &server, &FOO_NS1.name())
+            .expect("handshake must succeed");
+        assert!(Negotiated::accepted(&session).client_spiffe_id.is_none());
+    }
+
+    #[test]
+    fn negotiated_protocol_version() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let client = BAR_NS1.validate().expect("bar.ns1 must be valid");
+
+        // The proxy's configurations only enable TLS 1.2.
+        let (client_session, server_session) = handshake(
+            &client.tls_client_config(),
+            &server.tls_server_config(),
+            &FOO_NS1.name(),
+        )
+        .expect("handshake must succeed");
+        for n in &[
+            Negotiated::new(&client_session),
+            Negotiated::accepted(&server_session),
+        ] {
+            assert_eq!(n.protocol_version, Some(rustls::ProtocolVersion::TLSv1_2));
+        }
+
+        // Endpoints that support TLS 1.3, as Rustls does by default, use it.
+        let versions = vec![
+            rustls::ProtocolVersion::TLSv1_3,
+            rustls::ProtocolVersion::TLSv1_2,
+        ];
+        let mut client_config = client.tls_client_config().as_ref().clone();
+        client_config.versions = versions.clone();
+        let mut server_config = server.tls_server_config().as_ref().clone();
+        server_config.versions = versions;
+        let (client_session, server_session) = handshake(
+            &Arc::new(client_config),
+            &Arc::new(server_config),
+            &FOO_NS1.name(),
+        )
+        .expect("handshake must succeed");
+        for n in &[
+            Negotiated::new(&client_session),
+            Negotiated::accepted(&server_session),
+        ] {
+            assert_eq!(n.protocol_version, Some(rustls::ProtocolVersion::TLSv1_3));
+        }
+    }
+}
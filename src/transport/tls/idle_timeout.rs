@@ -0,0 +1,182 @@
+use bytes::Buf;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio_timer::{clock, Delay};
+
+use transport::io::internal::Io;
+use transport::{AddrInfo, SetKeepalive};
+
+/// Wraps an I/O stream so that it fails once it has been idle for too long.
+///
+/// The timeout is reset whenever a read or write makes progress. Once the
+/// stream has been idle for the whole timeout, reads and writes that would
+/// block fail with `io::ErrorKind::TimedOut` instead.
+#[derive(Debug)]
+pub(super) struct IdleTimeout<I> {
+    io: I,
+    timeout: Duration,
+    idle: Delay,
+}
+
+// === impl IdleTimeout ===
+
+impl<I> IdleTimeout<I> {
+    pub(super) fn new(io: I, timeout: Duration) -> Self {
+        Self {
+            io,
+            timeout,
+            idle: Delay::new(clock::now() + timeout),
+        }
+    }
+
+    /// Resets the timeout if an operation made progress, or fails if it
+    /// would block and the stream has been idle for too long.
+    fn check<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        let would_block = match result {
+            Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+
+        if !would_block {
+            self.idle.reset(clock::now() + self.timeout);
+            return result;
+        }
+
+        match self.idle.poll() {
+            Ok(Async::NotReady) => result,
+            Ok(Async::Ready(())) => {
+                debug!("connection idle for {:?}", self.timeout);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection idle timeout",
+                ))
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+
+    /// Like `check`, for operations that return `Async::NotReady` rather
+    /// than `io::ErrorKind::WouldBlock`.
+    fn check_poll<T>(&mut self, poll: Poll<T, io::Error>) -> Poll<T, io::Error> {
+        let result = match poll {
+            Ok(Async::Ready(t)) => Ok(t),
+            Ok(Async::NotReady) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(e),
+        };
+        match self.check(result) {
+            Ok(t) => Ok(Async::Ready(t)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<I: io::Read> io::Read for IdleTimeout<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.io.read(buf);
+        self.check(result)
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for IdleTimeout<I> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<I: io::Write> io::Write for IdleTimeout<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.io.write(buf);
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.io.flush();
+        self.check(result)
+    }
+}
+
+impl<I: AsyncWrite> AsyncWrite for IdleTimeout<I> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let poll = self.io.shutdown();
+        self.check_poll(poll)
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        let poll = self.io.write_buf(buf);
+        self.check_poll(poll)
+    }
+}
+
+impl<I: AddrInfo> AddrInfo for IdleTimeout<I> {
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.io.peer_addr()
+    }
+
+    fn get_original_dst(&self) -> Option<SocketAddr> {
+        self.io.get_original_dst()
+    }
+}
+
+impl<I: SetKeepalive> SetKeepalive for IdleTimeout<I> {
+    fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.io.keepalive()
+    }
+
+    fn set_keepalive(&mut self, ka: Option<Duration>) -> io::Result<()> {
+        self.io.set_keepalive(ka)
+    }
+}
+
+impl<I: Io> Io for IdleTimeout<I> {
+    fn shutdown_write(&mut self) -> Result<(), io::Error> {
+        self.io.shutdown_write()
+    }
+
+    fn write_buf_erased(&mut self, mut buf: &mut Buf) -> Poll<usize, io::Error> {
+        self.write_buf(&mut buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        self.io.send_close_notify()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// A stream that never becomes ready.
+    #[derive(Debug)]
+    struct Stalled;
+
+    impl io::Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Stalled {}
+
+    #[test]
+    fn times_out_stalled_reads() {
+        let mut rt = Runtime::new().expect("runtime");
+        let mut io = IdleTimeout::new(Stalled, Duration::from_millis(10));
+
+        let err = rt
+            .block_on(future::poll_fn(|| {
+                let mut buf = [0; 8];
+                io.poll_read(&mut buf)
+            }))
+            .expect_err("read must time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
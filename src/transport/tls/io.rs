@@ -10,10 +10,17 @@ use transport::{AddrInfo, SetKeepalive};
 
 /// Wraps a TLS stream to implement Io.
 #[derive(Debug)]
-pub(super) struct TlsIo<S, C>(TlsStream<S, C>)
+pub(super) struct TlsIo<S, C>
 where
     S: Debug,
-    C: Debug;
+    C: Debug,
+{
+    stream: TlsStream<S, C>,
+
+    /// Whether a close_notify has been queued by `send_close_notify`, so that
+    /// shutting down must not queue another.
+    close_notify_sent: bool,
+}
 
 // === imp TlsIo ===
 
@@ -22,8 +29,11 @@ where
     S: Debug,
     C: Debug,
 {
-    fn from(s: TlsStream<S, C>) -> Self {
-        TlsIo(s)
+    fn from(stream: TlsStream<S, C>) -> Self {
+        TlsIo {
+            stream,
+            close_notify_sent: false,
+        }
     }
 }
 
@@ -33,7 +43,7 @@ where
     C: Session + Debug,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        self.stream.read(buf)
     }
 }
 
@@ -43,7 +53,7 @@ where
     C: Session + Debug,
 {
     unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
-        self.0.prepare_uninitialized_buffer(buf)
+        self.stream.prepare_uninitialized_buffer(buf)
     }
 }
 
@@ -53,11 +63,11 @@ where
     C: Session + Debug,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.stream.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        self.stream.flush()
     }
 }
 
@@ -67,11 +77,21 @@ where
     C: Session + Debug,
 {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        self.0.shutdown()
+        if !self.close_notify_sent {
+            return self.stream.shutdown();
+        }
+
+        // The close_notify has already been queued, so only flush it.
+        match io::Write::flush(&mut self.stream) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        self.stream.get_mut().0.shutdown()
     }
 
     fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
-        self.0.write_buf(buf)
+        self.stream.write_buf(buf)
     }
 }
 
@@ -81,11 +101,15 @@ where
     C: Session + Debug,
 {
     fn local_addr(&self) -> Result<SocketAddr, io::Error> {
-        self.0.get_ref().0.local_addr()
+        self.stream.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.stream.get_ref().0.peer_addr()
     }
 
     fn get_original_dst(&self) -> Option<SocketAddr> {
-        self.0.get_ref().0.get_original_dst()
+        self.stream.get_ref().0.get_original_dst()
     }
 }
 
@@ -95,11 +119,11 @@ where
     C: Session + Debug,
 {
     fn keepalive(&self) -> io::Result<Option<::std::time::Duration>> {
-        self.0.get_ref().0.keepalive()
+        self.stream.get_ref().0.keepalive()
     }
 
     fn set_keepalive(&mut self, ka: Option<::std::time::Duration>) -> io::Result<()> {
-        self.0.get_mut().0.set_keepalive(ka)
+        self.stream.get_mut().0.set_keepalive(ka)
     }
 }
 
@@ -109,10 +133,17 @@ where
     C: Session + Debug,
 {
     fn shutdown_write(&mut self) -> Result<(), io::Error> {
-        self.0.get_mut().0.shutdown_write()
+        self.stream.get_mut().0.shutdown_write()
     }
 
     fn write_buf_erased(&mut self, mut buf: &mut Buf) -> Poll<usize, io::Error> {
-        self.0.write_buf(&mut buf)
+        self.stream.write_buf(&mut buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        if !self.close_notify_sent {
+            self.stream.get_mut().1.send_close_notify();
+            self.close_notify_sent = true;
+        }
     }
 }
@@ -4,27 +4,110 @@ use futures::{
     stream, Async, Future, IntoFuture, Poll, Stream,
 };
 use indexmap::IndexSet;
+use std::fmt;
 use std::io;
-use std::net::{SocketAddr, TcpListener as StdListener};
+use std::net::{IpAddr, SocketAddr, TcpListener as StdListener};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::{
-    io::AsyncRead,
     net::{TcpListener, TcpStream},
     reactor::Handle,
 };
 
 use super::{rustls, tokio_rustls, webpki};
 use identity;
+use tokio_timer::{clock, Delay};
 use transport::prefixed::Prefixed;
-use transport::tls::{self, conditional_accept, Acceptor, Connection, ReasonForNoPeerName};
+use transport::tls::{
+    self, conditional_accept, Acceptor, Connection, HandshakeFields, Negotiated,
+    ReasonForNoIdentity, ReasonForNoPeerName,
+};
 use transport::{set_nodelay_or_warn, AddrInfo, BoxedIo, GetOriginalDst};
 use Conditional;
 
 pub use super::rustls::ServerConfig as Config;
 
+/// Determines whether TLS clients must authenticate with a certificate.
+///
+/// Unless client authentication is disabled, clients are asked for a
+/// certificate, and a client that presents one must present a valid one. When
+/// clients don't present a certificate, their connections have no peer
+/// identity (`ReasonForNoPeerName::NotProvidedByRemote`). Clients are
+/// identified by their certificate's DNS name or, failing that, its SPIFFE
+/// ID; when it has neither, their connections have no peer identity either
+/// (`ReasonForNoPeerName::NoNameInCertificate`). When client
+/// authentication is disabled, connections have no peer identity because none
+/// was requested (`ReasonForNoPeerName::ClientAuthDisabled`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClientAuth {
+    /// Clients that don't present a certificate are accepted.
+    Optional,
+
+    /// Clients that don't present a certificate are rejected.
+    Required,
+
+    /// Clients are not asked for a certificate, so connections never have a
+    /// peer identity.
+    None,
+}
+
+/// Observes the outcome of each TLS handshake attempted by a `Listen`.
+///
+/// `started` is invoked as each handshake begins, and then exactly one of the
+/// other methods is invoked as it resolves. Connections that are not upgraded
+/// to TLS are not reported, except for those that start a ClientHello but
+/// don't complete it within the peek limits.
+pub trait HandshakeMetrics: Send + Sync {
+    /// The client sent a ClientHello for our name, and the handshake began;
+    /// or the client started a ClientHello that couldn't be read.
+    fn started(&self) {}
+
+    /// The handshake completed.
+    fn succeeded(&self);
+
+    /// The handshake failed because the client's certificate was missing or
+    /// invalid.
+    fn failed_client_auth(&self);
+
+    /// The handshake failed because the client rejected our certificate.
+    fn failed_server_auth(&self);
+
+    /// The handshake, or the ClientHello that starts it, timed out.
+    fn timed_out(&self);
+
+    /// The handshake failed for any other reason.
+    fn failed(&self);
+}
+
+pub use super::conditional_accept::{
+    ClientHelloError, DEFAULT_PEEK_CAPACITY, DEFAULT_PEEK_TIMEOUT,
+};
+
+/// The default limit on how long a TLS handshake may take once the
+/// ClientHello has been read.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub trait HasConfig {
     fn tls_server_name(&self) -> identity::Name;
     fn tls_server_config(&self) -> Arc<Config>;
+
+    /// Returns how `tls_server_config` authenticates clients.
+    ///
+    /// Defaults to `ClientAuth::Optional`, which is how server configs have
+    /// always authenticated clients.
+    fn tls_client_auth(&self) -> ClientAuth {
+        ClientAuth::Optional
+    }
+
+    /// Returns why `tls_server_config` can't accept TLS connections yet, if
+    /// it can't, e.g. because credentials haven't been loaded.
+    ///
+    /// Connections that aren't upgraded to TLS report this as the reason
+    /// that their peer has no identity, rather than blaming the peer.
+    /// Defaults to `None`.
+    fn tls_unavailable(&self) -> Option<ReasonForNoIdentity> {
+        None
+    }
 }
 
 /// Produces a server config that fails to handshake all connections.
@@ -33,25 +116,93 @@ pub fn empty_config() -> Arc<Config> {
     Arc::new(Config::new(verifier))
 }
 
+/// Builds a configuration for TLS servers that present `key` and verify
+/// clients' certificates against `anchors`, as `client_auth` requires.
+///
+/// Sessions are not resumed unless `with_session_cache` is used.
+pub fn server_config(
+    anchors: &identity::TrustAnchors,
+    key: &identity::CrtKey,
+    client_auth: ClientAuth,
+) -> Config {
+    server_config_with_resolver(anchors, key.server_cert_resolver(), client_auth)
+}
+
+/// Like `server_config`, but presents the certificates that `resolver`
+/// resolves, so that a `CrtKey`'s configuration may be built along with the
+/// key.
+pub fn server_config_with_resolver(
+    anchors: &identity::TrustAnchors,
+    resolver: Arc<dyn rustls::ResolvesServerCert>,
+    client_auth: ClientAuth,
+) -> Config {
+    let mut c = Config::new(anchors.client_cert_verifier(client_auth));
+    c.versions = identity::TLS_VERSIONS.to_vec();
+    c.cert_resolver = resolver;
+    c.session_storage = Arc::new(rustls::NoServerSessionStorage {});
+    c
+}
+
+/// Restricts the cipher suites that may be negotiated to `allowed`, in order
+/// of preference.
+///
+/// Fails if `allowed` is empty or if it includes suites that can't be used
+/// with our keys.
+pub fn with_ciphersuites(
+    mut config: Config,
+    allowed: &[&'static rustls::SupportedCipherSuite],
+) -> Result<Config, tls::InvalidCipherSuites> {
+    config.ciphersuites = tls::ciphersuites(allowed)?;
+    Ok(config)
+}
+
+/// Enables session resumption, caching up to `sessions` sessions so that
+/// clients that reconnect may skip the full handshake.
+///
+/// Connections that resume a cached session report that they were resumed
+/// by `Connection::is_resumed`.
+pub fn with_session_cache(mut config: Config, sessions: usize) -> Config {
+    let sessions = rustls::ServerSessionMemoryCache::new(sessions);
+    config.session_storage = Arc::new(tls::resumption::ServerSessions(sessions));
+    config
+}
+
 pub struct Listen<L, G = ()> {
     inner: Option<StdListener>,
     local_addr: SocketAddr,
     tls: tls::Conditional<L>,
     disable_protocol_detection_ports: IndexSet<u16>,
     get_original_dst: G,
+    handshake_metrics: Arc<dyn HandshakeMetrics>,
+    peek_capacity: usize,
+    peek_timeout: Duration,
+    handshake_timeout: Duration,
+    max_concurrent_handshakes: usize,
+    skip_tls_for_loopback: bool,
 }
 
 /// A server socket that is in the process of conditionally upgrading to TLS.
 enum Handshake {
     Init(Option<Inner>),
-    Upgrade(super::Accept<Prefixed<TcpStream>>),
+    Upgrade {
+        future: super::Accept<Prefixed<TcpStream>>,
+        metrics: Arc<dyn HandshakeMetrics>,
+        client_auth: ClientAuth,
+        fields: HandshakeFields,
+        started: Instant,
+        timeout: Delay,
+        resumed: bool,
+    },
 }
 
 struct Inner {
-    socket: TcpStream,
+    peek: conditional_accept::PeekClientHello<TcpStream>,
     config: Arc<Config>,
+    client_auth: ClientAuth,
     server_name: identity::Name,
-    peek_buf: BytesMut,
+    unavailable: Option<ReasonForNoIdentity>,
+    metrics: Arc<dyn HandshakeMetrics>,
+    handshake_timeout: Duration,
 }
 
 // === impl Listen ===
@@ -66,6 +217,12 @@ impl<L: HasConfig> Listen<L> {
             tls,
             disable_protocol_detection_ports: IndexSet::new(),
             get_original_dst: (),
+            handshake_metrics: Arc::new(()),
+            peek_capacity: DEFAULT_PEEK_CAPACITY,
+            peek_timeout: DEFAULT_PEEK_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_concurrent_handshakes: usize::max_value(),
+            skip_tls_for_loopback: false,
         })
     }
 
@@ -79,6 +236,12 @@ impl<L: HasConfig> Listen<L> {
             tls: self.tls,
             disable_protocol_detection_ports: self.disable_protocol_detection_ports,
             get_original_dst,
+            handshake_metrics: self.handshake_metrics,
+            peek_capacity: self.peek_capacity,
+            peek_timeout: self.peek_timeout,
+            handshake_timeout: self.handshake_timeout,
+            max_concurrent_handshakes: self.max_concurrent_handshakes,
+            skip_tls_for_loopback: self.skip_tls_for_loopback,
         }
     }
 }
@@ -94,6 +257,80 @@ impl<L: HasConfig, G> Listen<L, G> {
         }
     }
 
+    /// Reports the outcome of each TLS handshake to `handshake_metrics`.
+    pub fn with_handshake_metrics(self, handshake_metrics: Arc<dyn HandshakeMetrics>) -> Self {
+        Self {
+            handshake_metrics,
+            ..self
+        }
+    }
+
+    /// Limits how much of each connection is buffered while waiting for a
+    /// complete ClientHello.
+    ///
+    /// Connections whose ClientHello is larger fail with
+    /// `ClientHelloError::TooLarge`.
+    pub fn with_peek_capacity(self, peek_capacity: usize) -> Self {
+        Self {
+            peek_capacity,
+            ..self
+        }
+    }
+
+    /// Limits how long to wait for each connection's complete ClientHello,
+    /// so that clients can't hold connections open by sending it slowly.
+    ///
+    /// Connections that start a ClientHello but take longer fail with
+    /// `ClientHelloError::TimedOut`; connections that send nothing that looks
+    /// like TLS in that time are passed through as plaintext.
+    pub fn with_peek_timeout(self, peek_timeout: Duration) -> Self {
+        Self {
+            peek_timeout,
+            ..self
+        }
+    }
+
+    /// Limits how long each TLS handshake may take once its ClientHello has
+    /// been read, so that clients can't hold connections open by stalling
+    /// the rest of the handshake.
+    ///
+    /// Handshakes that take longer fail with `io::ErrorKind::TimedOut`.
+    pub fn with_handshake_timeout(self, handshake_timeout: Duration) -> Self {
+        Self {
+            handshake_timeout,
+            ..self
+        }
+    }
+
+    /// Limits how many accepted connections may be handshaking at once.
+    ///
+    /// Once the limit is reached, no more connections are accepted until a
+    /// handshake completes, so that a flood of connections can't exhaust the
+    /// proxy's memory. By default, or if `max_concurrent_handshakes` is 0,
+    /// there is no limit.
+    pub fn with_max_concurrent_handshakes(self, max_concurrent_handshakes: usize) -> Self {
+        let max_concurrent_handshakes = match max_concurrent_handshakes {
+            0 => usize::max_value(),
+            n => n,
+        };
+        Self {
+            max_concurrent_handshakes,
+            ..self
+        }
+    }
+
+    /// Accepts connections from loopback addresses without attempting a TLS
+    /// handshake, so that they have no peer identity
+    /// (`ReasonForNoPeerName::Loopback`).
+    ///
+    /// By default, loopback connections are handled like any other.
+    pub fn without_tls_for_loopback(self) -> Self {
+        Self {
+            skip_tls_for_loopback: true,
+            ..self
+        }
+    }
+
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
@@ -158,6 +395,7 @@ impl<L: HasConfig, G> Listen<L, G> {
             .inner
             .take()
             .expect("listener shouldn't be taken twice");
+        let max_concurrent_handshakes = self.max_concurrent_handshakes;
         future::lazy(move || {
             // Create the TCP listener lazily, so that it's not bound to a
             // reactor until the future is run. This will avoid
@@ -174,7 +412,7 @@ impl<L: HasConfig, G> Listen<L, G> {
 
             incoming
                 .take(connection_limit)
-                .and_then(move |(socket, remote_addr)| {
+                .map(move |(socket, remote_addr)| {
                     // TODO: On Linux and most other platforms it would be better
                     // to set the `TCP_NODELAY` option on the bound socket and
                     // then have the listening sockets inherit it. However, that
@@ -186,6 +424,8 @@ impl<L: HasConfig, G> Listen<L, G> {
                     self.new_conn(socket, remote_addr)
                         .map(move |conn| (conn, remote_addr))
                 })
+                // Stops accepting connections while the limit is reached.
+                .buffer_unordered(max_concurrent_handshakes)
                 .then(|r| {
                     future::ok(match r {
                         Ok(r) => Some(r),
@@ -225,13 +465,34 @@ impl<L: HasConfig, G> Listen<L, G> {
                     Connection::without_protocol_detection(socket).with_original_dst(Some(addr));
                 Either::A(future::ok(conn))
             }
+            // The connection is from this host, which doesn't need TLS.
+            (dst, Conditional::Some(_))
+                if self.skip_tls_for_loopback && is_loopback(remote_addr) =>
+            {
+                debug!(
+                    "accepted connection from {} to {:?}; skipping TLS for loopback",
+                    remote_addr, dst,
+                );
+                let conn = Connection::plain(socket, ReasonForNoPeerName::Loopback.into())
+                    .with_original_dst(dst);
+                Either::A(future::ok(conn))
+            }
             // TLS is enabled. Try to accept a TLS handshake.
             (dst, Conditional::Some(tls)) => {
                 debug!(
                     "accepted connection from {} to {:?}; attempting TLS handshake",
                     remote_addr, dst,
                 );
-                let handshake = Handshake::new(socket, tls).map(move |c| c.with_original_dst(dst));
+                let metrics = self.handshake_metrics.clone();
+                let handshake = Handshake::new(
+                    socket,
+                    tls,
+                    metrics,
+                    self.peek_capacity,
+                    self.peek_timeout,
+                    self.handshake_timeout,
+                )
+                .map(move |c| c.with_original_dst(dst));
                 Either::B(Either::A(handshake))
             }
             // TLS is disabled. Return a new plaintext connection.
@@ -247,6 +508,19 @@ impl<L: HasConfig, G> Listen<L, G> {
     }
 }
 
+/// Returns true if `addr` is a loopback address, including IPv4 loopback
+/// addresses that are mapped to IPv6 by a dual-stack socket.
+fn is_loopback(addr: SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            let mapped = s[..5] == [0; 5] && s[5] == 0xffff;
+            ip.is_loopback() || (mapped && (s[6] >> 8) == 127)
+        }
+    }
+}
+
 impl<L> GetOriginalDst for Listen<L, ()> {
     fn get_original_dst(&self, _socket: &AddrInfo) -> Option<SocketAddr> {
         None
@@ -259,18 +533,105 @@ impl<L, G: GetOriginalDst> GetOriginalDst for Listen<L, G> {
     }
 }
 
+// === impl HandshakeMetrics ===
+
+/// Discards handshake outcomes.
+impl HandshakeMetrics for () {
+    fn succeeded(&self) {}
+    fn failed_client_auth(&self) {}
+    fn failed_server_auth(&self) {}
+    fn timed_out(&self) {}
+    fn failed(&self) {}
+}
+
 // === impl Handshake ===
 
 impl Handshake {
-    fn new<T: HasConfig>(socket: TcpStream, tls: &T) -> Self {
+    fn new<T: HasConfig>(
+        socket: TcpStream,
+        tls: &T,
+        metrics: Arc<dyn HandshakeMetrics>,
+        peek_capacity: usize,
+        peek_timeout: Duration,
+        handshake_timeout: Duration,
+    ) -> Self {
         Handshake::Init(Some(Inner {
-            socket,
+            peek: conditional_accept::peek_client_hello(socket, peek_capacity, peek_timeout),
             server_name: tls.tls_server_name(),
             config: tls.tls_server_config(),
-            peek_buf: BytesMut::with_capacity(8192),
+            client_auth: tls.tls_client_auth(),
+            unavailable: tls.tls_unavailable(),
+            metrics,
+            handshake_timeout,
         }))
     }
 
+    /// Identifies a client that completed the handshake by its certificate's
+    /// DNS `name` or, failing that, its SPIFFE ID.
+    fn client_peer_identity(
+        name: Option<identity::Name>,
+        spiffe_id: Option<&identity::SpiffeId>,
+        crt_present: bool,
+        client_auth: ClientAuth,
+    ) -> Conditional<tls::PeerId, ReasonForNoPeerName> {
+        if let Some(name) = name {
+            return Conditional::Some(tls::PeerId::Name(name));
+        }
+        if let Some(id) = spiffe_id {
+            debug!("client is identified by SPIFFE ID {}", id);
+            return Conditional::Some(tls::PeerId::Spiffe(id.clone()));
+        }
+        if crt_present {
+            debug!("client certificate does not include a DNS name or SPIFFE ID");
+        }
+        Conditional::None(Self::no_client_identity(client_auth, crt_present))
+    }
+
+    /// Explains why a client that completed the handshake has no identity.
+    ///
+    /// A client that presented a certificate without a DNS name or SPIFFE ID
+    /// is distinguished from one that presented no certificate at all.
+    fn no_client_identity(client_auth: ClientAuth, crt_present: bool) -> ReasonForNoPeerName {
+        if crt_present {
+            ReasonForNoPeerName::NoNameInCertificate
+        } else if client_auth == ClientAuth::None {
+            ReasonForNoPeerName::ClientAuthDisabled
+        } else {
+            ReasonForNoPeerName::NotProvidedByRemote
+        }
+    }
+
+    /// Reports a failed handshake to `metrics`, returning the outcome that
+    /// was reported.
+    fn record_failure(metrics: &dyn HandshakeMetrics, e: &io::Error) -> &'static str {
+        if e.kind() == io::ErrorKind::TimedOut {
+            metrics.timed_out();
+            return "timed_out";
+        }
+
+        // Rustls errors are wrapped in I/O errors. Certificate errors are
+        // raised when we reject the client's certificate; when the client
+        // rejects ours, it tells us so with an alert.
+        match e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::TLSError>())
+        {
+            Some(rustls::TLSError::NoCertificatesPresented)
+            | Some(rustls::TLSError::WebPKIError(_)) => {
+                metrics.failed_client_auth();
+                "failed_client_auth"
+            }
+            Some(rustls::TLSError::AlertReceived(alert)) if tls::is_certificate_alert(*alert) => {
+                metrics.failed_server_auth();
+                "failed_server_auth"
+            }
+            _ => {
+                metrics.failed();
+                "failed"
+            }
+        }
+    }
+
     fn client_identity<S>(
         tls: &tokio_rustls::TlsStream<S, rustls::ServerSession>,
     ) -> Option<identity::Name> {
@@ -280,6 +641,8 @@ impl Handshake {
         let (_io, session) = tls.get_ref();
         let certs = session.get_peer_certificates()?;
         let c = certs.first().map(rustls::Certificate::as_ref)?;
+        #[cfg(test)]
+        identity::test_util::count_crt_parse();
         let end_cert = webpki::EndEntityCert::from(untrusted::Input::from(c)).ok()?;
         let dns_names = end_cert.dns_names().ok()?;
 
@@ -296,37 +659,102 @@ impl Future for Handshake {
         loop {
             *self = match self {
                 Handshake::Init(ref mut inner) => {
-                    let poll_match = inner
-                        .as_mut()
-                        .expect("polled after ready")
-                        .poll_match_client_hello();
-
-                    match try_ready!(poll_match) {
-                        conditional_accept::Match::Matched => {
-                            trace!("upgrading accepted connection to TLS");
-                            inner.take().unwrap().into_tls_upgrade()
+                    let peeked = inner.as_mut().expect("polled after ready").peek.poll();
+                    let (hello, socket, peek_buf) = match peeked {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(peeked)) => peeked,
+                        Err(e) => {
+                            // The client started a ClientHello, but it
+                            // couldn't be read.
+                            let metrics = &inner.as_ref().expect("polled after ready").metrics;
+                            metrics.started();
+                            let outcome = Self::record_failure(&**metrics, &e);
+                            debug!("tls handshake failed; outcome={} error={}", outcome, e);
+                            return Err(e);
                         }
-                        conditional_accept::Match::NotMatched => {
-                            trace!("passing through accepted connection without TLS");
-                            let conn = inner.take().unwrap().into_plaintext();
-                            return Ok(Async::Ready(conn));
-                        }
-                        conditional_accept::Match::Incomplete => {
-                            continue;
+                    };
+                    let inner = inner.take().expect("polled after ready");
+
+                    // Only clients that request our name are terminated.
+                    let matched = match hello {
+                        conditional_accept::ClientHello::Sni(Some(ref sni)) => {
+                            *sni == inner.server_name
                         }
+                        _ => false,
+                    };
+                    if matched {
+                        trace!("upgrading accepted connection to TLS");
+                        inner.into_tls_upgrade(socket, peek_buf)
+                    } else {
+                        trace!("passing through accepted connection without TLS");
+                        let conn = inner.into_plaintext(&hello, socket, peek_buf);
+                        return Ok(Async::Ready(conn));
                     }
                 }
-                Handshake::Upgrade(future) => {
-                    let io = try_ready!(future.poll());
-                    let client_id = Self::client_identity(&io)
-                        .map(Conditional::Some)
-                        .unwrap_or_else(|| {
-                            Conditional::None(super::ReasonForNoPeerName::NotProvidedByRemote)
-                        });
-                    trace!("accepted TLS connection; client={:?}", client_id);
+                Handshake::Upgrade {
+                    future,
+                    metrics,
+                    client_auth,
+                    fields,
+                    started,
+                    timeout,
+                    resumed,
+                } => {
+                    let (poll, observed) = tls::resumption::observe(|| future.poll());
+                    *resumed = *resumed || observed.found;
+                    let poll = match poll {
+                        Ok(Async::NotReady) => match timeout.poll() {
+                            Ok(Async::NotReady) => return Ok(Async::NotReady),
+                            Ok(Async::Ready(())) => Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "TLS handshake timed out",
+                            )),
+                            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+                        },
+                        poll => poll,
+                    };
+                    let io = match poll {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(io)) => {
+                            metrics.succeeded();
+                            io
+                        }
+                        Err(e) => {
+                            let outcome = Self::record_failure(&**metrics, &e);
+                            debug!(
+                                "tls handshake failed; {} outcome={} error={}",
+                                fields, outcome, e
+                            );
+                            return Err(e);
+                        }
+                    };
+                    let negotiated = Negotiated::accepted(io.get_ref().1)
+                        .with_handshake_duration(clock::now() - *started)
+                        .with_resumed(*resumed)
+                        .with_local_name(Some(fields.sni.clone()));
+                    let client_id = Self::client_peer_identity(
+                        Self::client_identity(&io),
+                        negotiated.client_spiffe_id(),
+                        negotiated.client_crt_present(),
+                        *client_auth,
+                    );
+                    match client_id {
+                        Conditional::Some(tls::PeerId::Name(ref name)) => trace!(
+                            "accepted TLS connection; client={}",
+                            name.log(identity::LogPolicy::current())
+                        ),
+                        ref client_id => trace!("accepted TLS connection; client={:?}", client_id),
+                    }
 
                     let io = BoxedIo::new(super::TlsIo::from(io));
-                    return Ok(Async::Ready(Connection::tls(io, client_id)));
+                    let conn = Connection::tls(io, negotiated, client_id);
+                    debug!(
+                        "tls handshake succeeded; {} outcome=succeeded version={:?} suite={:?}",
+                        fields,
+                        conn.protocol_version(),
+                        conn.negotiated_cipher_suite().map(|cs| cs.suite),
+                    );
+                    return Ok(Async::Ready(conn));
                 }
             }
         }
@@ -334,35 +762,1186 @@ impl Future for Handshake {
 }
 
 impl Inner {
-    /// Polls the underlying socket for more data and buffers it.
-    ///
-    /// The buffer is matched for a TLS client hello message.
-    ///
-    /// `NotMatched` is returned if the underlying socket has closed.
-    fn poll_match_client_hello(&mut self) -> Poll<conditional_accept::Match, io::Error> {
-        let sz = try_ready!(self.socket.read_buf(&mut self.peek_buf));
-        if sz == 0 {
-            // XXX: It is ambiguous whether this is the start of a TLS handshake or not.
-            // For now, resolve the ambiguity in favor of plaintext. TODO: revisit this
-            // when we add support for TLS policy.
-            return Ok(conditional_accept::Match::NotMatched.into());
+    fn into_tls_upgrade(self, socket: TcpStream, peek_buf: BytesMut) -> Handshake {
+        self.metrics.started();
+
+        // The ClientHello matched, so the client requested our name.
+        let fields = HandshakeFields {
+            peer: socket.peer_addr().ok(),
+            sni: self.server_name,
+        };
+        let started = clock::now();
+        let future =
+            Acceptor::from(self.config.clone()).accept(Prefixed::new(peek_buf.freeze(), socket));
+        Handshake::Upgrade {
+            future,
+            metrics: self.metrics,
+            client_auth: self.client_auth,
+            fields,
+            started,
+            timeout: Delay::new(started + self.handshake_timeout),
+            resumed: false,
         }
+    }
 
-        let buf = self.peek_buf.as_ref();
-        Ok(conditional_accept::match_client_hello(buf, &self.server_name).into())
+    fn into_plaintext(
+        self,
+        hello: &conditional_accept::ClientHello,
+        socket: TcpStream,
+        peek_buf: BytesMut,
+    ) -> Connection {
+        if let Some(why) = self.unavailable {
+            debug!("identity is not available: {}", why);
+            return Connection::plain_with_peek_buf(socket, peek_buf, why);
+        }
+
+        // Clients that don't send SNI can't be served a certificate, which is
+        // usually a client misconfiguration.
+        let reason = match hello {
+            conditional_accept::ClientHello::Sni(None) => {
+                debug!("client did not send SNI");
+                ReasonForNoPeerName::NoSniProvided
+            }
+            conditional_accept::ClientHello::InvalidSni => {
+                debug!("client sent an invalid SNI");
+                ReasonForNoPeerName::InvalidSni
+            }
+            conditional_accept::ClientHello::Malformed => {
+                debug!("client sent a malformed ClientHello");
+                ReasonForNoPeerName::MalformedClientHello
+            }
+            _ => ReasonForNoPeerName::NotProvidedByRemote,
+        };
+        Connection::plain_with_peek_buf(socket, peek_buf, reason.into())
     }
+}
 
-    fn into_tls_upgrade(self) -> Handshake {
-        let future = Acceptor::from(self.config.clone())
-            .accept(Prefixed::new(self.peek_buf.freeze(), self.socket));
-        Handshake::Upgrade(future)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use identity::test_util::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio;
+    use transport::tls::{client::HasConfig as _ClientHasConfig, Connector};
+
+    #[derive(Debug, Default)]
+    struct Counts {
+        succeeded: AtomicUsize,
+        failed_client_auth: AtomicUsize,
+        failed_server_auth: AtomicUsize,
+        timed_out: AtomicUsize,
+        failed: AtomicUsize,
     }
 
-    fn into_plaintext(self) -> Connection {
-        Connection::plain_with_peek_buf(
-            self.socket,
-            self.peek_buf,
-            ReasonForNoPeerName::NotProvidedByRemote.into(),
+    impl HandshakeMetrics for Counts {
+        fn succeeded(&self) {
+            self.succeeded.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn failed_client_auth(&self) {
+            self.failed_client_auth.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn failed_server_auth(&self) {
+            self.failed_server_auth.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn timed_out(&self) {
+            self.timed_out.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn failed(&self) {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn server_config_client_auth() {
+        use self::rustls::Session;
+
+        let anchors = FOO_NS1.trust_anchors();
+        let key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let anonymous = anchors.tls_client_config();
+        let authenticated = Arc::new(tls::client::client_config(&anchors, &key));
+        let connect = |client: &Arc<rustls::ClientConfig>, client_auth| {
+            let server = Arc::new(server_config(&anchors, &key, client_auth));
+            handshake(client, &server, &FOO_NS1.name())
+                .map(|(_, server)| server.get_peer_certificates().is_some())
+        };
+
+        assert_eq!(connect(&anonymous, ClientAuth::None).ok(), Some(false));
+        assert_eq!(connect(&authenticated, ClientAuth::None).ok(), Some(false));
+
+        assert_eq!(connect(&anonymous, ClientAuth::Optional).ok(), Some(false));
+        assert_eq!(
+            connect(&authenticated, ClientAuth::Optional).ok(),
+            Some(true)
+        );
+
+        assert!(connect(&anonymous, ClientAuth::Required).is_err());
+        assert_eq!(
+            connect(&authenticated, ClientAuth::Required).ok(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn pins_ciphersuites() {
+        use self::rustls::internal::msgs::enums::CipherSuite;
+        use self::rustls::Session;
+
+        let suite = |s: CipherSuite| {
+            *rustls::ALL_CIPHERSUITES
+                .iter()
+                .find(|cs| cs.suite == s)
+                .expect("suite must be supported")
+        };
+        let aes = suite(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256);
+        let chacha = suite(CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256);
+        let rsa = suite(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+
+        let anchors = FOO_NS1.trust_anchors();
+        let key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let client = |allowed: &[&'static rustls::SupportedCipherSuite]| {
+            let c = tls::client::client_config(&anchors, &key);
+            tls::client::with_ciphersuites(c, allowed).map(Arc::new)
+        };
+        let server = |allowed: &[&'static rustls::SupportedCipherSuite]| {
+            let c = server_config(&anchors, &key, ClientAuth::Optional);
+            with_ciphersuites(c, allowed).map(Arc::new)
+        };
+
+        let (_, session) = handshake(
+            &client(&[chacha]).unwrap(),
+            &server(&[chacha, aes]).unwrap(),
+            &FOO_NS1.name(),
         )
+        .expect("handshake must succeed");
+        let negotiated = session
+            .get_negotiated_ciphersuite()
+            .expect("cipher suite must be negotiated");
+        assert_eq!(negotiated.suite, chacha.suite);
+
+        assert!(
+            handshake(
+                &client(&[aes]).unwrap(),
+                &server(&[chacha]).unwrap(),
+                &FOO_NS1.name()
+            )
+            .is_err(),
+            "peers without a common cipher suite must not handshake"
+        );
+
+        let e = server(&[])
+            .map(|_| ())
+            .expect_err("an empty allowlist must be rejected");
+        assert!(
+            match e {
+                tls::InvalidCipherSuites::Empty => true,
+                _ => false,
+            },
+            "unexpected error: {:?}",
+            e
+        );
+        match client(&[aes, rsa])
+            .map(|_| ())
+            .expect_err("unsupported cipher suites must be rejected")
+        {
+            tls::InvalidCipherSuites::Unsupported(s) => assert_eq!(s, rsa.suite),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn resumes_sessions() {
+        use self::rustls::Session;
+
+        /// Completes a handshake, returning the number of bytes that the
+        /// server sent.
+        fn handshake_len(client: &Arc<rustls::ClientConfig>, server: &Arc<Config>) -> usize {
+            let mut client = rustls::ClientSession::new(client, FOO_NS1.name().as_dns_name_ref());
+            let mut server = rustls::ServerSession::new(server);
+            let mut len = 0;
+            while client.is_handshaking() || server.is_handshaking() {
+                let mut buf = Vec::new();
+                while client.wants_write() {
+                    client.write_tls(&mut buf).expect("write_tls");
+                }
+                server.read_tls(&mut &buf[..]).expect("read_tls");
+                server
+                    .process_new_packets()
+                    .expect("client messages must be valid");
+
+                buf.clear();
+                while server.wants_write() {
+                    server.write_tls(&mut buf).expect("write_tls");
+                }
+                len += buf.len();
+                client.read_tls(&mut &buf[..]).expect("read_tls");
+                client
+                    .process_new_packets()
+                    .expect("server messages must be valid");
+            }
+            len
+        }
+
+        let anchors = FOO_NS1.trust_anchors();
+        let key = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let connect = |resume: bool| {
+            let mut client = tls::client::client_config(&anchors, &key);
+            let mut server = server_config(&anchors, &key, ClientAuth::Optional);
+            if resume {
+                client = tls::client::with_resumption(client, &anchors, 8);
+                server = with_session_cache(server, 8);
+            }
+            let (client, server) = (Arc::new(client), Arc::new(server));
+            (
+                handshake_len(&client, &server),
+                handshake_len(&client, &server),
+            )
+        };
+
+        // A resumed handshake doesn't include the server's certificate.
+        let crt_len = FOO_NS1.crt_der().len();
+        let (first, second) = connect(false);
+        assert!(
+            second + crt_len > first,
+            "sessions must not be resumed by default: first={}, second={}",
+            first,
+            second
+        );
+        let (first, second) = connect(true);
+        assert!(
+            second + crt_len < first,
+            "session must be resumed: first={}, second={}",
+            first,
+            second
+        );
+    }
+
+    #[test]
+    fn shutdown_sends_close_notify() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), (conn, _)| {
+                // Wait for the client, so that the close_notify isn't read
+                // along with the handshake.
+                tokio::io::read_exact(conn, [0; 2]).and_then(|(mut conn, buf)| {
+                    assert_eq!(&buf, b"hi");
+                    conn.send_close_notify();
+                    future::poll_fn(move || conn.poll_shutdown())
+                })
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let config = BAR_NS1
+            .validate()
+            .expect("bar.ns1 must be valid")
+            .tls_client_config();
+        let client = TcpStream::connect(&addr)
+            .and_then(move |tcp| {
+                Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+            })
+            .and_then(|tls| tokio::io::write_all(tls, b"hi"))
+            .and_then(|(tls, _)| tokio::io::flush(tls))
+            .and_then(|tls| {
+                // Read the raw records that follow the handshake.
+                let (tcp, _) = tls.into_inner();
+                tokio::io::read_to_end(tcp, Vec::new())
+            })
+            .map(|(_, raw)| {
+                let mut records = &raw[..];
+                let mut last = None;
+                while records.len() >= 5 {
+                    let len = usize::from(records[3]) << 8 | usize::from(records[4]);
+                    last = Some(records[0]);
+                    records = &records[5 + len..];
+                }
+                // ContentType::alert
+                assert_eq!(last, Some(21), "a close_notify must precede EOF");
+            })
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn reports_missing_sni() {
+        use self::rustls::Session;
+        use transport::tls::HasPeerIdentity;
+
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), (conn, _)| {
+                assert_eq!(
+                    conn.peer_identity(),
+                    Conditional::None(ReasonForNoPeerName::NoSniProvided.into())
+                );
+                Ok(())
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let mut config = BAR_NS1
+            .validate()
+            .expect("bar.ns1 must be valid")
+            .tls_client_config()
+            .as_ref()
+            .clone();
+        config.enable_sni = false;
+        let mut session =
+            rustls::ClientSession::new(&Arc::new(config), FOO_NS1.name().as_dns_name_ref());
+        let mut hello = Vec::new();
+        while session.wants_write() {
+            session.write_tls(&mut hello).expect("write_tls");
+        }
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |tcp| tokio::io::write_all(tcp, hello))
+            .and_then(|(tcp, _)| tokio::io::read_to_end(tcp, Vec::new()))
+            .map(|_| ())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn reports_disabled_client_auth() {
+        use transport::tls::HasPeerIdentity;
+
+        let server = FOO_NS1
+            .trust_anchors()
+            .with_client_auth(ClientAuth::None)
+            .certify(FOO_NS1.key(), FOO_NS1.crt())
+            .expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), (conn, _)| {
+                let reason = ReasonForNoPeerName::ClientAuthDisabled;
+                assert_eq!(conn.peer_identity(), Conditional::None(reason.into()));
+                assert_eq!(reason.to_string(), "client_auth_disabled");
+                Ok(())
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        // The client would authenticate if it were asked to.
+        let config = BAR_NS1
+            .validate()
+            .expect("bar.ns1 must be valid")
+            .tls_client_config();
+        let client = TcpStream::connect(&addr)
+            .and_then(move |tcp| {
+                Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+            })
+            // Wait for the server to close the connection.
+            .and_then(|tls| tokio::io::read_to_end(tls.into_inner().0, Vec::new()))
+            .map(|_| ())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn loopback_addresses() {
+        for a in &[
+            "127.0.0.1:80",
+            "127.1.2.3:80",
+            "[::1]:80",
+            "[::ffff:127.0.0.1]:80",
+        ] {
+            assert!(is_loopback(a.parse().unwrap()), "{} is loopback", a);
+        }
+        for a in &[
+            "10.1.2.3:80",
+            "169.254.1.1:80",
+            "[fe80::1]:80",
+            "[::ffff:10.1.2.3]:80",
+            "[::2]:80",
+        ] {
+            assert!(!is_loopback(a.parse().unwrap()), "{} is not loopback", a);
+        }
+    }
+
+    #[test]
+    fn skips_tls_for_loopback() {
+        use std::sync::mpsc;
+        use transport::tls::HasPeerIdentity;
+
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(tls))
+            .expect("must bind")
+            .without_tls_for_loopback();
+        let addr = listen.local_addr();
+
+        let (ids_tx, ids_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(1, ids_tx, |ids, (conn, _)| {
+                ids.send(conn.peer_identity()).unwrap();
+                Ok(ids)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        // The client doesn't attempt a handshake, so the connection is only
+        // accepted if TLS is skipped.
+        let client = TcpStream::connect(&addr)
+            .and_then(|tcp| tokio::io::read_to_end(tcp, Vec::new()))
+            .map(|_| ())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+        assert_eq!(
+            ids_rx.try_recv().expect("connection must be accepted"),
+            Conditional::None(ReasonForNoPeerName::Loopback.into())
+        );
+    }
+
+    #[test]
+    fn plaintext_connections_report_unavailable_identity() {
+        use std::sync::mpsc;
+        use transport::tls::HasPeerIdentity;
+
+        /// Like the proxy's identity before it has been certified.
+        #[derive(Clone)]
+        struct Starting;
+
+        impl HasConfig for Starting {
+            fn tls_server_name(&self) -> identity::Name {
+                FOO_NS1.name()
+            }
+
+            fn tls_server_config(&self) -> Arc<Config> {
+                empty_config()
+            }
+
+            fn tls_unavailable(&self) -> Option<ReasonForNoIdentity> {
+                Some(ReasonForNoIdentity::TrustAnchorsUnavailable)
+            }
+        }
+
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(Starting))
+            .expect("must bind");
+        let addr = listen.local_addr();
+
+        let (ids_tx, ids_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(1, ids_tx, |ids, (conn, _)| {
+                ids.send(conn.peer_identity()).unwrap();
+                Ok(ids)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let client = TcpStream::connect(&addr)
+            .and_then(|tcp| tokio::io::write_all(tcp, b"GET / HTTP/1.1\r\n\r\n"))
+            .and_then(|(tcp, _)| tokio::io::read_to_end(tcp, Vec::new()))
+            .map(|_| ())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+        assert_eq!(
+            ids_rx.try_recv().expect("connection must be accepted"),
+            Conditional::None(ReasonForNoIdentity::TrustAnchorsUnavailable)
+        );
+    }
+
+    #[test]
+    fn accepted_connections_have_client_identity() {
+        use transport::tls::{HasIdentity, HasPeerIdentity, HasStatus, IdentityState, Mode};
+
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(2, 0, |n, (conn, _)| {
+                if n == 0 {
+                    // The first client authenticates with its certificate.
+                    assert_eq!(conn.peer_identity(), tls::peer_identity(BAR_NS1.name()));
+                    assert!(conn.tls_status().is_some());
+                    assert_eq!(
+                        conn.identity_state(),
+                        IdentityState {
+                            status: Conditional::Some(Mode::Mutual),
+                            server: tls::peer_identity(FOO_NS1.name()),
+                            client: tls::peer_identity(BAR_NS1.name()),
+                        }
+                    );
+                } else {
+                    // The second client does not, but the connection is
+                    // still secured with TLS.
+                    let reason = ReasonForNoPeerName::NotProvidedByRemote;
+                    assert_eq!(conn.peer_identity(), Conditional::None(reason.into()));
+                    assert_eq!(conn.tls_status(), Conditional::Some(()));
+                    assert!(conn.negotiated_cipher_suite().is_some());
+                    assert_eq!(
+                        conn.identity_state(),
+                        IdentityState {
+                            status: Conditional::Some(Mode::OneWay),
+                            server: tls::peer_identity(FOO_NS1.name()),
+                            client: Conditional::None(reason.into()),
+                        }
+                    );
+                }
+                Ok(n + 1)
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let connect = move |config: Arc<rustls::ClientConfig>| {
+            TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                })
+                // Wait for the server to close the connection.
+                .and_then(|tls| tokio::io::read_to_end(tls.into_inner().0, Vec::new()))
+                .map(|_| ())
+                .map_err(|e| panic!("client failed: {}", e))
+        };
+        let mtls = BAR_NS1
+            .validate()
+            .expect("bar.ns1 must be valid")
+            .tls_client_config();
+        let one_way = FOO_NS1.trust_anchors().tls_client_config();
+        let clients = connect(mtls).and_then(move |()| connect(one_way));
+
+        tokio::run(server.join(clients).map(|_| ()));
+    }
+
+    #[test]
+    fn client_peer_identity_prefers_dns_names() {
+        let spiffe_id = identity::SpiffeId::parse("spiffe://cluster.local/ns/ns1/sa/foo")
+            .expect("SPIFFE ID must be valid");
+        let identify = |name: Option<identity::Name>, spiffe_id| {
+            Handshake::client_peer_identity(name, spiffe_id, true, ClientAuth::Optional)
+        };
+
+        assert_eq!(
+            identify(Some(FOO_NS1.name()), Some(&spiffe_id)),
+            Conditional::Some(tls::PeerId::Name(FOO_NS1.name()))
+        );
+        assert_eq!(
+            identify(None, Some(&spiffe_id)),
+            Conditional::Some(tls::PeerId::Spiffe(spiffe_id.clone()))
+        );
+        assert_eq!(
+            identify(None, None),
+            Conditional::None(ReasonForNoPeerName::NoNameInCertificate)
+        );
+    }
+
+    #[test]
+    fn no_client_identity_reasons() {
+        let cases = vec![
+            (
+                ClientAuth::Optional,
+                false,
+                ReasonForNoPeerName::NotProvidedByRemote,
+            ),
+            (
+                ClientAuth::Required,
+                false,
+                ReasonForNoPeerName::NotProvidedByRemote,
+            ),
+            (
+                ClientAuth::None,
+                false,
+                ReasonForNoPeerName::ClientAuthDisabled,
+            ),
+            (
+                ClientAuth::Optional,
+                true,
+                ReasonForNoPeerName::NoNameInCertificate,
+            ),
+            (
+                ClientAuth::Required,
+                true,
+                ReasonForNoPeerName::NoNameInCertificate,
+            ),
+        ];
+        for (client_auth, crt_present, reason) in cases {
+            assert_eq!(
+                Handshake::no_client_identity(client_auth, crt_present),
+                reason,
+                "client_auth={:?} crt_present={}",
+                client_auth,
+                crt_present
+            );
+        }
+    }
+
+    #[test]
+    fn connections_know_their_peer_addr() {
+        use std::sync::mpsc;
+        use transport::tls::ReasonForNoIdentity;
+
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        for server_tls in vec![
+            Conditional::Some(tls),
+            Conditional::None(ReasonForNoIdentity::Disabled),
+        ] {
+            let is_tls = server_tls.is_some();
+            let listen =
+                Listen::bind("127.0.0.1:0".parse().unwrap(), server_tls).expect("must bind");
+            let addr = listen.local_addr();
+
+            let (peers_tx, peers_rx) = mpsc::channel();
+            let server = listen
+                .listen_and_fold_n(1, peers_tx, |peers, (conn, remote)| {
+                    let peer = conn.peer_addr().expect("must have a peer address");
+                    assert_eq!(peer, remote);
+                    peers.send(peer).unwrap();
+                    Ok(peers)
+                })
+                .map_err(|e| panic!("server failed: {}", e));
+
+            let (dialer_tx, dialer_rx) = mpsc::channel();
+            let client = TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    dialer_tx.send(tcp.local_addr().unwrap()).unwrap();
+                    if !is_tls {
+                        return Either::A(future::ok(tcp));
+                    }
+                    let config = FOO_NS1.trust_anchors().tls_client_config();
+                    let tls = Connector::from(config)
+                        .connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                        .map(|tls| tls.into_inner().0);
+                    Either::B(tls)
+                })
+                // Wait for the server to close the connection.
+                .and_then(|tcp| tokio::io::read_to_end(tcp, Vec::new()))
+                .map(|_| ())
+                .map_err(|e| panic!("client failed: {}", e));
+
+            tokio::run(server.join(client).map(|_| ()));
+            assert_eq!(peers_rx.recv().unwrap(), dialer_rx.recv().unwrap());
+        }
+    }
+
+    #[test]
+    fn counts_application_bytes() {
+        use std::sync::mpsc;
+
+        const PAYLOAD: &[u8] = b"hello, world";
+
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(tls))
+            .expect("must bind");
+        let addr = listen.local_addr();
+
+        let (counts_tx, counts_rx) = mpsc::channel();
+        let server = listen
+            .listen_and_fold_n(1, counts_tx, |counts, (conn, _)| {
+                let conn = conn.with_byte_counts();
+                tokio::io::read_exact(conn, vec![0; PAYLOAD.len()])
+                    .and_then(|(conn, buf)| tokio::io::write_all(conn, buf))
+                    .and_then(|(conn, _)| tokio::io::flush(conn))
+                    .map(move |conn| {
+                        counts.send(conn.byte_counts()).unwrap();
+                        counts
+                    })
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let client = TcpStream::connect(&addr)
+            .and_then(|tcp| {
+                let config = FOO_NS1.trust_anchors().tls_client_config();
+                Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+            })
+            .and_then(|tls| tokio::io::write_all(tls, PAYLOAD))
+            .and_then(|(tls, _)| tokio::io::read_exact(tls, vec![0; PAYLOAD.len()]))
+            .map(|(_, echoed)| assert_eq!(&echoed[..], PAYLOAD))
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+        let len = PAYLOAD.len() as u64;
+        assert_eq!(counts_rx.recv().unwrap(), (len, len));
+    }
+
+    #[test]
+    fn logs_failed_handshakes() {
+        use self::rustls::Session;
+        use logging::test_util::capture;
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut session = rustls::ClientSession::new(
+            &FOO_NS1.trust_anchors().tls_client_config(),
+            FOO_NS1.name().as_dns_name_ref(),
+        );
+        let mut hello = Vec::new();
+        while session.wants_write() {
+            session.write_tls(&mut hello).expect("write_tls");
+        }
+
+        // The handshake is driven on this thread so that its logs are
+        // captured.
+        let logs = capture(|| {
+            let mut rt = Runtime::new().expect("runtime");
+            let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+            let handshake = future::lazy(move || {
+                let listener =
+                    TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("must bind");
+                let addr = listener.local_addr().expect("must have an address");
+                // The client hangs up after sending its ClientHello.
+                let client = TcpStream::connect(&addr)
+                    .and_then(move |tcp| tokio::io::write_all(tcp, hello))
+                    .and_then(|(tcp, _)| tokio::io::shutdown(tcp))
+                    .then(|_| Ok(()));
+                tokio::executor::current_thread::spawn(client);
+
+                listener
+                    .incoming()
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .and_then(move |(socket, _)| {
+                        let socket = socket.expect("must accept");
+                        Handshake::new(
+                            socket,
+                            &tls,
+                            Arc::new(()),
+                            DEFAULT_PEEK_CAPACITY,
+                            DEFAULT_PEEK_TIMEOUT,
+                            DEFAULT_HANDSHAKE_TIMEOUT,
+                        )
+                    })
+            });
+            assert!(rt.block_on(handshake).is_err(), "handshake must fail");
+        });
+
+        let sni = format!("sni={}", FOO_NS1.name());
+        assert!(
+            logs.iter()
+                .any(|l| l.starts_with("DEBUG tls handshake failed;")
+                    && l.contains("peer=127.0.0.1:")
+                    && l.contains(&sni)
+                    && l.contains("outcome=failed")),
+            "logs must describe the failed handshake: {:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn connections_expose_negotiated_cipher_suite() {
+        use self::rustls::internal::msgs::enums::CipherSuite;
+        use tokio::runtime::current_thread::Runtime;
+
+        let suite = rustls::ALL_CIPHERSUITES
+            .iter()
+            .find(|s| s.suite == CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256)
+            .expect("suite must be supported");
+        let mut client = FOO_NS1.trust_anchors().tls_client_config().as_ref().clone();
+        client.ciphersuites = vec![*suite];
+        let client = Arc::new(client);
+
+        let mut rt = Runtime::new().expect("runtime");
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let accept = future::lazy(move || {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("must bind");
+            let addr = listener.local_addr().expect("must have an address");
+            // The plaintext client connects first, so that it's accepted
+            // first.
+            let clients = TcpStream::connect(&addr)
+                .and_then(move |plain| {
+                    TcpStream::connect(&addr)
+                        .and_then(move |tcp| {
+                            Connector::from(client).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                        })
+                        .and_then(|tls| tokio::io::read_to_end(tls, Vec::new()))
+                        .map(move |_| drop(plain))
+                })
+                .then(|_| Ok(()));
+            tokio::executor::current_thread::spawn(clients);
+
+            listener
+                .incoming()
+                .take(2)
+                .collect()
+                .and_then(move |mut sockets| {
+                    let socket = sockets.pop().expect("must accept");
+                    let plain = sockets.pop().expect("must accept");
+                    let plain = Connection::plain(plain, ReasonForNoPeerName::Loopback.into());
+                    Handshake::new(
+                        socket,
+                        &tls,
+                        Arc::new(()),
+                        DEFAULT_PEEK_CAPACITY,
+                        DEFAULT_PEEK_TIMEOUT,
+                        DEFAULT_HANDSHAKE_TIMEOUT,
+                    )
+                    .map(move |tls| (plain, tls))
+                })
+        });
+        let (plain, tls) = rt.block_on(accept).expect("handshake must succeed");
+
+        assert_eq!(
+            tls.negotiated_cipher_suite().map(|cs| cs.suite),
+            Some(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256)
+        );
+        assert!(plain.negotiated_cipher_suite().is_none());
+    }
+
+    #[test]
+    fn peer_identity_is_not_reparsed() {
+        use tokio::runtime::current_thread::Runtime;
+        use transport::tls::HasPeerIdentity;
+
+        // The handshake is driven on this thread so that its parses are
+        // counted.
+        let mut rt = Runtime::new().expect("runtime");
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let client = BAR_NS1
+            .validate()
+            .expect("bar.ns1 must be valid")
+            .tls_client_config();
+        let handshake = future::lazy(move || {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("must bind");
+            let addr = listener.local_addr().expect("must have an address");
+            let client = TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    Connector::from(client).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                })
+                .and_then(|tls| tokio::io::read_to_end(tls, Vec::new()))
+                .then(|_| Ok(()));
+            tokio::executor::current_thread::spawn(client);
+
+            listener
+                .incoming()
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(socket, _)| {
+                    let socket = socket.expect("must accept");
+                    Handshake::new(
+                        socket,
+                        &tls,
+                        Arc::new(()),
+                        DEFAULT_PEEK_CAPACITY,
+                        DEFAULT_PEEK_TIMEOUT,
+                        DEFAULT_HANDSHAKE_TIMEOUT,
+                    )
+                })
+        });
+        let before = crts_parsed();
+        let conn = rt.block_on(handshake).expect("handshake must succeed");
+        let parsed = crts_parsed();
+        assert!(
+            parsed > before,
+            "the handshake must parse the client's certificate"
+        );
+
+        for _ in 0..100 {
+            assert_eq!(conn.peer_identity(), tls::peer_identity(BAR_NS1.name()));
+        }
+        assert_eq!(
+            crts_parsed(),
+            parsed,
+            "peer_identity must not parse certificates"
+        );
+    }
+
+    #[test]
+    fn zero_max_concurrent_handshakes_is_unbounded() {
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind")
+            .with_max_concurrent_handshakes(0);
+        assert_eq!(listen.max_concurrent_handshakes, usize::max_value());
+    }
+
+    #[test]
+    fn limits_concurrent_handshakes() {
+        use std::sync::Mutex;
+
+        const CLIENTS: u64 = 8;
+        const MAX_HANDSHAKES: usize = 2;
+
+        /// Tracks the number of handshakes in progress, and the most that
+        /// have been in progress at once.
+        #[derive(Default)]
+        struct InFlight(Mutex<(usize, usize)>);
+
+        impl InFlight {
+            fn resolved(&self) {
+                self.0.lock().unwrap().0 -= 1;
+            }
+        }
+
+        impl HandshakeMetrics for InFlight {
+            fn started(&self) {
+                let mut n = self.0.lock().unwrap();
+                n.0 += 1;
+                n.1 = ::std::cmp::max(n.0, n.1);
+            }
+            fn succeeded(&self) {
+                self.resolved()
+            }
+            fn failed_client_auth(&self) {
+                self.resolved()
+            }
+            fn failed_server_auth(&self) {
+                self.resolved()
+            }
+            fn timed_out(&self) {
+                self.resolved()
+            }
+            fn failed(&self) {
+                self.resolved()
+            }
+        }
+
+        let in_flight = Arc::new(InFlight::default());
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind")
+            .with_handshake_metrics(in_flight.clone())
+            .with_max_concurrent_handshakes(MAX_HANDSHAKES);
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(CLIENTS, 0, |n, _| Ok(n + 1))
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let clients = (0..CLIENTS).map(move |_| {
+            let config = FOO_NS1.trust_anchors().tls_client_config();
+            TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                })
+                // Wait for the server to close the connection.
+                .and_then(|tls| tokio::io::read_to_end(tls.into_inner().0, Vec::new()))
+                .map(|_| ())
+                .map_err(|e| panic!("client failed: {}", e))
+        });
+
+        tokio::run(server.join(future::join_all(clients)).map(|_| ()));
+
+        let (current, max) = *in_flight.0.lock().unwrap();
+        assert_eq!(current, 0, "all handshakes must have resolved");
+        assert!(max >= 1, "handshakes must have been observed");
+        assert!(
+            max <= MAX_HANDSHAKES,
+            "at most {} handshakes may be in progress at once; saw {}",
+            MAX_HANDSHAKES,
+            max
+        );
+    }
+
+    /// Sends `input` to a `Handshake` with the given limits, returning the
+    /// error that it fails with.
+    fn peek_client_hello(input: Vec<u8>, capacity: usize, timeout: Duration) -> io::Error {
+        use tokio::runtime::current_thread::Runtime;
+
+        let mut rt = Runtime::new().expect("runtime");
+        let tls = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let handshake = future::lazy(move || {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).expect("must bind");
+            let addr = listener.local_addr().expect("must have an address");
+            let client = TcpStream::connect(&addr)
+                .and_then(move |tcp| tokio::io::write_all(tcp, input))
+                // Hold the connection open until the server closes it.
+                .and_then(|(tcp, _)| tokio::io::read_to_end(tcp, Vec::new()))
+                .then(|_| Ok(()));
+            tokio::executor::current_thread::spawn(client);
+
+            listener
+                .incoming()
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(move |(socket, _)| {
+                    let socket = socket.expect("must accept");
+                    Handshake::new(
+                        socket,
+                        &tls,
+                        Arc::new(()),
+                        capacity,
+                        timeout,
+                        DEFAULT_HANDSHAKE_TIMEOUT,
+                    )
+                })
+        });
+        match rt.block_on(handshake) {
+            Ok(_) => panic!("handshake must fail"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn times_out_slow_client_hello() {
+        // Only the start of a TLS record is sent.
+        let partial = vec![0x16, 0x03, 0x01, 0x00, 0x2f, 0x01];
+        let e = peek_client_hello(partial, DEFAULT_PEEK_CAPACITY, Duration::from_millis(100));
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            e.get_ref()
+                .and_then(|e| e.downcast_ref::<ClientHelloError>()),
+            Some(&ClientHelloError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn rejects_large_client_hello() {
+        // A TLS record that claims to be 4KB long.
+        let mut large = vec![0x16, 0x03, 0x01, 0x10, 0x00, 0x01];
+        large.resize(1024, 0);
+        let e = peek_client_hello(large, 256, DEFAULT_PEEK_TIMEOUT);
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref()
+                .and_then(|e| e.downcast_ref::<ClientHelloError>()),
+            Some(&ClientHelloError::TooLarge)
+        );
+    }
+
+    /// Builds a minimal ClientHello with the given SNI.
+    fn client_hello_with_sni(sni: &[u8]) -> Vec<u8> {
+        fn vector(body: Vec<u8>) -> Vec<u8> {
+            let mut v = vec![(body.len() >> 8) as u8, body.len() as u8];
+            v.extend(body);
+            v
+        }
+
+        let mut host_name = vec![0x00]; // NameType::host_name
+        host_name.extend(vector(sni.to_vec()));
+        let mut extension = vec![0x00, 0x00]; // ExtensionType::server_name
+        extension.extend(vector(vector(host_name)));
+
+        let mut hello = vec![0x03, 0x03]; // version
+        hello.extend(&[0; 32]); // random
+        hello.push(0x00); // session_id
+        hello.extend(&[0x00, 0x02, 0xc0, 0x2b]); // cipher_suites
+        hello.extend(&[0x01, 0x00]); // compression_methods
+        hello.extend(vector(extension));
+
+        let mut handshake = vec![0x01, 0x00]; // client_hello and a 24-bit length
+        handshake.extend(vector(hello));
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend(vector(handshake));
+        record
+    }
+
+    #[test]
+    fn reports_invalid_sni() {
+        use transport::tls::HasPeerIdentity;
+
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind");
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), (conn, _)| {
+                assert_eq!(
+                    conn.peer_identity(),
+                    Conditional::None(ReasonForNoPeerName::InvalidSni.into())
+                );
+                Ok(())
+            })
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let hello = client_hello_with_sni(b"foo.bar.");
+        assert_eq!(
+            conditional_accept::read_client_hello(&hello),
+            Some(conditional_accept::ClientHello::InvalidSni)
+        );
+        let client = TcpStream::connect(&addr)
+            .and_then(move |tcp| tokio::io::write_all(tcp, hello))
+            .and_then(|(tcp, _)| tokio::io::read_to_end(tcp, Vec::new()))
+            .map(|_| ())
+            .map_err(|e| panic!("client failed: {}", e));
+
+        tokio::run(server.join(client).map(|_| ()));
+    }
+
+    #[test]
+    fn records_handshake_outcomes() {
+        let counts = Arc::new(Counts::default());
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind")
+            .with_handshake_metrics(counts.clone());
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(2, (), |(), _| Ok(()))
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let connect = move |config: Arc<rustls::ClientConfig>| {
+            TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    Connector::from(config).connect(FOO_NS1.name().as_dns_name_ref(), tcp)
+                })
+                .then(|r| Ok::<_, ()>(r.is_ok()))
+        };
+
+        // The first client trusts the server's CA; the second does not, so
+        // it rejects the server's certificate.
+        let valid = BAR_NS1.validate().expect("bar.ns1 must be valid");
+        let clients = connect(valid.tls_client_config()).and_then(move |ok| {
+            assert!(ok, "handshake must succeed");
+            connect(FOO_NS1_CA2.trust_anchors().tls_client_config()).map(|ok| {
+                assert!(!ok, "handshake must fail");
+            })
+        });
+
+        tokio::run(server.join(clients).map(|_| ()));
+
+        assert_eq!(counts.succeeded.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.failed_server_auth.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.failed.load(Ordering::SeqCst), 0);
+        assert_eq!(counts.failed_client_auth.load(Ordering::SeqCst), 0);
+        assert_eq!(counts.timed_out.load(Ordering::SeqCst), 0);
+    }
+
+    /// Sends `input` to a `Listen` with short timeouts and then stalls,
+    /// returning the handshake outcomes that were recorded.
+    fn stalled_handshake_counts(input: Vec<u8>) -> Arc<Counts> {
+        let counts = Arc::new(Counts::default());
+        let server = FOO_NS1.validate().expect("foo.ns1 must be valid");
+        let listen = Listen::bind("127.0.0.1:0".parse().unwrap(), Conditional::Some(server))
+            .expect("must bind")
+            .with_handshake_metrics(counts.clone())
+            .with_peek_timeout(Duration::from_millis(100))
+            .with_handshake_timeout(Duration::from_millis(100));
+        let addr = listen.local_addr();
+        let server = listen
+            .listen_and_fold_n(1, (), |(), _| Ok(()))
+            .map_err(|e| panic!("server failed: {}", e));
+
+        let client = TcpStream::connect(&addr)
+            .and_then(move |tcp| tokio::io::write_all(tcp, input))
+            // Hold the connection open until the server closes it.
+            .and_then(|(tcp, _)| tokio::io::read_to_end(tcp, Vec::new()))
+            .then(|_| Ok(()));
+
+        tokio::run(server.join(client).map(|_| ()));
+        counts
+    }
+
+    #[test]
+    fn records_client_hello_timeouts() {
+        // Only the start of a TLS record is sent.
+        let partial = vec![0x16, 0x03, 0x01, 0x00, 0x2f, 0x01];
+        let counts = stalled_handshake_counts(partial);
+        assert_eq!(counts.timed_out.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.failed.load(Ordering::SeqCst), 0);
+        assert_eq!(counts.succeeded.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn records_handshake_timeouts() {
+        use self::rustls::Session;
+
+        // A complete ClientHello for our name is sent, but the client never
+        // responds to the server's reply.
+        let config = FOO_NS1.trust_anchors().tls_client_config();
+        let mut session = rustls::ClientSession::new(&config, FOO_NS1.name().as_dns_name_ref());
+        let mut hello = Vec::new();
+        session
+            .write_tls(&mut hello)
+            .expect("must write ClientHello");
+        let counts = stalled_handshake_counts(hello);
+        assert_eq!(counts.timed_out.load(Ordering::SeqCst), 1);
+        assert_eq!(counts.failed.load(Ordering::SeqCst), 0);
+        assert_eq!(counts.succeeded.load(Ordering::SeqCst), 0);
     }
 }
@@ -6,17 +6,27 @@ extern crate webpki;
 
 use self::tokio_rustls::{Accept, TlsAcceptor as Acceptor, TlsConnector as Connector};
 use std::fmt;
+use std::net::SocketAddr;
 
 use identity;
 
 pub mod client;
 mod conditional_accept;
 mod connection;
+mod idle_timeout;
 mod io;
 pub mod listen;
+mod resumption;
 
+use self::connection::Negotiated;
 use self::io::TlsIo;
 
+#[cfg(fuzzing)]
+pub use self::conditional_accept::fuzz_client_hello;
+pub use self::conditional_accept::{
+    detect, match_client_hello, peek_client_hello, ClientHello, Detect, Detected, Match,
+    PeekClientHello, SelectConfig,
+};
 pub use self::connection::Connection;
 pub use self::listen::Listen;
 pub use self::rustls::TLSError as Error;
@@ -25,21 +35,79 @@ pub use self::rustls::TLSError as Error;
 /// not, the reason why.
 pub type Conditional<T> = ::Conditional<T, ReasonForNoIdentity>;
 
-pub type PeerIdentity = Conditional<identity::Name>;
+pub type PeerIdentity = Conditional<PeerId>;
 pub type Status = Conditional<()>;
 
+/// The identity that a server is expected to have.
+///
+/// Servers are verified by their DNS names, so, unlike a `PeerIdentity`,
+/// this is always a `Name`.
+pub type ServerIdentity = Conditional<identity::Name>;
+
+/// A peer's identity.
+///
+/// Peers are usually identified by a DNS name, but a client whose
+/// certificate only has a `spiffe://` URI SAN is identified by its SPIFFE ID.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PeerId {
+    Name(identity::Name),
+    Spiffe(identity::SpiffeId),
+}
+
+/// Indicates that a cipher suite allowlist can't be used.
+#[derive(Debug)]
+pub enum InvalidCipherSuites {
+    /// No cipher suites were allowed.
+    Empty,
+
+    /// A cipher suite can't be used with the proxy's keys.
+    Unsupported(rustls::internal::msgs::enums::CipherSuite),
+}
+
 pub trait HasPeerIdentity {
     fn peer_identity(&self) -> PeerIdentity;
 }
 
+/// Describes the identity that a target's server must be verified as.
+pub trait HasServerIdentity {
+    fn server_identity(&self) -> ServerIdentity;
+}
+
 pub trait HasStatus {
     fn tls_status(&self) -> Status;
 }
 
-impl<T: HasPeerIdentity> HasStatus for T {
-    fn tls_status(&self) -> Status {
-        self.peer_identity().map(|_| ())
-    }
+/// Describes the identities of both peers of a connection.
+pub trait HasIdentity {
+    fn identity_state(&self) -> IdentityState;
+}
+
+/// The identities of a connection's server and client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IdentityState {
+    /// Whether the connection is secured with TLS and, if so, whether the
+    /// client authenticated as well as the server.
+    pub status: Conditional<Mode>,
+    pub server: PeerIdentity,
+    pub client: PeerIdentity,
+}
+
+/// Describes a TLS handshake in logs, as `key=value` fields, so that the
+/// messages for a handshake can be correlated.
+#[derive(Debug)]
+struct HandshakeFields {
+    peer: Option<SocketAddr>,
+    sni: identity::Name,
+}
+
+/// Describes which peers of a TLS connection presented certificates.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Mode {
+    /// Only the server presented a certificate.
+    OneWay,
+
+    /// Both the server and the client presented certificates.
+    Mutual,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -47,8 +115,22 @@ pub enum ReasonForNoIdentity {
     /// Identity is administratively disabled.
     Disabled,
 
+    /// Trust anchors or credentials have not been loaded yet, e.g. while the
+    /// proxy is starting, so peers' identities can't be verified.
+    TrustAnchorsUnavailable,
+
     /// The remote peer does not have a known identity name.
     NoPeerName(ReasonForNoPeerName),
+
+    /// The certificate has expired.
+    CertificateExpired,
+
+    /// The certificate was not issued by a trust anchor for the expected
+    /// name.
+    CertificateNotTrusted,
+
+    /// The certificate is invalid for any other reason.
+    InvalidCertificate,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -71,6 +153,121 @@ pub enum ReasonForNoPeerName {
 
     // Identity was not provided by the remote peer.
     NotProvidedByRemote,
+
+    /// The remote peer sent a ClientHello without an SNI name, so the server
+    /// had no certificate to present for it.
+    NoSniProvided,
+
+    /// The remote peer sent a ClientHello with a malformed SNI name, e.g.
+    /// one with a trailing dot.
+    InvalidSni,
+
+    /// The remote peer sent a TLS record that isn't a ClientHello we can
+    /// parse, e.g. because its lengths are inconsistent.
+    MalformedClientHello,
+
+    /// The server has client authentication disabled, so it never asked the
+    /// remote peer for a certificate.
+    ClientAuthDisabled,
+
+    /// The remote peer presented a certificate that doesn't include a DNS
+    /// name or a SPIFFE ID.
+    NoNameInCertificate,
+}
+
+/// Checks that each suite in `allowed` can be negotiated with our keys,
+/// returning the allowlist in order.
+fn ciphersuites(
+    allowed: &[&'static rustls::SupportedCipherSuite],
+) -> Result<Vec<&'static rustls::SupportedCipherSuite>, InvalidCipherSuites> {
+    if allowed.is_empty() {
+        return Err(InvalidCipherSuites::Empty);
+    }
+
+    let supported = identity::tls_ciphersuites();
+    for cs in allowed {
+        if !supported.iter().any(|s| s.suite == cs.suite) {
+            return Err(InvalidCipherSuites::Unsupported(cs.suite));
+        }
+    }
+    Ok(allowed.to_vec())
+}
+
+/// Returns true if `alert` indicates that the peer rejected our certificate.
+fn is_certificate_alert(alert: rustls::internal::msgs::enums::AlertDescription) -> bool {
+    use self::rustls::internal::msgs::enums::AlertDescription;
+
+    match alert {
+        AlertDescription::BadCertificate
+        | AlertDescription::UnsupportedCertificate
+        | AlertDescription::CertificateRevoked
+        | AlertDescription::CertificateExpired
+        | AlertDescription::CertificateUnknown
+        | AlertDescription::UnknownCA => true,
+        _ => false,
+    }
+}
+
+/// Returns the identity of a peer named `name`.
+pub fn peer_identity(name: identity::Name) -> PeerIdentity {
+    ::Conditional::Some(PeerId::Name(name))
+}
+
+/// Returns the identity of a peer that could not be identified.
+pub fn no_peer_identity<R: Into<ReasonForNoIdentity>>(reason: R) -> PeerIdentity {
+    ::Conditional::None(reason.into())
+}
+
+/// Formats the peer's name or, if it has none, the reason why.
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ::Conditional::Some(name) => fmt::Display::fmt(name, f),
+            ::Conditional::None(r) => fmt::Display::fmt(r, f),
+        }
+    }
+}
+
+// === impl PeerId ===
+
+impl PeerId {
+    /// Returns the peer's DNS name, unless it is only identified by a SPIFFE
+    /// ID.
+    pub fn name(&self) -> Option<&identity::Name> {
+        match self {
+            PeerId::Name(n) => Some(n),
+            PeerId::Spiffe(_) => None,
+        }
+    }
+
+    /// Returns the peer's SPIFFE ID, if it is identified by one.
+    pub fn spiffe_id(&self) -> Option<&identity::SpiffeId> {
+        match self {
+            PeerId::Name(_) => None,
+            PeerId::Spiffe(id) => Some(id),
+        }
+    }
+}
+
+impl From<identity::Name> for PeerId {
+    fn from(n: identity::Name) -> Self {
+        PeerId::Name(n)
+    }
+}
+
+impl From<identity::SpiffeId> for PeerId {
+    fn from(id: identity::SpiffeId) -> Self {
+        PeerId::Spiffe(id)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerId::Name(n) => fmt::Display::fmt(n, f),
+            PeerId::Spiffe(id) => fmt::Display::fmt(id, f),
+        }
+    }
 }
 
 impl fmt::Display for Status {
@@ -82,33 +279,213 @@ impl fmt::Display for Status {
     }
 }
 
+// === impl HandshakeFields ===
+
+impl fmt::Display for HandshakeFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "peer={}", peer)?,
+            None => write!(f, "peer=-")?,
+        }
+        write!(f, " sni={}", self.sni.log(identity::LogPolicy::current()))
+    }
+}
+
+impl fmt::Display for InvalidCipherSuites {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidCipherSuites::Empty => write!(f, "no cipher suites are allowed"),
+            InvalidCipherSuites::Unsupported(cs) => write!(f, "unsupported cipher suite: {:?}", cs),
+        }
+    }
+}
+
+impl ::std::error::Error for InvalidCipherSuites {}
+
 impl From<ReasonForNoPeerName> for ReasonForNoIdentity {
     fn from(r: ReasonForNoPeerName) -> Self {
         ReasonForNoIdentity::NoPeerName(r)
     }
 }
 
-impl fmt::Display for ReasonForNoIdentity {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+/// Describes why a certificate was rejected, so that invalid certificates are
+/// reported consistently.
+impl From<identity::InvalidCrtKind> for ReasonForNoIdentity {
+    fn from(kind: identity::InvalidCrtKind) -> Self {
+        use identity::InvalidCrtKind;
+
+        match kind {
+            InvalidCrtKind::Expired => ReasonForNoIdentity::CertificateExpired,
+            InvalidCrtKind::UnknownIssuer
+            | InvalidCrtKind::NotValidForName
+            | InvalidCrtKind::BadSignature => ReasonForNoIdentity::CertificateNotTrusted,
+            InvalidCrtKind::Other => ReasonForNoIdentity::InvalidCertificate,
+        }
+    }
+}
+
+impl<'a> From<&'a identity::InvalidCrt> for ReasonForNoIdentity {
+    fn from(e: &'a identity::InvalidCrt) -> Self {
+        e.kind().into()
+    }
+}
+
+/// Returns the `tls` metric label for `status`.
+///
+/// Labels are fixed strings, so that they don't add to the cardinality of
+/// metrics: `"true"` for TLS connections, `"no_identity"` for connections
+/// whose peer has no name, and otherwise the reason that TLS was not used.
+pub fn tls_status_label(status: &Status) -> &'static str {
+    match status {
+        ::Conditional::Some(()) => "true",
+        ::Conditional::None(ReasonForNoIdentity::NoPeerName(_)) => "no_identity",
+        ::Conditional::None(r) => r.as_str(),
+    }
+}
+
+/// Returns the `tls` metric label for a peer's identity, as
+/// `tls_status_label` does.
+///
+/// The peer's name is never included, since that could add a label value
+/// for every peer.
+pub fn peer_identity_label(pid: &PeerIdentity) -> &'static str {
+    match pid {
+        ::Conditional::Some(_) => tls_status_label(&::Conditional::Some(())),
+        ::Conditional::None(r) => tls_status_label(&::Conditional::None(*r)),
+    }
+}
+
+impl ReasonForNoIdentity {
+    /// Returns the reason as a fixed string, e.g. for metric labels.
+    pub fn as_str(&self) -> &'static str {
         match self {
-            ReasonForNoIdentity::Disabled => write!(f, "disabled"),
-            ReasonForNoIdentity::NoPeerName(n) => write!(f, "{}", n),
+            ReasonForNoIdentity::Disabled => "disabled",
+            ReasonForNoIdentity::TrustAnchorsUnavailable => "trust_anchors_unavailable",
+            ReasonForNoIdentity::CertificateExpired => "certificate_expired",
+            ReasonForNoIdentity::CertificateNotTrusted => "certificate_not_trusted",
+            ReasonForNoIdentity::InvalidCertificate => "invalid_certificate",
+            ReasonForNoIdentity::NoPeerName(n) => n.as_str(),
         }
     }
 }
 
-impl fmt::Display for ReasonForNoPeerName {
+impl fmt::Display for ReasonForNoIdentity {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ReasonForNoPeerName {
+    /// Returns the reason as a fixed string, e.g. for metric labels.
+    pub fn as_str(&self) -> &'static str {
         match self {
-            ReasonForNoPeerName::Loopback => write!(f, "loopback"),
-            ReasonForNoPeerName::NoAuthorityInHttpRequest => {
-                write!(f, "no_authority_in_http_request")
-            }
-            ReasonForNoPeerName::NotHttp => write!(f, "not_http"),
-            ReasonForNoPeerName::NotProvidedByRemote => write!(f, "not_provided_by_remote"),
+            ReasonForNoPeerName::Loopback => "loopback",
+            ReasonForNoPeerName::NoAuthorityInHttpRequest => "no_authority_in_http_request",
+            ReasonForNoPeerName::NotHttp => "not_http",
+            ReasonForNoPeerName::NotProvidedByRemote => "not_provided_by_remote",
+            ReasonForNoPeerName::NoSniProvided => "no_sni_provided",
+            ReasonForNoPeerName::InvalidSni => "invalid_sni",
+            ReasonForNoPeerName::MalformedClientHello => "malformed_client_hello",
+            ReasonForNoPeerName::ClientAuthDisabled => "client_auth_disabled",
+            ReasonForNoPeerName::NoNameInCertificate => "no_name_in_certificate",
             ReasonForNoPeerName::NotProvidedByServiceDiscovery => {
-                write!(f, "not_provided_by_service_discovery")
+                "not_provided_by_service_discovery"
             }
         }
     }
 }
+
+impl fmt::Display for ReasonForNoPeerName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fmt_peer_identity() {
+        let name = identity::Name::from_hostname(b"foo.ns1.svc.cluster.local").unwrap();
+        assert_eq!(peer_identity(name).to_string(), "foo.ns1.svc.cluster.local");
+        let id = identity::SpiffeId::parse("spiffe://cluster.local/ns/ns1/sa/foo").unwrap();
+        let spiffe: PeerIdentity = ::Conditional::Some(id.into());
+        assert_eq!(spiffe.to_string(), "spiffe://cluster.local/ns/ns1/sa/foo");
+        assert_eq!(
+            no_peer_identity(ReasonForNoPeerName::NotProvidedByRemote).to_string(),
+            "not_provided_by_remote"
+        );
+        assert_eq!(
+            no_peer_identity(ReasonForNoIdentity::Disabled).to_string(),
+            "disabled"
+        );
+    }
+
+    #[test]
+    fn invalid_crt_reasons() {
+        use identity::InvalidCrtKind;
+
+        let reasons = vec![
+            (InvalidCrtKind::Expired, "certificate_expired"),
+            (InvalidCrtKind::UnknownIssuer, "certificate_not_trusted"),
+            (InvalidCrtKind::NotValidForName, "certificate_not_trusted"),
+            (InvalidCrtKind::BadSignature, "certificate_not_trusted"),
+            (InvalidCrtKind::Other, "invalid_certificate"),
+        ];
+        for (kind, label) in reasons {
+            let reason = ReasonForNoIdentity::from(kind);
+            assert_eq!(reason.to_string(), label, "{:?}", kind);
+            assert_eq!(no_peer_identity(reason).to_string(), label, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let name = identity::Name::from_hostname(b"foo.ns1.svc.cluster.local").unwrap();
+        assert_eq!(peer_identity_label(&peer_identity(name)), "true");
+        assert_eq!(tls_status_label(&::Conditional::Some(())), "true");
+
+        let reasons = vec![
+            (ReasonForNoIdentity::Disabled, "disabled"),
+            (
+                ReasonForNoIdentity::TrustAnchorsUnavailable,
+                "trust_anchors_unavailable",
+            ),
+            (
+                ReasonForNoIdentity::CertificateExpired,
+                "certificate_expired",
+            ),
+            (
+                ReasonForNoIdentity::CertificateNotTrusted,
+                "certificate_not_trusted",
+            ),
+            (
+                ReasonForNoIdentity::InvalidCertificate,
+                "invalid_certificate",
+            ),
+            (ReasonForNoPeerName::Loopback.into(), "no_identity"),
+            (ReasonForNoPeerName::NotHttp.into(), "no_identity"),
+            (
+                ReasonForNoPeerName::NotProvidedByRemote.into(),
+                "no_identity",
+            ),
+            (ReasonForNoPeerName::InvalidSni.into(), "no_identity"),
+            (
+                ReasonForNoPeerName::MalformedClientHello.into(),
+                "no_identity",
+            ),
+        ];
+        for (reason, label) in reasons {
+            assert_eq!(tls_status_label(&::Conditional::None(reason)), label);
+            assert_eq!(peer_identity_label(&no_peer_identity(reason)), label);
+            assert_eq!(reason.as_str(), reason.to_string());
+        }
+
+        assert_eq!(ReasonForNoPeerName::Loopback.as_str(), "loopback");
+        assert_eq!(
+            ReasonForNoIdentity::from(ReasonForNoPeerName::NoSniProvided).as_str(),
+            "no_sni_provided"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Observes whether TLS handshakes resume sessions.
+//!
+//! Rustls (as of 0.15) doesn't report whether a handshake resumed a session,
+//! but it only resumes sessions that it finds in its session storage. The
+//! storage is shared by every connection with the same configuration, so
+//! lookups are attributed to the handshake that is being polled on the
+//! current thread: Rustls only reads the storage while it processes the
+//! peer's messages, which it does as the handshake future is polled.
+//!
+//! Clients read the storage as they start the handshake, so they know that
+//! they offered a session rather than that the server resumed it. A server
+//! that resumes a session doesn't send its certificate, so clients also
+//! observe whether the server's certificate was verified: handshakes that
+//! offered a session and didn't verify a certificate were resumed.
+
+use std::cell::Cell;
+use std::sync::Arc;
+
+use super::{rustls, webpki};
+
+/// Wraps a server's session storage, so that `observe` reports handshakes
+/// that found the client's session.
+pub(super) struct ServerSessions(pub Arc<dyn rustls::StoresServerSessions>);
+
+/// Wraps a client's session storage, so that `observe` reports handshakes
+/// that offered a session.
+pub(super) struct ClientSessions(pub Arc<dyn rustls::StoresClientSessions>);
+
+/// Wraps a client's server certificate verifier, so that `observe` reports
+/// handshakes that verified the server's certificate.
+pub(super) struct ServerVerifier(pub Arc<dyn rustls::ServerCertVerifier>);
+
+/// What `observe` saw while a handshake was polled.
+#[derive(Copy, Clone, Debug, Default)]
+pub(super) struct Observed {
+    /// A stored session was found.
+    pub found: bool,
+
+    /// The server's certificate was verified.
+    pub verified: bool,
+}
+
+thread_local! {
+    static OBSERVED: Cell<Observed> = Cell::new(Observed::default());
+}
+
+/// Calls `poll`, returning what it did with the session storage and the
+/// server certificate verifier.
+pub(super) fn observe<T, F: FnOnce() -> T>(poll: F) -> (T, Observed) {
+    let prior = OBSERVED.with(|o| o.replace(Observed::default()));
+    let out = poll();
+    let observed = OBSERVED.with(|o| o.replace(prior));
+    (out, observed)
+}
+
+fn found() {
+    OBSERVED.with(|o| {
+        o.set(Observed {
+            found: true,
+            ..o.get()
+        })
+    });
+}
+
+fn verified() {
+    OBSERVED.with(|o| {
+        o.set(Observed {
+            verified: true,
+            ..o.get()
+        })
+    });
+}
+
+// === impl ServerSessions ===
+
+impl rustls::StoresServerSessions for ServerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.0.get(key);
+        if value.is_some() {
+            found();
+        }
+        value
+    }
+}
+
+// === impl ClientSessions ===
+
+impl rustls::StoresClientSessions for ClientSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.0.get(key);
+        // Clients also store key exchange hints, which aren't sessions.
+        if value.is_some() && key.starts_with(b"session") {
+            found();
+        }
+        value
+    }
+}
+
+// === impl ServerVerifier ===
+
+impl rustls::ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        verified();
+        self.0
+            .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+    }
+}